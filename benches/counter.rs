@@ -0,0 +1,47 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Incrementing one shared atomic against the per-CPU sharded `Counter`, from several threads.
+//! On a single-CPU host `Counter` has one shard, and both should perform alike.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use etherip::stats::Counter;
+
+/// Increments by each thread per iteration.
+const INCREMENTS: u64 = 10_000;
+
+/// Run `increment` `INCREMENTS` times on each of `threads` threads.
+fn contend(threads: usize, increment: impl Fn() + Sync) {
+  std::thread::scope(|scope| {
+    for _ in 0..threads {
+      scope.spawn(|| {
+        for _ in 0..INCREMENTS {
+          increment();
+        }
+      });
+    }
+  });
+}
+
+fn counters(c: &mut Criterion) {
+  let mut group = c.benchmark_group("counter");
+  for threads in [1, 2, 4, 8] {
+    group.throughput(Throughput::Elements(threads as u64 * INCREMENTS));
+    group.bench_with_input(BenchmarkId::new("atomic", threads), &threads, |b, &threads| {
+      let counter = AtomicU64::new(0);
+      b.iter(|| contend(threads, || {
+        counter.fetch_add(1, Ordering::Relaxed);
+      }));
+    });
+    group.bench_with_input(BenchmarkId::new("sharded", threads), &threads, |b, &threads| {
+      let counter = Counter::default();
+      b.iter(|| contend(threads, || counter.inc()));
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, counters);
+criterion_main!(benches);
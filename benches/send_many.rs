@@ -0,0 +1,60 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Sending a batch of datagrams with `send_many` (sendmmsg) against one `send_to` each.
+//! Needs CAP_NET_RAW; the datagrams go to the loopback address.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use etherip::tokio;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use etherip::{EtherIpBuffer, EtherIpDatagram, EtherIpSocket, SocketFamily};
+
+/// Datagrams per batch, up to the default `batch_size` of an egress queue.
+const BATCH_SIZES: [usize; 3] = [1, 8, 32];
+
+fn datagram_of_len(frame_len: usize) -> EtherIpDatagram {
+  let mut datagram = EtherIpDatagram::new();
+  let (mut len, buf) = datagram.ethrnet_frame_mut();
+  buf[..frame_len].fill(0x5a);
+  len.set(frame_len);
+  datagram
+}
+
+fn send_batches(c: &mut Criterion) {
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let socket = match runtime.block_on(async { EtherIpSocket::new_with_family(SocketFamily::Inet) }) {
+    Ok(socket) => socket,
+    Err(e) => {
+      eprintln!("skipping the send_many benchmark: cannot open an EtherIP socket: {}", e);
+      return;
+    },
+  };
+  let datagram = datagram_of_len(1514);
+  let dst = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+  let mut group = c.benchmark_group("send_many");
+  for batch_size in BATCH_SIZES {
+    let batch: Vec<(&EtherIpDatagram, IpAddr)> = vec![(&datagram, dst); batch_size];
+    group.throughput(Throughput::Elements(batch_size as u64));
+    group.bench_with_input(BenchmarkId::new("send_to", batch_size), &batch, |b, batch| {
+      b.iter(|| runtime.block_on(async {
+        for (datagram, dst) in batch {
+          socket.send_to(*datagram, dst).await.expect("send_to");
+        }
+      }))
+    });
+    group.bench_with_input(BenchmarkId::new("send_many", batch_size), &batch, |b, batch| {
+      b.iter(|| runtime.block_on(async {
+        for result in socket.send_many(batch).await.expect("send_many") {
+          result.expect("send_many");
+        }
+      }))
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, send_batches);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Filtering of tunneled frames by the addresses of the IP packets they carry.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::serde;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::flows::FlowKey;
+
+/// IP prefix such as `192.0.2.0/24`. A bare address is a host prefix.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+  addr: IpAddr,
+  len: u8,
+}
+
+impl IpPrefix {
+  pub fn new(addr: IpAddr, len: u8) -> std::io::Result<Self> {
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    if len > max_len {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("prefix length {} is longer than {}", len, max_len)));
+    }
+    Ok(Self { addr, len })
+  }
+
+  /// true if `addr` is in the prefix. IPv4 prefixes never contain IPv6 addresses, nor the reverse.
+  pub fn contains(&self, addr: &IpAddr) -> bool {
+    match (self.addr, addr) {
+      (IpAddr::V4(prefix), IpAddr::V4(addr)) => {
+        let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+        (u32::from(prefix) ^ u32::from(*addr)) & mask == 0
+      },
+      (IpAddr::V6(prefix), IpAddr::V6(addr)) => {
+        let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+        (u128::from(prefix) ^ u128::from(*addr)) & mask == 0
+      },
+      _ => false,
+    }
+  }
+}
+
+impl fmt::Display for IpPrefix {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.len)
+  }
+}
+
+impl fmt::Debug for IpPrefix {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+impl FromStr for IpPrefix {
+  type Err = std::io::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid IP prefix: {}", s));
+    let (addr, len) = match s.split_once('/') {
+      Some((addr, len)) => (addr.parse::<IpAddr>().map_err(|_| invalid())?, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+      None => (s.parse::<IpAddr>().map_err(|_| invalid())?, None),
+    };
+    let len = len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+    Self::new(addr, len)
+  }
+}
+
+impl<'de> Deserialize<'de> for IpPrefix {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Prefixes the inner packets of a link must come from and go to. An empty allow list
+/// allows every address; deny lists take precedence. A non-empty allow list without
+/// prefixes of a family, e.g. `::/0`, denies all packets of that family. Frames not
+/// carrying IPv4 or IPv6, such as ARP, are not filtered.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct InnerIpFilter {
+  #[serde(default)]
+  pub allow_sources: Vec<IpPrefix>,
+
+  #[serde(default)]
+  pub allow_destinations: Vec<IpPrefix>,
+
+  #[serde(default)]
+  pub deny_sources: Vec<IpPrefix>,
+
+  #[serde(default)]
+  pub deny_destinations: Vec<IpPrefix>,
+}
+
+impl InnerIpFilter {
+  /// true if the filter lets the frame through.
+  pub fn accepts(&self, frame: &[u8]) -> bool {
+    let Some(key) = FlowKey::parse(frame) else {
+      return true;
+    };
+    let allowed = |prefixes: &[IpPrefix], addr: &IpAddr| prefixes.is_empty() || prefixes.iter().any(|prefix| prefix.contains(addr));
+    let denied = |prefixes: &[IpPrefix], addr: &IpAddr| prefixes.iter().any(|prefix| prefix.contains(addr));
+    allowed(&self.allow_sources, &key.src) && allowed(&self.allow_destinations, &key.dst)
+      && !denied(&self.deny_sources, &key.src) && !denied(&self.deny_destinations, &key.dst)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ethernet::{ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+
+  fn prefixes(list: &[&str]) -> Vec<IpPrefix> {
+    list.iter().map(|prefix| prefix.parse().unwrap()).collect()
+  }
+
+  /// An Ethernet frame carrying an IP packet from `src` to `dst`.
+  fn frame(src: &str, dst: &str) -> Vec<u8> {
+    let (ethertype, packet) = match (src.parse().unwrap(), dst.parse().unwrap()) {
+      (IpAddr::V4(src), IpAddr::V4(dst)) => {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&src.octets());
+        packet[16..20].copy_from_slice(&dst.octets());
+        (ETHERTYPE_IPV4, packet)
+      },
+      (IpAddr::V6(src), IpAddr::V6(dst)) => {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[8..24].copy_from_slice(&src.octets());
+        packet[24..40].copy_from_slice(&dst.octets());
+        (ETHERTYPE_IPV6, packet)
+      },
+      _ => panic!("mixed address families"),
+    };
+    let mut frame = vec![0u8; 12];
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(&packet);
+    frame
+  }
+
+  #[test]
+  fn prefixes_match_their_leading_bits_only() {
+    let prefix: IpPrefix = "192.0.2.0/24".parse().unwrap();
+    assert!(prefix.contains(&"192.0.2.255".parse().unwrap()));
+    assert!(!prefix.contains(&"192.0.3.0".parse().unwrap()));
+    assert!(!prefix.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+    let all: IpPrefix = "0.0.0.0/0".parse().unwrap();
+    assert!(all.contains(&"203.0.113.1".parse().unwrap()));
+    assert!(!all.contains(&"2001:db8::1".parse().unwrap()));
+    let host: IpPrefix = "2001:db8::1".parse().unwrap();
+    assert_eq!(host.to_string(), "2001:db8::1/128");
+    assert!(host.contains(&"2001:db8::1".parse().unwrap()));
+    assert!(!host.contains(&"2001:db8::2".parse().unwrap()));
+  }
+
+  #[test]
+  fn invalid_prefixes_are_refused() {
+    for prefix in ["192.0.2.0/33", "2001:db8::/129", "192.0.2.0/", "example.com", "192.0.2.0/-1"] {
+      assert!(prefix.parse::<IpPrefix>().is_err(), "{}", prefix);
+    }
+  }
+
+  #[test]
+  fn empty_filters_accept_everything() {
+    let filter = InnerIpFilter::default();
+    assert!(filter.accepts(&frame("192.0.2.1", "198.51.100.1")));
+    assert!(filter.accepts(&frame("2001:db8::1", "2001:db8::2")));
+  }
+
+  #[test]
+  fn deny_lists_take_precedence_over_allow_lists() {
+    let filter = InnerIpFilter {
+      allow_sources: prefixes(&["192.0.2.0/24"]),
+      deny_sources: prefixes(&["192.0.2.128/25"]),
+      deny_destinations: prefixes(&["198.51.100.1"]),
+      ..Default::default()
+    };
+    assert!(filter.accepts(&frame("192.0.2.1", "198.51.100.2")));
+    assert!(!filter.accepts(&frame("192.0.2.129", "198.51.100.2")));
+    assert!(!filter.accepts(&frame("192.0.2.1", "198.51.100.1")));
+    assert!(!filter.accepts(&frame("203.0.113.1", "198.51.100.2")));
+  }
+
+  #[test]
+  fn sources_and_destinations_must_both_be_allowed() {
+    let filter = InnerIpFilter {
+      allow_sources: prefixes(&["192.0.2.0/24"]),
+      allow_destinations: prefixes(&["198.51.100.0/24"]),
+      ..Default::default()
+    };
+    assert!(filter.accepts(&frame("192.0.2.1", "198.51.100.1")));
+    assert!(!filter.accepts(&frame("198.51.100.1", "192.0.2.1")));
+  }
+
+  #[test]
+  fn allow_lists_of_one_family_deny_the_other() {
+    let filter = InnerIpFilter {
+      allow_sources: prefixes(&["0.0.0.0/0"]),
+      ..Default::default()
+    };
+    assert!(filter.accepts(&frame("192.0.2.1", "198.51.100.1")));
+    assert!(!filter.accepts(&frame("2001:db8::1", "2001:db8::2")));
+  }
+
+  #[test]
+  fn frames_without_ip_are_not_filtered() {
+    let filter = InnerIpFilter {
+      allow_sources: prefixes(&["192.0.2.0/24"]),
+      deny_sources: prefixes(&["0.0.0.0/0", "::/0"]),
+      ..Default::default()
+    };
+    let mut arp = vec![0u8; 42];
+    arp[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+    assert!(filter.accepts(&arp));
+    assert!(!filter.accepts(&frame("192.0.2.1", "198.51.100.1")));
+  }
+}
@@ -0,0 +1,39 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! CPU affinity of the calling thread.
+//!
+//! Tokio does not let tasks choose their worker thread, so a link pinned to CPUs
+//! runs on a dedicated thread with its own current-thread runtime, which is pinned here.
+
+use std::io::{Error, ErrorKind};
+
+use crate::libc;
+
+/// Number of CPUs a CPU set can hold.
+pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+/// Check that `cpus` is a non-empty list of CPU numbers a CPU set can hold.
+pub fn validate(cpus: &[usize]) -> std::io::Result<()> {
+  if cpus.is_empty() {
+    return Err(Error::new(ErrorKind::InvalidInput, "empty CPU list"));
+  }
+  if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= MAX_CPUS) {
+    return Err(Error::new(ErrorKind::InvalidInput, format!("CPU {} is out of range (0-{})", cpu, MAX_CPUS - 1)));
+  }
+  Ok(())
+}
+
+/// Restrict the calling thread to `cpus`.
+pub fn set_current_thread_affinity(cpus: &[usize]) -> std::io::Result<()> {
+  validate(cpus)?;
+  let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+  for cpu in cpus {
+    unsafe { libc::CPU_SET(*cpu, &mut set) };
+  }
+  // pid 0 is the calling thread.
+  if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(())
+}
@@ -0,0 +1,120 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Fixed pool of datagram buffers shared by all links, which bounds the memory
+//! frame buffers take regardless of the number of links.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::parking_lot::Mutex;
+use crate::tokio;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::metrics::MetricsWriter;
+use crate::{EtherIpBuffer, HeapEtherIpDatagram};
+
+/// Room for the shims and trailers of a link around the frame.
+const BUFFER_OVERHEAD: usize = crate::seqno::SEQNO_SHIM_SIZE + crate::mtu::MTU_SHIM_SIZE + crate::compress::COMPRESSION_SHIM_SIZE + crate::auth::AUTH_TAG_SIZE;
+
+/// Pool of at most `capacity` datagram buffers. Buffers are allocated on first use and
+/// kept for reuse; when all of them are taken, `acquire` waits for one to be returned.
+#[derive(Debug)]
+pub struct BufferArena {
+  max_frame_size: usize,
+  permits: Semaphore,
+  capacity: usize,
+  free: Mutex<Vec<HeapEtherIpDatagram>>,
+  in_use: AtomicUsize,
+  /// Buffers allocated so far, at most `capacity`.
+  allocated: AtomicUsize,
+  /// Times `acquire` had to wait for a buffer.
+  waits: AtomicU64,
+}
+
+impl BufferArena {
+  /// Create an arena of `capacity` buffers (at least 1), each holding an Ethernet frame
+  /// of up to `max_frame_size` bytes together with every shim and trailer a link may add.
+  pub fn new(capacity: usize, max_frame_size: usize) -> Self {
+    let capacity = capacity.max(1);
+    Self {
+      max_frame_size,
+      permits: Semaphore::new(capacity),
+      capacity,
+      free: Mutex::new(Vec::new()),
+      in_use: AtomicUsize::new(0),
+      allocated: AtomicUsize::new(0),
+      waits: AtomicU64::new(0),
+    }
+  }
+
+  /// Take a buffer, waiting until one is returned if all of them are in use.
+  pub async fn acquire(&self) -> ArenaBuffer<'_> {
+    let permit = match self.permits.try_acquire() {
+      Ok(permit) => permit,
+      Err(_) => {
+        self.waits.fetch_add(1, Ordering::Relaxed);
+        // Never closed, so acquiring cannot fail.
+        self.permits.acquire().await.expect("the arena semaphore is never closed")
+      },
+    };
+    let datagram = self.free.lock().pop().unwrap_or_else(|| {
+      self.allocated.fetch_add(1, Ordering::Relaxed);
+      HeapEtherIpDatagram::with_max_frame_size(BUFFER_OVERHEAD + self.max_frame_size)
+    });
+    self.in_use.fetch_add(1, Ordering::Relaxed);
+    ArenaBuffer { arena: self, datagram: Some(datagram), _permit: permit }
+  }
+
+  /// Largest Ethernet frame the buffers hold.
+  pub fn max_frame_size(&self) -> usize {
+    self.max_frame_size
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  pub fn in_use(&self) -> usize {
+    self.in_use.load(Ordering::Relaxed)
+  }
+
+  /// Write the utilization of the arena.
+  pub fn render(&self, writer: &mut MetricsWriter) {
+    writer.family("etherip_buffer_arena_buffers", "gauge", "Buffers the shared buffer arena holds at most.");
+    writer.sample("etherip_buffer_arena_buffers", &[], self.capacity);
+    writer.family("etherip_buffer_arena_allocated_buffers", "gauge", "Buffers of the shared buffer arena allocated so far.");
+    writer.sample("etherip_buffer_arena_allocated_buffers", &[], self.allocated.load(Ordering::Relaxed));
+    writer.family("etherip_buffer_arena_buffers_in_use", "gauge", "Buffers of the shared buffer arena currently taken.");
+    writer.sample("etherip_buffer_arena_buffers_in_use", &[], self.in_use());
+    writer.family("etherip_buffer_arena_waits_total", "counter", "Times a link waited for a buffer of the exhausted arena.");
+    writer.sample("etherip_buffer_arena_waits_total", &[], self.waits.load(Ordering::Relaxed));
+  }
+}
+
+/// Buffer taken from a `BufferArena`, returned to it when dropped.
+pub struct ArenaBuffer<'a> {
+  arena: &'a BufferArena,
+  datagram: Option<HeapEtherIpDatagram>,
+  _permit: SemaphorePermit<'a>,
+}
+
+impl EtherIpBuffer for ArenaBuffer<'_> {
+  fn parts(&self) -> (usize, &[u8]) {
+    self.datagram.as_ref().expect("taken only on drop").parts()
+  }
+
+  fn parts_mut(&mut self) -> (&mut usize, &mut [u8]) {
+    self.datagram.as_mut().expect("taken only on drop").parts_mut()
+  }
+}
+
+impl Drop for ArenaBuffer<'_> {
+  fn drop(&mut self) {
+    if let Some(datagram) = self.datagram.take() {
+      self.arena.free.lock().push(datagram);
+    }
+    // The permit is released after the buffer is back, so the next taker finds it.
+    self.arena.in_use.fetch_sub(1, Ordering::Relaxed);
+  }
+}
@@ -0,0 +1,364 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Minimal ARP/ND responder for management addresses behind a TAP interface.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::ethernet::{EthernetHeader, MacAddr, ETHERNET_HEADER_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV6};
+
+const ARP_PACKET_SIZE: usize = 28;
+const IPV6_HEADER_SIZE: usize = 40;
+const IPPROTO_ICMPV6: u8 = 58;
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const NA_SIZE: usize = 32;
+
+/// All-nodes multicast address and its Ethernet address.
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+const ALL_NODES_MAC: MacAddr = MacAddr([0x33, 0x33, 0, 0, 0, 1]);
+
+/// Minimum Ethernet frame size (excluding FCS); replies are padded to it.
+const MIN_FRAME_SIZE: usize = 60;
+
+/// Kind of reply produced by the responder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+  /// ARP reply of the given frame length.
+  Arp(usize),
+  /// Neighbor advertisement of the given frame length.
+  Nd(usize),
+}
+
+impl Reply {
+  pub fn frame_len(&self) -> usize {
+    match self {
+      Reply::Arp(len) | Reply::Nd(len) => *len,
+    }
+  }
+}
+
+/// Answers ARP requests and neighbor solicitations for a fixed set of IP/MAC bindings.
+#[derive(Debug, Clone, Default)]
+pub struct ArpResponder {
+  bindings: HashMap<IpAddr, MacAddr>,
+}
+
+impl ArpResponder {
+  pub fn new(bindings: HashMap<IpAddr, MacAddr>) -> Self {
+    Self { bindings }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bindings.is_empty()
+  }
+
+  /// Build a reply to `frame` into `reply` if it is an ARP request or a neighbor
+  /// solicitation for one of the bound addresses.
+  pub fn respond(&self, frame: &[u8], reply: &mut [u8]) -> Option<Reply> {
+    if self.bindings.is_empty() || reply.len() < MIN_FRAME_SIZE.max(ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + NA_SIZE) {
+      return None;
+    }
+    let (header, payload) = EthernetHeader::parse(frame)?;
+    match header.ethertype {
+      ETHERTYPE_ARP => self.respond_arp(payload, reply).map(Reply::Arp),
+      ETHERTYPE_IPV6 => self.respond_nd(&header, payload, reply).map(Reply::Nd),
+      _ => None,
+    }
+  }
+
+  fn respond_arp(&self, packet: &[u8], reply: &mut [u8]) -> Option<usize> {
+    if packet.len() < ARP_PACKET_SIZE {
+      return None;
+    }
+    // Ethernet/IPv4 request: htype 1, ptype 0x0800, hlen 6, plen 4, oper 1.
+    if packet[0..8] != [0, 1, 0x08, 0x00, 6, 4, 0, 1] {
+      return None;
+    }
+    let sender_mac = &packet[8..14];
+    let sender_ip = &packet[14..18];
+    let target_ip: [u8; 4] = packet[24..28].try_into().ok()?;
+    let mac = *self.bindings.get(&IpAddr::V4(Ipv4Addr::from(target_ip)))?;
+
+    let mut sender = [0u8; 6];
+    sender.copy_from_slice(sender_mac);
+    let header = EthernetHeader {
+      destination: MacAddr(sender),
+      source: mac,
+      ethertype: ETHERTYPE_ARP,
+    };
+    let offset = header.write(reply)?;
+    let arp = &mut reply[offset..offset + ARP_PACKET_SIZE];
+    arp[0..8].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+    arp[8..14].copy_from_slice(&mac.0);
+    arp[14..18].copy_from_slice(&target_ip);
+    arp[18..24].copy_from_slice(sender_mac);
+    arp[24..28].copy_from_slice(sender_ip);
+    Some(pad(reply, offset + ARP_PACKET_SIZE))
+  }
+
+  fn respond_nd(&self, request_header: &EthernetHeader, packet: &[u8], reply: &mut [u8]) -> Option<usize> {
+    if packet.len() < IPV6_HEADER_SIZE + 24 || packet[0] >> 4 != 6 {
+      return None;
+    }
+    // Neighbor discovery messages must not have been forwarded (hop limit 255).
+    if packet[6] != IPPROTO_ICMPV6 || packet[7] != 255 {
+      return None;
+    }
+    let icmp = &packet[IPV6_HEADER_SIZE..];
+    if icmp[0] != ICMPV6_NEIGHBOR_SOLICITATION || icmp[1] != 0 {
+      return None;
+    }
+    let source: [u8; 16] = packet[8..24].try_into().ok()?;
+    let target: [u8; 16] = icmp[8..24].try_into().ok()?;
+    let mac = *self.bindings.get(&IpAddr::V6(Ipv6Addr::from(target)))?;
+
+    // Solicitations from the unspecified address (DAD) are answered to all-nodes.
+    let solicited = source != [0; 16];
+    let (destination_ip, destination_mac) = if solicited {
+      (source, request_header.source)
+    } else {
+      (ALL_NODES.octets(), ALL_NODES_MAC)
+    };
+
+    let header = EthernetHeader {
+      destination: destination_mac,
+      source: mac,
+      ethertype: ETHERTYPE_IPV6,
+    };
+    let offset = header.write(reply)?;
+    // Flags: solicited (if answering a unicast request) and override.
+    let flags = if solicited { 0x60 } else { 0x20 };
+    write_na(&target, &destination_ip, mac, flags, &mut reply[offset..]);
+    Some(pad(reply, offset + IPV6_HEADER_SIZE + NA_SIZE))
+  }
+}
+
+/// Size of the buffer needed by [`announcement`].
+pub const ANNOUNCEMENT_BUFFER_SIZE: usize = ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + NA_SIZE;
+
+/// Build an unsolicited announcement of `ip` at `mac` into `frame`: a broadcast gratuitous ARP
+/// reply for IPv4, or an unsolicited neighbor advertisement to all-nodes with the override flag for IPv6.
+/// Returns the frame length, or `None` if `frame` is shorter than [`ANNOUNCEMENT_BUFFER_SIZE`].
+pub fn announcement(ip: IpAddr, mac: MacAddr, frame: &mut [u8]) -> Option<usize> {
+  if frame.len() < ANNOUNCEMENT_BUFFER_SIZE.max(MIN_FRAME_SIZE) {
+    return None;
+  }
+  match ip {
+    IpAddr::V4(ip) => {
+      let header = EthernetHeader {
+        destination: MacAddr::BROADCAST,
+        source: mac,
+        ethertype: ETHERTYPE_ARP,
+      };
+      let offset = header.write(frame)?;
+      let arp = &mut frame[offset..offset + ARP_PACKET_SIZE];
+      arp[0..8].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+      arp[8..14].copy_from_slice(&mac.0);
+      arp[14..18].copy_from_slice(&ip.octets());
+      arp[18..24].copy_from_slice(&MacAddr::BROADCAST.0);
+      arp[24..28].copy_from_slice(&ip.octets());
+      Some(pad(frame, offset + ARP_PACKET_SIZE))
+    },
+    IpAddr::V6(ip) => {
+      let header = EthernetHeader {
+        destination: ALL_NODES_MAC,
+        source: mac,
+        ethertype: ETHERTYPE_IPV6,
+      };
+      let offset = header.write(frame)?;
+      // Flags: override.
+      let len = offset + write_na(&ip.octets(), &ALL_NODES.octets(), mac, 0x20, &mut frame[offset..]);
+      Some(pad(frame, len))
+    },
+  }
+}
+
+/// Write an IPv6 packet holding a neighbor advertisement for `target` with `flags`, sent from `target` itself.
+fn write_na(target: &[u8; 16], destination: &[u8; 16], mac: MacAddr, flags: u8, packet: &mut [u8]) -> usize {
+  let ip = &mut packet[..IPV6_HEADER_SIZE + NA_SIZE];
+  ip[0..4].copy_from_slice(&[0x60, 0, 0, 0]);
+  ip[4..6].copy_from_slice(&(NA_SIZE as u16).to_be_bytes());
+  ip[6] = IPPROTO_ICMPV6;
+  ip[7] = 255;
+  ip[8..24].copy_from_slice(target);
+  ip[24..40].copy_from_slice(destination);
+
+  let na = &mut ip[IPV6_HEADER_SIZE..];
+  na[0] = ICMPV6_NEIGHBOR_ADVERTISEMENT;
+  na[1] = 0;
+  na[2..4].copy_from_slice(&[0, 0]);
+  na[4..8].copy_from_slice(&[flags, 0, 0, 0]);
+  na[8..24].copy_from_slice(target);
+  // Target link-layer address option.
+  na[24] = 2;
+  na[25] = 1;
+  na[26..32].copy_from_slice(&mac.0);
+  let checksum = icmpv6_checksum(target, destination, na);
+  na[2..4].copy_from_slice(&checksum.to_be_bytes());
+  IPV6_HEADER_SIZE + NA_SIZE
+}
+
+fn pad(frame: &mut [u8], len: usize) -> usize {
+  if len < MIN_FRAME_SIZE {
+    frame[len..MIN_FRAME_SIZE].fill(0);
+    MIN_FRAME_SIZE
+  } else {
+    len
+  }
+}
+
+fn icmpv6_checksum(source: &[u8; 16], destination: &[u8; 16], message: &[u8]) -> u16 {
+  let mut sum: u32 = 0;
+  let mut add = |bytes: &[u8]| {
+    for chunk in bytes.chunks(2) {
+      let word = if chunk.len() == 2 { u16::from_be_bytes([chunk[0], chunk[1]]) } else { u16::from_be_bytes([chunk[0], 0]) };
+      sum += word as u32;
+    }
+  };
+  add(source);
+  add(destination);
+  add(&(message.len() as u32).to_be_bytes());
+  add(&[0, 0, 0, IPPROTO_ICMPV6]);
+  add(message);
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HOST_MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x01]);
+  const PEER_MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x02]);
+
+  fn responder() -> ArpResponder {
+    ArpResponder::new(HashMap::from([
+      ("192.0.2.1".parse().unwrap(), HOST_MAC),
+      ("2001:db8::1".parse().unwrap(), HOST_MAC),
+    ]))
+  }
+
+  /// An Ethernet frame from the peer carrying `payload`.
+  fn frame(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; ETHERNET_HEADER_SIZE];
+    EthernetHeader { destination: MacAddr::BROADCAST, source: PEER_MAC, ethertype }.write(&mut frame).unwrap();
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  /// An ARP request from 192.0.2.2 at the peer for `target`.
+  fn arp_request(target: [u8; 4]) -> Vec<u8> {
+    let mut arp = vec![0, 1, 0x08, 0x00, 6, 4, 0, 1];
+    arp.extend_from_slice(&PEER_MAC.0);
+    arp.extend_from_slice(&[192, 0, 2, 2]);
+    arp.extend_from_slice(&[0; 6]);
+    arp.extend_from_slice(&target);
+    arp
+  }
+
+  /// A neighbor solicitation for `target` from `source`, with hop limit `hop_limit`.
+  fn neighbor_solicitation(source: Ipv6Addr, target: Ipv6Addr, hop_limit: u8) -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0, 0, 24, IPPROTO_ICMPV6, hop_limit];
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&"ff02::1:ff00:1".parse::<Ipv6Addr>().unwrap().octets());
+    packet.extend_from_slice(&[ICMPV6_NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0]);
+    packet.extend_from_slice(&target.octets());
+    packet
+  }
+
+  #[test]
+  fn arp_requests_for_bound_addresses_are_answered() {
+    let mut reply = [0xaa; 128];
+    let len = responder().respond(&frame(ETHERTYPE_ARP, &arp_request([192, 0, 2, 1])), &mut reply);
+    assert_eq!(len, Some(Reply::Arp(MIN_FRAME_SIZE)));
+
+    let (header, arp) = EthernetHeader::parse(&reply[..MIN_FRAME_SIZE]).unwrap();
+    assert_eq!((header.destination, header.source, header.ethertype), (PEER_MAC, HOST_MAC, ETHERTYPE_ARP));
+    assert_eq!(arp[0..8], [0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+    assert_eq!(arp[8..14], HOST_MAC.0);
+    assert_eq!(arp[14..18], [192, 0, 2, 1]);
+    assert_eq!(arp[18..24], PEER_MAC.0);
+    assert_eq!(arp[24..28], [192, 0, 2, 2]);
+    // Padded with zeros to the minimum frame size.
+    assert!(arp[ARP_PACKET_SIZE..].iter().all(|&b| b == 0));
+  }
+
+  #[test]
+  fn truncated_and_foreign_arp_frames_are_ignored() {
+    let responder = responder();
+    let mut reply = [0u8; 128];
+    let request = arp_request([192, 0, 2, 1]);
+    // Truncated inside the ARP packet, and inside the Ethernet header.
+    assert_eq!(responder.respond(&frame(ETHERTYPE_ARP, &request[..ARP_PACKET_SIZE - 1]), &mut reply), None);
+    assert_eq!(responder.respond(&frame(ETHERTYPE_ARP, &request)[..ETHERNET_HEADER_SIZE - 1], &mut reply), None);
+
+    // Other hardware or protocol types, address sizes and operations.
+    for (offset, value) in [(1, 6), (2, 0x86), (4, 8), (5, 16), (7, 2)] {
+      let mut foreign = request.clone();
+      foreign[offset] = value;
+      assert_eq!(responder.respond(&frame(ETHERTYPE_ARP, &foreign), &mut reply), None, "byte {} = {}", offset, value);
+    }
+    // Unbound targets, and ARP carried under another EtherType.
+    assert_eq!(responder.respond(&frame(ETHERTYPE_ARP, &arp_request([192, 0, 2, 3])), &mut reply), None);
+    assert_eq!(responder.respond(&frame(0x0800, &request), &mut reply), None);
+    // No room for the reply.
+    assert_eq!(responder.respond(&frame(ETHERTYPE_ARP, &request), &mut reply[..MIN_FRAME_SIZE]), None);
+    assert_eq!(ArpResponder::default().respond(&frame(ETHERTYPE_ARP, &request), &mut reply), None);
+  }
+
+  #[test]
+  fn neighbor_solicitations_are_answered_with_a_valid_checksum() {
+    let (source, target) = ("2001:db8::2".parse().unwrap(), "2001:db8::1".parse().unwrap());
+    let mut reply = [0u8; 128];
+    let len = responder().respond(&frame(ETHERTYPE_IPV6, &neighbor_solicitation(source, target, 255)), &mut reply).unwrap();
+    assert_eq!(len, Reply::Nd(ANNOUNCEMENT_BUFFER_SIZE));
+
+    let (header, packet) = EthernetHeader::parse(&reply[..len.frame_len()]).unwrap();
+    assert_eq!((header.destination, header.source), (PEER_MAC, HOST_MAC));
+    assert_eq!(packet[8..24], target.octets());
+    assert_eq!(packet[24..40], source.octets());
+    let na = &packet[IPV6_HEADER_SIZE..];
+    assert_eq!((na[0], na[4]), (ICMPV6_NEIGHBOR_ADVERTISEMENT, 0x60));
+    assert_eq!(na[26..32], HOST_MAC.0);
+    // Summing the message with its checksum in gives zero.
+    assert_eq!(icmpv6_checksum(&target.octets(), &source.octets(), na), 0);
+
+    // Duplicate address detection is answered to all nodes, without the solicited flag.
+    let len = responder().respond(&frame(ETHERTYPE_IPV6, &neighbor_solicitation(Ipv6Addr::UNSPECIFIED, target, 255)), &mut reply).unwrap();
+    let (header, packet) = EthernetHeader::parse(&reply[..len.frame_len()]).unwrap();
+    assert_eq!(header.destination, ALL_NODES_MAC);
+    assert_eq!((packet[24..40].to_vec(), packet[IPV6_HEADER_SIZE + 4]), (ALL_NODES.octets().to_vec(), 0x20));
+  }
+
+  #[test]
+  fn malformed_neighbor_solicitations_are_ignored() {
+    let responder = responder();
+    let (source, target) = ("2001:db8::2".parse().unwrap(), "2001:db8::1".parse().unwrap());
+    let mut reply = [0u8; 128];
+    let solicitation = neighbor_solicitation(source, target, 255);
+    assert_eq!(responder.respond(&frame(ETHERTYPE_IPV6, &solicitation[..IPV6_HEADER_SIZE + 23]), &mut reply), None);
+    // Forwarded solicitations, and ones for addresses that are not bound.
+    assert_eq!(responder.respond(&frame(ETHERTYPE_IPV6, &neighbor_solicitation(source, target, 254)), &mut reply), None);
+    assert_eq!(responder.respond(&frame(ETHERTYPE_IPV6, &neighbor_solicitation(source, "2001:db8::3".parse().unwrap(), 255)), &mut reply), None);
+    let mut ipv4 = solicitation.clone();
+    ipv4[0] = 0x45;
+    assert_eq!(responder.respond(&frame(ETHERTYPE_IPV6, &ipv4), &mut reply), None);
+  }
+
+  #[test]
+  fn announcements_need_a_full_buffer() {
+    let mut frame = [0u8; ANNOUNCEMENT_BUFFER_SIZE];
+    assert_eq!(announcement("192.0.2.1".parse().unwrap(), HOST_MAC, &mut frame), Some(MIN_FRAME_SIZE));
+    let (header, arp) = EthernetHeader::parse(&frame).unwrap();
+    assert!(header.destination.is_broadcast());
+    // A gratuitous ARP reply has the announced address as sender and target.
+    assert_eq!((arp[14..18].to_vec(), arp[24..28].to_vec()), (vec![192, 0, 2, 1], vec![192, 0, 2, 1]));
+
+    assert_eq!(announcement("2001:db8::1".parse().unwrap(), HOST_MAC, &mut frame), Some(ANNOUNCEMENT_BUFFER_SIZE));
+    assert_eq!(announcement("2001:db8::1".parse().unwrap(), HOST_MAC, &mut frame[..ANNOUNCEMENT_BUFFER_SIZE - 1]), None);
+  }
+}
@@ -0,0 +1,177 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Optional authentication of EtherIP datagrams with a shared secret.
+//!
+//! This is not part of RFC 3378 and breaks wire compatibility with standard EtherIP:
+//! when enabled, an HMAC-SHA256 tag truncated to 16 bytes is appended after the
+//! Ethernet frame, covering the whole datagram (header, shims and frame).
+//! Both ends of a link must enable it with the same key. Replayed datagrams are
+//! not detected.
+
+use crate::hmac;
+use crate::sha2;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::EtherIpBuffer;
+
+/// Size of the authentication tag.
+pub const AUTH_TAG_SIZE: usize = 16;
+
+/// Shortest accepted key.
+pub const MIN_KEY_SIZE: usize = 16;
+
+/// Signs and verifies the datagrams of a link.
+#[derive(Clone)]
+pub struct Authenticator {
+  mac: Hmac<Sha256>,
+}
+
+impl std::fmt::Debug for Authenticator {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // The HMAC state is derived from the key.
+    f.debug_struct("Authenticator").finish_non_exhaustive()
+  }
+}
+
+impl Authenticator {
+  /// Create an authenticator with `key`, which must be at least `MIN_KEY_SIZE` bytes long.
+  pub fn new(key: &[u8]) -> std::io::Result<Self> {
+    if key.len() < MIN_KEY_SIZE {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("authentication key must be at least {} bytes long", MIN_KEY_SIZE)));
+    }
+    let mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(Self { mac })
+  }
+
+  /// Append the tag of the datagram to it. Returns `false` if there is no room for the tag.
+  pub fn sign_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> bool {
+    let (mut len, buf) = datagram.datagram_mut();
+    let data_len = len.get();
+    if data_len + AUTH_TAG_SIZE > buf.len() {
+      return false;
+    }
+    let mut mac = self.mac.clone();
+    mac.update(&buf[..data_len]);
+    let tag = mac.finalize().into_bytes();
+    buf[data_len..data_len + AUTH_TAG_SIZE].copy_from_slice(&tag[..AUTH_TAG_SIZE]);
+    len.set(data_len + AUTH_TAG_SIZE);
+    true
+  }
+
+  /// Verify and strip the tag of a received datagram, in constant time.
+  /// Returns `false` (leaving the datagram as is) if the tag is missing or wrong.
+  pub fn verify_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> bool {
+    let (mut len, buf) = datagram.datagram_mut();
+    let Some(data_len) = len.get().checked_sub(AUTH_TAG_SIZE) else {
+      return false;
+    };
+    let mut mac = self.mac.clone();
+    mac.update(&buf[..data_len]);
+    if mac.verify_truncated_left(&buf[data_len..data_len + AUTH_TAG_SIZE]).is_err() {
+      return false;
+    }
+    len.set(data_len);
+    true
+  }
+}
+
+/// Decode a key written as hexadecimal digits.
+pub fn parse_hex_key(hex: &str) -> std::io::Result<Vec<u8>> {
+  let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "authentication key must be an even number of hexadecimal digits");
+  let digits = hex.trim().as_bytes();
+  if !digits.len().is_multiple_of(2) || !digits.iter().all(u8::is_ascii_hexdigit) {
+    return Err(invalid());
+  }
+  digits.chunks(2).map(|pair| {
+    let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+    u8::from_str_radix(pair, 16).map_err(|_| invalid())
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::EtherIpDatagram;
+
+  const KEY: &[u8; MIN_KEY_SIZE] = b"0123456789abcdef";
+
+  /// A datagram carrying a frame of `frame_len` bytes.
+  fn datagram_with_frame<const N: usize>(frame_len: usize) -> EtherIpDatagram<N> {
+    let mut datagram = EtherIpDatagram::<N>::new_sized();
+    let (mut len, buf) = datagram.ethrnet_frame_mut();
+    buf.iter_mut().take(frame_len).enumerate().for_each(|(i, b)| *b = i as u8);
+    len.set(frame_len);
+    datagram
+  }
+
+  #[test]
+  fn signed_datagrams_verify() {
+    let authenticator = Authenticator::new(KEY).unwrap();
+    let mut datagram: EtherIpDatagram = datagram_with_frame(60);
+    let unsigned = datagram.datagram().unwrap().to_vec();
+    assert!(authenticator.sign_datagram(&mut datagram));
+    assert_eq!(datagram.datagram().unwrap().len(), unsigned.len() + AUTH_TAG_SIZE);
+
+    assert!(authenticator.verify_datagram(&mut datagram));
+    assert_eq!(datagram.datagram().unwrap(), &unsigned[..]);
+    // Another key does not verify it.
+    assert!(authenticator.sign_datagram(&mut datagram));
+    assert!(!Authenticator::new(b"fedcba9876543210").unwrap().verify_datagram(&mut datagram));
+  }
+
+  #[test]
+  fn flipped_bits_are_rejected_anywhere() {
+    let authenticator = Authenticator::new(KEY).unwrap();
+    let mut signed: EtherIpDatagram = datagram_with_frame(60);
+    assert!(authenticator.sign_datagram(&mut signed));
+    let signed_len = signed.datagram().unwrap().len();
+    // In the EtherIP header, the Ethernet frame and the tag.
+    for offset in [0, 2, 2 + 59, signed_len - AUTH_TAG_SIZE, signed_len - 1] {
+      let mut datagram = signed.clone();
+      let (_, buf) = datagram.datagram_mut();
+      buf[offset] ^= 0x01;
+      assert!(!authenticator.verify_datagram(&mut datagram), "bit flipped at {}", offset);
+      assert_eq!(datagram.datagram_mut().0.get(), signed_len);
+    }
+  }
+
+  #[test]
+  fn datagrams_shorter_than_a_tag_are_rejected_as_they_are() {
+    let authenticator = Authenticator::new(KEY).unwrap();
+    let mut datagram: EtherIpDatagram = datagram_with_frame(AUTH_TAG_SIZE - 3);
+    assert!(!authenticator.verify_datagram(&mut datagram));
+    assert_eq!(datagram.datagram_mut().0.get(), AUTH_TAG_SIZE - 1);
+  }
+
+  #[test]
+  fn full_buffers_are_not_signed() {
+    let authenticator = Authenticator::new(KEY).unwrap();
+    let mut full = datagram_with_frame::<64>(62);
+    assert!(!authenticator.sign_datagram(&mut full));
+    assert_eq!(full.datagram_mut().0.get(), 64);
+    // Room for exactly one tag.
+    let mut roomy = datagram_with_frame::<64>(62 - AUTH_TAG_SIZE);
+    assert!(authenticator.sign_datagram(&mut roomy));
+    assert_eq!(roomy.datagram_mut().0.get(), 64);
+  }
+
+  #[test]
+  fn short_keys_are_refused() {
+    let error = Authenticator::new(&KEY[..MIN_KEY_SIZE - 1]).err().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(Authenticator::new(&[0u8; 64]).is_ok());
+  }
+
+  #[test]
+  fn hex_keys_need_an_even_number_of_digits() {
+    assert_eq!(parse_hex_key(" 00ff7A\n").unwrap(), vec![0x00, 0xff, 0x7a]);
+    assert_eq!(parse_hex_key("00f").err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    assert_eq!(parse_hex_key("00fg").err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    assert_eq!(parse_hex_key("+0ff").err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    assert_eq!(parse_hex_key("00 ff").err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+  }
+}
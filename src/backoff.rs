@@ -0,0 +1,157 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Backoff of sends to a remote that keeps refusing them.
+//!
+//! When the path to a peer is down, every send can fail at once (e.g. with EHOSTUNREACH),
+//! and a busy link then spends its time on failing sends and their errors. After enough
+//! consecutive failures, sends are suspended and frames dropped; a single probe send is
+//! let through when the suspension ends, and its failure suspends sends again for twice
+//! as long, up to a maximum. The first successful send resumes sending.
+
+use std::time::{Duration, Instant};
+
+/// What a send failure did to the state of a backoff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+  /// Sends are still attempted.
+  Counted,
+  /// Sends were suspended for this long after enough consecutive failures.
+  Suspended(Duration),
+  /// A probe failed and sends were suspended again for this long.
+  ProbeFailed(Duration),
+}
+
+/// Consecutive send failures of a link and the suspension they caused.
+#[derive(Debug)]
+pub struct SendBackoff {
+  threshold: u32,
+  initial_delay: Duration,
+  max_delay: Duration,
+  failures: u32,
+  delay: Duration,
+  suspended_until: Option<Instant>,
+  suppressed: u64,
+}
+
+impl SendBackoff {
+  /// Suspend sends after `threshold` consecutive failures, for `initial_delay` first
+  /// and doubling after each failed probe, up to `max_delay`.
+  pub fn new(threshold: u32, initial_delay: Duration, max_delay: Duration) -> Self {
+    Self {
+      threshold: threshold.max(1),
+      initial_delay,
+      max_delay: max_delay.max(initial_delay),
+      failures: 0,
+      delay: initial_delay,
+      suspended_until: None,
+      suppressed: 0,
+    }
+  }
+
+  /// Whether a send should be attempted now. Sends are refused while suspended and
+  /// counted as suppressed; once the suspension is over, sends are attempted as probes.
+  pub fn should_send(&mut self) -> bool {
+    match self.suspended_until {
+      Some(until) if Instant::now() < until => {
+        self.suppressed += 1;
+        false
+      },
+      _ => true,
+    }
+  }
+
+  /// Record a successful send, resuming sending. Returns the number of sends suppressed
+  /// since sending was suspended, if it was.
+  pub fn record_success(&mut self) -> Option<u64> {
+    self.failures = 0;
+    self.delay = self.initial_delay;
+    self.suspended_until.take()?;
+    Some(std::mem::take(&mut self.suppressed))
+  }
+
+  /// Record a failed send.
+  pub fn record_failure(&mut self) -> Failure {
+    self.failures = self.failures.saturating_add(1);
+    if self.failures < self.threshold {
+      return Failure::Counted;
+    }
+    let delay = self.delay;
+    self.delay = (delay * 2).min(self.max_delay);
+    match self.suspended_until.replace(Instant::now() + delay) {
+      None => Failure::Suspended(delay),
+      Some(_) => Failure::ProbeFailed(delay),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  /// End the current suspension, as if its delay had passed.
+  fn expire(backoff: &mut SendBackoff) {
+    if let Some(until) = backoff.suspended_until.as_mut() {
+      *until = Instant::now().checked_sub(SECOND).expect("monotonic clock far enough from its origin");
+    }
+  }
+
+  #[test]
+  fn sends_are_suspended_after_the_threshold() {
+    let mut backoff = SendBackoff::new(3, SECOND, 60 * SECOND);
+    assert_eq!(backoff.record_failure(), Failure::Counted);
+    assert_eq!(backoff.record_failure(), Failure::Counted);
+    assert!(backoff.should_send());
+    assert_eq!(backoff.record_failure(), Failure::Suspended(SECOND));
+    assert!(!backoff.should_send());
+    assert!(!backoff.should_send());
+  }
+
+  #[test]
+  fn failed_probes_double_the_delay_up_to_the_maximum() {
+    let mut backoff = SendBackoff::new(1, SECOND, 10 * SECOND);
+    assert_eq!(backoff.record_failure(), Failure::Suspended(SECOND));
+    let mut delays = Vec::new();
+    for _ in 0..6 {
+      expire(&mut backoff);
+      assert!(backoff.should_send(), "a probe is let through");
+      match backoff.record_failure() {
+        Failure::ProbeFailed(delay) => delays.push(delay.as_secs()),
+        failure => panic!("unexpected {:?}", failure),
+      }
+    }
+    assert_eq!(delays, [2, 4, 8, 10, 10, 10]);
+  }
+
+  #[test]
+  fn successes_resume_sending_and_report_the_suppressed_sends() {
+    let mut backoff = SendBackoff::new(2, SECOND, 60 * SECOND);
+    assert_eq!(backoff.record_success(), None);
+    backoff.record_failure();
+    backoff.record_failure();
+    for _ in 0..5 {
+      assert!(!backoff.should_send());
+    }
+    expire(&mut backoff);
+    assert!(backoff.should_send());
+    backoff.record_failure();
+    assert!(!backoff.should_send());
+    expire(&mut backoff);
+    assert_eq!(backoff.record_success(), Some(6));
+    assert!(backoff.should_send());
+
+    // Both the failure count and the delay start over.
+    assert_eq!(backoff.record_failure(), Failure::Counted);
+    assert_eq!(backoff.record_failure(), Failure::Suspended(SECOND));
+  }
+
+  #[test]
+  fn settings_are_clamped() {
+    let mut backoff = SendBackoff::new(0, 4 * SECOND, SECOND);
+    assert_eq!(backoff.record_failure(), Failure::Suspended(4 * SECOND));
+    expire(&mut backoff);
+    assert_eq!(backoff.record_failure(), Failure::ProbeFailed(4 * SECOND));
+  }
+}
@@ -4,9 +4,13 @@
 //! etheripd - EtherIP daemon
 //! Note that this does not daemonize, because it is intended to be run under a process supervisor like systemd.
 
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
 
 use etherip::tokio;
 use etherip::log;
@@ -18,27 +22,154 @@ use etherip::futures;
 use parking_lot::RwLock;
 
 use etherip::clap;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
+use etherip::acl;
+use etherip::affinity;
+use etherip::arena;
+use etherip::arp;
+use etherip::auth;
+use etherip::backoff;
+use etherip::bpf;
 use etherip::config;
+use etherip::logging;
+use etherip::metrics;
+use etherip::mtu;
+use etherip::privileges;
+use etherip::probe;
+use etherip::queue;
+use etherip::ratelimit;
+use etherip::seqno;
+use etherip::compress;
+use etherip::netns;
+use etherip::packet;
+use etherip::tcp;
+use etherip::udp;
+use etherip::watchdog;
+use etherip::stats;
 use etherip::tap;
 
+use etherip::ethernet::EthernetHeader;
+use etherip::ethernet::ETHERNET_HEADER_SIZE;
+use etherip::ethernet::MacRewriter;
 use etherip::EtherIpSocket;
+use etherip::EtherIpBuffer;
 use etherip::EtherIpDatagram;
+use etherip::HeapEtherIpDatagram;
+use etherip::SocketFamily;
+use etherip::ParserMode;
+use etherip::is_fatal_socket_error;
+use etherip::path_mtu;
+use etherip::transport::{DatagramSink, DatagramSource, FrameSink, FrameSource};
 
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc, oneshot};
 
-const APP_NAME: &'static str = "etheripd";
-const DEFAULT_CONFIG_PATH: &'static str = "/etc/etheripd/etheripd.toml";
+const APP_NAME: &str = "etheripd";
+const DEFAULT_CONFIG_PATH: &str = "/etc/etheripd/etheripd.toml";
 
+/// Number of flows of each link shown by `GET /flows`.
+const FLOWS_SHOWN: usize = 20;
+
+/// Number of learned MAC addresses of each link shown by `GET /fdb`, the most recently seen.
+const FDB_ENTRIES_SHOWN: usize = 1000;
+
+/// Frame buffers shared by the TAP readers of all links, if `buffer_arena_size` is set.
+static BUFFER_ARENA: std::sync::OnceLock<arena::BufferArena> = std::sync::OnceLock::new();
+
+
+/// How the daemon reacts to signals, shown by `--help`.
+const SIGNALS_HELP: &str = "Signals:
+  HUP   Reload the configuration file and restart the link tasks.
+  USR2  Re-resolve the remotes of all links now, without reloading; each link uses
+        the new address from its next frame. Nothing else is touched.
+  TERM  Shut down, as INT does.";
 
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = SIGNALS_HELP)]
 struct Args {
-  #[clap(short = 'c', long, value_parser, default_value = DEFAULT_CONFIG_PATH)]
+  #[clap(short = 'c', long, value_parser, default_value = DEFAULT_CONFIG_PATH, global = true)]
   config: PathBuf,
+
+  #[clap(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Run a single link in the foreground with verbose logging, ignoring the other links.
+  RunLink {
+    /// Name of the link to run.
+    #[clap(short = 'l', long)]
+    link: String,
+
+    /// Where to send log messages.
+    #[clap(long, value_enum, default_value = "stderr")]
+    log: LogTarget,
+  },
+
+  /// Print the version and the detected capabilities and kernel features.
+  Info {
+    /// Print JSON instead of human-readable text.
+    #[clap(long)]
+    json: bool,
+  },
+
+  /// Print which link or relay of the configuration would receive datagrams from a source
+  /// address, or why they would be dropped. Exits with an error if they would be dropped.
+  MatchSource {
+    /// Source address of the datagrams.
+    source: IpAddr,
+  },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogTarget {
+  Stderr,
+  Syslog,
+}
+
+/// Logger that writes to standard error, used for foreground debugging.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level() <= log::max_level()
+  }
+
+  fn log(&self, record: &log::Record) {
+    if self.enabled(record.metadata()) {
+      eprintln!("{} [{}] {}", APP_NAME, record.level(), record.args());
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+/// Log to the local syslog daemon, falling back to syslog over TCP and UDP on localhost like `syslog::init`.
+/// Records are filtered by the per-link levels of `logging::set_levels`.
+fn init_syslog() -> Result<(), anyhow::Error> {
+  let formatter = syslog::Formatter3164 {
+    facility: syslog::Facility::LOG_DAEMON,
+    hostname: None,
+    process: APP_NAME.to_string(),
+    pid: std::process::id(),
+  };
+  let logger = syslog::unix(formatter.clone())
+    .or_else(|_| syslog::tcp(formatter.clone(), ("127.0.0.1", 601)))
+    .or_else(|_| syslog::udp(formatter, ("127.0.0.1", 0), ("127.0.0.1", 514)))
+    .map_err(|e| anyhow::anyhow!("{}", e))?;
+  log::set_boxed_logger(Box::new(logging::LinkLevelLogger::new(syslog::BasicLogger::new(logger)))).map_err(|e| anyhow::anyhow!("{}", e))?;
+  logging::set_levels(log::LevelFilter::Info, []);
+  Ok(())
+}
+
+/// Log a message about a link, subject to the link's log level override.
+macro_rules! link_log {
+  ($link_name:expr, $level:expr, $($arg:tt)+) => {
+    log::log!(target: &logging::link_target($link_name), $level, $($arg)+)
+  };
 }
 
 async fn load_config<P: AsRef<Path>>(config_path: P) -> Result<config::Config, anyhow::Error> {
@@ -47,178 +178,2528 @@ async fn load_config<P: AsRef<Path>>(config_path: P) -> Result<config::Config, a
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-  syslog::init(syslog::Facility::LOG_DAEMON, log::LevelFilter::Info, Some(APP_NAME)).map_err(|e| anyhow::anyhow!("{}", e))?;
   let args = Args::parse();
-  let config_path = args.config;
-  let config = match load_config(&config_path).await {
+  match args.command {
+    Some(Command::RunLink { link, log }) => run_link(args.config, link, log).await,
+    Some(Command::Info { json }) => print_info(args.config, json).await,
+    Some(Command::MatchSource { source }) => match_source(args.config, source).await,
+    None => {
+      init_syslog()?;
+      run_daemon(args.config).await
+    },
+  }
+}
+
+/// Print where datagrams from `source` would go under the configuration, with the same
+/// checks as the receiving path. Hostname remotes are resolved now, and the reverse path
+/// check is made without the arrival interface, which only the strict mode looks at.
+/// The contents of the datagrams, such as their authentication, are not considered.
+async fn match_source(config_path: PathBuf, source: IpAddr) -> Result<(), anyhow::Error> {
+  let config = load_config(&config_path).await?;
+  let mut link_map = config::AddrStringMap::new(config.link_pairs());
+  let _ = link_map.update().await;
+  let is_relay = |name: &str| config.relays.contains_key(name);
+  let is_multicast = |name: &str| config.links.get(name).is_some_and(|link_config| link_config.is_multicast());
+  match SourceChecks::default().check(&link_map, config.rpf, &source, None, is_relay, is_multicast) {
+    SourceMatch::Relay(relay_name) => println!("relay {}", relay_name),
+    SourceMatch::Link(link_name) => println!("link {}", link_name),
+    SourceMatch::Dropped { name, relay, reason } => {
+      let kind = if relay { "relay" } else { "link" };
+      anyhow::bail!("datagrams from {} for {} {} would be dropped: {}", source, kind, name, reason.describe());
+    },
+    SourceMatch::Unknown => anyhow::bail!("{} is not a remote of any enabled raw link or relay", source),
+  }
+  Ok(())
+}
+
+/// Print the version and the result of probing the runtime environment.
+async fn print_info(config_path: PathBuf, json: bool) -> Result<(), anyhow::Error> {
+  // The configuration is optional here; it only tells which clone device to probe.
+  let tun_device = load_config(&config_path).await.ok().and_then(|config| config.tun_device);
+  let probes = probe::probe_all(tun_device.as_deref());
+  let version = env!("CARGO_PKG_VERSION");
+
+  if !json {
+    println!("{} {}", APP_NAME, version);
+    for (name, result) in &probes {
+      println!("{}: {}", name, result);
+    }
+    return Ok(());
+  }
+
+  let probes = probes.iter().map(|(name, result)| {
+    let result = match result {
+      probe::Probe::Yes => "{\"result\":\"yes\"}".to_string(),
+      probe::Probe::No => "{\"result\":\"no\"}".to_string(),
+      probe::Probe::Unknown(reason) => format!("{{\"result\":\"unknown\",\"reason\":{}}}", json_string(reason)),
+    };
+    format!("{}:{}", json_string(name), result)
+  }).collect::<Vec<_>>();
+  println!("{{\"name\":{},\"version\":{},\"probes\":{{{}}}}}", json_string(APP_NAME), json_string(version), probes.join(","));
+  Ok(())
+}
+
+/// Quote a string as a JSON string literal.
+fn json_string(value: &str) -> String {
+  let mut quoted = String::with_capacity(value.len() + 2);
+  quoted.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Run a single link in the foreground until interrupted.
+async fn run_link(config_path: PathBuf, link_name: String, log_target: LogTarget) -> Result<(), anyhow::Error> {
+  match log_target {
+    LogTarget::Stderr => log::set_logger(&StderrLogger).map_err(|e| anyhow::anyhow!("{}", e))?,
+    LogTarget::Syslog => init_syslog()?,
+  }
+  logging::set_levels(log::LevelFilter::Debug, []);
+
+  let mut config = match load_config(&config_path).await {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("Invalid or nonexistent configuration: {}", config_path.display());
+      return Err(e);
+    }
+  };
+  let link_config = config.links.remove(&link_name).ok_or_else(|| anyhow::anyhow!("Link {} is not configured in {}", link_name, config_path.display()))?;
+  if !link_config.enabled {
+    anyhow::bail!("Link {} is disabled in {}", link_name, config_path.display());
+  }
+  config.links = HashMap::from([(link_name.clone(), link_config.clone())]);
+  config.relays.clear();
+
+  let tap = Arc::new(open_tap(&link_name, &link_config, &config.tap_options())?);
+  let mirror = open_mirror(&link_name, &link_config, &config.tap_options()).map(Arc::new);
+  let input = open_input(&link_name, &link_config);
+  if link_config.transport != config::Transport::Raw {
+    log::info!("Running link {} in the foreground over {:?} (remote {})", link_name, link_config.transport, link_config.remote);
+    let stats = stats::Stats::new();
+    select! {
+      result = tokio::signal::ctrl_c() => {
+        result?;
+        log::info!("Interrupted, stopping link {}", link_name);
+      },
+      result = async {
+        match link_config.transport {
+          config::Transport::Udp => run_udp_link(link_name.clone(), link_config, LinkInterfaces { tap, mirror, input }, stats.link(&link_name)).await,
+          _ => run_tcp_link(link_name.clone(), link_config, LinkInterfaces { tap, mirror, input }, stats.link(&link_name)).await,
+        }
+      } => {
+        log::info!("Link {} exited", link_name);
+        result?;
+      },
+    }
+    return Ok(());
+  }
+  let etherip_socket = Arc::new(open_socket(config.socket_family(), config.bind_address)?);
+  warn_if_v6only(&etherip_socket, &config);
+  let stats = stats::Stats::new();
+  sync_scope_ids(&etherip_socket, &config);
+  let mut link_map = config.link_map();
+  let _ = link_map.update().await;
+  if link_config.tclass_echo {
+    etherip_socket.set_recv_tclass(true)?;
+  }
+  if config.rpf != config::Rpf::Off {
+    etherip_socket.set_recv_pktinfo(true)?;
+  }
+  if link_config.is_multicast() {
+    etherip_socket.set_multicast_loop(false)?;
+  }
+  let tclass = link_config.tclass_echo.then(|| Arc::new(TclassMirror::default()));
+  let receivers = HashMap::from([(link_name.clone(), LinkReceiver::new(tap.clone(), mirror, open_output(&link_name, &link_config), &link_config, stats.link(&link_name), tclass.clone()))]);
+
+  log::info!("Running link {} in the foreground (remote {})", link_name, link_config.remote);
+  select! {
+    result = tokio::signal::ctrl_c() => {
+      result?;
+      log::info!("Interrupted, stopping link {}", link_name);
+    },
+    result = receive_from_input(link_name.clone(), link_config, tap, input, etherip_socket.clone(), stats.link(&link_name), tclass) => {
+      log::info!("TAP receiver {} exited", link_name);
+      result?;
+    },
+    result = receive_from_etherip_socket(etherip_socket, receivers, HashMap::new(), &mut link_map, config.rpf, None, None) => {
+      log::info!("EtherIP socket receiver exited");
+      result?;
+    },
+  }
+  Ok(())
+}
+
+async fn run_daemon(config_path: PathBuf) -> Result<(), anyhow::Error> {
+  let mut config = match load_config(&config_path).await {
     Ok(config) => config,
     Err(e) => {
       eprintln!("Invalid or nonexistent configuration: {}", config_path.display());
       return Err(e);
     }
   };
+  config::set_max_concurrent_resolutions(config.max_concurrent_resolutions);
+  if config.buffer_arena_size > 0 {
+    let _ = BUFFER_ARENA.set(arena::BufferArena::new(config.buffer_arena_size, config.max_frame_size));
+  }
+  // Resolved before any interface or endpoint comes up, so the first frames are not dropped.
+  for (link_name, reason) in config.resolve_remotes().await {
+    link_log!(&link_name, log::Level::Warn, "Link {} starts pending: its remote could not be resolved ({})", link_name, reason);
+  }
   let config = Arc::new(RwLock::new(config));
 
-  let mut hup_stream = signal(SignalKind::hangup())?;
+  let mut term_stream = signal(SignalKind::terminate())?;
 
   let (reload_sender, _) = broadcast::channel(16);
-  let reload_sender_2 = reload_sender.clone();
+  let mut reload_receiver = reload_sender.subscribe();
 
   let (kill_sender, _) = broadcast::channel(16);
 
-  let reloading_config = config.clone();
+  let (shutdown_sender, shutdown_receiver) = broadcast::channel::<()>(1);
 
-  // Thread that reloads the configuration when a HUP signal is received.
-  tokio::spawn(async move {
-    loop {
-      hup_stream.recv().await;
-      let new_config = load_config(&config_path).await;
-      let mut config_changed = false;
-      match new_config {
-        Ok(new_config) => {
-          let mut config = reloading_config.write();
-          *config = new_config;
-          config_changed = true;
-          log::info!("Reloaded configuration from {}", config_path.display());
-        },
-        Err(e) => {
-          log::warn!("Failed to reload configuration from {}: {}", config_path.display(), e);
+  // Set once the daemon runs as `user`, after which links cannot be created or deleted.
+  let privileges_dropped = Arc::new(AtomicBool::new(false));
+
+  let reload_task = spawn_reload_task(config.clone(), config_path.clone(), privileges_dropped.clone(), reload_sender, shutdown_receiver)?;
+
+  let stats = Arc::new(stats::Stats::new());
+
+  #[cfg(feature = "task-metrics")]
+  let task_monitors = Arc::new(metrics::TaskMonitors::new());
+
+  let metrics_listen = config.read().metrics_listen;
+  if let Some(metrics_listen) = metrics_listen {
+    let stats = stats.clone();
+    #[cfg(feature = "task-metrics")]
+    let task_monitors = task_monitors.clone();
+    tokio::spawn(async move {
+      let result = metrics::serve(metrics_listen, move |endpoint| {
+        let mut writer = metrics::MetricsWriter::new();
+        match endpoint {
+          metrics::Endpoint::Metrics => {
+            writer.family("etherip_build_info", "gauge", "Version of the EtherIP daemon.");
+            writer.sample("etherip_build_info", &[("version", env!("CARGO_PKG_VERSION"))], 1);
+            stats.render(&mut writer);
+            if let Some(arena) = BUFFER_ARENA.get() {
+              arena.render(&mut writer);
+            }
+            #[cfg(feature = "task-metrics")]
+            task_monitors.render(&mut writer);
+          },
+          metrics::Endpoint::Stats => stats.render_since_reset(&mut writer, false),
+          metrics::Endpoint::StatsReset => stats.render_since_reset(&mut writer, true),
+          metrics::Endpoint::Flows => stats.render_flows(&mut writer, FLOWS_SHOWN),
+          metrics::Endpoint::Fdb => stats.render_fdb(&mut writer, FDB_ENTRIES_SHOWN),
+          metrics::Endpoint::FdbTable => return stats.format_fdb_table(FDB_ENTRIES_SHOWN),
         }
+        writer.finish()
+      }).await;
+      if let Err(e) = result {
+        log::error!("Metrics endpoint on {} failed: {}", metrics_listen, e);
       }
-      if config_changed {
-        reload_sender_2.send(()).unwrap();
-      }
-    }
-  });
+    });
+  }
 
   let tap_interfaces = RwLock::new(HashMap::new() as HashMap<String, Arc<tap::Tap>>);
-  let etherip_socket = Arc::new(EtherIpSocket::new()?);
+  // Network namespaces of the TAP interfaces not created in the daemon's namespace.
+  let mut tap_namespaces: HashMap<String, String> = HashMap::new();
+  // Opened `mirror_to` interfaces by link, with their network namespaces.
+  let mut mirror_taps: HashMap<String, (Arc<tap::Tap>, Option<String>)> = HashMap::new();
+  let mut drop_to = config.read().user.clone().map(|user| (user, config.read().group.clone()));
+  let socket_family = config.read().socket_family();
+  if config.read().native_ipv4 && socket_family != SocketFamily::Inet {
+    log::warn!("native_ipv4 is ignored because some links use IPv6");
+  }
+  let mut bind_address = config.read().bind_address;
+  let mut etherip_socket = Arc::new(open_socket(socket_family, bind_address)?);
+  warn_if_v6only(&etherip_socket, &config.read());
+
+  // Signalled by the socket receiver when the socket becomes unusable.
+  let (socket_failure_sender, mut socket_failure_receiver) = mpsc::channel::<()>(1);
+
+  // Signalled by the watchdog when a forwarding task stalls.
+  let (stall_sender, mut stall_receiver) = mpsc::channel::<ForwardingTask>(16);
+
+  // Link map handed back by the socket receiver when it stops after a socket failure.
+  let mut previous_link_map: Option<config::AddrStringMap<String>> = None;
+
+  // The socket receiver outlives reloads, which hand it the new link receivers,
+  // so that inbound traffic keeps flowing while the link tasks are recycled.
+  let mut socket_task: Option<SocketTask> = None;
+
+  // Source-specific multicast groups currently joined, as (group, source, ifindex).
+  let mut ssm_joins: HashSet<(IpAddr, IpAddr, u32)> = HashSet::new();
 
   loop {
-    let etherip_socket = etherip_socket.clone();
-    let config = config.read();
-    log::set_max_level(config.level_filter());
+    let (links, relay_configs, link_pairs, tap_options, shared_tap_reader, max_frame_size, rpf, new_bind_address, watchdog_timeout) = {
+      let config = config.read();
+      logging::set_levels(config.level_filter(), config.link_level_filters());
+      (config.links.clone(), config.relays.clone(), config.link_pairs(), config.tap_options(), config.shared_tap_reader, config.max_frame_size, config.rpf, config.bind_address, config.watchdog_timeout)
+    };
 
-    let links = config.links.clone();
-    let mut link_map = config.link_map();
-    drop(config);
+    // A bound raw socket cannot be bound again, so a new one replaces it. The socket
+    // receiver is stopped first and restarted below with the link map it hands back.
+    if let Some(new_socket) = rebind_socket(socket_family, bind_address, new_bind_address) {
+      if let Some((task, update_sender)) = socket_task.take() {
+        drop(update_sender);
+        previous_link_map = Some(task.await?);
+      }
+      etherip_socket = Arc::new(new_socket);
+      ssm_joins.clear();
+      log::info!("Rebound the EtherIP socket to {}", new_bind_address.map_or("the unspecified address".to_string(), |addr| addr.to_string()));
+      bind_address = new_bind_address;
+    }
 
-    let _ = link_map.update().await;
+    if links.is_empty() {
+      log::warn!("No links are configured in {}; idling until the configuration is reloaded", config_path.display());
+    }
 
     {
       let mut tap_interfaces = tap_interfaces.write();
-      for (link_name, _link_config) in &links {
+      for (link_name, link_config) in &links {
         if !tap_interfaces.contains_key(link_name) {
-          let tap = tap::Tap::new(link_name)?;
+          let tap = open_tap(link_name, link_config, &tap_options)?;
           tap_interfaces.insert(link_name.clone(), Arc::new(tap));
+          if let Some(netns) = &link_config.netns {
+            tap_namespaces.insert(link_name.clone(), netns.clone());
+          }
+        }
+      }
+
+      // Someone else may have changed the MTU since it was last read.
+      for (link_name, tap) in tap_interfaces.iter().filter(|(link_name, _)| links.contains_key(*link_name)) {
+        let previous = tap.mtu();
+        match in_link_netns(tap_namespaces.get(link_name).map(String::as_str), || tap.refresh_mtu()) {
+          Ok(mtu) if mtu != previous => link_log!(link_name, log::Level::Info, "MTU of TAP interface {} is now {}", link_name, mtu),
+          Ok(_) => {},
+          Err(e) => link_log!(link_name, log::Level::Warn, "Failed to read the MTU of TAP interface {}: {}", link_name, e),
+        }
+      }
+    }
+
+    // Mirrors that are no longer configured are closed along with the removed TAP interfaces.
+    let retired_mirrors: Vec<(Arc<tap::Tap>, Option<String>)> = mirror_taps.extract_if(|link_name, (mirror, _)| {
+      links.get(link_name).and_then(|link_config| link_config.mirror_to.as_deref()) != Some(mirror.name())
+    }).map(|(_, mirror)| mirror).collect();
+    for (link_name, link_config) in &links {
+      if link_config.mirror_to.is_some() && !mirror_taps.contains_key(link_name) {
+        if let Some(mirror) = open_mirror(link_name, link_config, &tap_options) {
+          mirror_taps.insert(link_name.clone(), (Arc::new(mirror), link_config.netns.clone()));
         }
       }
+    }
+
+    stats.retain_links(|link_name| links.contains_key(link_name));
+    stats.retain_relays(|relay_name| relay_configs.contains_key(relay_name));
+    #[cfg(feature = "task-metrics")]
+    task_monitors.retain_links(|link_name| links.contains_key(link_name));
+
+    // Disabled links keep their TAP interfaces, but get no receivers or tasks.
+    for (link_name, link_config) in &links {
+      stats.link(link_name).enabled.set(link_config.enabled.into());
+      stats.link(link_name).flows.set_capacity(link_config.flow_table_size);
+      stats.link(link_name).fdb.set_capacity(link_config.fdb_size);
+      if !link_config.enabled {
+        link_log!(link_name, log::Level::Info, "Link {} is disabled", link_name);
+      }
+    }
+    let enabled_links: HashMap<String, config::LinkConfig> = links.iter()
+      .filter(|(_, link_config)| link_config.enabled)
+      .map(|(link_name, link_config)| (link_name.clone(), link_config.clone()))
+      .collect();
+
+    let tclass_mirrors: HashMap<String, Arc<TclassMirror>> = enabled_links.iter()
+      .filter(|(_, link_config)| link_config.tclass_echo)
+      .map(|(link_name, _)| (link_name.clone(), Arc::new(TclassMirror::default())))
+      .collect();
+
+    let inputs: HashMap<String, Arc<packet::PacketSocket>> = enabled_links.iter()
+      .filter_map(|(link_name, link_config)| Some((link_name.clone(), open_input(link_name, link_config)?)))
+      .collect();
+    // Links with an input are read by their own tasks.
+    let shared_reader_reads = |link_name: &String, link_config: &config::LinkConfig| {
+      shared_tap_reader && link_config.transport == config::Transport::Raw && !inputs.contains_key(link_name)
+    };
+
+    let receivers: HashMap<String, LinkReceiver<tap::Tap>> = {
+      let tap_interfaces = tap_interfaces.read();
+      enabled_links.iter().filter(|(_, link_config)| link_config.transport == config::Transport::Raw).map(|(link_name, link_config)| {
+        let mirror = mirror_taps.get(link_name).map(|(mirror, _)| mirror.clone());
+        (link_name.clone(), LinkReceiver::new(tap_interfaces[link_name].clone(), mirror, open_output(link_name, link_config), link_config, stats.link(link_name), tclass_mirrors.get(link_name).cloned()))
+      }).collect()
+    };
+    let (applied, applied_receiver) = oneshot::channel();
+    let relays: HashMap<String, Relay> = relay_configs.iter()
+      .map(|(relay_name, relay_config)| (relay_name.clone(), Relay::new(relay_config, stats.relay(relay_name))))
+      .collect();
+    let update = ReceiverUpdate { receivers, relays, link_pairs, rpf, applied };
+    let update = match &socket_task {
+      Some((_, update_sender)) => match update_sender.send(update).await {
+        Ok(()) => {
+          if applied_receiver.await.is_err() {
+            log::debug!("EtherIP socket receiver stopped before the reload was applied");
+          }
+          None
+        },
+        Err(mpsc::error::SendError(update)) => Some(update),
+      },
+      None => Some(update),
+    };
+    // Start the socket receiver, or restart it if it has stopped on its own.
+    if let Some(update) = update {
+      if let Some((task, _)) = socket_task.take() {
+        previous_link_map = Some(task.await?);
+      }
+      let ReceiverUpdate { receivers, relays, link_pairs, rpf, .. } = update;
+      let mut link_map = match previous_link_map.take() {
+        Some(mut link_map) => {
+          link_map.reconcile(link_pairs);
+          link_map
+        },
+        None => config::AddrStringMap::new(link_pairs),
+      };
+      let _ = link_map.update().await;
+
+      let etherip_socket = etherip_socket.clone();
+      let socket_failure_sender = socket_failure_sender.clone();
+      let (update_sender, mut update_receiver) = mpsc::channel(1);
+      let stats = stats.clone();
+      #[cfg(feature = "task-metrics")]
+      let monitor = task_monitors.monitor("socket_rx", "");
+
+      let task = async move {
+        let result = receive_from_etherip_socket(etherip_socket, receivers, relays, &mut link_map, rpf, Some(&mut update_receiver), Some(&stats.socket_rx_heartbeat)).await;
+        log::info!("EtherIP socket receiver exited");
+        if let Err(e) = result {
+          if e.downcast_ref::<std::io::Error>().is_some_and(is_fatal_socket_error) {
+            log::error!("EtherIP socket failed: {}", e);
+            let _ = socket_failure_sender.try_send(());
+          }
+        }
+        link_map
+      };
+      #[cfg(feature = "task-metrics")]
+      let task = monitor.instrument(task);
+      socket_task = Some((tokio::spawn(task), update_sender));
+    }
 
+    {
+      let mut tap_interfaces = tap_interfaces.write();
       let to_remove: Vec<String> = tap_interfaces.keys().filter(|link_name| !links.contains_key(*link_name)).cloned().collect();
       for link_name in to_remove {
-        tap_interfaces.remove(&link_name);
-        tap::tap_del_ioctl(&link_name)?;
+        // The link tasks have been joined and the socket receiver has dropped its link
+        // receivers, so this is normally the last reference.
+        if let Some(Ok(tap)) = tap_interfaces.remove(&link_name).map(Arc::try_unwrap) {
+          if let Err(e) = tap.close() {
+            log::warn!("Failed to close TAP interface {}: {}", link_name, e);
+          }
+        }
+        let netns = tap_namespaces.remove(&link_name);
+        in_link_netns(netns.as_deref(), || tap::tap_del_ioctl_at(tap_options.tun_device.as_deref(), &link_name))?;
+      }
+    }
+    for (mirror, netns) in retired_mirrors {
+      let mirror_name = mirror.name().to_string();
+      if let Ok(mirror) = Arc::try_unwrap(mirror) {
+        if let Err(e) = mirror.close() {
+          log::warn!("Failed to close mirror interface {}: {}", mirror_name, e);
+        }
+      }
+      if let Err(e) = in_link_netns(netns.as_deref(), || tap::tap_del_ioctl_at(tap_options.tun_device.as_deref(), &mirror_name)) {
+        log::warn!("Failed to delete mirror interface {}: {}", mirror_name, e);
       }
     }
 
-    let mut tasks = Vec::new();
-    for (link_name, link_config) in &links {
-      let link_name = link_name.clone();
-      let link_config = link_config.clone();
+    sync_ssm_joins(&etherip_socket, &enabled_links, &mut ssm_joins);
+    sync_scope_ids(&etherip_socket, &config.read());
+    sync_peer_filter(&etherip_socket, &config.read());
+
+    if let Err(e) = etherip_socket.set_recv_tclass(enabled_links.values().any(|link_config| link_config.tclass_echo)) {
+      log::warn!("Failed to enable receiving the traffic class: {}", e);
+    }
+    if let Err(e) = etherip_socket.set_recv_pktinfo(rpf != config::Rpf::Off) {
+      log::warn!("Failed to enable receiving the arrival interface: {}", e);
+    }
+    // Multicast links would otherwise receive their own datagrams.
+    let multicast = enabled_links.values().any(|link_config| link_config.transport == config::Transport::Raw && link_config.is_multicast());
+    if let Err(e) = etherip_socket.set_multicast_loop(!multicast) {
+      log::warn!("Failed to set multicast loopback: {}", e);
+    }
+
+    // Everything needing privileges has been opened by the first pass; fail closed.
+    if let Some((user, group)) = drop_to.take() {
+      privileges::drop_privileges(&user, group.as_deref()).map_err(|e| anyhow::anyhow!("Failed to drop privileges to user {}: {}", user, e))?;
+      privileges_dropped.store(true, Ordering::Relaxed);
+      log::info!("Running as user {}", user);
+    }
+
+    let spawn_shared_tap_reader = || {
       let mut kill_receiver = kill_sender.subscribe();
-      let tap = tap_interfaces.read().get(&link_name).unwrap().clone();
+      let shared_links = {
+        let tap_interfaces = tap_interfaces.read();
+        enabled_links.iter().filter(|(link_name, link_config)| shared_reader_reads(link_name, link_config)).map(|(link_name, link_config)| {
+          SharedTapLink {
+            transmitter: LinkTransmitter::new(link_name.clone(), link_config, stats.link(link_name), tclass_mirrors.get(link_name).cloned()),
+            link_config: link_config.clone(),
+            tap: tap_interfaces[link_name].clone(),
+          }
+        }).collect()
+      };
       let etherip_socket = etherip_socket.clone();
+      #[cfg(feature = "task-metrics")]
+      let monitor = task_monitors.monitor("tap_rx", "");
 
-      tasks.push(tokio::spawn(async move {
+      let (task, abort_handle) = futures::future::abortable(async move {
         select! {
           _ = kill_receiver.recv() => {
-            log::debug!("TAP receiver {} killed", link_name);
+            log::debug!("Shared TAP receiver killed");
           },
-          _ = receive_from_tap(link_name.clone(), link_config, tap, etherip_socket) => {
-            log::info!("TAP receiver {} exited", link_name);
+          _ = receive_from_taps(shared_links, etherip_socket, max_frame_size) => {
+            log::info!("Shared TAP receiver exited");
           }
         }
-      }));
-    }
-
-    {
+      });
+      let task = async move {
+        let _ = task.await;
+      };
+      #[cfg(feature = "task-metrics")]
+      let task = monitor.instrument(task);
+      (tokio::spawn(task), abort_handle)
+    };
+    let spawn_link_task = |link_name: &String, link_config: &config::LinkConfig| {
+      let link_name = link_name.clone();
+      let link_config = link_config.clone();
       let mut kill_receiver = kill_sender.subscribe();
-      let tap_interfaces = tap_interfaces.read().clone();
+      let tap = tap_interfaces.read().get(&link_name).unwrap().clone();
+      let mirror = mirror_taps.get(&link_name).map(|(mirror, _)| mirror.clone());
+      let input = inputs.get(&link_name).cloned();
+      let etherip_socket = etherip_socket.clone();
+      let link_stats = stats.link(&link_name);
+      let tclass = tclass_mirrors.get(&link_name).cloned();
+      let cpu_affinity = link_config.cpu_affinity.clone();
+      let link_name_for_thread = link_name.clone();
+      #[cfg(feature = "task-metrics")]
+      let monitor = task_monitors.monitor("tap_rx", &link_name);
 
-      tasks.push(tokio::spawn(async move {
+      let (task, abort_handle) = futures::future::abortable(async move {
         select! {
           _ = kill_receiver.recv() => {
-            log::debug!("EtherIP socket receiver killed");
+            link_log!(&link_name, log::Level::Debug, "TAP receiver {} killed", link_name);
           },
-          _ = receive_from_etherip_socket(etherip_socket, tap_interfaces, link_map) => {
-            log::info!("EtherIP socket receiver exited");
+          result = run_link_transport(link_name.clone(), link_config, LinkInterfaces { tap, mirror, input }, etherip_socket, link_stats, tclass) => {
+            link_log!(&link_name, log::Level::Info, "TAP receiver {} exited", link_name);
+            if let Err(e) = result {
+              link_log!(&link_name, log::Level::Error, "Link {} failed: {}", link_name, e);
+            }
           }
         }
-      }));
+      });
+      let task = async move {
+        let _ = task.await;
+      };
+      #[cfg(feature = "task-metrics")]
+      let task = monitor.instrument(task);
+      let handle = match cpu_affinity {
+        Some(cpus) => spawn_pinned(link_name_for_thread, cpus, task),
+        None => tokio::spawn(task),
+      };
+      (handle, abort_handle)
+    };
+
+    let mut tasks = HashMap::new();
+    if enabled_links.iter().any(|(link_name, link_config)| shared_reader_reads(link_name, link_config)) {
+      tasks.insert(ForwardingTask::SharedTapReader, spawn_shared_tap_reader());
+    }
+    for (link_name, link_config) in enabled_links.iter().filter(|(link_name, link_config)| !shared_reader_reads(link_name, link_config)) {
+      tasks.insert(ForwardingTask::Link(link_name.clone()), spawn_link_task(link_name, link_config));
     }
 
-    reload_sender.subscribe().recv().await?;
-    kill_sender.send(()).unwrap();
-    let results = futures::future::join_all(tasks).await;
+    let watchdog_task = (watchdog_timeout > 0).then(|| {
+      let mut watchdog = watchdog::Watchdog::new(Duration::from_secs(watchdog_timeout));
+      for (link_name, link_config) in &enabled_links {
+        let task = match shared_reader_reads(link_name, link_config) {
+          true => ForwardingTask::SharedTapReader,
+          false => ForwardingTask::Link(link_name.clone()),
+        };
+        let input: Arc<dyn AsRawFd + Send + Sync> = match inputs.get(link_name) {
+          Some(input) => input.clone(),
+          None => tap_interfaces.read()[link_name].clone(),
+        };
+        let link_stats = stats.link(link_name);
+        watchdog.watch(task, move || link_stats.tx_heartbeat.get(), input);
+      }
+      if socket_task.is_some() {
+        let stats = stats.clone();
+        watchdog.watch(ForwardingTask::SocketReceiver, move || stats.socket_rx_heartbeat.get(), etherip_socket.clone());
+      }
+      let mut kill_receiver = kill_sender.subscribe();
+      let stall_sender = stall_sender.clone();
+      tokio::spawn(async move {
+        select! {
+          _ = kill_receiver.recv() => {},
+          _ = run_watchdog(watchdog, stall_sender) => {},
+        }
+      })
+    });
+    // Stalls reported before this rebuild concern tasks that no longer exist.
+    while stall_receiver.try_recv().is_ok() {}
+
+    let mut socket_failed = false;
+    let shutdown = loop {
+      select! {
+        // Lagging behind several reloads still means one rebuild with the latest configuration.
+        _ = reload_receiver.recv() => break false,
+        Some(()) = socket_failure_receiver.recv() => {
+          socket_failed = true;
+          break false;
+        },
+        Some(task) = stall_receiver.recv() => {
+          log::warn!("Restarting the {}, which has not read its waiting input for {} seconds", task, watchdog_timeout);
+          match &task {
+            // The socket receiver is restarted by a rebuild, with a new link map.
+            ForwardingTask::SocketReceiver => {
+              stats.socket_receiver_restarts.inc();
+              if let Some((task, _)) = socket_task.take() {
+                task.abort();
+                let _ = task.await;
+              }
+              break false;
+            },
+            ForwardingTask::SharedTapReader => stats.shared_tap_reader_restarts.inc(),
+            ForwardingTask::Link(link_name) => stats.link(link_name).watchdog_restarts.inc(),
+          }
+          if let Some((handle, abort_handle)) = tasks.remove(&task) {
+            abort_handle.abort();
+            handle.await?;
+          }
+          let restarted = match &task {
+            ForwardingTask::Link(link_name) => spawn_link_task(link_name, &enabled_links[link_name]),
+            _ => spawn_shared_tap_reader(),
+          };
+          tasks.insert(task, restarted);
+        },
+        _ = term_stream.recv() => break true,
+        result = tokio::signal::ctrl_c() => {
+          result?;
+          break true;
+        },
+      }
+    };
+    // Tasks that already exited have dropped their receivers; there may be none left.
+    if kill_sender.send(()).is_err() {
+      log::debug!("No link tasks left to stop");
+    }
+    let results = futures::future::join_all(tasks.into_values().map(|(handle, _)| handle).chain(watchdog_task)).await;
     for result in results {
       result?;
     }
+    if socket_failed || shutdown {
+      // Closing the update channel stops the socket receiver if it is still running.
+      if let Some((task, update_sender)) = socket_task.take() {
+        drop(update_sender);
+        previous_link_map = Some(task.await?);
+      }
+    }
+
+    if socket_failed && !shutdown {
+      etherip_socket = recreate_socket(socket_family, bind_address, &stats).await;
+      // The memberships were dropped along with the old socket.
+      ssm_joins.clear();
+    }
+
+    if shutdown {
+      log::info!("Shutting down");
+      let _ = shutdown_sender.send(());
+      reload_task.await?;
+      return Ok(());
+    }
   }
 }
 
-async fn receive_from_tap(link_name: String, link_config: config::LinkConfig, tap: Arc<tap::Tap>, etherip_socket: Arc<EtherIpSocket>) -> Result<(), anyhow::Error> {
-  let mut datagram = EtherIpDatagram::new();
-  let mut remote_addr = link_config.remote_addr();
-  loop {
-    let _ = remote_addr.update_ip_addr().await;
-    let (mut len_setter, mut buf) = datagram.ethrnet_frame_mut();
-    match tap.read(&mut buf).await {
-      Ok(len) => len_setter.set(len),
-      Err(e) => {
-        log::warn!("Failed to read from TAP interface {}: {}", link_name, e);
-        continue;
-      }
-    }
-    drop(len_setter);
+/// Forwarding task the watchdog can restart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ForwardingTask {
+  /// Task reading the TAP interface or input of a link.
+  Link(String),
+  SharedTapReader,
+  SocketReceiver,
+}
 
-    if let Some(remote_addr) = remote_addr.try_get_ip_addr() {
-      let _ = etherip_socket.send_to(&datagram, &remote_addr).await;
-    } else {
-      log::debug!("Sending a packet to an unknown remote address");
-      continue;
+impl std::fmt::Display for ForwardingTask {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ForwardingTask::Link(link_name) => write!(f, "TAP reader of link {}", link_name),
+      ForwardingTask::SharedTapReader => write!(f, "shared TAP reader"),
+      ForwardingTask::SocketReceiver => write!(f, "EtherIP socket receiver"),
     }
   }
 }
 
-async fn receive_from_etherip_socket(etherip_socket: Arc<EtherIpSocket>, tap_interfaces: HashMap<String, Arc<tap::Tap>>, mut link_map: config::AddrStringMap<String>) -> Result<(), anyhow::Error> {
-  let mut datagram = EtherIpDatagram::new();
+/// Interval between two checks of the watchdog.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Report the tasks the watchdog finds stalled until the receiving side is gone.
+async fn run_watchdog(mut watchdog: watchdog::Watchdog<ForwardingTask>, stall_sender: mpsc::Sender<ForwardingTask>) {
+  let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
   loop {
-    let _ = link_map.update().await;
+    interval.tick().await;
+    for task in watchdog.check() {
+      if stall_sender.send(task).await.is_err() {
+        return;
+      }
+    }
+  }
+}
 
-    let src = match etherip_socket.recv_from(&mut datagram).await {
-      Ok((_, src)) => src,
-      Err(e) => {
-        log::warn!("Failed to receive from EtherIP socket: {}", e);
-        continue;
+/// Spawn the task that reloads the configuration when a HUP signal is received, notifying
+/// `reload_sender`, and has the remotes re-resolved when a USR2 signal is.
+/// It stops once `shutdown_receiver` is signalled or closed.
+fn spawn_reload_task(config: Arc<RwLock<config::Config>>, config_path: PathBuf, privileges_dropped: Arc<AtomicBool>, reload_sender: broadcast::Sender<()>, mut shutdown_receiver: broadcast::Receiver<()>) -> std::io::Result<tokio::task::JoinHandle<()>> {
+  let mut hup_stream = signal(SignalKind::hangup())?;
+  let mut usr2_stream = signal(SignalKind::user_defined2())?;
+  Ok(tokio::spawn(async move {
+    loop {
+      select! {
+        _ = shutdown_receiver.recv() => {
+          log::debug!("Reload task stopped");
+          break;
+        },
+        _ = hup_stream.recv() => {},
+        _ = usr2_stream.recv() => {
+          config::refresh_remotes_now();
+          log::info!("Re-resolving the remotes of all links");
+          continue;
+        },
       }
-    };
+      let new_config = load_config(&config_path).await;
+      let mut config_changed = false;
+      match new_config {
+        Ok(new_config) if privileges_dropped.load(Ordering::Relaxed) && !same_links(&config.read(), &new_config) => {
+          log::warn!("Ignoring the configuration in {}: links cannot be added or removed after dropping privileges; restart the daemon instead", config_path.display());
+        },
+        Ok(new_config) => {
+          config::set_max_concurrent_resolutions(new_config.max_concurrent_resolutions);
+          let mut config = config.write();
+          *config = new_config;
+          config_changed = true;
+          log::info!("Reloaded configuration from {}", config_path.display());
+        },
+        Err(e) => {
+          log::warn!("Failed to reload configuration from {}: {}", config_path.display(), e);
+        }
+      }
+      if config_changed && reload_sender.send(()).is_err() {
+        log::debug!("No link tasks are waiting for the reloaded configuration");
+      }
+    }
+  }))
+}
 
-    let eth_frame = if let Some(eth_frame) = datagram.ethrnet_frame() {
-      eth_frame
-    } else {
-      log::debug!("Received a packet with an invalid EtherIP header from {}", src);
-      continue;
-    };
+/// Whether two configurations have the same set of links.
+fn same_links(config: &config::Config, new_config: &config::Config) -> bool {
+  config.links.len() == new_config.links.len() && new_config.links.keys().all(|link_name| config.links.contains_key(link_name))
+}
+
+/// Warn at startup when IPv4 links cannot work because the socket is IPv6-only.
+fn warn_if_v6only(etherip_socket: &EtherIpSocket, config: &config::Config) {
+  if !etherip_socket.is_v6only() {
+    return;
+  }
+  for (link_name, _) in config.links.iter().filter(|(_, link_config)| link_config.ip_version == config::IpVersion::V4) {
+    link_log!(link_name, log::Level::Warn, "Link {} cannot reach its IPv4 remote because net.ipv6.bindv6only is set; set it to 0 or enable native_ipv4", link_name);
+  }
+}
+
+/// Open the EtherIP socket, bound to `bind_address` if set.
+fn open_socket(socket_family: SocketFamily, bind_address: Option<IpAddr>) -> std::io::Result<EtherIpSocket> {
+  match bind_address {
+    Some(addr) => EtherIpSocket::new_bound(socket_family, &addr),
+    None => EtherIpSocket::new_with_family(socket_family),
+  }
+}
+
+/// Open a socket bound to `new_bind_address` if it differs from `bind_address`.
+/// `None` if the address is unchanged or the new socket cannot be opened, in which
+/// case the current socket is kept.
+fn rebind_socket(socket_family: SocketFamily, bind_address: Option<IpAddr>, new_bind_address: Option<IpAddr>) -> Option<EtherIpSocket> {
+  if new_bind_address == bind_address {
+    return None;
+  }
+  match open_socket(socket_family, new_bind_address) {
+    Ok(new_socket) => Some(new_socket),
+    Err(e) => {
+      log::error!("Failed to bind a new EtherIP socket to {:?}, keeping the current one: {}", new_bind_address, e);
+      None
+    },
+  }
+}
 
-    match link_map.get(&src) {
-      Some(link_name) => {
-        let tap = tap_interfaces.get(link_name).ok_or_else(|| anyhow::anyhow!("Link {} does not exist", link_name))?;
-        let _ = tap.write(eth_frame).await;
+/// Open a new EtherIP socket after the previous one failed, retrying with exponential backoff.
+async fn recreate_socket(socket_family: SocketFamily, bind_address: Option<IpAddr>, stats: &stats::Stats) -> Arc<EtherIpSocket> {
+  let mut delay = Duration::from_secs(1);
+  loop {
+    tokio::time::sleep(delay).await;
+    match open_socket(socket_family, bind_address) {
+      Ok(etherip_socket) => {
+        stats.socket_recreations.inc();
+        log::info!("Recreated the EtherIP socket");
+        return Arc::new(etherip_socket);
+      },
+      Err(e) => {
+        log::error!("Failed to recreate the EtherIP socket: {}", e);
+        delay = (delay * 2).min(Duration::from_secs(60));
       },
-      None => {
-        log::debug!("Received a packet from an unknown source IP address: {}", src);
-        continue;
-      }
     }
   }
 }
+
+/// Open the TAP interface of a link and apply its interface settings.
+fn open_tap(link_name: &str, link_config: &config::LinkConfig, tap_options: &tap::TapOptions) -> Result<tap::Tap, anyhow::Error> {
+  let tap = in_link_netns(link_config.netns.as_deref(), || create_tap(link_name, link_config, tap_options))?;
+  if let Some(netns) = &link_config.netns {
+    link_log!(link_name, log::Level::Info, "Created TAP interface {} in network namespace {}", link_name, netns);
+  }
+  Ok(tap)
+}
+
+/// Open the `mirror_to` interface of a link, if it has one. A link whose mirror
+/// cannot be opened runs without it.
+fn open_mirror(link_name: &str, link_config: &config::LinkConfig, tap_options: &tap::TapOptions) -> Option<tap::Tap> {
+  let mirror_to = link_config.mirror_to.as_deref()?;
+  match in_link_netns(link_config.netns.as_deref(), || tap::Tap::new_with_options(mirror_to, tap_options)) {
+    Ok(mirror) => {
+      link_log!(link_name, log::Level::Info, "Link {}: mirroring received frames to {}", link_name, mirror_to);
+      Some(mirror)
+    },
+    Err(e) => {
+      link_log!(link_name, log::Level::Warn, "Link {}: failed to open mirror interface {}: {}", link_name, mirror_to, e);
+      None
+    },
+  }
+}
+
+/// Open the interface a link writes received frames to, if its `output` is not its TAP.
+/// A link whose output cannot be opened writes to its TAP interface instead.
+fn open_output(link_name: &str, link_config: &config::LinkConfig) -> Option<Arc<packet::PacketSocket>> {
+  let packet::Attachment::Raw(ifname) = &link_config.output else {
+    return None;
+  };
+  match in_link_netns(link_config.netns.as_deref(), || packet::PacketSocket::new_output(ifname)) {
+    Ok(output) => {
+      link_log!(link_name, log::Level::Info, "Link {}: writing received frames to {}", link_name, ifname);
+      Some(Arc::new(output))
+    },
+    Err(e) => {
+      link_log!(link_name, log::Level::Error, "Link {}: failed to open output interface {}, writing to the TAP interface instead: {}", link_name, ifname, e);
+      None
+    },
+  }
+}
+
+/// Open the AF_PACKET socket a link reads frames from instead of its TAP interface, if it has one.
+/// Failures are logged, and the link reads its TAP interface instead.
+fn open_input(link_name: &str, link_config: &config::LinkConfig) -> Option<Arc<packet::PacketSocket>> {
+  let packet::Attachment::Raw(ifname) = &link_config.input else {
+    return None;
+  };
+  let result = link_config.input_filter.as_deref().map(bpf::parse_bytecode).transpose()
+    .and_then(|filter| in_link_netns(link_config.netns.as_deref(), || packet::PacketSocket::new_input(ifname, filter.as_deref())));
+  match result {
+    Ok(input) => {
+      link_log!(link_name, log::Level::Info, "Link {}: reading frames to send from {}", link_name, ifname);
+      Some(Arc::new(input))
+    },
+    Err(e) => {
+      link_log!(link_name, log::Level::Error, "Link {}: failed to open input interface {}, reading the TAP interface instead: {}", link_name, ifname, e);
+      None
+    },
+  }
+}
+
+/// Run `f` in the network namespace of a link, if it has one.
+fn in_link_netns<T, F: FnOnce() -> std::io::Result<T>>(netns: Option<&str>, f: F) -> std::io::Result<T> {
+  match netns {
+    Some(netns) => netns::run_in(netns, f),
+    None => f(),
+  }
+}
+
+fn create_tap(link_name: &str, link_config: &config::LinkConfig, tap_options: &tap::TapOptions) -> std::io::Result<tap::Tap> {
+  let tap = tap::Tap::new_with_options(link_name, tap_options)?;
+  if let Some(inner_mtu) = link_config.inner_mtu() {
+    tap.set_mtu(inner_mtu)?;
+    link_log!(link_name, log::Level::Debug, "Set the MTU of {} to {} ({} bytes of tunnel overhead)", link_name, inner_mtu, link_config.tunnel_overhead());
+  }
+  if let Some(txqueuelen) = link_config.txqueuelen {
+    tap::set_txqueuelen(link_name, txqueuelen)?;
+    match tap::get_txqueuelen(link_name) {
+      Ok(actual) if actual == txqueuelen => link_log!(link_name, log::Level::Debug, "Set txqueuelen of {} to {}", link_name, actual),
+      Ok(actual) => link_log!(link_name, log::Level::Warn, "txqueuelen of {} is {} instead of {}", link_name, actual, txqueuelen),
+      Err(e) => link_log!(link_name, log::Level::Warn, "Failed to read back the txqueuelen of {}: {}", link_name, e),
+    }
+  }
+  Ok(tap)
+}
+
+/// Join the source-specific multicast groups of the configured links and leave the ones no longer configured.
+fn sync_ssm_joins(etherip_socket: &EtherIpSocket, links: &HashMap<String, config::LinkConfig>, ssm_joins: &mut HashSet<(IpAddr, IpAddr, u32)>) {
+  let mut wanted = HashSet::new();
+  for (link_name, link_config) in links {
+    let Some(ssm) = &link_config.ssm else {
+      continue;
+    };
+    match ssm.ifindex() {
+      Ok(ifindex) => {
+        wanted.insert((ssm.group, ssm.source, ifindex));
+      },
+      Err(e) => link_log!(link_name, log::Level::Warn, "Link {}: cannot find multicast interface {:?}: {}", link_name, ssm.interface, e),
+    }
+  }
+
+  ssm_joins.retain(|(group, source, ifindex)| {
+    if wanted.contains(&(*group, *source, *ifindex)) {
+      return true;
+    }
+    if let Err(e) = etherip_socket.leave_ssm(group, source, *ifindex) {
+      log::warn!("Failed to leave multicast group {} (source {}): {}", group, source, e);
+    }
+    false
+  });
+  for (group, source, ifindex) in wanted {
+    if ssm_joins.contains(&(group, source, ifindex)) {
+      continue;
+    }
+    match etherip_socket.join_ssm(&group, &source, ifindex) {
+      Ok(()) => {
+        log::info!("Joined multicast group {} (source {})", group, source);
+        ssm_joins.insert((group, source, ifindex));
+      },
+      Err(e) => log::warn!("Failed to join multicast group {} (source {}): {}", group, source, e),
+    }
+  }
+}
+
+/// Set the outbound scopes of the links with a link-local remote.
+fn sync_scope_ids(etherip_socket: &EtherIpSocket, config: &config::Config) {
+  let mut scope_ids = HashMap::new();
+  for (link_name, scope_id) in config.scope_ids() {
+    match scope_id {
+      Ok((addr, scope_id)) => {
+        scope_ids.insert(addr, scope_id);
+      },
+      Err(e) => link_log!(&link_name, log::Level::Warn, "Link {}: cannot find underlay interface {:?}: {}", link_name, config.links[&link_name].interface, e),
+    }
+  }
+  etherip_socket.set_scope_ids(scope_ids);
+}
+
+/// Attach or detach the kernel peer filter of the EtherIP socket.
+/// Sources are still checked in userspace, so failing to attach only costs performance.
+fn sync_peer_filter(etherip_socket: &EtherIpSocket, config: &config::Config) {
+  let peers = config.peer_filter.then(|| config.static_peer_addrs()).flatten();
+  if config.peer_filter && peers.is_none() {
+    log::info!("Not attaching the peer filter because some remotes are hostnames");
+  }
+  if let Some(peers) = peers {
+    match etherip_socket.attach_peer_filter(&peers) {
+      Ok(()) => {
+        log::info!("Attached the peer filter for {} remote addresses", peers.len());
+        return;
+      },
+      Err(e) => log::warn!("Failed to attach the peer filter, filtering in userspace: {}", e),
+    }
+  }
+  if let Err(e) = etherip_socket.detach_filter() {
+    log::warn!("Failed to detach the peer filter: {}", e);
+  }
+}
+
+/// Forward the frames of a link: from its input or TAP interface to the EtherIP socket for raw links,
+/// and in both directions over the link's own connection for TCP links.
+async fn run_link_transport(link_name: String, link_config: config::LinkConfig, interfaces: LinkInterfaces, etherip_socket: Arc<EtherIpSocket>, link_stats: Arc<stats::LinkStats>, tclass: Option<Arc<TclassMirror>>) -> Result<(), anyhow::Error> {
+  match link_config.transport {
+    config::Transport::Raw => receive_from_input(link_name, link_config, interfaces.tap, interfaces.input, etherip_socket, link_stats, tclass).await,
+    config::Transport::Tcp => run_tcp_link(link_name, link_config, interfaces, link_stats).await,
+    config::Transport::Udp => run_udp_link(link_name, link_config, interfaces, link_stats).await,
+  }
+}
+
+/// Interfaces on the local side of a link.
+struct LinkInterfaces {
+  tap: Arc<tap::Tap>,
+  mirror: Option<Arc<tap::Tap>>,
+  /// Read instead of the TAP interface, if set.
+  input: Option<Arc<packet::PacketSocket>>,
+}
+
+/// Stack size of the threads of links with a CPU affinity.
+const PINNED_THREAD_STACK_SIZE: usize = 8 << 20;
+
+/// Run a link task on a dedicated thread pinned to `cpus`, with its own current-thread runtime.
+/// The returned handle completes when the task does.
+fn spawn_pinned<F>(link_name: String, cpus: Vec<usize>, task: F) -> tokio::task::JoinHandle<()>
+where
+  F: std::future::Future<Output = ()> + Send + 'static,
+{
+  // Link futures hold datagram buffers: keep them on the heap like spawned tasks,
+  // and give the thread a main-thread-sized stack for polling them.
+  let task = Box::pin(task);
+  let (done_sender, done_receiver) = tokio::sync::oneshot::channel();
+  let thread_link_name = link_name.clone();
+  let result = std::thread::Builder::new().name(format!("etherip-{}", link_name)).stack_size(PINNED_THREAD_STACK_SIZE).spawn(move || {
+    let link_name = thread_link_name;
+    if let Err(e) = affinity::set_current_thread_affinity(&cpus) {
+      link_log!(&link_name, log::Level::Warn, "Link {}: failed to set CPU affinity to {:?}: {}", link_name, cpus, e);
+    }
+    match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+      Ok(runtime) => runtime.block_on(task),
+      Err(e) => link_log!(&link_name, log::Level::Error, "Link {}: failed to create a runtime: {}", link_name, e),
+    }
+    let _ = done_sender.send(());
+  });
+  if let Err(e) = result {
+    link_log!(&link_name, log::Level::Error, "Link {}: failed to spawn a thread: {}", link_name, e);
+  }
+  tokio::spawn(async move {
+    let _ = done_receiver.await;
+  })
+}
+
+/// Warn about links whose remotes newly resolve to the same address. The shared socket
+/// tells links apart only by the source address, so one of them receives nothing.
+fn report_remote_collisions<T>(link_map: &mut config::AddrStringMap<String>, receivers: &HashMap<String, LinkReceiver<T>>) {
+  for (addr, shadowed, winner) in link_map.take_new_collisions() {
+    link_log!(shadowed, log::Level::Warn, "Link {}: remote {} is also the remote of link {}, which receives all datagrams from it; use the udp or tcp transport for one of them", shadowed, addr, winner);
+    if let Some(receiver) = receivers.get(shadowed) {
+      receiver.stats.remote_collisions.inc();
+    }
+  }
+}
+
+/// Run a link over its UDP transport, which replaces the EtherIP socket in both directions.
+async fn run_udp_link(link_name: String, link_config: config::LinkConfig, interfaces: LinkInterfaces, link_stats: Arc<stats::LinkStats>) -> Result<(), anyhow::Error> {
+  let LinkInterfaces { tap, mirror, input } = interfaces;
+  let udp_config = &link_config.udp;
+  let transport = Arc::new(udp::UdpTransport::bind(link_name.clone(), link_config.remote_addr(), udp_config.port, udp_config.local_port(), udp_config.keepalive_interval()).await?);
+  link_log!(&link_name, log::Level::Info, "Link {}: UDP transport bound to {}", link_name, transport.local_addr()?);
+  let mut link_map = config::AddrStringMap::new(vec![(link_config.remote_addr(), link_name.clone())]);
+  let receivers = HashMap::from([(link_name.clone(), LinkReceiver::new(tap.clone(), mirror, open_output(&link_name, &link_config), &link_config, link_stats.clone(), None))]);
+  select! {
+    result = transport.run() => result.map_err(Into::into),
+    result = receive_from_input(link_name, link_config, tap, input, transport.clone(), link_stats, None) => result,
+    result = receive_from_etherip_socket(transport.clone(), receivers, HashMap::new(), &mut link_map, config::Rpf::Off, None, None) => result,
+  }
+}
+
+/// Run a link over its TCP transport, which replaces the EtherIP socket in both directions.
+async fn run_tcp_link(link_name: String, link_config: config::LinkConfig, interfaces: LinkInterfaces, link_stats: Arc<stats::LinkStats>) -> Result<(), anyhow::Error> {
+  let LinkInterfaces { tap, mirror, input } = interfaces;
+  let transport = Arc::new(tcp::TcpTransport::new(link_name.clone(), link_config.remote_addr(), link_config.tcp.port, link_config.tcp.role()));
+  let mut link_map = config::AddrStringMap::new(vec![(link_config.remote_addr(), link_name.clone())]);
+  let receivers = HashMap::from([(link_name.clone(), LinkReceiver::new(tap.clone(), mirror, open_output(&link_name, &link_config), &link_config, link_stats.clone(), None))]);
+  select! {
+    result = transport.run() => result.map_err(Into::into),
+    result = receive_from_input(link_name, link_config, tap, input, transport.clone(), link_stats, None) => result,
+    result = receive_from_etherip_socket(transport.clone(), receivers, HashMap::new(), &mut link_map, config::Rpf::Off, None, None) => result,
+  }
+}
+
+async fn receive_from_tap<T, S>(link_name: String, link_config: config::LinkConfig, tap: Arc<T>, etherip_socket: Arc<S>, link_stats: Arc<stats::LinkStats>, tclass: Option<Arc<TclassMirror>>) -> Result<(), anyhow::Error>
+where
+  T: FrameSource + FrameSink,
+  S: DatagramSink,
+{
+  let mut transmitter = LinkTransmitter::new(link_name, &link_config, link_stats.clone(), tclass);
+  let background = link_background(transmitter.link_name.clone(), link_config, transmitter.egress_queue.clone(), transmitter.seqno_counter.clone(), etherip_socket.clone(), link_stats);
+
+  select! {
+    result = read_from_tap(&mut transmitter, tap.as_ref(), etherip_socket.as_ref()) => result,
+    result = background => result,
+  }
+}
+
+/// Read frames from the input of a link, or from its TAP interface if it has none,
+/// and send them to its remote. Neighbor replies go back to where the frames came from.
+async fn receive_from_input<S>(link_name: String, link_config: config::LinkConfig, tap: Arc<tap::Tap>, input: Option<Arc<packet::PacketSocket>>, etherip_socket: Arc<S>, link_stats: Arc<stats::LinkStats>, tclass: Option<Arc<TclassMirror>>) -> Result<(), anyhow::Error>
+where
+  S: DatagramSink,
+{
+  match input {
+    Some(input) => receive_from_tap(link_name, link_config, input, etherip_socket, link_stats, tclass).await,
+    None => receive_from_tap(link_name, link_config, tap, etherip_socket, link_stats, tclass).await,
+  }
+}
+
+/// A link read by the shared TAP reader.
+struct SharedTapLink {
+  transmitter: LinkTransmitter,
+  link_config: config::LinkConfig,
+  tap: Arc<tap::Tap>,
+}
+
+/// Read the TAP interfaces of all links from a single task.
+async fn receive_from_taps<S>(links: Vec<SharedTapLink>, etherip_socket: Arc<S>, max_frame_size: usize) -> Result<(), anyhow::Error>
+where
+  S: DatagramSink,
+{
+  let mut transmitters = Vec::with_capacity(links.len());
+  let mut taps = Vec::with_capacity(links.len());
+  let mut backgrounds = Vec::with_capacity(links.len());
+  for SharedTapLink { transmitter, link_config, tap } in links {
+    backgrounds.push(link_background(transmitter.link_name.clone(), link_config, transmitter.egress_queue.clone(), transmitter.seqno_counter.clone(), etherip_socket.clone(), transmitter.link_stats.clone()));
+    transmitters.push(transmitter);
+    taps.push(tap);
+  }
+
+  select! {
+    result = read_from_taps(&mut transmitters, &taps, etherip_socket.as_ref(), max_frame_size) => result,
+    result = futures::future::try_join_all(backgrounds) => result.map(|_| ()),
+  }
+}
+
+/// Work of a link that runs alongside reading its TAP interface:
+/// sending the egress queue, MTU advertisements and announcements.
+async fn link_background<S>(link_name: String, link_config: config::LinkConfig, egress_queue: Option<Arc<queue::EgressQueue>>, seqno_counter: Option<Arc<seqno::SequenceCounter>>, etherip_socket: Arc<S>, link_stats: Arc<stats::LinkStats>) -> Result<(), anyhow::Error>
+where
+  S: DatagramSink,
+{
+  let sender = async {
+    match &egress_queue {
+      Some(egress_queue) => send_from_queue(&link_name, &link_config, egress_queue, etherip_socket.as_ref(), &link_stats).await,
+      None => std::future::pending().await,
+    }
+  };
+  let advertiser = async {
+    if link_config.mtu_negotiate {
+      advertise_mtu(&link_name, &link_config, seqno_counter.as_deref(), etherip_socket.as_ref(), &link_stats).await
+    } else {
+      std::future::pending().await
+    }
+  };
+
+  let announcer = async {
+    announce_on_up(&link_name, &link_config, seqno_counter.as_deref(), etherip_socket.as_ref(), &link_stats).await;
+    std::future::pending().await
+  };
+  let path_mtu_monitor = async {
+    if let Some(interval) = link_config.path_mtu_interval() {
+      monitor_path_mtu(&link_name, &link_config, interval, &link_stats).await;
+    }
+    std::future::pending().await
+  };
+  let source_address_monitor = async {
+    monitor_source_address(&link_name, &link_config, etherip_socket.as_ref(), &link_stats).await;
+    std::future::pending().await
+  };
+
+  select! {
+    result = sender => result,
+    result = advertiser => result,
+    result = announcer => result,
+    result = path_mtu_monitor => result,
+    result = source_address_monitor => result,
+  }
+}
+
+/// Interval between checks of the source address the kernel picks for a remote.
+const SOURCE_ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Track the source address of the datagrams sent to the remote: the address the socket is
+/// bound to, or else the one the kernel picks for the route to the remote. Changes are
+/// logged, so that asymmetric routing and source selection surprises can be spotted.
+async fn monitor_source_address<S>(link_name: &str, link_config: &config::LinkConfig, etherip_socket: &S, link_stats: &stats::LinkStats)
+where
+  S: DatagramSink,
+{
+  let mut remote_addr = link_config.remote_addr();
+  let mut interval = tokio::time::interval(SOURCE_ADDRESS_CHECK_INTERVAL);
+  loop {
+    interval.tick().await;
+    let _ = remote_addr.update_ip_addr().await;
+    let Some(addr) = remote_addr.try_get_ip_addr() else {
+      continue;
+    };
+    let source = match etherip_socket.local_addr() {
+      Some(source) => source,
+      None => match etherip::source_address(&addr) {
+        Ok(source) => source,
+        Err(e) => {
+          link_log!(link_name, log::Level::Debug, "Link {}: failed to find the source address for {}: {}", link_name, addr, e);
+          continue;
+        },
+      },
+    };
+    let previous = link_stats.source_address.write().replace(source);
+    if previous != Some(source) {
+      link_log!(link_name, log::Level::Debug, "Link {}: datagrams to {} are sent from {}", link_name, addr, source);
+    }
+  }
+}
+
+/// Read the path MTU to the remote every `interval` seconds, logging changes so that
+/// shrinking paths, a common cause of lost large frames, can be spotted. Warns once
+/// if it is smaller than the `outer_mtu` the TAP MTU was derived from.
+async fn monitor_path_mtu(link_name: &str, link_config: &config::LinkConfig, interval: u64, link_stats: &stats::LinkStats) {
+  let mut remote_addr = link_config.remote_addr();
+  let mut interval = tokio::time::interval(Duration::from_secs(interval));
+  let mut previous = None;
+  let mut warned = false;
+  loop {
+    interval.tick().await;
+    let _ = remote_addr.update_ip_addr().await;
+    let Some(addr) = remote_addr.try_get_ip_addr() else {
+      continue;
+    };
+    match path_mtu(&addr) {
+      Ok(mtu) => {
+        if previous != Some((addr, mtu)) {
+          link_log!(link_name, log::Level::Debug, "Link {}: path MTU to {} is {}", link_name, addr, mtu);
+          previous = Some((addr, mtu));
+        }
+        if let Some(outer_mtu) = link_config.outer_mtu {
+          if mtu < outer_mtu && !warned {
+            link_log!(link_name, log::Level::Warn, "Link {}: path MTU to {} is {}, smaller than the outer_mtu of {}; large frames will be fragmented or dropped", link_name, addr, mtu, outer_mtu);
+            warned = true;
+          }
+        }
+        link_stats.path_mtu.set(mtu.into());
+      },
+      Err(e) => link_log!(link_name, log::Level::Debug, "Link {}: failed to read the path MTU to {}: {}", link_name, addr, e),
+    }
+  }
+}
+
+/// Authenticator of a link, if it authenticates datagrams. It is built when the configuration
+/// is loaded, and validation refuses links with `auth` but without one.
+fn authenticator(link_config: &config::LinkConfig) -> Option<auth::Authenticator> {
+  link_config.auth.as_ref().and_then(config::AuthConfig::authenticator).cloned()
+}
+
+/// MAC rewriter of a link, if it translates MAC addresses.
+fn mac_rewriter(link_config: &config::LinkConfig) -> Option<MacRewriter> {
+  let mac_rewriter = MacRewriter::new(&link_config.mac_rewrite);
+  (!mac_rewriter.is_empty()).then_some(mac_rewriter)
+}
+
+/// Transmit state of a link: everything needed to tunnel a frame read from its TAP interface.
+struct LinkTransmitter {
+  link_name: String,
+  link_stats: Arc<stats::LinkStats>,
+  remote_addr: config::AddrString,
+  responder: arp::ArpResponder,
+  mac_rewriter: Option<MacRewriter>,
+  shim_size: usize,
+  seqno_counter: Option<Arc<seqno::SequenceCounter>>,
+  mtu_shim_offset: Option<usize>,
+  compressor: Option<compress::Compressor>,
+  trailer_size: usize,
+  authenticator: Option<auth::Authenticator>,
+  egress_queue: Option<Arc<queue::EgressQueue>>,
+  broadcast_limit: Option<ratelimit::TokenBucket>,
+  send_backoff: Option<backoff::SendBackoff>,
+  tclass: Option<Arc<TclassMirror>>,
+  frame_size_histogram: bool,
+  reply: [u8; 128],
+}
+
+impl LinkTransmitter {
+  fn new(link_name: String, link_config: &config::LinkConfig, link_stats: Arc<stats::LinkStats>, tclass: Option<Arc<TclassMirror>>) -> Self {
+    Self {
+      link_name,
+      link_stats,
+      remote_addr: link_config.remote_addr(),
+      responder: arp::ArpResponder::new(link_config.arp_responder.clone()),
+      mac_rewriter: mac_rewriter(link_config),
+      shim_size: link_config.shim_size(),
+      seqno_counter: link_config.seqno.then(Default::default),
+      mtu_shim_offset: link_config.mtu_shim_offset(),
+      compressor: (link_config.compression != compress::Compression::None).then(compress::Compressor::new),
+      trailer_size: link_config.trailer_size(),
+      authenticator: authenticator(link_config),
+      egress_queue: link_config.egress_queue.as_ref().map(|queue_config| Arc::new(queue::EgressQueue::new(queue_config.capacity))),
+      broadcast_limit: link_config.broadcast_limit.as_ref().map(|limit| ratelimit::TokenBucket::new(limit.frames_per_second, limit.burst())),
+      send_backoff: link_config.send_backoff.as_ref().map(|backoff_config| backoff_config.backoff()),
+      tclass,
+      frame_size_histogram: link_config.frame_size_histogram,
+      reply: [0u8; 128],
+    }
+  }
+
+  /// Count a frame read from the TAP interface that is longer than its MTU allows.
+  /// The cached MTU may be stale if the MTU was raised by someone else; it is re-read on reload.
+  fn drop_oversize_frame(&self, len: usize) {
+    self.link_stats.tx_oversize_drops.inc();
+    link_log!(&self.link_name, log::Level::Debug, "Dropped a frame of {} bytes longer than the MTU of TAP interface {} allows", len, self.link_name);
+  }
+
+  /// Tunnel the frame in `datagram`, which starts after the sequence number and compression shims (if any).
+  async fn forward<D, T, S>(&mut self, datagram: &mut D, tap: &T, etherip_socket: &S)
+  where
+    D: EtherIpBuffer + ?Sized,
+    T: FrameSink,
+    S: DatagramSink,
+  {
+    let shim_size = self.shim_size;
+    if self.frame_size_histogram {
+      if let Some(frame) = datagram.ethrnet_frame() {
+        self.link_stats.tx_frame_sizes.observe(frame.len() - shim_size);
+      }
+    }
+    if self.link_stats.flows.is_enabled() {
+      if let Some(frame) = datagram.ethrnet_frame() {
+        self.link_stats.flows.record(&frame[shim_size..]);
+      }
+    }
+    if !self.responder.is_empty() {
+      if let Some(frame) = datagram.ethrnet_frame() {
+        if let Some(kind) = self.responder.respond(&frame[shim_size..], &mut self.reply) {
+          match kind {
+            arp::Reply::Arp(_) => self.link_stats.arp_replies.inc(),
+            arp::Reply::Nd(_) => self.link_stats.nd_replies.inc(),
+          }
+          if let Err(e) = tap.send_frame(&self.reply[..kind.frame_len()]).await {
+            link_log!(&self.link_name, log::Level::Warn, "Failed to write a neighbor reply to TAP interface {}: {}", self.link_name, e);
+          }
+          return;
+        }
+      }
+    }
+
+    if let Some(broadcast_limit) = &mut self.broadcast_limit {
+      let is_multicast = datagram.ethrnet_frame()
+        .and_then(|frame| EthernetHeader::parse(&frame[shim_size..]))
+        .is_some_and(|(header, _)| header.destination.is_multicast());
+      if is_multicast && !broadcast_limit.try_take() {
+        self.link_stats.broadcast_limit_drops.inc();
+        return;
+      }
+    }
+
+    if let Some(mac_rewriter) = &self.mac_rewriter {
+      let frame_end = datagram.ethrnet_frame().map_or(shim_size, |frame| frame.len());
+      let (_, buf) = datagram.ethrnet_frame_mut();
+      if mac_rewriter.rewrite_outbound(&mut buf[shim_size..frame_end]) {
+        self.link_stats.mac_rewrites.inc();
+      }
+    }
+
+    // Classify before the frame is compressed.
+    let class = match (&self.egress_queue, datagram.ethrnet_frame()) {
+      (Some(_), Some(frame)) => Some(queue::classify(&frame[shim_size..])),
+      _ => None,
+    };
+
+    if let Some(seqno_counter) = &self.seqno_counter {
+      let (_, buf) = datagram.ethrnet_frame_mut();
+      buf[..seqno::SEQNO_SHIM_SIZE].copy_from_slice(&seqno_counter.next_seqno().to_be_bytes());
+    }
+
+    if let Some(mtu_shim_offset) = self.mtu_shim_offset {
+      let (_, buf) = datagram.ethrnet_frame_mut();
+      buf[mtu_shim_offset] = mtu::FLAG_FRAME;
+    }
+
+    if let Some(compressor) = &mut self.compressor {
+      let frame_len = datagram.ethrnet_frame().map_or(0, |frame| frame.len() - shim_size);
+      let (mut len_setter, buf) = datagram.ethrnet_frame_mut();
+      let compression_offset = shim_size - compress::COMPRESSION_SHIM_SIZE;
+      let (compressed, len) = compressor.compress(&mut buf[compression_offset..], frame_len);
+      len_setter.set(compression_offset + len);
+      match compressed {
+        compress::Compressed::Lz4 { saved } => {
+          self.link_stats.compressed_frames.inc();
+          self.link_stats.compression_saved_bytes.add(saved as u64);
+        },
+        compress::Compressed::Uncompressed => self.link_stats.uncompressed_frames.inc(),
+      }
+    }
+
+    if let Some(authenticator) = &self.authenticator {
+      if !authenticator.sign_datagram(datagram) {
+        link_log!(&self.link_name, log::Level::Debug, "No room for the authentication tag of a frame");
+        return;
+      }
+    }
+
+    if let Some(egress_queue) = &self.egress_queue {
+      if let (Some(class), Some(data)) = (class, datagram.datagram()) {
+        if !egress_queue.push(class, data.to_vec()) {
+          self.link_stats.egress_queue_drops.inc();
+        }
+      }
+      return;
+    }
+
+    if let Some(send_backoff) = &mut self.send_backoff {
+      if !send_backoff.should_send() {
+        self.link_stats.suppressed_sends.inc();
+        return;
+      }
+    }
+
+    let _ = self.remote_addr.update_ip_addr().await;
+    if let Some(remote_addr) = self.remote_addr.try_get_ip_addr() {
+      let result = match self.tclass.as_ref().and_then(|tclass| tclass.get()) {
+        Some(tclass) => etherip_socket.send_datagram_with_tclass(datagram, &remote_addr, tclass).await,
+        None => etherip_socket.send_datagram(datagram, &remote_addr).await,
+      };
+      if let Err(e) = &result {
+        count_send_error(&self.link_stats, e);
+      }
+      if let Some(send_backoff) = &mut self.send_backoff {
+        update_send_backoff(&self.link_name, send_backoff, result.as_ref().err());
+      }
+    } else {
+      link_log!(&self.link_name, log::Level::Debug, "Sending a packet to an unknown remote address");
+    }
+  }
+}
+
+async fn read_from_tap<T, S>(transmitter: &mut LinkTransmitter, tap: &T, etherip_socket: &S) -> Result<(), anyhow::Error>
+where
+  T: FrameSource + FrameSink,
+  S: DatagramSink,
+{
+  // Datagrams are boxed so that link futures, which hold several, stay small enough for worker stacks.
+  let mut own_datagram: Option<Box<EtherIpDatagram>> = None;
+  loop {
+    match BUFFER_ARENA.get() {
+      // A buffer is only taken once a frame is waiting, so idle links hold none.
+      Some(arena) => {
+        if let Err(e) = tap.readable().await {
+          link_log!(&transmitter.link_name, log::Level::Warn, "Failed to wait for TAP interface {}: {}", transmitter.link_name, e);
+          transmitter.link_stats.last_error.set("TAP wait", &e);
+          continue;
+        }
+        let mut datagram = arena.acquire().await;
+        read_frame(transmitter, tap, &mut datagram, Some(arena.max_frame_size()), etherip_socket).await;
+      },
+      None => {
+        let datagram = own_datagram.get_or_insert_with(|| Box::new(EtherIpDatagram::new()));
+        read_frame(transmitter, tap, datagram.as_mut(), None, etherip_socket).await;
+      },
+    }
+  }
+}
+
+/// Read a frame from the TAP interface into `datagram` and forward it. Frames longer than
+/// `max_frame_size` or than the interface sends are dropped.
+async fn read_frame<T, S, D>(transmitter: &mut LinkTransmitter, tap: &T, datagram: &mut D, max_frame_size: Option<usize>, etherip_socket: &S)
+where
+  T: FrameSource + FrameSink,
+  S: DatagramSink,
+  D: EtherIpBuffer + ?Sized,
+{
+  let shim_size = transmitter.shim_size;
+  let trailer_size = transmitter.trailer_size;
+  let (mut len_setter, buf) = datagram.ethrnet_frame_mut();
+  // One byte more than the longest expected frame, so that longer ones are detected.
+  let max_frame_size = match (tap.max_frame_size(), max_frame_size) {
+    (Some(tap_max), Some(max)) => Some(tap_max.min(max)),
+    (tap_max, max) => tap_max.or(max),
+  };
+  let frame_end = match max_frame_size {
+    Some(max_frame_size) => (shim_size + max_frame_size + 1).min(buf.len() - trailer_size),
+    None => buf.len() - trailer_size,
+  };
+  let result = tap.recv_frame(&mut buf[shim_size..frame_end]).await;
+  transmitter.link_stats.tx_heartbeat.beat();
+  match result {
+    Ok(len) if max_frame_size.is_some_and(|max_frame_size| len > max_frame_size) => {
+      transmitter.drop_oversize_frame(len);
+      return;
+    },
+    Ok(len) => len_setter.set(shim_size + len),
+    Err(e) => {
+      link_log!(&transmitter.link_name, log::Level::Warn, "Failed to read from TAP interface {}: {}", transmitter.link_name, e);
+      transmitter.link_stats.last_error.set("TAP read", &e);
+      return;
+    }
+  }
+  transmitter.forward(datagram, tap, etherip_socket).await;
+}
+
+/// Read from all TAP interfaces in turn. Frames longer than `max_frame_size` are dropped.
+async fn read_from_taps<S>(transmitters: &mut [LinkTransmitter], taps: &[Arc<tap::Tap>], etherip_socket: &S, max_frame_size: usize) -> Result<(), anyhow::Error>
+where
+  S: DatagramSink,
+{
+  // Room for the frame together with every shim and trailer a link may add.
+  let mut datagram = HeapEtherIpDatagram::with_max_frame_size(seqno::SEQNO_SHIM_SIZE + mtu::MTU_SHIM_SIZE + compress::COMPRESSION_SHIM_SIZE + max_frame_size + auth::AUTH_TAG_SIZE);
+  let mut frame = vec![0u8; max_frame_size + 1];
+  // Start polling after the last interface read from, so that a busy one cannot starve the others.
+  let mut next = 0;
+  loop {
+    let (index, result) = std::future::poll_fn(|cx| {
+      for offset in 0..taps.len() {
+        let index = (next + offset) % taps.len();
+        // One byte more than the longest expected frame, so that longer ones are detected.
+        let frame_end = (taps[index].max_frame_size() + 1).min(frame.len());
+        if let std::task::Poll::Ready(result) = taps[index].poll_read(cx, &mut frame[..frame_end]) {
+          return std::task::Poll::Ready((index, result));
+        }
+      }
+      std::task::Poll::Pending
+    }).await;
+    next = index + 1;
+
+    let transmitter = &mut transmitters[index];
+    transmitter.link_stats.tx_heartbeat.beat();
+    let len = match result {
+      Ok(len) if len > taps[index].max_frame_size().min(max_frame_size) => {
+        transmitter.drop_oversize_frame(len);
+        continue;
+      },
+      Ok(len) => len,
+      Err(e) => {
+        link_log!(&transmitter.link_name, log::Level::Warn, "Failed to read from TAP interface {}: {}", transmitter.link_name, e);
+        continue;
+      }
+    };
+    let shim_size = transmitter.shim_size;
+    let (mut len_setter, buf) = datagram.ethrnet_frame_mut();
+    let len = len.min(buf.len() - shim_size - transmitter.trailer_size);
+    buf[shim_size..shim_size + len].copy_from_slice(&frame[..len]);
+    len_setter.set(shim_size + len);
+    transmitter.forward(&mut datagram, taps[index].as_ref(), etherip_socket).await;
+  }
+}
+
+/// Periodically send this end's MTU advertisement to the peer.
+async fn advertise_mtu<S>(link_name: &str, link_config: &config::LinkConfig, seqno_counter: Option<&seqno::SequenceCounter>, etherip_socket: &S, link_stats: &stats::LinkStats) -> Result<(), anyhow::Error>
+where
+  S: DatagramSink,
+{
+  let mut frame = [0u8; mtu::MTU_ADVERTISEMENT_SIZE];
+  mtu::MtuAdvertisement { mtu: link_config.max_mtu }.write(&mut frame);
+  let mut encoder = OwnFrameEncoder::new(link_config, seqno_counter, mtu::MTU_ADVERTISEMENT_SIZE);
+  let mut remote_addr = link_config.remote_addr();
+  let mut interval = tokio::time::interval(mtu::MTU_ADVERTISEMENT_INTERVAL);
+  loop {
+    interval.tick().await;
+    let _ = remote_addr.update_ip_addr().await;
+    let Some(remote_addr) = remote_addr.try_get_ip_addr() else {
+      continue;
+    };
+    let Some(datagram) = encoder.encode(&frame, mtu::FLAG_ADVERTISEMENT) else {
+      link_log!(link_name, log::Level::Warn, "Link {}: no room for the authentication tag of an MTU advertisement", link_name);
+      continue;
+    };
+    if let Err(e) = etherip_socket.send_datagram(datagram, &remote_addr).await {
+      count_send_error(link_stats, &e);
+    }
+  }
+}
+
+/// Encoder of the frames the daemon sends itself, such as MTU advertisements and
+/// announcements, with the shims and trailer of the link as `LinkTransmitter::forward` adds them.
+struct OwnFrameEncoder<'a> {
+  shim_size: usize,
+  seqno_counter: Option<&'a seqno::SequenceCounter>,
+  mtu_shim_offset: Option<usize>,
+  compressor: Option<compress::Compressor>,
+  authenticator: Option<auth::Authenticator>,
+  datagram: HeapEtherIpDatagram,
+}
+
+impl<'a> OwnFrameEncoder<'a> {
+  /// An encoder of frames of up to `max_frame_size` bytes.
+  fn new(link_config: &config::LinkConfig, seqno_counter: Option<&'a seqno::SequenceCounter>, max_frame_size: usize) -> Self {
+    let shim_size = link_config.shim_size();
+    Self {
+      shim_size,
+      seqno_counter,
+      mtu_shim_offset: link_config.mtu_shim_offset(),
+      compressor: (link_config.compression != compress::Compression::None).then(compress::Compressor::new),
+      authenticator: authenticator(link_config),
+      datagram: HeapEtherIpDatagram::with_max_frame_size(shim_size + max_frame_size + link_config.trailer_size()),
+    }
+  }
+
+  /// Encode `frame`, marked with `mtu_flag` in the MTU negotiation shim if the link has one.
+  /// Returns `None` if there is no room for the authentication tag.
+  fn encode(&mut self, frame: &[u8], mtu_flag: u8) -> Option<&HeapEtherIpDatagram> {
+    let shim_size = self.shim_size;
+    let (mut len_setter, buf) = self.datagram.ethrnet_frame_mut();
+    buf[shim_size..shim_size + frame.len()].copy_from_slice(frame);
+    let mut len = shim_size + frame.len();
+    if let Some(seqno_counter) = self.seqno_counter {
+      buf[..seqno::SEQNO_SHIM_SIZE].copy_from_slice(&seqno_counter.next_seqno().to_be_bytes());
+    }
+    if let Some(mtu_shim_offset) = self.mtu_shim_offset {
+      buf[mtu_shim_offset] = mtu_flag;
+    }
+    if let Some(compressor) = &mut self.compressor {
+      let compression_offset = shim_size - compress::COMPRESSION_SHIM_SIZE;
+      len = compression_offset + compressor.compress(&mut buf[compression_offset..], frame.len()).1;
+    }
+    len_setter.set(len);
+    if let Some(authenticator) = &self.authenticator {
+      if !authenticator.sign_datagram(&mut self.datagram) {
+        return None;
+      }
+    }
+    Some(&self.datagram)
+  }
+}
+
+/// Number of times the `announce_on_up` bindings are sent, one second apart.
+const ANNOUNCEMENT_COUNT: usize = 3;
+
+/// Send gratuitous ARP replies and unsolicited neighbor advertisements for the link's
+/// `announce_on_up` bindings. Rounds that fail to send (e.g. before a TCP transport
+/// has connected) are retried, so the announcements follow the link coming up.
+async fn announce_on_up<S>(link_name: &str, link_config: &config::LinkConfig, seqno_counter: Option<&seqno::SequenceCounter>, etherip_socket: &S, link_stats: &stats::LinkStats)
+where
+  S: DatagramSink,
+{
+  if link_config.announce_on_up.is_empty() {
+    return;
+  }
+  let mut frames = Vec::with_capacity(link_config.announce_on_up.len());
+  for (ip, mac) in &link_config.announce_on_up {
+    let mut frame = [0u8; arp::ANNOUNCEMENT_BUFFER_SIZE];
+    if let Some(len) = arp::announcement(*ip, *mac, &mut frame) {
+      frames.push(frame[..len].to_vec());
+    }
+  }
+
+  let mut encoder = OwnFrameEncoder::new(link_config, seqno_counter, arp::ANNOUNCEMENT_BUFFER_SIZE);
+  let mut remote_addr = link_config.remote_addr();
+  let mut interval = tokio::time::interval(Duration::from_secs(1));
+  let mut sent = 0;
+  while sent < ANNOUNCEMENT_COUNT {
+    interval.tick().await;
+    let _ = remote_addr.update_ip_addr().await;
+    let Some(remote_addr) = remote_addr.try_get_ip_addr() else {
+      continue;
+    };
+    let mut succeeded = true;
+    for frame in &frames {
+      let Some(datagram) = encoder.encode(frame, mtu::FLAG_FRAME) else {
+        link_log!(link_name, log::Level::Warn, "Link {}: no room for the authentication tag of an announcement", link_name);
+        continue;
+      };
+      if let Err(e) = etherip_socket.send_datagram(datagram, &remote_addr).await {
+        link_log!(link_name, log::Level::Debug, "Link {}: failed to send an announcement: {}", link_name, e);
+        count_send_error(link_stats, &e);
+        succeeded = false;
+        break;
+      }
+    }
+    if succeeded {
+      sent += 1;
+    }
+  }
+  link_log!(link_name, log::Level::Info, "Link {}: announced {} address(es) to the peer", link_name, frames.len());
+}
+
+/// Count a datagram that could not be sent, separating the ones too large for the path,
+/// and record the error as the last one of the link. Datagrams dropped because the send
+/// queue was full are only counted, as congestion rather than errors.
+fn count_send_error(link_stats: &stats::LinkStats, error: &std::io::Error) {
+  if etherip::QueueFull::from_error(error).is_some() {
+    link_stats.queue_full_drops.inc();
+    return;
+  }
+  link_stats.last_error.set("send", error);
+  match etherip::TooLarge::from_error(error) {
+    Some(_) => link_stats.tx_too_large.inc(),
+    None => link_stats.send_errors.inc(),
+  }
+}
+
+/// Record the outcome of a send in a link's backoff, logging when sends are suspended
+/// or resumed. Datagrams too large for the path or dropped by a full send queue say nothing
+/// about the peer and are ignored.
+fn update_send_backoff(link_name: &str, send_backoff: &mut backoff::SendBackoff, error: Option<&std::io::Error>) {
+  let Some(error) = error else {
+    if let Some(suppressed) = send_backoff.record_success() {
+      link_log!(link_name, log::Level::Info, "Link {}: sends resumed; {} frame(s) dropped while suspended", link_name, suppressed);
+    }
+    return;
+  };
+  if etherip::TooLarge::from_error(error).is_some() || etherip::QueueFull::from_error(error).is_some() {
+    return;
+  }
+  match send_backoff.record_failure() {
+    backoff::Failure::Counted => {},
+    backoff::Failure::Suspended(delay) => {
+      link_log!(link_name, log::Level::Warn, "Link {}: suspending sends for {:?} after consecutive failures: {}", link_name, delay, error);
+    },
+    backoff::Failure::ProbeFailed(delay) => {
+      link_log!(link_name, log::Level::Debug, "Link {}: probe send failed, suspending sends for {:?}: {}", link_name, delay, error);
+    },
+  }
+}
+
+/// Send the datagrams of a link's egress queue in batches, control frames first.
+async fn send_from_queue<S>(link_name: &str, link_config: &config::LinkConfig, egress_queue: &queue::EgressQueue, etherip_socket: &S, link_stats: &stats::LinkStats) -> Result<(), anyhow::Error>
+where
+  S: DatagramSink,
+{
+  let batch_size = link_config.egress_queue.as_ref().map_or(1, |queue_config| queue_config.batch_size.max(1));
+  let mut remote_addr = link_config.remote_addr();
+  let mut send_backoff = link_config.send_backoff.as_ref().map(|backoff_config| backoff_config.backoff());
+  loop {
+    let batch = egress_queue.pop_batch(batch_size).await;
+    if let Some(send_backoff) = &mut send_backoff {
+      if !send_backoff.should_send() {
+        link_stats.suppressed_sends.add(batch.len() as u64);
+        continue;
+      }
+    }
+    let _ = remote_addr.update_ip_addr().await;
+    let Some(remote_addr) = remote_addr.try_get_ip_addr() else {
+      link_log!(link_name, log::Level::Debug, "Sending a packet to an unknown remote address");
+      continue;
+    };
+
+    let datagrams: Vec<(&[u8], std::net::IpAddr)> = batch.iter().map(|data| (data.as_slice(), remote_addr)).collect();
+    match etherip_socket.send_datagrams(&datagrams).await {
+      Ok(results) => {
+        results.iter().filter_map(|result| result.as_ref().err()).for_each(|e| count_send_error(link_stats, e));
+        if let Some(send_backoff) = &mut send_backoff {
+          // A batch counts as one send, which succeeded if any of its datagrams was sent.
+          let error = match results.iter().any(|result| result.is_ok()) {
+            true => None,
+            false => results.iter().find_map(|result| result.as_ref().err()),
+          };
+          update_send_backoff(link_name, send_backoff, error);
+        }
+      },
+      Err(e) => {
+        link_stats.send_errors.add(datagrams.len() as u64);
+        link_stats.last_error.set("send", &e);
+        if let Some(send_backoff) = &mut send_backoff {
+          update_send_backoff(link_name, send_backoff, Some(&e));
+        }
+      },
+    }
+  }
+}
+
+/// Traffic class last received from a link's peer, mirrored onto the datagrams sent to it.
+#[derive(Debug, Default)]
+struct TclassMirror(AtomicU16);
+
+impl TclassMirror {
+  /// Marks a stored value, so that 0 means nothing was received yet.
+  const VALID: u16 = 0x100;
+
+  fn set(&self, tclass: u8) {
+    self.0.store(Self::VALID | tclass as u16, Ordering::Relaxed);
+  }
+
+  fn get(&self) -> Option<u8> {
+    let value = self.0.load(Ordering::Relaxed);
+    (value & Self::VALID != 0).then_some(value as u8)
+  }
+}
+
+/// Socket receiver task of the daemon, which hands back its link map when it stops,
+/// and the sender of its receiver updates.
+type SocketTask = (tokio::task::JoinHandle<config::AddrStringMap<String>>, mpsc::Sender<ReceiverUpdate<tap::Tap>>);
+
+/// Relay state of the EtherIP socket receiver.
+struct Relay {
+  parser_mode: ParserMode,
+  reserved_bits: etherip::ReservedBits,
+  max_frame_size: usize,
+  stats: Arc<stats::RelayStats>,
+}
+
+impl Relay {
+  fn new(relay_config: &config::RelayConfig, stats: Arc<stats::RelayStats>) -> Self {
+    Self {
+      parser_mode: relay_config.parser_mode,
+      reserved_bits: relay_config.reserved_bits,
+      max_frame_size: relay_config.max_frame_size,
+      stats,
+    }
+  }
+
+  /// Send a datagram received from `src` on to `dst`, the other remote if it is resolved.
+  async fn forward<S, D>(&self, datagram: &mut D, src: &IpAddr, dst: Option<IpAddr>, etherip_socket: &S)
+  where
+    S: DatagramSink,
+    D: EtherIpBuffer + ?Sized,
+  {
+    let Some(frame_len) = datagram.ethrnet_frame_with_mode(self.parser_mode).map(<[u8]>::len) else {
+      self.stats.invalid_datagrams.inc();
+      log::debug!("Not relaying an invalid datagram from {}", src);
+      return;
+    };
+    if frame_len > self.max_frame_size {
+      self.stats.oversize_drops.inc();
+      return;
+    }
+    let Some(dst) = dst else {
+      self.stats.unresolved_drops.inc();
+      return;
+    };
+    datagram.prepare_retransmit(self.reserved_bits);
+    match etherip_socket.send_datagram(datagram, &dst).await {
+      Ok(len) => {
+        self.stats.datagrams.inc();
+        self.stats.bytes.add(len as u64);
+      },
+      Err(e) => match etherip::TooLarge::from_error(&e) {
+        Some(_) => self.stats.tx_too_large.inc(),
+        None => {
+          self.stats.send_errors.inc();
+          log::debug!("Failed to relay a datagram from {} to {}: {}", src, dst, e);
+        },
+      },
+    }
+  }
+}
+
+/// New link receivers handed to a running socket receiver on reload.
+struct ReceiverUpdate<T> {
+  receivers: HashMap<String, LinkReceiver<T>>,
+  relays: HashMap<String, Relay>,
+  link_pairs: Vec<(config::AddrString, String)>,
+  rpf: config::Rpf,
+  /// Signalled once the previous receivers (and their TAP references) have been dropped.
+  applied: oneshot::Sender<()>,
+}
+
+/// Per-link state of the EtherIP socket receiver.
+struct LinkReceiver<T> {
+  tap: Arc<T>,
+  /// Interface receiving a copy of every frame written to `tap`.
+  mirror: Option<Arc<T>>,
+  /// Interface received frames are written to instead of `tap`, if `output` says so.
+  output: Option<Arc<packet::PacketSocket>>,
+  stats: Arc<stats::LinkStats>,
+  seqno: Option<seqno::SequenceTracker>,
+  decompressor: Option<compress::Decompressor>,
+  mtu: Option<mtu::MtuNegotiation>,
+  netns: Option<String>,
+  tclass: Option<Arc<TclassMirror>>,
+  parser_mode: ParserMode,
+  frame_size_histogram: bool,
+  /// Drop datagrams from this host's own addresses, in case multicast loopback is on.
+  multicast: bool,
+  authenticator: Option<auth::Authenticator>,
+  on_invalid: config::OnInvalid,
+  mac_rewriter: Option<MacRewriter>,
+  inner_ip_filter: Option<acl::InnerIpFilter>,
+  /// Copy of the last received frame, translated by `mac_rewriter`.
+  rewritten: Vec<u8>,
+}
+
+impl<T> LinkReceiver<T> {
+  fn new(tap: Arc<T>, mirror: Option<Arc<T>>, output: Option<Arc<packet::PacketSocket>>, link_config: &config::LinkConfig, stats: Arc<stats::LinkStats>, tclass: Option<Arc<TclassMirror>>) -> Self {
+    Self {
+      tap,
+      mirror,
+      output,
+      stats,
+      seqno: link_config.seqno.then(seqno::SequenceTracker::default),
+      decompressor: (link_config.compression != compress::Compression::None).then(compress::Decompressor::new),
+      mtu: link_config.mtu_negotiate.then(|| mtu::MtuNegotiation::new(link_config.max_mtu)),
+      netns: link_config.netns.clone(),
+      tclass,
+      parser_mode: link_config.parser_mode,
+      frame_size_histogram: link_config.frame_size_histogram,
+      multicast: link_config.is_multicast(),
+      authenticator: authenticator(link_config),
+      on_invalid: link_config.on_invalid.unwrap_or_default(),
+      mac_rewriter: mac_rewriter(link_config),
+      inner_ip_filter: link_config.inner_ip_filter.clone(),
+      rewritten: Vec::new(),
+    }
+  }
+
+  /// Count and log a received datagram dropped as invalid, as the `on_invalid` policy says.
+  fn reject(&self, link_name: &str, src: &IpAddr, len: usize, reason: &str) {
+    if self.on_invalid != config::OnInvalid::Drop {
+      self.stats.invalid_datagrams.inc();
+    }
+    self.log_rejection(link_name, src, len, reason);
+  }
+
+  /// Log a received datagram dropped for `reason`, as the `on_invalid` policy says.
+  fn log_rejection(&self, link_name: &str, src: &IpAddr, len: usize, reason: &str) {
+    match self.on_invalid {
+      config::OnInvalid::Drop => {},
+      config::OnInvalid::Count => link_log!(link_name, log::Level::Debug, "Received a packet {} from {}", reason, src),
+      config::OnInvalid::Log => link_log!(link_name, log::Level::Info, "Link {}: dropped a {}-byte packet {} from {}", link_name, len, reason, src),
+    }
+  }
+}
+
+/// How long the addresses of this host are cached.
+const LOCAL_ADDRS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Cached addresses of this host.
+#[derive(Default)]
+struct LocalAddrs {
+  addrs: HashSet<IpAddr>,
+  updated: Option<std::time::Instant>,
+}
+
+impl LocalAddrs {
+  fn contains(&mut self, addr: &IpAddr) -> bool {
+    if self.updated.is_none_or(|updated| updated.elapsed() >= LOCAL_ADDRS_REFRESH_INTERVAL) {
+      match etherip::local_addrs() {
+        Ok(addrs) => self.addrs = addrs,
+        Err(e) => log::warn!("Failed to get the local addresses: {}", e),
+      }
+      self.updated = Some(std::time::Instant::now());
+    }
+    self.addrs.contains(addr)
+  }
+}
+
+/// How long the route back to a source is cached by the reverse path check.
+const RPF_ROUTE_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Reverse path check of received datagrams, with the routes back to their sources cached.
+#[derive(Default)]
+struct ReversePathFilter {
+  /// Interface of the route back to each source (`None` if unreachable), with when it was looked up.
+  routes: HashMap<IpAddr, (Option<u32>, std::time::Instant)>,
+}
+
+impl ReversePathFilter {
+  /// Whether a datagram from `src` that arrived on `ifindex` passes the check in `mode`.
+  /// A datagram whose arrival interface is unknown passes the strict check if its source is reachable.
+  fn accepts(&mut self, mode: config::Rpf, src: &IpAddr, ifindex: Option<u32>) -> bool {
+    if mode == config::Rpf::Off {
+      return true;
+    }
+    let route = match self.routes.get(src) {
+      Some((route, updated)) if updated.elapsed() < RPF_ROUTE_CACHE_TTL => *route,
+      _ => {
+        self.routes.retain(|_, (_, updated)| updated.elapsed() < RPF_ROUTE_CACHE_TTL);
+        let route = etherip::route_interface(src).ok();
+        self.routes.insert(*src, (route, std::time::Instant::now()));
+        route
+      },
+    };
+    match (mode, route, ifindex) {
+      (_, None, _) => false,
+      (config::Rpf::Strict, Some(route), Some(ifindex)) => route == ifindex,
+      _ => true,
+    }
+  }
+}
+
+/// What happens to a datagram from a source, decided before its contents are looked at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceMatch<'a> {
+  /// Sent on by the relay with this name.
+  Relay(&'a String),
+  /// Received by the link with this name.
+  Link(&'a String),
+  /// From a remote of this relay or link, but dropped.
+  Dropped { name: &'a String, relay: bool, reason: SourceDrop },
+  /// From no remote of any link or relay.
+  Unknown,
+}
+
+/// Why a datagram from a known source is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceDrop {
+  ReversePath,
+  /// Sent by this host to a multicast link, and looped back.
+  LoopedBack,
+}
+
+impl SourceDrop {
+  fn describe(self) -> &'static str {
+    match self {
+      SourceDrop::ReversePath => "failing the reverse path check",
+      SourceDrop::LoopedBack => "looped back from this host",
+    }
+  }
+}
+
+/// Checks made on the source of every received datagram.
+#[derive(Default)]
+struct SourceChecks {
+  local_addrs: LocalAddrs,
+  reverse_path_filter: ReversePathFilter,
+}
+
+impl SourceChecks {
+  /// Find the relay or link `src` is a remote of in `link_map`, then apply the reverse
+  /// path check of `rpf` and, for multicast links, drop what this host sent itself.
+  fn check<'a>(&mut self, link_map: &'a config::AddrStringMap<String>, rpf: config::Rpf, src: &IpAddr, ifindex: Option<u32>, is_relay: impl Fn(&str) -> bool, is_multicast: impl Fn(&str) -> bool) -> SourceMatch<'a> {
+    let Some(name) = link_map.get(src) else {
+      return SourceMatch::Unknown;
+    };
+    let relay = is_relay(name);
+    if !self.reverse_path_filter.accepts(rpf, src, ifindex) {
+      return SourceMatch::Dropped { name, relay, reason: SourceDrop::ReversePath };
+    }
+    if relay {
+      return SourceMatch::Relay(name);
+    }
+    if is_multicast(name) && self.local_addrs.contains(src) {
+      return SourceMatch::Dropped { name, relay, reason: SourceDrop::LoopedBack };
+    }
+    SourceMatch::Link(name)
+  }
+}
+
+/// Deliver datagrams from the socket to the links. With `updates`, the receivers are
+/// replaced whenever an update arrives, and the function returns once the channel is closed.
+/// Datagrams from the remotes of `relays` are sent on to their other remote.
+/// `heartbeat`, if any, is advanced for every datagram read.
+async fn receive_from_etherip_socket<S, T>(etherip_socket: Arc<S>, mut receivers: HashMap<String, LinkReceiver<T>>, mut relays: HashMap<String, Relay>, link_map: &mut config::AddrStringMap<String>, mut rpf: config::Rpf, mut updates: Option<&mut mpsc::Receiver<ReceiverUpdate<T>>>, heartbeat: Option<&watchdog::Heartbeat>) -> Result<(), anyhow::Error>
+where
+  S: DatagramSource + DatagramSink,
+  T: FrameSink,
+{
+  let mut datagram = Box::new(EtherIpDatagram::new());
+  let mut source_checks = SourceChecks::default();
+  loop {
+    let _ = link_map.update().await;
+    report_remote_collisions(link_map, &receivers);
+
+    let received = match updates.as_deref_mut() {
+      Some(updates) => select! {
+        result = etherip_socket.recv_datagram_with_info(&mut datagram) => result,
+        update = updates.recv() => {
+          let Some(update) = update else {
+            return Ok(());
+          };
+          receivers = update.receivers;
+          relays = update.relays;
+          link_map.reconcile(update.link_pairs);
+          rpf = update.rpf;
+          let _ = update.applied.send(());
+          continue;
+        },
+      },
+      None => etherip_socket.recv_datagram_with_info(&mut datagram).await,
+    };
+    if let Some(heartbeat) = heartbeat {
+      heartbeat.beat();
+    }
+    let (len, src, info) = match received {
+      Ok((len, src, info)) => (len, src, info),
+      Err(e) if is_fatal_socket_error(&e) => return Err(e.into()),
+      Err(e) => {
+        match etherip::TruncatedPacket::from_error(&e) {
+          // Dropped rather than forwarded as a corrupted frame.
+          Some(truncated) => match link_map.get(&truncated.src_addr).and_then(|link_name| receivers.get(link_name).map(|receiver| (link_name, receiver))) {
+            Some((link_name, receiver)) => {
+              receiver.stats.rx_truncated_drops.inc();
+              receiver.log_rejection(link_name, &truncated.src_addr, truncated.len, "longer than the receive buffer");
+            },
+            None => log::debug!("Dropped a truncated packet from an unknown source: {}", e),
+          },
+          None => log::warn!("Failed to receive from EtherIP socket: {}", e),
+        }
+        continue;
+      }
+    };
+
+    let is_relay = |name: &str| relays.contains_key(name);
+    let is_multicast = |name: &str| receivers.get(name).is_some_and(|receiver| receiver.multicast);
+    match source_checks.check(link_map, rpf, &src, info.ifindex, is_relay, is_multicast) {
+      SourceMatch::Relay(relay_name) => {
+        let dst = link_map.addrs_of(relay_name).find(|addr| *addr != src);
+        relays[relay_name].forward(datagram.as_mut(), &src, dst, etherip_socket.as_ref()).await;
+      },
+      SourceMatch::Dropped { name, relay: true, .. } => relays[name].stats.rpf_drops.inc(),
+      SourceMatch::Dropped { name: link_name, relay: false, reason } => {
+        let receiver = receivers.get(link_name).ok_or_else(|| anyhow::anyhow!("Link {} does not exist", link_name))?;
+        match reason {
+          SourceDrop::ReversePath => {
+            receiver.stats.rpf_drops.inc();
+            receiver.log_rejection(link_name, &src, len, reason.describe());
+          },
+          SourceDrop::LoopedBack => receiver.stats.looped_back_drops.inc(),
+        }
+      },
+      SourceMatch::Link(link_name) => {
+        let receiver = receivers.get_mut(link_name).ok_or_else(|| anyhow::anyhow!("Link {} does not exist", link_name))?;
+        // Nothing else is looked at before the datagram is authenticated.
+        if let Some(authenticator) = &receiver.authenticator {
+          if !authenticator.verify_datagram(&mut datagram) {
+            receiver.stats.auth_failures.inc();
+            receiver.log_rejection(link_name, &src, len, "failing authentication");
+            continue;
+          }
+        }
+        if datagram.header().is_some_and(|header| header.reserved != 0) {
+          receiver.stats.reserved_bits_violations.inc();
+        }
+        let Some(eth_frame) = datagram.ethrnet_frame_with_mode(receiver.parser_mode) else {
+          let reason = match datagram.header() {
+            Some(_) => "with an invalid EtherIP header",
+            None => "too short for an EtherIP header",
+          };
+          receiver.reject(link_name, &src, len, reason);
+          continue;
+        };
+        if let (Some(tclass), Some(received_tclass)) = (&receiver.tclass, info.tclass) {
+          tclass.set(received_tclass);
+        }
+        let eth_frame = match &mut receiver.seqno {
+          Some(tracker) => {
+            let Some((seqno, eth_frame)) = seqno::split_seqno(eth_frame) else {
+              receiver.reject(link_name, &src, len, "without a sequence number");
+              continue;
+            };
+            match tracker.observe(seqno) {
+              seqno::SequenceEvent::InOrder => {},
+              seqno::SequenceEvent::Gap(missing) => {
+                receiver.stats.seqno_gaps.inc();
+                receiver.stats.seqno_missing.add(missing as u64);
+              },
+              seqno::SequenceEvent::OutOfOrder => receiver.stats.seqno_out_of_order.inc(),
+            }
+            eth_frame
+          },
+          None => eth_frame,
+        };
+        let (is_advertisement, eth_frame) = match &receiver.mtu {
+          Some(_) => match mtu::split_mtu_shim(eth_frame) {
+            Some(split) => split,
+            None => {
+              receiver.reject(link_name, &src, len, "without a valid MTU negotiation shim");
+              continue;
+            },
+          },
+          None => (false, eth_frame),
+        };
+        let eth_frame = match &mut receiver.decompressor {
+          Some(decompressor) => match decompressor.decompress(eth_frame) {
+            Some(eth_frame) => eth_frame,
+            None => {
+              receiver.stats.decompression_errors.inc();
+              receiver.log_rejection(link_name, &src, len, "that could not be decompressed");
+              continue;
+            },
+          },
+          None => eth_frame,
+        };
+        if is_advertisement {
+          let (Some(negotiation), Some(advertisement)) = (&mut receiver.mtu, mtu::MtuAdvertisement::parse(eth_frame)) else {
+            receiver.reject(link_name, &src, len, "with an invalid MTU advertisement");
+            continue;
+          };
+          if let Some(mtu) = negotiation.observe(advertisement) {
+            match in_link_netns(receiver.netns.as_deref(), || receiver.tap.set_mtu(mtu as u32)) {
+              Ok(()) => link_log!(link_name, log::Level::Info, "Negotiated MTU {} on link {} (peer advertised {})", mtu, link_name, advertisement.mtu),
+              Err(e) => link_log!(link_name, log::Level::Warn, "Failed to set the negotiated MTU {} on link {}: {}", mtu, link_name, e),
+            }
+          }
+          continue;
+        }
+        if eth_frame.len() < ETHERNET_HEADER_SIZE {
+          receiver.reject(link_name, &src, len, "with a truncated Ethernet frame");
+          continue;
+        }
+        if receiver.inner_ip_filter.as_ref().is_some_and(|filter| !filter.accepts(eth_frame)) {
+          receiver.stats.inner_ip_filter_drops.inc();
+          receiver.log_rejection(link_name, &src, len, "carrying an IP packet the inner IP filter denies");
+          continue;
+        }
+        // The frame may be borrowed from the decompressor, so it is translated in a copy.
+        let eth_frame = match &receiver.mac_rewriter {
+          Some(mac_rewriter) => {
+            receiver.rewritten.clear();
+            receiver.rewritten.extend_from_slice(eth_frame);
+            if mac_rewriter.rewrite_inbound(&mut receiver.rewritten) {
+              receiver.stats.mac_rewrites.inc();
+            }
+            &receiver.rewritten[..]
+          },
+          None => eth_frame,
+        };
+        if receiver.frame_size_histogram {
+          receiver.stats.rx_frame_sizes.observe(eth_frame.len());
+        }
+        receiver.stats.flows.record(eth_frame);
+        receiver.stats.fdb.learn(eth_frame, &src);
+        let (result, operation) = match &receiver.output {
+          Some(output) => (output.send_frame(eth_frame).await, "output write"),
+          None => (receiver.tap.send_frame(eth_frame).await, "TAP write"),
+        };
+        if let Err(e) = result {
+          receiver.stats.last_error.set(operation, &e);
+        }
+        if let Some(mirror) = &receiver.mirror {
+          if let Err(e) = mirror.send_frame(eth_frame).await {
+            receiver.stats.mirror_write_errors.inc();
+            receiver.stats.last_error.set("mirror write", &e);
+          }
+        }
+      },
+      SourceMatch::Unknown => log::debug!("Received a packet from an unknown source IP address: {}", src),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use etherip::transport::{MemoryDatagrams, MemoryFrames};
+
+  /// Configuration of a link to `remote`, with `extra` TOML lines.
+  fn link_config(remote: &str, extra: &str) -> config::LinkConfig {
+    etherip::toml::from_str(&format!("remote = \"{}\"\nip_version = \"V4\"\n{}", remote, extra)).expect("valid link configuration")
+  }
+
+  /// An Ethernet frame between two unicast addresses carrying `payload`.
+  fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x88, 0xb5];
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  /// Encode `frame` as an EtherIP datagram.
+  fn datagram_of(frame: &[u8]) -> Vec<u8> {
+    let mut datagram = EtherIpDatagram::new();
+    let (mut len, buf) = datagram.ethrnet_frame_mut();
+    buf[..frame.len()].copy_from_slice(frame);
+    len.set(frame.len());
+    datagram.datagram().unwrap().to_vec()
+  }
+
+  fn ip(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+  }
+
+  /// Run `task` until `until` completes, failing if either takes too long or the task ends first.
+  async fn run_until<T>(task: impl std::future::Future<Output = Result<(), anyhow::Error>>, until: impl std::future::Future<Output = T>) -> T {
+    let result = tokio::time::timeout(Duration::from_secs(5), async {
+      select! {
+        result = task => panic!("forwarding stopped: {:?}", result),
+        value = until => value,
+      }
+    }).await;
+    result.expect("timed out")
+  }
+
+  #[tokio::test]
+  async fn frames_from_the_tap_reach_the_remote_of_the_link() {
+    let link_config = link_config("192.0.2.20", "");
+    let tap = Arc::new(MemoryFrames::new());
+    let socket = Arc::new(MemoryDatagrams::new());
+    let stats = Arc::new(stats::LinkStats::default());
+    tap.push_received(&frame(b"outbound"));
+
+    let forwarding = receive_from_tap("b".to_string(), link_config, tap.clone(), socket.clone(), stats, None);
+    let (datagram, dst) = run_until(forwarding, socket.take_sent()).await.unwrap();
+    assert_eq!(dst, ip("192.0.2.20"));
+    assert_eq!(datagram, datagram_of(&frame(b"outbound")));
+  }
+
+  #[tokio::test]
+  async fn datagrams_from_a_remote_reach_the_tap_of_its_link() {
+    let links = [("a", "192.0.2.10"), ("b", "192.0.2.20")];
+    let taps: HashMap<&str, Arc<MemoryFrames>> = links.iter().map(|(name, _)| (*name, Arc::new(MemoryFrames::new()))).collect();
+    let receivers = links.iter().map(|(name, remote)| {
+      let receiver = LinkReceiver::new(taps[name].clone(), None, None, &link_config(remote, ""), Arc::new(stats::LinkStats::default()), None);
+      (name.to_string(), receiver)
+    }).collect();
+    let mut link_map = config::AddrStringMap::new(links.iter().map(|(name, remote)| (link_config(remote, "").remote_addr(), name.to_string())).collect());
+    let socket = Arc::new(MemoryDatagrams::new());
+    socket.push_received(&datagram_of(&frame(b"inbound")), ip("192.0.2.20"));
+
+    let forwarding = receive_from_etherip_socket(socket.clone(), receivers, HashMap::new(), &mut link_map, config::Rpf::Off, None, None);
+    let received = run_until(forwarding, taps["b"].take_sent()).await;
+    assert_eq!(received, Some(frame(b"inbound")));
+    assert!(tokio::time::timeout(Duration::from_millis(50), taps["a"].take_sent()).await.is_err());
+  }
+
+  #[test]
+  fn links_sharing_a_remote_count_a_collision() {
+    let links = [("a", "192.0.2.10"), ("b", "192.0.2.10")];
+    let receivers: HashMap<String, LinkReceiver<MemoryFrames>> = links.iter().map(|(name, remote)| {
+      let receiver = LinkReceiver::new(Arc::new(MemoryFrames::new()), None, None, &link_config(remote, ""), Arc::new(stats::LinkStats::default()), None);
+      (name.to_string(), receiver)
+    }).collect();
+    let mut link_map = config::AddrStringMap::new(links.iter().map(|(name, remote)| (link_config(remote, "").remote_addr(), name.to_string())).collect());
+
+    report_remote_collisions(&mut link_map, &receivers);
+    report_remote_collisions(&mut link_map, &receivers);
+    // Only the link that lost its remote counts it, and only once.
+    assert_eq!(receivers["a"].stats.remote_collisions.get(), 1);
+    assert_eq!(receivers["b"].stats.remote_collisions.get(), 0);
+    assert_eq!(link_map.get(&ip("192.0.2.10")), Some(&"b".to_string()));
+  }
+
+  /// Configuration without links.
+  const NO_LINKS: &str = "log_level = \"Warn\"\n[links]\n";
+
+  /// Write `contents` to a configuration file of its own, named after `name`.
+  fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("etheripd-test-{}-{}.toml", std::process::id(), name));
+    std::fs::write(&path, contents).expect("write the configuration");
+    path
+  }
+
+  /// Raise `signal` every 50 ms until `until` completes, since a listener registered
+  /// after a signal was raised does not see it. The test's own listener keeps the
+  /// signal from terminating the process.
+  async fn raise_until<T>(signal: etherip::libc::c_int, until: impl std::future::Future<Output = T>) -> T {
+    let _listener = tokio::signal::unix::signal(SignalKind::from_raw(signal)).expect("listen for the signal");
+    let raise = async {
+      loop {
+        unsafe { etherip::libc::raise(signal) };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+      }
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+      select! {
+        value = until => value,
+        _ = raise => unreachable!(),
+      }
+    }).await.expect("timed out")
+  }
+
+  #[tokio::test]
+  async fn reload_task_reloads_then_stops_on_shutdown() {
+    let path = config_file("reload", NO_LINKS);
+    let config = Arc::new(RwLock::new(config::Config::from_path(&path).unwrap()));
+    let (reload_sender, mut reload_receiver) = broadcast::channel(16);
+    let (shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+    let task = spawn_reload_task(config.clone(), path.clone(), Arc::new(AtomicBool::new(false)), reload_sender, shutdown_receiver).unwrap();
+
+    std::fs::write(&path, "log_level = \"Debug\"\n[links]\n").unwrap();
+    raise_until(etherip::libc::SIGHUP, reload_receiver.recv()).await.expect("reload notified");
+    assert_eq!(config.read().log_level, config::LogLevel::Debug);
+
+    shutdown_sender.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), task).await.expect("reload task stopped").unwrap();
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[tokio::test]
+  async fn daemon_without_links_reloads_and_shuts_down() {
+    if matches!(EtherIpSocket::new(), Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied) {
+      return;
+    }
+    // Keeps the HUP signal from terminating the process if it is raised before the daemon listens.
+    let _hup_listener = signal(SignalKind::hangup()).unwrap();
+    let path = config_file("no-links", NO_LINKS);
+    let daemon = tokio::spawn(run_daemon(path.clone()));
+    // Nothing waits for the reload when there are no links; it must not bring the daemon down.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    unsafe { etherip::libc::raise(etherip::libc::SIGHUP) };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!daemon.is_finished(), "the daemon stopped after a reload");
+
+    let result = raise_until(etherip::libc::SIGTERM, daemon).await;
+    result.expect("daemon task").expect("clean shutdown");
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[tokio::test]
+  async fn mtu_advertisements_are_told_from_frames_by_their_shim() {
+    let link_config = link_config("192.0.2.20", "seqno = true\ncompression = \"lz4\"\nmtu_negotiate = true");
+    // Sent by a host behind the peer, it looks like an advertisement but is tunneled as a frame.
+    let lookalike = frame(b"EIPM\x01\x00\x05\xdc");
+    let peer_tap = Arc::new(MemoryFrames::new());
+    let peer_socket = Arc::new(MemoryDatagrams::new());
+    peer_tap.push_received(&lookalike);
+    // The first advertisement is sent at once, along with the frame.
+    let peer = receive_from_tap("b".to_string(), link_config.clone(), peer_tap, peer_socket.clone(), Arc::new(stats::LinkStats::default()), None);
+    let sent = run_until(peer, async { [peer_socket.take_sent().await.unwrap(), peer_socket.take_sent().await.unwrap()] }).await;
+
+    let tap = Arc::new(MemoryFrames::new());
+    let stats = Arc::new(stats::LinkStats::default());
+    let receivers = HashMap::from([("b".to_string(), LinkReceiver::new(tap.clone(), None, None, &link_config, stats.clone(), None))]);
+    let mut link_map = config::AddrStringMap::new(vec![(link_config.remote_addr(), "b".to_string())]);
+    let socket = Arc::new(MemoryDatagrams::new());
+    for (datagram, _) in &sent {
+      socket.push_received(datagram, ip("192.0.2.20"));
+    }
+    let local = receive_from_etherip_socket(socket, receivers, HashMap::new(), &mut link_map, config::Rpf::Off, None, None);
+    let received = run_until(local, async {
+      let frame = tap.take_sent().await;
+      // Only the frame is written; the advertisement is consumed.
+      assert!(tokio::time::timeout(Duration::from_millis(50), tap.take_sent()).await.is_err());
+      frame
+    }).await;
+    assert_eq!(received, Some(lookalike));
+    assert_eq!(stats.invalid_datagrams.get(), 0);
+  }
+
+  #[tokio::test]
+  async fn datagrams_keep_flowing_across_a_reload() {
+    let link_config = link_config("192.0.2.20", "");
+    let old_tap = Arc::new(MemoryFrames::new());
+    let receivers = HashMap::from([("b".to_string(), LinkReceiver::new(old_tap.clone(), None, None, &link_config, Arc::new(stats::LinkStats::default()), None))]);
+    let mut link_map = config::AddrStringMap::new(vec![(link_config.remote_addr(), "b".to_string())]);
+    let socket = Arc::new(MemoryDatagrams::new());
+    let (update_sender, mut update_receiver) = mpsc::channel(1);
+
+    let forwarding = receive_from_etherip_socket(socket.clone(), receivers, HashMap::new(), &mut link_map, config::Rpf::Off, Some(&mut update_receiver), None);
+    let new_tap = Arc::new(MemoryFrames::new());
+    let (before, after) = run_until(forwarding, async {
+      socket.push_received(&datagram_of(&frame(b"before")), ip("192.0.2.20"));
+      let before = old_tap.take_sent().await;
+
+      // The reload hands over new receivers without stopping the socket receiver.
+      let (applied, applied_receiver) = oneshot::channel();
+      let receivers = HashMap::from([("b".to_string(), LinkReceiver::new(new_tap.clone(), None, None, &link_config, Arc::new(stats::LinkStats::default()), None))]);
+      let update = ReceiverUpdate { receivers, relays: HashMap::new(), link_pairs: vec![(link_config.remote_addr(), "b".to_string())], rpf: config::Rpf::Off, applied };
+      assert!(update_sender.send(update).await.is_ok(), "the socket receiver stopped");
+      applied_receiver.await.expect("reload applied");
+      // The previous receivers were dropped, releasing their TAP.
+      assert_eq!(Arc::strong_count(&old_tap), 1);
+
+      socket.push_received(&datagram_of(&frame(b"after")), ip("192.0.2.20"));
+      (before, new_tap.take_sent().await)
+    }).await;
+    assert_eq!(before, Some(frame(b"before")));
+    assert_eq!(after, Some(frame(b"after")));
+  }
+
+  #[tokio::test]
+  async fn changed_bind_address_opens_a_rebound_socket() {
+    let (old_addr, new_addr) = (Some(ip("127.0.0.1")), Some(ip("127.0.0.2")));
+    let old = match open_socket(SocketFamily::Inet, old_addr) {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+      Err(e) => panic!("cannot open an EtherIP socket: {}", e),
+    };
+    assert!(rebind_socket(SocketFamily::Inet, old_addr, old_addr).is_none(), "rebound to the same address");
+    // An address of no interface cannot be bound, so the current socket is kept.
+    assert!(rebind_socket(SocketFamily::Inet, old_addr, Some(ip("192.0.2.1"))).is_none());
+
+    let new = rebind_socket(SocketFamily::Inet, old_addr, new_addr).expect("rebound socket");
+    assert_eq!(new.local_addr().unwrap(), ip("127.0.0.2"));
+    // Only datagrams to the new address reach the new socket.
+    let sender = EtherIpSocket::new_with_family(SocketFamily::Inet).unwrap();
+    let mut datagram = EtherIpDatagram::new();
+    for (payload, dst) in [(&b"to the old address"[..], "127.0.0.1"), (b"to the new address", "127.0.0.2")] {
+      let (mut len, buf) = datagram.ethrnet_frame_mut();
+      let sent_frame = frame(payload);
+      buf[..sent_frame.len()].copy_from_slice(&sent_frame);
+      len.set(sent_frame.len());
+      sender.send_to(&datagram, &ip(dst)).await.expect("send");
+    }
+    let received = tokio::time::timeout(Duration::from_secs(5), async {
+      loop {
+        let mut received = EtherIpDatagram::new();
+        new.recv_from(&mut received).await.expect("receive");
+        // EtherIP traffic from elsewhere is skipped.
+        let received_frame = received.ethrnet_frame().map(<[u8]>::to_vec);
+        if received_frame == Some(frame(b"to the old address")) || received_frame == Some(frame(b"to the new address")) {
+          return received_frame;
+        }
+      }
+    }).await.expect("timed out");
+    assert_eq!(received, Some(frame(b"to the new address")));
+    drop(old);
+  }
+
+  /// Run `monitor_source_address` for a link to `remote` until it has found a source address.
+  async fn monitored_source_address<S: DatagramSink>(remote: &str, socket: &S) -> IpAddr {
+    let (link_config, link_stats) = (link_config(remote, ""), stats::LinkStats::default());
+    let monitor = monitor_source_address("b", &link_config, socket, &link_stats);
+    let found = async {
+      loop {
+        if let Some(source) = *link_stats.source_address.read() {
+          return source;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+      select! {
+        _ = monitor => unreachable!(),
+        source = found => source,
+      }
+    }).await.expect("timed out")
+  }
+
+  #[tokio::test]
+  async fn source_address_follows_the_route_unless_bound() {
+    // Unbound, datagrams to any loopback address leave from the address of lo.
+    let unbound = MemoryDatagrams::new();
+    assert_eq!(monitored_source_address("127.0.0.1", &unbound).await, ip("127.0.0.1"));
+    assert_eq!(monitored_source_address("127.0.0.3", &unbound).await, ip("127.0.0.1"));
+
+    let bound = match open_socket(SocketFamily::Inet, Some(ip("127.0.0.2"))) {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+      Err(e) => panic!("cannot open an EtherIP socket: {}", e),
+    };
+    assert_eq!(monitored_source_address("127.0.0.1", &bound).await, ip("127.0.0.2"));
+  }
+
+  #[tokio::test]
+  async fn oversized_datagrams_are_counted_as_too_large() {
+    let socket = match open_socket(SocketFamily::Inet, None) {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+      Err(e) => panic!("cannot open an EtherIP socket: {}", e),
+    };
+    // The largest frame makes a datagram no IPv4 packet can carry, so the kernel refuses it with EMSGSIZE.
+    let mut oversized = etherip::HeapEtherIpDatagram::with_max_frame_size(65534);
+    let (mut len, buf) = oversized.ethrnet_frame_mut();
+    let oversized_frame = frame(&[0x5a; 65534 - 14]);
+    buf.copy_from_slice(&oversized_frame);
+    len.set(oversized_frame.len());
+
+    let link_stats = stats::LinkStats::default();
+    let error = socket.send_datagram(&oversized, &ip("127.0.0.1")).await.expect_err("sent an oversized datagram");
+    assert_eq!(etherip::TooLarge::from_error(&error), Some(etherip::TooLarge { len: 65536 }));
+    count_send_error(&link_stats, &error);
+
+    // In a batch, only the oversized datagram fails and is counted.
+    let small = datagram_of(&frame(b"fits"));
+    let batch = [(oversized.datagram().unwrap(), ip("127.0.0.1")), (small.as_slice(), ip("127.0.0.1"))];
+    let results = socket.send_datagrams(&batch).await.expect("send the batch");
+    assert!(results[1].is_ok());
+    results.iter().filter_map(|result| result.as_ref().err()).for_each(|e| count_send_error(&link_stats, e));
+
+    assert_eq!(link_stats.tx_too_large.get(), 2);
+    assert_eq!(link_stats.send_errors.get(), 0);
+  }
+
+  #[test]
+  fn source_checks_accept_remotes_and_drop_the_rest() {
+    let pairs = [("a", "127.0.0.1"), ("r", "127.0.0.2")];
+    let link_map = config::AddrStringMap::new(pairs.iter().map(|(name, remote)| (link_config(remote, "").remote_addr(), name.to_string())).collect());
+    let (a, r) = ("a".to_string(), "r".to_string());
+    let lo = etherip::interface_index("lo").expect("index of lo");
+    let mut checks = SourceChecks::default();
+    let is_relay = |name: &str| name == "r";
+    let unicast = |_: &str| false;
+
+    assert_eq!(checks.check(&link_map, config::Rpf::Off, &ip("127.0.0.1"), None, is_relay, unicast), SourceMatch::Link(&a));
+    assert_eq!(checks.check(&link_map, config::Rpf::Off, &ip("127.0.0.2"), None, is_relay, unicast), SourceMatch::Relay(&r));
+    assert_eq!(checks.check(&link_map, config::Rpf::Off, &ip("192.0.2.99"), None, is_relay, unicast), SourceMatch::Unknown);
+
+    // The route back to a loopback source goes out of lo, and nowhere else.
+    assert_eq!(checks.check(&link_map, config::Rpf::Strict, &ip("127.0.0.1"), Some(lo), is_relay, unicast), SourceMatch::Link(&a));
+    assert_eq!(checks.check(&link_map, config::Rpf::Loose, &ip("127.0.0.1"), Some(lo + 1000), is_relay, unicast), SourceMatch::Link(&a));
+    assert_eq!(
+      checks.check(&link_map, config::Rpf::Strict, &ip("127.0.0.1"), Some(lo + 1000), is_relay, unicast),
+      SourceMatch::Dropped { name: &a, relay: false, reason: SourceDrop::ReversePath },
+    );
+    assert_eq!(
+      checks.check(&link_map, config::Rpf::Strict, &ip("127.0.0.2"), Some(lo + 1000), is_relay, unicast),
+      SourceMatch::Dropped { name: &r, relay: true, reason: SourceDrop::ReversePath },
+    );
+
+    // A multicast link drops datagrams from an address of this host, a relay does not.
+    let multicast = |_: &str| true;
+    assert_eq!(
+      checks.check(&link_map, config::Rpf::Off, &ip("127.0.0.1"), None, is_relay, multicast),
+      SourceMatch::Dropped { name: &a, relay: false, reason: SourceDrop::LoopedBack },
+    );
+    assert_eq!(checks.check(&link_map, config::Rpf::Off, &ip("127.0.0.2"), None, is_relay, multicast), SourceMatch::Relay(&r));
+  }
+}
@@ -1,10 +1,105 @@
 // -*- indent-tabs-mode: nil; tab-width: 2; -*-
 // vim: set ts=&2 sw=2 et ai :
 
+use std::path::PathBuf;
+
+use etherip::clap;
+use clap::Parser;
+
+use etherip::libc;
+use etherip::ethernet::MacAddr;
+use etherip::privileges;
 use etherip::tap;
 
+/// Create a persistent TAP interface, optionally configuring it in the same step.
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+  /// Name of the interface.
+  #[clap(value_parser = parse_ifname)]
+  ifname: String,
+
+  /// MTU of the interface.
+  #[clap(long, value_parser = clap::value_parser!(u32).range(68..=65535))]
+  mtu: Option<u32>,
+
+  /// MAC address of the interface, e.g. 02:00:00:00:00:01.
+  #[clap(long)]
+  mac: Option<MacAddr>,
+
+  /// Bring the interface up.
+  #[clap(long)]
+  up: bool,
+
+  /// User (name or uid) allowed to attach to the interface.
+  #[clap(long)]
+  owner: Option<String>,
+
+  /// Group (name or gid) allowed to attach to the interface.
+  #[clap(long)]
+  group: Option<String>,
+
+  /// Remove the interface when this command exits instead of keeping it.
+  /// The command then waits until it is interrupted.
+  #[clap(long)]
+  no_persist: bool,
+
+  /// Path of the TUN/TAP clone device.
+  #[clap(long)]
+  tun_device: Option<PathBuf>,
+}
+
+/// Reject names the kernel would refuse, so that mistakes are reported before anything is created.
+fn parse_ifname(ifname: &str) -> Result<String, String> {
+  if ifname.is_empty() || ifname.len() >= libc::IFNAMSIZ {
+    return Err(format!("must be 1 to {} bytes long", libc::IFNAMSIZ - 1));
+  }
+  if ifname.contains(|c: char| c == '/' || c.is_ascii_whitespace()) {
+    return Err("must not contain '/' or whitespace".to_string());
+  }
+  Ok(ifname.to_string())
+}
+
+fn resolve_owner(owner: &str) -> std::io::Result<libc::uid_t> {
+  match owner.parse() {
+    Ok(uid) => Ok(uid),
+    Err(_) => privileges::lookup_user(owner).map(|(uid, _)| uid),
+  }
+}
+
+fn resolve_group(group: &str) -> std::io::Result<libc::gid_t> {
+  match group.parse() {
+    Ok(gid) => Ok(gid),
+    Err(_) => privileges::lookup_group(group),
+  }
+}
+
 fn main() -> std::io::Result<()> {
-  let ifname = std::env::args().nth(1).expect("Usage: tap-add <ifname>");
+  let args = Args::parse();
+
+  let options = tap::TapAddOptions {
+    owner: args.owner.as_deref().map(resolve_owner).transpose()?,
+    group: args.group.as_deref().map(resolve_group).transpose()?,
+    persist: !args.no_persist,
+    tun_device: args.tun_device,
+  };
+  let _tap = tap::tap_add_with_options(&args.ifname, &options)?;
+
+  if let Some(mtu) = args.mtu {
+    tap::set_interface_mtu(&args.ifname, mtu)?;
+  }
+  if let Some(mac) = args.mac {
+    tap::set_mac(&args.ifname, &mac)?;
+  }
+  if args.up {
+    tap::set_up(&args.ifname, true)?;
+  }
 
-  tap::tap_add_ioctl(&ifname)
+  if args.no_persist {
+    // The interface goes away with the process, when a signal terminates it.
+    loop {
+      std::thread::park();
+    }
+  }
+  Ok(())
 }
@@ -1,10 +1,94 @@
 // -*- indent-tabs-mode: nil; tab-width: 2; -*-
 // vim: set ts=&2 sw=2 et ai :
 
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use etherip::anyhow;
+use etherip::clap;
+use clap::Parser;
+
+use etherip::config;
 use etherip::tap;
 
-fn main() -> std::io::Result<()> {
-  let ifname = std::env::args().nth(1).expect("Usage: tap-del <ifname>");
+const DEFAULT_CONFIG_PATH: &str = "/etc/etheripd/etheripd.toml";
+
+/// Exit code when the named interface is not an existing TAP interface.
+const EXIT_NOT_FOUND: u8 = 3;
+
+/// Delete a persistent TAP interface. Interfaces count as managed by etheripd when its
+/// configuration has a link or a `mirror_to` interface of that name.
+///
+/// Exits with 0 when the interfaces were (or, with --dry-run, would be) deleted,
+/// 1 on errors, 2 on invalid arguments and 3 when the named interface is not an existing TAP interface.
+#[derive(Parser)]
+#[clap(author, version, about, long_about)]
+struct Args {
+  /// Name of the interface.
+  #[clap(required_unless_present = "all_managed", conflicts_with = "all_managed")]
+  ifname: Option<String>,
+
+  /// Delete every TAP interface managed by etheripd instead of a named one.
+  #[clap(long)]
+  all_managed: bool,
+
+  /// Print what would be deleted without deleting anything.
+  #[clap(long)]
+  dry_run: bool,
+
+  /// Configuration of etheripd, which tells the managed interfaces.
+  #[clap(short = 'c', long, default_value = DEFAULT_CONFIG_PATH)]
+  config: PathBuf,
+}
+
+fn main() -> ExitCode {
+  let args = Args::parse();
+  match run(&args) {
+    Ok(code) => code,
+    Err(e) => {
+      eprintln!("tap-del: {}", e);
+      ExitCode::FAILURE
+    },
+  }
+}
+
+fn run(args: &Args) -> Result<ExitCode, anyhow::Error> {
+  let taps = tap::list_taps()?;
+  let Some(ifname) = &args.ifname else {
+    let managed = managed_taps(args)?;
+    for tap in taps.iter().filter(|tap| managed.contains(&tap.name)) {
+      if args.dry_run {
+        println!("{}: would be deleted", tap.name);
+      } else {
+        tap::tap_del_ioctl(&tap.name)?;
+        println!("{}: deleted", tap.name);
+      }
+    }
+    return Ok(ExitCode::SUCCESS);
+  };
+
+  let Some(tap) = taps.iter().find(|tap| tap.name == *ifname) else {
+    eprintln!("{}: no such TAP interface", ifname);
+    return Ok(ExitCode::from(EXIT_NOT_FOUND));
+  };
+  if args.dry_run {
+    // Whether the interface is managed is only informative here, so an unreadable configuration is not fatal.
+    let managed = match managed_taps(args) {
+      Ok(managed) if managed.contains(ifname) => "managed by etheripd",
+      Ok(_) => "not managed by etheripd",
+      Err(_) => "unknown to etheripd (no readable configuration)",
+    };
+    let persistent = if tap.persistent { "persistent" } else { "not persistent" };
+    println!("{}: {} TAP interface, {}; would be deleted", ifname, persistent, managed);
+    return Ok(ExitCode::SUCCESS);
+  }
+  tap::tap_del_ioctl(ifname)?;
+  Ok(ExitCode::SUCCESS)
+}
 
-  tap::tap_del_ioctl(&ifname)
+fn managed_taps(args: &Args) -> Result<HashSet<String>, anyhow::Error> {
+  let config = config::Config::from_path(&args.config)
+    .map_err(|e| anyhow::anyhow!("failed to read {}: {}", args.config.display(), e))?;
+  Ok(config.tap_names())
 }
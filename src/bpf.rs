@@ -0,0 +1,117 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Classic BPF programs for filtering the EtherIP socket and AF_PACKET inputs in the kernel.
+
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+
+use crate::libc;
+use crate::SocketFamily;
+
+/// Return value of a BPF program accepting the whole packet.
+const ACCEPT: u32 = u32::MAX;
+
+/// Return value of a BPF program dropping the packet.
+const DROP: u32 = 0;
+
+/// Offset of the source address in the IPv4 header.
+const IPV4_SOURCE_OFFSET: u32 = 12;
+
+/// Offset of the source address in the IPv6 header.
+const IPV6_SOURCE_OFFSET: u32 = 8;
+
+/// Load a 32-bit word at `offset` from the start of the network (IP) header.
+/// The negative base makes this work on IPv6 raw sockets, whose packets start after the IP header.
+fn load_network_word(offset: u32) -> libc::sock_filter {
+  instruction(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, (libc::SKF_NET_OFF as u32).wrapping_add(offset), 0, 0)
+}
+
+fn jump_if_equal(value: u32, jt: u8, jf: u8) -> libc::sock_filter {
+  instruction(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, value, jt, jf)
+}
+
+fn ret(value: u32) -> libc::sock_filter {
+  instruction(libc::BPF_RET | libc::BPF_K, value, 0, 0)
+}
+
+fn instruction(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+  libc::sock_filter {
+    code: code as u16,
+    jt,
+    jf,
+    k,
+  }
+}
+
+/// Build a program accepting only packets whose source is one of `peers`, for a socket of `family`.
+/// Peers the socket cannot receive from (IPv4 ones on an AF_INET6 socket and vice versa) are ignored.
+/// Returns `None` if the program would exceed the kernel's instruction limit.
+pub fn peer_filter(family: SocketFamily, peers: &[IpAddr]) -> Option<Vec<libc::sock_filter>> {
+  let mut program = Vec::new();
+  match family {
+    SocketFamily::Inet => {
+      program.push(load_network_word(IPV4_SOURCE_OFFSET));
+      for peer in peers {
+        let v4_addr = match peer {
+          IpAddr::V4(v4_addr) => *v4_addr,
+          IpAddr::V6(v6_addr) => match v6_addr.to_ipv4_mapped() {
+            Some(v4_addr) => v4_addr,
+            None => continue,
+          },
+        };
+        program.push(jump_if_equal(u32::from(v4_addr), 0, 1));
+        program.push(ret(ACCEPT));
+      }
+    },
+    SocketFamily::Inet6 => {
+      for peer in peers {
+        let IpAddr::V6(v6_addr) = peer else {
+          continue;
+        };
+        if v6_addr.to_ipv4_mapped().is_some() {
+          continue;
+        }
+        // On a mismatch, skip to the next address; on a full match, fall through to the accept.
+        let octets = v6_addr.octets();
+        for i in 0..4 {
+          let word = u32::from_be_bytes([octets[i * 4], octets[i * 4 + 1], octets[i * 4 + 2], octets[i * 4 + 3]]);
+          program.push(load_network_word(IPV6_SOURCE_OFFSET + i as u32 * 4));
+          program.push(jump_if_equal(word, 0, (7 - i * 2) as u8));
+        }
+        program.push(ret(ACCEPT));
+      }
+    },
+  }
+  program.push(ret(DROP));
+  (program.len() <= libc::BPF_MAXINSNS as usize).then_some(program)
+}
+
+/// Parse a program written as the output of `tcpdump -ddd`, with lines joined by commas
+/// as in iptables' `--bytecode`: the instruction count, then `code jt jf k` for each one.
+pub fn parse_bytecode(s: &str) -> std::io::Result<Vec<libc::sock_filter>> {
+  let invalid = |message: String| Error::new(ErrorKind::InvalidInput, format!("invalid BPF bytecode: {}", message));
+  let mut lines = s.trim().split(',').map(str::trim);
+  let count: usize = lines.next().unwrap_or_default().parse().map_err(|_| invalid("missing instruction count".to_string()))?;
+  let mut program = Vec::with_capacity(count.min(libc::BPF_MAXINSNS as usize));
+  for line in lines {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [code, jt, jf, k] = fields[..] else {
+      return Err(invalid(format!("instruction {:?} does not have 4 fields", line)));
+    };
+    let parse_error = |_| invalid(format!("instruction {:?} has a field out of range", line));
+    program.push(libc::sock_filter {
+      code: code.parse().map_err(parse_error)?,
+      jt: jt.parse().map_err(parse_error)?,
+      jf: jf.parse().map_err(parse_error)?,
+      k: k.parse().map_err(parse_error)?,
+    });
+  }
+  if program.len() != count {
+    return Err(invalid(format!("{} instructions given, but the count is {}", program.len(), count)));
+  }
+  if program.is_empty() || program.len() > libc::BPF_MAXINSNS as usize {
+    return Err(invalid(format!("a program has 1 to {} instructions", libc::BPF_MAXINSNS)));
+  }
+  Ok(program)
+}
@@ -0,0 +1,104 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Reporting of missing Linux capabilities.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crate::libc;
+
+/// Linux capabilities needed by this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+  /// Needed to open raw IP sockets.
+  NetRaw,
+  /// Needed to create and configure TAP interfaces.
+  NetAdmin,
+  /// Needed to enter network namespaces.
+  SysAdmin,
+}
+
+impl Capability {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Capability::NetRaw => "CAP_NET_RAW",
+      Capability::NetAdmin => "CAP_NET_ADMIN",
+      Capability::SysAdmin => "CAP_SYS_ADMIN",
+    }
+  }
+
+  /// Bit number of the capability (`<linux/capability.h>`).
+  pub fn number(&self) -> u32 {
+    match self {
+      Capability::NetRaw => 13,
+      Capability::NetAdmin => 12,
+      Capability::SysAdmin => 21,
+    }
+  }
+
+  /// Whether the current process holds the capability in its effective set,
+  /// as reported by `/proc/self/status`.
+  pub fn is_effective(&self) -> std::io::Result<bool> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let cap_eff = status.lines()
+      .find_map(|line| line.strip_prefix("CapEff:"))
+      .ok_or_else(|| Error::new(ErrorKind::InvalidData, "CapEff missing from /proc/self/status"))?;
+    let cap_eff = u64::from_str_radix(cap_eff.trim(), 16).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(cap_eff & (1 << self.number()) != 0)
+  }
+}
+
+impl fmt::Display for Capability {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+/// An operation failed with EPERM/EACCES, most likely because a capability is missing.
+/// The original OS error is available via `source()`.
+#[derive(Debug)]
+pub struct MissingCapabilityError {
+  capability: Capability,
+  operation: &'static str,
+  source: Error,
+}
+
+impl MissingCapabilityError {
+  pub fn capability(&self) -> Capability {
+    self.capability
+  }
+
+  pub fn os_error(&self) -> &Error {
+    &self.source
+  }
+}
+
+impl fmt::Display for MissingCapabilityError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let setcap = match self.capability {
+      Capability::SysAdmin => "cap_net_raw,cap_net_admin,cap_sys_admin",
+      _ => "cap_net_raw,cap_net_admin",
+    };
+    write!(f, "permission denied to {} ({}): {} is required; run as root or grant it with `setcap {}+ep <path to executable>`", self.operation, self.source, self.capability, setcap)
+  }
+}
+
+impl std::error::Error for MissingCapabilityError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    Some(&self.source)
+  }
+}
+
+/// Wrap an EPERM/EACCES error from `operation` in a `MissingCapabilityError`.
+/// Other errors are returned unchanged.
+pub fn explain_permission_error(error: Error, capability: Capability, operation: &'static str) -> Error {
+  match error.raw_os_error() {
+    Some(libc::EPERM) | Some(libc::EACCES) => Error::new(ErrorKind::PermissionDenied, MissingCapabilityError {
+      capability,
+      operation,
+      source: error,
+    }),
+    _ => error,
+  }
+}
@@ -0,0 +1,139 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! EtherIP over byte streams, for test rigs and stream-tunneled variants.
+//!
+//! Each datagram is prefixed with its length as a 16-bit big-endian integer,
+//! followed by the EtherIP header and the Ethernet frame.
+
+use std::io::{Error, ErrorKind};
+
+use crate::tokio_util;
+
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::ethernet::ETHERNET_HEADER_SIZE;
+use crate::{EtherIpHeader, ParserMode, ETHERIP_HEADER_SIZE};
+
+/// Size of the length prefix.
+pub const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Largest Ethernet frame that fits in a length-prefixed datagram.
+pub const MAX_FRAME_SIZE: usize = u16::MAX as usize - ETHERIP_HEADER_SIZE;
+
+/// Codec of length-prefixed EtherIP datagrams whose items are Ethernet frames.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EtherIpCodec {
+  mode: ParserMode,
+}
+
+impl EtherIpCodec {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Create a codec checking received headers in `mode`.
+  pub fn with_mode(mode: ParserMode) -> Self {
+    Self {
+      mode,
+    }
+  }
+}
+
+impl Decoder for EtherIpCodec {
+  type Item = BytesMut;
+  type Error = Error;
+
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    if src.len() < LENGTH_PREFIX_SIZE {
+      return Ok(None);
+    }
+    let len = u16::from_be_bytes([src[0], src[1]]) as usize;
+    if len < ETHERIP_HEADER_SIZE + ETHERNET_HEADER_SIZE {
+      return Err(Error::new(ErrorKind::InvalidData, "EtherIP datagram too short"));
+    }
+    if src.len() < LENGTH_PREFIX_SIZE + len {
+      src.reserve(LENGTH_PREFIX_SIZE + len - src.len());
+      return Ok(None);
+    }
+    src.advance(LENGTH_PREFIX_SIZE);
+    let mut datagram = src.split_to(len);
+    match EtherIpHeader::decode(&datagram) {
+      Some(header) if header.is_acceptable(self.mode) => {},
+      _ => return Err(Error::new(ErrorKind::InvalidData, "invalid EtherIP header")),
+    }
+    datagram.advance(ETHERIP_HEADER_SIZE);
+    Ok(Some(datagram))
+  }
+}
+
+impl Encoder<&[u8]> for EtherIpCodec {
+  type Error = Error;
+
+  fn encode(&mut self, frame: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+    if frame.len() < ETHERNET_HEADER_SIZE || frame.len() > MAX_FRAME_SIZE {
+      return Err(Error::new(ErrorKind::InvalidInput, "invalid Ethernet frame length"));
+    }
+    let len = ETHERIP_HEADER_SIZE + frame.len();
+    dst.reserve(LENGTH_PREFIX_SIZE + len);
+    dst.put_u16(len as u16);
+    dst.put_slice(&EtherIpHeader::default().encode());
+    dst.put_slice(frame);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame_of(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn frames_round_trip() {
+    let frames = [frame_of(ETHERNET_HEADER_SIZE), frame_of(1514), frame_of(MAX_FRAME_SIZE)];
+    let mut codec = EtherIpCodec::new();
+    let mut stream = BytesMut::new();
+    for frame in &frames {
+      codec.encode(frame.as_slice(), &mut stream).unwrap();
+    }
+    for frame in &frames {
+      assert_eq!(codec.decode(&mut stream).unwrap().as_deref(), Some(frame.as_slice()));
+    }
+    assert_eq!(codec.decode(&mut stream).unwrap(), None);
+  }
+
+  #[test]
+  fn partial_datagrams_wait_for_the_rest() {
+    let frame = frame_of(60);
+    let mut codec = EtherIpCodec::new();
+    let mut encoded = BytesMut::new();
+    codec.encode(frame.as_slice(), &mut encoded).unwrap();
+    let mut stream = BytesMut::new();
+    for byte in &encoded[..encoded.len() - 1] {
+      stream.put_u8(*byte);
+      assert_eq!(codec.decode(&mut stream).unwrap(), None);
+    }
+    stream.put_u8(encoded[encoded.len() - 1]);
+    assert_eq!(codec.decode(&mut stream).unwrap().as_deref(), Some(frame.as_slice()));
+  }
+
+  #[test]
+  fn invalid_frames_and_datagrams_are_rejected() {
+    let mut codec = EtherIpCodec::new();
+    let mut stream = BytesMut::new();
+    assert!(codec.encode(&frame_of(ETHERNET_HEADER_SIZE - 1)[..], &mut stream).is_err());
+    assert!(stream.is_empty());
+
+    codec.encode(&frame_of(60)[..], &mut stream).unwrap();
+    // Version 2 in the header.
+    stream[LENGTH_PREFIX_SIZE] = 0x20;
+    assert_eq!(codec.decode(&mut stream).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let mut short = BytesMut::from(&[0, 4, 0x30, 0, 0, 0][..]);
+    assert_eq!(codec.decode(&mut short).unwrap_err().kind(), ErrorKind::InvalidData);
+  }
+}
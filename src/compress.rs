@@ -0,0 +1,171 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Optional compression of the tunneled Ethernet frames.
+//!
+//! This is not part of RFC 3378: when enabled, a 1-byte shim telling whether the frame
+//! is compressed is inserted before the Ethernet frame (after the sequence number, if any).
+//! Both ends of a link must enable it, and only instances of this crate understand it.
+
+use crate::lz4_flex;
+use crate::serde;
+
+use serde::Deserialize;
+
+/// Size of the compression shim.
+pub const COMPRESSION_SHIM_SIZE: usize = 1;
+
+const FLAG_UNCOMPRESSED: u8 = 0;
+const FLAG_LZ4: u8 = 1;
+
+/// Frames smaller than this are sent uncompressed.
+pub const MIN_COMPRESSED_FRAME_SIZE: usize = 128;
+
+/// Compression algorithm of a link.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+  #[default]
+  None,
+  Lz4,
+}
+
+/// Outcome of compressing a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compressed {
+  /// The frame was compressed, saving the given number of bytes.
+  Lz4 { saved: usize },
+  /// The frame was left as is because it is small or incompressible.
+  Uncompressed,
+}
+
+/// Frame compressor of the sending side.
+#[derive(Debug, Clone)]
+pub struct Compressor {
+  scratch: Vec<u8>,
+}
+
+impl Compressor {
+  pub fn new() -> Self {
+    Self {
+      scratch: vec![0u8; lz4_flex::block::get_maximum_output_size(u16::MAX as usize)],
+    }
+  }
+
+  /// Compress the frame of `len` bytes at `buf[COMPRESSION_SHIM_SIZE..]` in place and
+  /// fill in the shim, returning the outcome and the length including the shim.
+  /// A frame is only compressed if that saves at least 1/16 of its size.
+  pub fn compress(&mut self, buf: &mut [u8], len: usize) -> (Compressed, usize) {
+    let frame = &buf[COMPRESSION_SHIM_SIZE..COMPRESSION_SHIM_SIZE + len];
+    if len >= MIN_COMPRESSED_FRAME_SIZE {
+      if let Ok(compressed_len) = lz4_flex::block::compress_into(frame, &mut self.scratch) {
+        if compressed_len + len / 16 < len {
+          buf[0] = FLAG_LZ4;
+          buf[COMPRESSION_SHIM_SIZE..COMPRESSION_SHIM_SIZE + compressed_len].copy_from_slice(&self.scratch[..compressed_len]);
+          return (Compressed::Lz4 { saved: len - compressed_len }, COMPRESSION_SHIM_SIZE + compressed_len);
+        }
+      }
+    }
+    buf[0] = FLAG_UNCOMPRESSED;
+    (Compressed::Uncompressed, COMPRESSION_SHIM_SIZE + len)
+  }
+}
+
+impl Default for Compressor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Frame decompressor of the receiving side.
+#[derive(Debug, Clone)]
+pub struct Decompressor {
+  buf: Vec<u8>,
+}
+
+impl Decompressor {
+  pub fn new() -> Self {
+    Self {
+      buf: vec![0u8; u16::MAX as usize],
+    }
+  }
+
+  /// Strip the compression shim off `data`, decompressing the frame if needed.
+  /// Returns `None` for a missing shim, an unknown flag or corrupt compressed data.
+  pub fn decompress<'a>(&'a mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+    let (&flag, frame) = data.split_first()?;
+    match flag {
+      FLAG_UNCOMPRESSED => Some(frame),
+      FLAG_LZ4 => {
+        let len = lz4_flex::block::decompress_into(frame, &mut self.buf).ok()?;
+        Some(&self.buf[..len])
+      },
+      _ => None,
+    }
+  }
+}
+
+impl Default for Decompressor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A buffer with room for the shim, holding `frame` after it.
+  fn shimmed(frame: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0xee; COMPRESSION_SHIM_SIZE + frame.len()];
+    buf[COMPRESSION_SHIM_SIZE..].copy_from_slice(frame);
+    buf
+  }
+
+  /// Bytes from a xorshift generator, which LZ4 cannot shrink.
+  fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545f491u32;
+    (0..len).map(|_| {
+      state ^= state << 13;
+      state ^= state >> 17;
+      state ^= state << 5;
+      state as u8
+    }).collect()
+  }
+
+  #[test]
+  fn compressible_frames_round_trip() {
+    let frame: Vec<u8> = (0..1500).map(|i| (i % 16) as u8).collect();
+    let mut buf = shimmed(&frame);
+    let (compressed, len) = Compressor::new().compress(&mut buf, frame.len());
+    assert_eq!(buf[0], FLAG_LZ4);
+    assert_eq!(compressed, Compressed::Lz4 { saved: frame.len() + COMPRESSION_SHIM_SIZE - len });
+    assert!(len < frame.len() / 2, "compressed to {} bytes", len);
+    assert_eq!(Decompressor::new().decompress(&buf[..len]), Some(&frame[..]));
+  }
+
+  #[test]
+  fn small_and_incompressible_frames_are_not_expanded() {
+    let mut compressor = Compressor::new();
+    // Compressible, but below the size worth compressing.
+    let small = [0u8; MIN_COMPRESSED_FRAME_SIZE - 1];
+    let mut buf = shimmed(&small);
+    assert_eq!(compressor.compress(&mut buf, small.len()), (Compressed::Uncompressed, COMPRESSION_SHIM_SIZE + small.len()));
+    assert_eq!((buf[0], &buf[COMPRESSION_SHIM_SIZE..]), (FLAG_UNCOMPRESSED, &small[..]));
+
+    let incompressible = noise(1500);
+    let mut buf = shimmed(&incompressible);
+    assert_eq!(compressor.compress(&mut buf, incompressible.len()), (Compressed::Uncompressed, COMPRESSION_SHIM_SIZE + incompressible.len()));
+    assert_eq!(Decompressor::new().decompress(&buf), Some(&incompressible[..]));
+  }
+
+  #[test]
+  fn malformed_shims_are_refused() {
+    let mut decompressor = Decompressor::new();
+    assert_eq!(decompressor.decompress(&[]), None);
+    assert_eq!(decompressor.decompress(&[2, 0x02, 0, 0]), None);
+    // An LZ4 block pointing back before its start.
+    assert_eq!(decompressor.decompress(&[FLAG_LZ4, 0x0f, 0xff, 0xff]), None);
+    assert_eq!(decompressor.decompress(&[FLAG_UNCOMPRESSED]), Some(&[][..]));
+  }
+}
@@ -3,14 +3,18 @@
 
 //! Configuration for the EtherIP daemon.
 
-use std::{collections::HashMap, path::Path};
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, Ipv6Addr, SocketAddr}, path::{Path, PathBuf}, sync::Arc};
 
 use crate::tokio;
+use crate::futures;
+use crate::parking_lot;
 use crate::serde;
 use crate::toml;
+use crate::hickory_resolver;
 use crate::anyhow;
 
 use serde::Deserialize;
+use crate::ethernet::MacAddr;
 use crate::log;
 use log::LevelFilter;
 
@@ -19,78 +23,1056 @@ use log::LevelFilter;
 pub struct Config {
   pub log_level: LogLevel,
   pub links: HashMap<String, LinkConfig>,
+
+  /// Relays, which forward EtherIP datagrams between two remotes over the EtherIP socket
+  /// without a TAP interface, e.g. to chain tunnels through a host both ends can reach.
+  #[serde(default)]
+  pub relays: HashMap<String, RelayConfig>,
+
+  /// Address to serve Prometheus metrics on (`GET /metrics`), along with the link counts
+  /// since the last reset (`GET /stats`, `POST /stats/reset`), the busiest inner flows
+  /// (`GET /flows`) and the learned MAC addresses (`GET /fdb`, `GET /fdb/table`).
+  /// Only read at startup.
+  #[serde(default)]
+  pub metrics_listen: Option<std::net::SocketAddr>,
+
+  /// Unprivileged user to switch to once the socket and TAP interfaces are open.
+  /// Reloads can then no longer add or remove links, and a failed EtherIP socket
+  /// cannot be reopened; restart the daemon for those. Only read at startup.
+  #[serde(default)]
+  pub user: Option<String>,
+
+  /// Group to switch to along with `user`, instead of the user's primary group.
+  #[serde(default)]
+  pub group: Option<String>,
+
+  /// Path of the TUN/TAP clone device, if not `/dev/net/tun`.
+  #[serde(default)]
+  pub tun_device: Option<PathBuf>,
+
+  /// Use a native AF_INET socket instead of a dual-stack AF_INET6 one when all links are IPv4,
+  /// for hosts where IPv6 is disabled. Only read at startup.
+  #[serde(default)]
+  pub native_ipv4: bool,
+
+  /// Local address the EtherIP socket binds, which becomes the source address of the
+  /// datagrams; only datagrams sent to it are received. All raw links must use its IP
+  /// version. Unset binds the unspecified address. Reloading with another address
+  /// recreates the socket.
+  #[serde(default)]
+  pub bind_address: Option<IpAddr>,
+
+  /// Read all TAP interfaces from a single task instead of one task per link,
+  /// which scales better to hundreds of links.
+  #[serde(default)]
+  pub shared_tap_reader: bool,
+
+  /// Drop datagrams from unknown sources in the kernel with a BPF program on the EtherIP socket.
+  /// Only applied when every remote is an IP address; otherwise sources are filtered in userspace.
+  #[serde(default)]
+  pub peer_filter: bool,
+
+  /// Default handling of received datagrams that fail validation, for links without `on_invalid`.
+  #[serde(default)]
+  pub on_invalid: OnInvalid,
+
+  /// Reverse path check of received raw datagrams against the route back to their source,
+  /// dropping the ones that fail it as possibly spoofed. Not applied to TCP and UDP links.
+  #[serde(default)]
+  pub rpf: Rpf,
+
+  /// Largest number of links the daemon instantiates; larger configurations are rejected.
+  #[serde(default = "Config::default_max_links")]
+  pub max_links: usize,
+
+  /// Seconds the daemon waits at startup for the hostname remotes to resolve
+  /// before it starts forwarding. 0 skips the wait.
+  #[serde(default = "Config::default_startup_resolve_timeout")]
+  pub startup_resolve_timeout: u64,
+
+  /// Largest Ethernet frame tunneled, which sizes the frame buffers. Lower it on
+  /// memory-constrained hosts running small-MTU links; longer frames are dropped.
+  #[serde(default = "Config::default_max_frame_size")]
+  pub max_frame_size: usize,
+
+  /// Largest number of hostname lookups in flight at once. Each one occupies a thread
+  /// of the blocking pool, so many dynamic links could otherwise starve it.
+  #[serde(default = "Config::default_max_concurrent_resolutions")]
+  pub max_concurrent_resolutions: usize,
+
+  /// Number of frame buffers the TAP readers of all links share, bounding their memory
+  /// regardless of the number of links. A link waits for a buffer when all of them are
+  /// in use. 0 gives every link a buffer of its own. Only read at startup.
+  #[serde(default)]
+  pub buffer_arena_size: usize,
+
+  /// Seconds a forwarding task may leave frames waiting on its input without taking any
+  /// before the watchdog restarts it. 0 disables the watchdog.
+  #[serde(default = "Config::default_watchdog_timeout")]
+  pub watchdog_timeout: u64,
 }
 
+/// Default of `max_links`.
+pub const DEFAULT_MAX_LINKS: usize = 4096;
+
+/// Default of `max_concurrent_resolutions`.
+pub const DEFAULT_MAX_CONCURRENT_RESOLUTIONS: usize = 16;
+
+/// Seconds between path MTU checks of links with `outer_mtu` and no `path_mtu_interval`.
+pub const DEFAULT_OUTER_MTU_CHECK_INTERVAL: u64 = 60;
+
+/// Smallest TAP MTU `outer_mtu` may lead to, the minimum MTU of IPv4.
+pub const MIN_INNER_MTU: u32 = 68;
+
+/// Sizes of the outer headers counted by `LinkConfig::tunnel_overhead`, without options.
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const TCP_HEADER_SIZE: usize = 20;
+
+/// Default of `startup_resolve_timeout`, in seconds.
+pub const DEFAULT_STARTUP_RESOLVE_TIMEOUT: u64 = 5;
+
+/// Default of `watchdog_timeout`, in seconds.
+pub const DEFAULT_WATCHDOG_TIMEOUT: u64 = 30;
+
 impl Config {
+  fn default_max_links() -> usize {
+    DEFAULT_MAX_LINKS
+  }
+
+  fn default_startup_resolve_timeout() -> u64 {
+    DEFAULT_STARTUP_RESOLVE_TIMEOUT
+  }
+
+  fn default_watchdog_timeout() -> u64 {
+    DEFAULT_WATCHDOG_TIMEOUT
+  }
+
+  fn default_max_frame_size() -> usize {
+    crate::ETHERIP_MAX_FRAME_SIZE
+  }
+
+  fn default_max_concurrent_resolutions() -> usize {
+    DEFAULT_MAX_CONCURRENT_RESOLUTIONS
+  }
+
+  /// Resolve the hostname remotes of all links concurrently, waiting at most
+  /// `startup_resolve_timeout` seconds, so that they are known from the first frame.
+  /// Returns the links left unresolved with the reason; they resolve lazily later.
+  pub async fn resolve_remotes(&mut self) -> Vec<(String, String)> {
+    let timeout = std::time::Duration::from_secs(self.startup_resolve_timeout);
+    let lookups = self.links.iter()
+      .filter(|(_, link)| timeout > std::time::Duration::ZERO && !link.remote_addr().is_static_ip_addr())
+      .map(|(name, link)| async move {
+        let result = match tokio::time::timeout(timeout, lookup_remote(&link.remote, link.ip_version, link.remote_source, link.resolver)).await {
+          Ok(result) => result,
+          Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")),
+        };
+        (name.clone(), result)
+      });
+    let results = futures::future::join_all(lookups).await;
+    let mut pending = Vec::new();
+    for (name, result) in results {
+      match result {
+        Ok(ip_addr) => self.links.get_mut(&name).unwrap().resolved_remote = Some(ip_addr),
+        Err(e) => pending.push((name, e.to_string())),
+      }
+    }
+    pending
+  }
+
+  /// Names of the TAP interfaces the daemon creates: those of the links and their `mirror_to` interfaces.
+  pub fn tap_names(&self) -> HashSet<String> {
+    self.links.iter()
+      .flat_map(|(link_name, link)| std::iter::once(link_name.clone()).chain(link.mirror_to.clone()))
+      .collect()
+  }
+
   /// read the configuration from a file.
   pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
     let config_str = std::fs::read_to_string(path)?;
-    let config = toml::from_str(&config_str)?;
-    Ok(config)
+    Self::parse(&config_str)
   }
 
   /// read the configuration from a file asynchronously using tokio.
   pub async fn from_path_async<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
     let config_str = tokio::fs::read_to_string(path).await?;
-    let config = toml::from_str(&config_str)?;
+    Self::parse(&config_str)
+  }
+
+  /// Parse and validate a configuration, applying the global defaults of link settings.
+  fn parse(config_str: &str) -> Result<Self, anyhow::Error> {
+    let mut config: Self = toml::from_str(config_str)?;
+    for (link_name, link) in config.links.iter_mut() {
+      link.on_invalid.get_or_insert(config.on_invalid);
+      if let Some(auth) = &mut link.auth {
+        auth.load_key().map_err(|e| anyhow::anyhow!("Link {}: invalid `auth`: {}", link_name, e))?;
+      }
+    }
+    config.validate()?;
     Ok(config)
   }
 
+  /// Check the consistency of the configuration beyond what deserialization enforces.
+  pub fn validate(&self) -> Result<(), anyhow::Error> {
+    if self.links.len() > self.max_links {
+      log::error!("Configuration has {} links, more than max_links ({})", self.links.len(), self.max_links);
+      anyhow::bail!("{} links are configured, but at most {} are allowed (raise `max_links` if intended)", self.links.len(), self.max_links);
+    }
+    if self.max_concurrent_resolutions == 0 {
+      anyhow::bail!("max_concurrent_resolutions must be at least 1");
+    }
+    if !(crate::ethernet::ETHERNET_HEADER_SIZE..=crate::ETHERIP_MAX_FRAME_SIZE).contains(&self.max_frame_size) {
+      anyhow::bail!("max_frame_size must be between {} and {}", crate::ethernet::ETHERNET_HEADER_SIZE, crate::ETHERIP_MAX_FRAME_SIZE);
+    }
+    if self.group.is_some() && self.user.is_none() {
+      anyhow::bail!("`group` is only used along with `user`");
+    }
+    let mut link_names: Vec<&String> = self.links.keys().collect();
+    link_names.sort();
+    let mut mirrors = HashSet::new();
+    for (relay_name, relay) in &self.relays {
+      if self.links.contains_key(relay_name) {
+        anyhow::bail!("Relay {}: a link has the same name", relay_name);
+      }
+      relay.validate().map_err(|e| anyhow::anyhow!("Relay {}: {}", relay_name, e))?;
+      if let Some(bind_address) = self.bind_address {
+        if bind_address.is_ipv4() != (relay.ip_version == IpVersion::V4) {
+          anyhow::bail!("Relay {}: its IP version differs from the one of bind_address {}", relay_name, bind_address);
+        }
+      }
+    }
+    for link_name in link_names {
+      let link = &self.links[link_name];
+      link.validate().map_err(|e| anyhow::anyhow!("Link {}: {}", link_name, e))?;
+      if let Some(bind_address) = self.bind_address {
+        if link.transport == Transport::Raw && bind_address.is_ipv4() != (link.ip_version == IpVersion::V4) {
+          anyhow::bail!("Link {}: its IP version differs from the one of bind_address {}", link_name, bind_address);
+        }
+      }
+      if let Some(mirror_to) = &link.mirror_to {
+        if self.links.contains_key(mirror_to) || !mirrors.insert(mirror_to) {
+          anyhow::bail!("Link {}: `mirror_to` interface {} is already used by another link", link_name, mirror_to);
+        }
+      }
+      if let crate::packet::Attachment::Raw(ifname) = &link.output {
+        if self.tap_names().contains(ifname) {
+          anyhow::bail!("Link {}: `output` interface {} is a TAP interface of the daemon", link_name, ifname);
+        }
+      }
+      if let crate::packet::Attachment::Raw(ifname) = &link.input {
+        if self.tap_names().contains(ifname) {
+          anyhow::bail!("Link {}: `input` interface {} is a TAP interface of the daemon", link_name, ifname);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Get the outbound scopes of the links with a link-local remote.
+  /// Links whose interface does not exist are returned as errors.
+  pub fn scope_ids(&self) -> Vec<(String, std::io::Result<(Ipv6Addr, u32)>)> {
+    self.links.iter()
+      .filter_map(|(name, link)| link.link_local_remote().map(|addr| (name.clone(), link.scope_id().map(|scope_id| (addr, scope_id)))))
+      .collect()
+  }
+
+  /// Options for opening the TAP interfaces of the links.
+  pub fn tap_options(&self) -> crate::tap::TapOptions {
+    crate::tap::TapOptions {
+      tun_device: self.tun_device.clone(),
+      ..Default::default()
+    }
+  }
+
+  /// Address family of the EtherIP socket for the configured links.
+  pub fn socket_family(&self) -> crate::SocketFamily {
+    let all_v4 = self.links.values().map(|link| link.ip_version).chain(self.relays.values().map(|relay| relay.ip_version)).all(|ip_version| ip_version == IpVersion::V4);
+    if matches!(self.bind_address, Some(IpAddr::V4(_))) || (self.native_ipv4 && all_v4) {
+      crate::SocketFamily::Inet
+    } else {
+      crate::SocketFamily::Inet6
+    }
+  }
+
   /// Get the log level as a `LevelFilter`.
   pub fn level_filter(&self) -> LevelFilter {
     self.log_level.into()
   }
 
+  /// Get the log level overrides of the links that have one.
+  pub fn link_level_filters(&self) -> Vec<(String, LevelFilter)> {
+    self.links.iter()
+      .filter_map(|(name, link)| link.log_level.map(|log_level| (name.clone(), log_level.into())))
+      .collect()
+  }
+
+  /// Get the addresses of all peers, or `None` if some of them are hostnames.
+  pub fn static_peer_addrs(&self) -> Option<Vec<IpAddr>> {
+    self.link_pairs().iter()
+      .map(|(addr, _)| addr.is_static_ip_addr().then(|| addr.try_get_ip_addr()).flatten())
+      .collect()
+  }
+
   /// Get a map of remote IP addresses to link names.
   pub fn link_map(&self) -> AddrStringMap<String> {
+    AddrStringMap::new(self.link_pairs())
+  }
+
+  /// Get the remote addresses of the enabled links carried over the EtherIP socket,
+  /// and of the relays, ordered by name so that maps built from them are deterministic.
+  pub fn link_pairs(&self) -> Vec<(AddrString, String)> {
     let mut pairs = Vec::new();
-    for (name, link) in &self.links {
+    for (name, link) in self.links.iter().filter(|(_, link)| link.enabled && link.transport == Transport::Raw) {
       pairs.push((link.remote_addr(), name.clone()));
+      if let Some(ssm) = &link.ssm {
+        pairs.push((AddrString::new(ssm.source.to_string(), link.ip_version), name.clone()));
+      }
     }
-    AddrStringMap::new(pairs)
+    for (name, relay) in &self.relays {
+      for remote in &relay.remotes {
+        pairs.push((AddrString::new(remote.clone(), relay.ip_version), name.clone()));
+      }
+    }
+    pairs.sort_by(|a, b| a.1.cmp(&b.1));
+    pairs
   }
 }
 
 /// Configuration for a link.
 #[derive(Deserialize, Clone, Debug)]
 pub struct LinkConfig {
-  /// Remote IP address or hostname.
+  /// Remote IP address or hostname, or where to read the address from as `remote_source` says.
   pub remote: String,
 
+  /// How `remote` is turned into an address.
+  #[serde(default)]
+  pub remote_source: RemoteSource,
+
+  /// DNS server `remote` is resolved through instead of the system resolver,
+  /// e.g. when only a split-horizon server knows the remote.
+  #[serde(default)]
+  pub resolver: Option<SocketAddr>,
+
   /// IP version
   pub ip_version: IpVersion,
+
+  /// Forward frames over the link. A disabled link keeps its TAP interface, so that
+  /// it can be disabled for maintenance and enabled again by reloading.
+  #[serde(default = "LinkConfig::default_enabled")]
+  pub enabled: bool,
+
+  /// Underlay interface the remote is reached through. Required when `remote` is
+  /// an IPv6 link-local address, whose outbound scope is set to this interface.
+  #[serde(default)]
+  pub interface: Option<String>,
+
+  /// IP/MAC bindings answered locally on the TAP interface (ARP and ND).
+  #[serde(default)]
+  pub arp_responder: HashMap<IpAddr, MacAddr>,
+
+  /// IP/MAC bindings announced through the tunnel when the link comes up
+  /// (gratuitous ARP for IPv4, unsolicited neighbor advertisements for IPv6),
+  /// so that switches behind the peer learn them without waiting for traffic.
+  #[serde(default)]
+  pub announce_on_up: HashMap<IpAddr, MacAddr>,
+
+  /// MAC addresses of hosts behind the TAP interface mapped to the ones they use through
+  /// the tunnel: outgoing sources are translated, and incoming destinations back.
+  /// Only Ethernet headers are rewritten, not ARP or ND payloads.
+  #[serde(default)]
+  pub mac_rewrite: HashMap<MacAddr, MacAddr>,
+
+  /// Prefixes the IP packets carried by received frames must come from and go to;
+  /// frames violating them are dropped. Unset accepts all frames.
+  #[serde(default)]
+  pub inner_ip_filter: Option<crate::acl::InnerIpFilter>,
+
+  /// Authenticate datagrams with a shared secret. Not RFC 3378 compliant:
+  /// both ends must enable it with the same key.
+  #[serde(default)]
+  pub auth: Option<AuthConfig>,
+
+  /// Handling of received datagrams that fail validation. Defaults to the global `on_invalid`.
+  #[serde(default)]
+  pub on_invalid: Option<OnInvalid>,
+
+  /// CPUs the link's TAP reader and sender run on. The link then gets a dedicated thread
+  /// (with its own current-thread runtime) pinned to them instead of sharing the
+  /// daemon's worker threads. Datagrams received on the shared EtherIP socket are still
+  /// dispatched by its receiver. Ignored for raw links with `shared_tap_reader`.
+  #[serde(default)]
+  pub cpu_affinity: Option<Vec<usize>>,
+
+  /// Source-specific multicast subscription. When set, `remote` is usually the group
+  /// and datagrams from `ssm.source` are delivered to this link.
+  #[serde(default)]
+  pub ssm: Option<SsmConfig>,
+
+  /// Prioritized egress queue. When unset, frames are sent as soon as they are read.
+  #[serde(default)]
+  pub egress_queue: Option<EgressQueueConfig>,
+
+  /// Rate limit of the broadcast and multicast frames sent to the remote, so that a storm
+  /// on the local segment does not flood the tunnel. Unicast frames are never limited.
+  /// When unset, there is no limit.
+  #[serde(default)]
+  pub broadcast_limit: Option<BroadcastLimitConfig>,
+
+  /// Suspend sends to the remote for a while after consecutive send failures, dropping
+  /// the frames meanwhile, so that a peer whose path is down does not cause a storm of
+  /// failing sends. When unset, every frame is sent.
+  #[serde(default)]
+  pub send_backoff: Option<SendBackoffConfig>,
+
+  /// Carry a 16-bit sequence number between the EtherIP header and the frame
+  /// to detect loss and reordering. Not RFC 3378 compliant; both ends must enable it.
+  #[serde(default)]
+  pub seqno: bool,
+
+  /// Exchange MTU advertisements with the peer and set the TAP MTU to the smaller
+  /// of the two `max_mtu` values. Both ends must enable it, as it adds a shim
+  /// telling advertisements from frames to every datagram.
+  #[serde(default)]
+  pub mtu_negotiate: bool,
+
+  /// MTU advertised to the peer when `mtu_negotiate` is enabled.
+  #[serde(default = "LinkConfig::default_max_mtu")]
+  pub max_mtu: u16,
+
+  /// Seconds between reads of the path MTU to the remote, which is logged at DEBUG
+  /// when it changes and exported as a metric. 0 disables it, unless `outer_mtu` is set.
+  #[serde(default)]
+  pub path_mtu_interval: u64,
+
+  /// MTU the path to the remote is expected to have. The TAP MTU is set to it minus the
+  /// tunnel overhead when the interface is created, and the path MTU is read every
+  /// `path_mtu_interval` seconds (every minute if 0), warning once if it is smaller.
+  #[serde(default)]
+  pub outer_mtu: Option<u32>,
+
+  /// Send datagrams with the traffic class (DSCP and ECN) last received from the peer.
+  /// Not applied to datagrams sent through the egress queue.
+  #[serde(default)]
+  pub tclass_echo: bool,
+
+  /// Transmit queue length of the TAP interface, applied when it is created.
+  /// The kernel default is 1000; high-throughput tunnels may need 5000 or more.
+  #[serde(default)]
+  pub txqueuelen: Option<u32>,
+
+  /// Whether datagrams with nonzero reserved header bits are dropped (`Strict`, the default)
+  /// or accepted (`Lenient`) for interoperability with older senders. They are counted either way.
+  #[serde(default)]
+  pub parser_mode: crate::ParserMode,
+
+  /// Network namespace (as named by `ip netns`) to create the TAP interface in, which needs
+  /// CAP_SYS_ADMIN. The EtherIP socket stays in the daemon's namespace. Only read when the
+  /// TAP interface is created.
+  #[serde(default)]
+  pub netns: Option<String>,
+
+  /// Carrier of the EtherIP datagrams: IP protocol 97 (`"raw"`, the default),
+  /// TCP (`"tcp"`) for networks that block it, or UDP (`"udp"`) to traverse NAT.
+  /// Both ends must use the same transport.
+  #[serde(default)]
+  pub transport: Transport,
+
+  /// Settings of the TCP transport.
+  #[serde(default)]
+  pub tcp: TcpConfig,
+
+  /// Settings of the UDP transport.
+  #[serde(default)]
+  pub udp: UdpConfig,
+
+  /// Compress the tunneled frames (`"lz4"`). Not RFC 3378 compliant;
+  /// both ends must run this daemon with the same setting.
+  #[serde(default)]
+  pub compression: crate::compress::Compression,
+
+  /// Log level of messages about this link, overriding the global one.
+  #[serde(default)]
+  pub log_level: Option<LogLevel>,
+
+  /// Record a histogram of the sizes of forwarded frames, exported by the metrics endpoint.
+  #[serde(default)]
+  pub frame_size_histogram: bool,
+
+  /// Number of inner flows (IP 5-tuples) tracked for `GET /flows` on the metrics endpoint,
+  /// the least recently seen being evicted. 0, the default, spares the per-frame parsing.
+  #[serde(default)]
+  pub flow_table_size: usize,
+
+  /// Number of MAC addresses learned from received frames for `GET /fdb` on the metrics
+  /// endpoint, the least recently seen being evicted. 0, the default, disables learning.
+  #[serde(default)]
+  pub fdb_size: usize,
+
+  /// TAP interface, created in the link's namespace, that receives a copy of every frame
+  /// written to the link's TAP interface, for monitoring. Each copy costs another write
+  /// system call on the receive path, which raw links share with each other.
+  #[serde(default)]
+  pub mirror_to: Option<String>,
+
+  /// Where received frames are written: `"tap"`, the default, or `"raw:IFNAME"` to put
+  /// them directly on an existing interface of the link's namespace through an AF_PACKET
+  /// socket, bridging without a software bridge. The TAP interface is still created and
+  /// read from; `mirror_to` still receives a copy.
+  #[serde(default)]
+  pub output: crate::packet::Attachment,
+
+  /// Where frames sent to the remote are read from: `"tap"`, the default, or `"raw:IFNAME"`
+  /// to capture every frame arriving on an existing interface of the link's namespace,
+  /// which is put in promiscuous mode, through an AF_PACKET socket. Frames the host sends
+  /// on the interface are not captured, so with the same interface as `output` the link
+  /// extends that segment to the remote without looping its own frames; a second path
+  /// between the segments still loops broadcasts, as no spanning tree is run. Receive
+  /// offloads such as GRO merge frames beyond the MTU, which are dropped; turn them off.
+  #[serde(default)]
+  pub input: crate::packet::Attachment,
+
+  /// Classic BPF program selecting the frames captured by a `raw:` input, in the format
+  /// of `tcpdump -ddd` with lines joined by commas, e.g. from `tcpdump -ddd not stp | tr '\n' ','`.
+  #[serde(default)]
+  pub input_filter: Option<String>,
+
+  /// Address of `remote` resolved at startup, which `remote_addr()` starts from.
+  #[serde(skip)]
+  pub resolved_remote: Option<IpAddr>,
+}
+
+/// Where the address of a remote comes from. Dynamic sources are read again every
+/// `REMOTE_REFRESH_INTERVAL`, and retried with exponential backoff when they fail.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteSource {
+  /// `remote` is an IP address.
+  Static,
+  /// `remote` is an IP address or a hostname to resolve.
+  #[default]
+  Dns,
+  /// `remote` is the path of a file holding the address, e.g. written by a discovery service.
+  File,
+  /// `remote` is a shell command printing the address.
+  Command,
+}
+
+/// Carrier of the EtherIP datagrams of a link.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+  #[default]
+  Raw,
+  Tcp,
+  Udp,
+}
+
+/// TCP transport of a link. One end connects to the other, which listens.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TcpConfig {
+  /// Port to connect to, or to listen on.
+  #[serde(default = "TcpConfig::default_port")]
+  pub port: u16,
+
+  /// Accept the connection from the remote instead of connecting to it.
+  #[serde(default)]
+  pub listen: bool,
+}
+
+impl TcpConfig {
+  fn default_port() -> u16 {
+    crate::tcp::DEFAULT_TCP_PORT
+  }
+
+  pub fn role(&self) -> crate::tcp::TcpRole {
+    if self.listen {
+      crate::tcp::TcpRole::Listen
+    } else {
+      crate::tcp::TcpRole::Connect
+    }
+  }
+}
+
+impl Default for TcpConfig {
+  fn default() -> Self {
+    Self {
+      port: Self::default_port(),
+      listen: false,
+    }
+  }
+}
+
+/// UDP transport of a link.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UdpConfig {
+  /// Port of the remote, used until the remote is heard from.
+  #[serde(default = "UdpConfig::default_port")]
+  pub port: u16,
+
+  /// Local port to bind. Defaults to `port`; 0 picks an ephemeral one, which suits an end behind NAT.
+  #[serde(default)]
+  pub local_port: Option<u16>,
+
+  /// Seconds between keepalives holding NAT bindings open.
+  #[serde(default = "UdpConfig::default_keepalive_interval")]
+  pub keepalive_interval: u64,
+}
+
+impl UdpConfig {
+  fn default_port() -> u16 {
+    crate::udp::DEFAULT_UDP_PORT
+  }
+
+  fn default_keepalive_interval() -> u64 {
+    crate::udp::DEFAULT_KEEPALIVE_INTERVAL.as_secs()
+  }
+
+  pub fn local_port(&self) -> u16 {
+    self.local_port.unwrap_or(self.port)
+  }
+
+  pub fn keepalive_interval(&self) -> std::time::Duration {
+    std::time::Duration::from_secs(self.keepalive_interval.max(1))
+  }
+}
+
+impl Default for UdpConfig {
+  fn default() -> Self {
+    Self {
+      port: Self::default_port(),
+      local_port: None,
+      keepalive_interval: Self::default_keepalive_interval(),
+    }
+  }
+}
+
+/// Egress queue of a link, which sends control frames before data frames.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EgressQueueConfig {
+  /// Maximum number of queued frames per priority.
+  #[serde(default = "EgressQueueConfig::default_capacity")]
+  pub capacity: usize,
+
+  /// Maximum number of frames sent with a single system call.
+  #[serde(default = "EgressQueueConfig::default_batch_size")]
+  pub batch_size: usize,
+}
+
+impl EgressQueueConfig {
+  fn default_capacity() -> usize {
+    1024
+  }
+
+  fn default_batch_size() -> usize {
+    32
+  }
+}
+
+/// Token bucket limiting the broadcast and multicast frames of a link.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BroadcastLimitConfig {
+  /// Frames sent per second on average.
+  pub frames_per_second: u32,
+
+  /// Frames that can be sent at once after a quiet period. Defaults to `frames_per_second`.
+  #[serde(default)]
+  pub burst: Option<u32>,
+}
+
+impl BroadcastLimitConfig {
+  pub fn burst(&self) -> u32 {
+    self.burst.unwrap_or(self.frames_per_second)
+  }
+}
+
+/// Backoff of the sends of a link after consecutive send failures.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SendBackoffConfig {
+  /// Consecutive failed sends after which sends are suspended. With an egress queue,
+  /// each batch counts as one send.
+  #[serde(default = "SendBackoffConfig::default_failures")]
+  pub failures: u32,
+
+  /// Milliseconds sends are first suspended for. Each failed probe doubles it.
+  #[serde(default = "SendBackoffConfig::default_initial_delay_ms")]
+  pub initial_delay_ms: u64,
+
+  /// Longest suspension, in milliseconds.
+  #[serde(default = "SendBackoffConfig::default_max_delay_ms")]
+  pub max_delay_ms: u64,
+}
+
+impl SendBackoffConfig {
+  pub fn default_failures() -> u32 {
+    8
+  }
+
+  pub fn default_initial_delay_ms() -> u64 {
+    100
+  }
+
+  pub fn default_max_delay_ms() -> u64 {
+    10_000
+  }
+
+  pub fn backoff(&self) -> crate::backoff::SendBackoff {
+    crate::backoff::SendBackoff::new(self.failures, std::time::Duration::from_millis(self.initial_delay_ms), std::time::Duration::from_millis(self.max_delay_ms))
+  }
+}
+
+/// Relay between two remotes: datagrams received from either one are sent to the other
+/// as they are, without being decapsulated. Authentication, sequence numbers and
+/// compression are left to the two ends.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RelayConfig {
+  /// The two remotes, as IP addresses or hostnames.
+  pub remotes: [String; 2],
+
+  pub ip_version: IpVersion,
+
+  /// Whether datagrams with nonzero reserved header bits are dropped (`Strict`, the default)
+  /// or relayed (`Lenient`).
+  #[serde(default)]
+  pub parser_mode: crate::ParserMode,
+
+  /// Whether the reserved header bits of relayed datagrams are cleared (`Clear`, the
+  /// default) or kept (`Preserve`). Only matters with `parser_mode = "Lenient"`.
+  #[serde(default)]
+  pub reserved_bits: crate::ReservedBits,
+
+  /// Largest Ethernet frame relayed. Longer ones are dropped, so that a relay in front of
+  /// a path with a smaller MTU does not send datagrams it would fragment or refuse.
+  #[serde(default = "RelayConfig::default_max_frame_size")]
+  pub max_frame_size: usize,
+}
+
+impl RelayConfig {
+  fn default_max_frame_size() -> usize {
+    crate::ETHERIP_MAX_FRAME_SIZE
+  }
+
+  /// Check the consistency of the relay configuration.
+  pub fn validate(&self) -> Result<(), anyhow::Error> {
+    if self.remotes[0] == self.remotes[1] {
+      anyhow::bail!("both remotes are {}", self.remotes[0]);
+    }
+    for remote in &self.remotes {
+      match (remote.parse::<IpAddr>(), self.ip_version) {
+        (Ok(addr @ IpAddr::V6(_)), IpVersion::V4) => anyhow::bail!("remote {} is an IPv6 address, but `ip_version` is V4", addr),
+        (Ok(addr @ IpAddr::V4(_)), IpVersion::V6) => anyhow::bail!("remote {} is an IPv4 address, but `ip_version` is V6", addr),
+        _ => {},
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Handling of received datagrams that fail validation (bad header, truncated frame, missing shim).
+/// They are always dropped; the policy only sets how they are reported.
+/// Authentication and decompression failures are counted by their own counters regardless.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnInvalid {
+  /// Drop silently.
+  Drop,
+  /// Count in `invalid_datagrams` and log at debug level.
+  #[default]
+  Count,
+  /// Count and log each one at info level with details. Formatting a log line per
+  /// datagram is costly if a peer or an attacker floods the link with invalid ones.
+  Log,
+}
+
+/// Reverse path check of received datagrams, like the `rp_filter` sysctl but applied to
+/// the sources of the links only. The route back to a source is looked up in the main
+/// routing table and cached for a while, so policy routing and route changes are not
+/// seen at once. Multipoint links with asymmetric paths (a multicast group whose members
+/// are reached through another interface than the one they send from) need `loose` or `off`.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Rpf {
+  /// No check.
+  #[default]
+  Off,
+  /// The source must be reachable through some interface.
+  Loose,
+  /// The route back to the source must go out of the interface the datagram arrived on.
+  Strict,
+}
+
+/// Datagram authentication of a link. Exactly one of `key`, `key_file` and `key_env` is set.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthConfig {
+  /// Shared secret as hexadecimal digits, at least 16 bytes long.
+  #[serde(default)]
+  pub key: Option<String>,
+
+  /// File holding the key as hexadecimal digits, read when the configuration is loaded
+  /// or reloaded. It must stay readable by the user the daemon runs as, and should not
+  /// be readable by others.
+  #[serde(default)]
+  pub key_file: Option<PathBuf>,
+
+  /// Environment variable holding the key as hexadecimal digits, read when the
+  /// configuration is loaded or reloaded.
+  #[serde(default)]
+  pub key_env: Option<String>,
+
+  /// Authenticator with the key, built by `load_key`.
+  #[serde(skip)]
+  authenticator: Option<crate::auth::Authenticator>,
+}
+
+impl AuthConfig {
+  /// Read the key from `key_file` or `key_env` into `key`, warning if the file is world-readable,
+  /// and build the authenticator with it.
+  pub fn load_key(&mut self) -> Result<(), anyhow::Error> {
+    match (&self.key, &self.key_file, &self.key_env) {
+      (Some(_), None, None) => {},
+      (None, Some(key_file), None) => {
+        let cannot_read = |e: std::io::Error| anyhow::anyhow!("cannot read `key_file` {}: {}", key_file.display(), e);
+        // The permissions are those of the file the key is read from, even if it is replaced meanwhile.
+        let mut file = std::fs::File::open(key_file).map_err(cannot_read)?;
+        let mode = std::os::unix::fs::PermissionsExt::mode(&file.metadata().map_err(cannot_read)?.permissions());
+        let mut key = String::new();
+        std::io::Read::read_to_string(&mut file, &mut key).map_err(cannot_read)?;
+        if mode & 0o004 != 0 {
+          log::warn!("Authentication key file {} is world-readable", key_file.display());
+        }
+        self.key = Some(key);
+      },
+      (None, None, Some(key_env)) => {
+        let key = std::env::var(key_env).map_err(|e| anyhow::anyhow!("cannot read `key_env` {}: {}", key_env, e))?;
+        self.key = Some(key);
+      },
+      _ => anyhow::bail!("exactly one of `key`, `key_file` and `key_env` must be set"),
+    }
+    let key = self.key.as_deref().unwrap_or_default();
+    self.authenticator = Some(crate::auth::Authenticator::new(&crate::auth::parse_hex_key(key)?)?);
+    Ok(())
+  }
+
+  /// Authenticator with the key, if `load_key` has succeeded. It always has in a validated configuration.
+  pub fn authenticator(&self) -> Option<&crate::auth::Authenticator> {
+    self.authenticator.as_ref()
+  }
+}
+
+/// Source-specific multicast subscription of a link.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SsmConfig {
+  /// Multicast group address.
+  pub group: IpAddr,
+
+  /// Source address to receive from.
+  pub source: IpAddr,
+
+  /// Interface to join the group on. The kernel chooses one if unset.
+  #[serde(default)]
+  pub interface: Option<String>,
+}
+
+impl SsmConfig {
+  /// Get the interface index to join on (0 if unset).
+  pub fn ifindex(&self) -> std::io::Result<u32> {
+    match &self.interface {
+      Some(ifname) => crate::interface_index(ifname),
+      None => Ok(0),
+    }
+  }
 }
 
 impl LinkConfig {
+  fn default_enabled() -> bool {
+    true
+  }
+
+  fn default_max_mtu() -> u16 {
+    1500
+  }
+
+  /// Seconds between reads of the path MTU, if it is monitored.
+  pub fn path_mtu_interval(&self) -> Option<u64> {
+    match (self.path_mtu_interval, self.outer_mtu) {
+      (0, None) => None,
+      (0, Some(_)) => Some(DEFAULT_OUTER_MTU_CHECK_INTERVAL),
+      (interval, _) => Some(interval),
+    }
+  }
+
+  /// Total size of the shims inserted before the frames.
+  pub fn shim_size(&self) -> usize {
+    let mut shim_size = 0;
+    if self.seqno {
+      shim_size += crate::seqno::SEQNO_SHIM_SIZE;
+    }
+    if self.mtu_negotiate {
+      shim_size += crate::mtu::MTU_SHIM_SIZE;
+    }
+    if self.compression != crate::compress::Compression::None {
+      shim_size += crate::compress::COMPRESSION_SHIM_SIZE;
+    }
+    shim_size
+  }
+
+  /// Offset of the MTU negotiation shim, if `mtu_negotiate` is enabled.
+  pub fn mtu_shim_offset(&self) -> Option<usize> {
+    match (self.mtu_negotiate, self.seqno) {
+      (false, _) => None,
+      (true, true) => Some(crate::seqno::SEQNO_SHIM_SIZE),
+      (true, false) => Some(0),
+    }
+  }
+
+  /// Size of the trailer appended after the frames.
+  pub fn trailer_size(&self) -> usize {
+    if self.auth.is_some() {
+      crate::auth::AUTH_TAG_SIZE
+    } else {
+      0
+    }
+  }
+
+  /// Bytes a datagram takes on the path beyond the IP packet it tunnels: the outer IP
+  /// and transport headers, the EtherIP header, shims, the Ethernet header and the trailer.
+  /// VLAN tags and IPv4 options are not counted.
+  pub fn tunnel_overhead(&self) -> usize {
+    let ip_header_size = match self.ip_version {
+      IpVersion::V4 => IPV4_HEADER_SIZE,
+      IpVersion::V6 => IPV6_HEADER_SIZE,
+    };
+    let transport_header_size = match self.transport {
+      Transport::Raw => 0,
+      Transport::Udp => UDP_HEADER_SIZE,
+      Transport::Tcp => TCP_HEADER_SIZE + crate::tcp::LENGTH_PREFIX_SIZE,
+    };
+    ip_header_size + transport_header_size + crate::ETHERIP_HEADER_SIZE + self.shim_size()
+      + crate::ethernet::ETHERNET_HEADER_SIZE + self.trailer_size()
+  }
+
+  /// TAP MTU that fits `outer_mtu`, if it is set.
+  pub fn inner_mtu(&self) -> Option<u32> {
+    self.outer_mtu.map(|outer_mtu| outer_mtu.saturating_sub(self.tunnel_overhead() as u32))
+  }
+
   pub fn remote_addr(&self) -> AddrString {
-    AddrString::new(self.remote.clone(), self.ip_version)
+    let mut addr = AddrString::with_source(self.remote.clone(), self.ip_version, self.remote_source)
+      .with_resolver(self.resolver);
+    if let Some(ip_addr) = self.resolved_remote {
+      addr.set_resolved(ip_addr);
+    }
+    addr
+  }
+
+  /// The remote address if it is a static IPv6 link-local address.
+  pub fn link_local_remote(&self) -> Option<Ipv6Addr> {
+    match self.remote.parse() {
+      Ok(IpAddr::V6(addr)) if addr.is_unicast_link_local() => Some(addr),
+      _ => None,
+    }
+  }
+
+  /// Get the index of the underlay interface, used as the scope of a link-local remote.
+  pub fn scope_id(&self) -> std::io::Result<u32> {
+    match &self.interface {
+      Some(ifname) => crate::interface_index(ifname),
+      None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no interface is configured")),
+    }
+  }
+
+  /// Whether `remote` is a multicast group address.
+  pub fn is_multicast(&self) -> bool {
+    self.remote.parse::<IpAddr>().is_ok_and(|addr| addr.is_multicast())
+  }
+
+  /// Check the consistency of the link configuration.
+  pub fn validate(&self) -> Result<(), anyhow::Error> {
+    if self.remote_source == RemoteSource::Static && self.remote.parse::<IpAddr>().is_err() {
+      anyhow::bail!("remote {} is not an IP address, as `remote_source = \"static\"` requires", self.remote);
+    }
+    // A literal address is used as it is, so its family must match.
+    if matches!(self.remote_source, RemoteSource::Static | RemoteSource::Dns) {
+      match (self.remote.parse::<IpAddr>(), self.ip_version) {
+        (Ok(addr @ IpAddr::V6(_)), IpVersion::V4) => anyhow::bail!("remote {} is an IPv6 address, but `ip_version` is V4", addr),
+        (Ok(addr @ IpAddr::V4(_)), IpVersion::V6) => anyhow::bail!("remote {} is an IPv4 address, but `ip_version` is V6", addr),
+        _ => {},
+      }
+    }
+    if self.resolver.is_some() && self.remote_source != RemoteSource::Dns {
+      anyhow::bail!("resolver requires `remote_source = \"dns\"`");
+    }
+    if self.remote.trim().is_empty() {
+      anyhow::bail!("remote is empty");
+    }
+    if let Some(addr) = self.link_local_remote() {
+      if self.interface.is_none() {
+        anyhow::bail!("remote {} is link-local, so `interface` must be set to the interface it is reached through", addr);
+      }
+    }
+    if self.auth.as_ref().is_some_and(|auth| auth.authenticator().is_none()) {
+      anyhow::bail!("invalid `auth`: the authentication key has not been loaded");
+    }
+    if let Some(cpus) = &self.cpu_affinity {
+      crate::affinity::validate(cpus).map_err(|e| anyhow::anyhow!("invalid `cpu_affinity`: {}", e))?;
+    }
+    if let Some(inner_mtu) = self.inner_mtu() {
+      if inner_mtu < MIN_INNER_MTU {
+        anyhow::bail!("`outer_mtu` leaves an MTU of {} after the {} bytes of tunnel overhead, below the minimum of {}", inner_mtu, self.tunnel_overhead(), MIN_INNER_MTU);
+      }
+    }
+    let mut tunnel_macs = HashSet::new();
+    for (local, tunnel) in &self.mac_rewrite {
+      if local.is_multicast() || tunnel.is_multicast() {
+        anyhow::bail!("`mac_rewrite` can only translate unicast addresses");
+      }
+      if !tunnel_macs.insert(tunnel) {
+        anyhow::bail!("`mac_rewrite` maps several addresses to {}", tunnel);
+      }
+    }
+    if let Some(broadcast_limit) = &self.broadcast_limit {
+      if broadcast_limit.frames_per_second == 0 || broadcast_limit.burst() == 0 {
+        anyhow::bail!("`broadcast_limit` must allow at least one frame per second and a burst of one frame");
+      }
+    }
+    if let Some(send_backoff) = &self.send_backoff {
+      if send_backoff.failures == 0 || send_backoff.initial_delay_ms == 0 {
+        anyhow::bail!("`send_backoff` needs at least one failure and a nonzero `initial_delay_ms`");
+      }
+      if send_backoff.max_delay_ms < send_backoff.initial_delay_ms {
+        anyhow::bail!("`send_backoff.max_delay_ms` must not be smaller than `initial_delay_ms`");
+      }
+    }
+    if let Some(input_filter) = &self.input_filter {
+      if self.input == crate::packet::Attachment::Tap {
+        anyhow::bail!("`input_filter` requires a `raw:` input");
+      }
+      crate::bpf::parse_bytecode(input_filter).map_err(|e| anyhow::anyhow!("invalid `input_filter`: {}", e))?;
+    }
+    Ok(())
   }
 }
 
 /// IP version.
-#[derive(Deserialize, Clone, Copy, Debug)]
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum IpVersion {
   V4,
   V6,
 }
 
 /// Log level.
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Debug, Default)]
 pub enum LogLevel {
   Off,
   Error,
+  #[default]
   Warn,
   Info,
   Debug,
   Trace,
 }
 
-impl Default for LogLevel {
-  fn default() -> Self {
-    LogLevel::Warn
-  }
-}
-
 impl From<LogLevel> for LevelFilter {
   fn from(value: LogLevel) -> Self {
     match value {
@@ -117,11 +1099,34 @@ impl From<LevelFilter> for LogLevel {
   }
 }
 
+/// Permits of the hostname lookups in flight, replaced when the limit changes.
+static RESOLUTION_PERMITS: parking_lot::RwLock<Option<(usize, Arc<tokio::sync::Semaphore>)>> = parking_lot::RwLock::new(None);
+
+/// Set the largest number of concurrent hostname lookups (at least 1).
+/// Lookups already in flight keep the permits they were given under the previous limit.
+pub fn set_max_concurrent_resolutions(limit: usize) {
+  let limit = limit.max(1);
+  let mut permits = RESOLUTION_PERMITS.write();
+  if permits.as_ref().is_none_or(|(current, _)| *current != limit) {
+    *permits = Some((limit, Arc::new(tokio::sync::Semaphore::new(limit))));
+  }
+}
+
+fn resolution_permits() -> Arc<tokio::sync::Semaphore> {
+  if let Some((_, semaphore)) = RESOLUTION_PERMITS.read().as_ref() {
+    return semaphore.clone();
+  }
+  set_max_concurrent_resolutions(DEFAULT_MAX_CONCURRENT_RESOLUTIONS);
+  resolution_permits()
+}
+
 pub async fn lookup_addr(addr: &str, ip_version: IpVersion) -> std::io::Result<std::net::IpAddr> {
   if let Ok(ip) = addr.parse() {
     return Ok(ip);
   }
 
+  // Never closed, so acquiring cannot fail.
+  let _permit = resolution_permits().acquire_owned().await.map_err(std::io::Error::other)?;
   let addrs = tokio::net::lookup_host(format!("{}:0", addr)).await?;
   for addr in addrs {
     match ip_version {
@@ -140,13 +1145,86 @@ pub async fn lookup_addr(addr: &str, ip_version: IpVersion) -> std::io::Result<s
   Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address found"))
 }
 
+/// Like `lookup_addr`, but asks the DNS server at `resolver` instead of the system resolver.
+pub async fn lookup_addr_via(addr: &str, ip_version: IpVersion, resolver: SocketAddr) -> std::io::Result<std::net::IpAddr> {
+  use hickory_resolver::config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts};
+
+  if let Ok(ip) = addr.parse() {
+    return Ok(ip);
+  }
+
+  let _permit = resolution_permits().acquire_owned().await.map_err(std::io::Error::other)?;
+  let name_servers = NameServerConfigGroup::from_ips_clear(&[resolver.ip()], resolver.port(), true);
+  let mut opts = ResolverOpts::default();
+  opts.ip_strategy = match ip_version {
+    IpVersion::V4 => LookupIpStrategy::Ipv4Only,
+    IpVersion::V6 => LookupIpStrategy::Ipv6Only,
+  };
+  // The system configuration (search domains, hosts file) is not consulted.
+  opts.use_hosts_file = false;
+  let resolver = hickory_resolver::TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], name_servers), opts);
+  let lookup = resolver.lookup_ip(addr).await.map_err(std::io::Error::other)?;
+  lookup.iter().next()
+    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address found"))
+}
+
+/// Interval between reads of the address of a remote that is not an IP address.
+pub const REMOTE_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Incremented by `refresh_remotes_now` to make every remote re-read its address.
+static REMOTE_REFRESH_GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Make every remote that is not a static IP address re-read its address on its next
+/// update, regardless of `REMOTE_REFRESH_INTERVAL` and of the backoff after failures.
+pub fn refresh_remotes_now() {
+  REMOTE_REFRESH_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Longest time a `remote_source = "command"` command may run.
+pub const REMOTE_COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Read the address of a remote from its source.
+pub async fn lookup_remote(addr: &str, ip_version: IpVersion, source: RemoteSource, resolver: Option<SocketAddr>) -> std::io::Result<std::net::IpAddr> {
+  match (source, resolver) {
+    (RemoteSource::Dns, Some(resolver)) => lookup_addr_via(addr, ip_version, resolver).await,
+    (RemoteSource::Static | RemoteSource::Dns, _) => lookup_addr(addr, ip_version).await,
+    (RemoteSource::File, _) => parse_remote_addr(&tokio::fs::read_to_string(addr).await?, ip_version),
+    (RemoteSource::Command, _) => {
+      let output = tokio::process::Command::new("/bin/sh").arg("-c").arg(addr).kill_on_drop(true).output();
+      let output = tokio::time::timeout(REMOTE_COMMAND_TIMEOUT, output).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "remote command timed out"))??;
+      if !output.status.success() {
+        return Err(std::io::Error::other(format!("remote command failed with {}", output.status)));
+      }
+      parse_remote_addr(&String::from_utf8_lossy(&output.stdout), ip_version)
+    },
+  }
+}
+
+/// Parse an address read from a file or printed by a command, which must be of `ip_version`.
+fn parse_remote_addr(output: &str, ip_version: IpVersion) -> std::io::Result<std::net::IpAddr> {
+  let output = output.trim();
+  let ip_addr: std::net::IpAddr = output.parse()
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("not an IP address: {:?}", output)))?;
+  match (ip_version, ip_addr) {
+    (IpVersion::V4, std::net::IpAddr::V4(_)) | (IpVersion::V6, std::net::IpAddr::V6(_)) => Ok(ip_addr),
+    _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not an {:?} address", ip_addr, ip_version))),
+  }
+}
+
 pub struct AddrString {
-  /// IP address or hostname.
+  /// IP address or hostname, or where to read the address from.
   addr_string: String,
 
   /// IP version
   ip_version: IpVersion,
 
+  /// How `addr_string` is turned into an address.
+  source: RemoteSource,
+
+  /// DNS server hostnames are resolved through, instead of the system resolver.
+  resolver: Option<SocketAddr>,
+
   /// true if `addr_string` is an IP address.
   is_static_ip_addr: bool,
 
@@ -155,12 +1233,35 @@ pub struct AddrString {
 
   /// Time of the previous update.
   previous_update: Option<std::time::Instant>,
+
+  /// Updates failed in a row, which delay the next attempt exponentially.
+  failures: u32,
+
+  /// Time before which a failed update is not retried.
+  retry_at: Option<std::time::Instant>,
+
+  /// Value of `REMOTE_REFRESH_GENERATION` at the previous update.
+  refresh_generation: u64,
 }
 
 impl AddrString {
   pub fn new(addr_string: String, ip_version: IpVersion) -> Self {
-    let ip_addr = addr_string.parse().ok();
-    AddrString { addr_string, ip_version, is_static_ip_addr: ip_addr.is_some(), ip_addr, previous_update: None }
+    Self::with_source(addr_string, ip_version, RemoteSource::Dns)
+  }
+
+  pub fn with_source(addr_string: String, ip_version: IpVersion, source: RemoteSource) -> Self {
+    let ip_addr = match source {
+      RemoteSource::Static | RemoteSource::Dns => addr_string.parse().ok(),
+      RemoteSource::File | RemoteSource::Command => None,
+    };
+    let refresh_generation = REMOTE_REFRESH_GENERATION.load(std::sync::atomic::Ordering::Relaxed);
+    AddrString { addr_string, ip_version, source, resolver: None, is_static_ip_addr: ip_addr.is_some(), ip_addr, previous_update: None, failures: 0, retry_at: None, refresh_generation }
+  }
+
+  /// Resolve hostnames through the DNS server at `resolver`, if it is set.
+  pub fn with_resolver(mut self, resolver: Option<SocketAddr>) -> Self {
+    self.resolver = resolver;
+    self
   }
 
   pub fn try_get_ip_addr(&self) -> Option<std::net::IpAddr> {
@@ -172,18 +1273,51 @@ impl AddrString {
       return Ok(());
     }
 
-    if self.ip_addr.is_some() && self.previous_update.map_or(false, |t| t.elapsed().as_secs() < 60) {
+    let refresh_generation = REMOTE_REFRESH_GENERATION.load(std::sync::atomic::Ordering::Relaxed);
+    let refresh_requested = refresh_generation != self.refresh_generation;
+    if !refresh_requested && self.ip_addr.is_some() && self.previous_update.is_some_and(|t| t.elapsed() < REMOTE_REFRESH_INTERVAL) {
+      return Ok(());
+    }
+    // Backing off after a failure; the previous address, if any, is kept meanwhile.
+    if !refresh_requested && self.retry_at.is_some_and(|t| t > std::time::Instant::now()) {
       return Ok(());
     }
+    self.refresh_generation = refresh_generation;
 
-    self.ip_addr = Some(lookup_addr(&self.addr_string, self.ip_version).await?);
-    self.previous_update = Some(std::time::Instant::now());
-    Ok(())
+    match lookup_remote(&self.addr_string, self.ip_version, self.source, self.resolver).await {
+      Ok(ip_addr) => {
+        self.ip_addr = Some(ip_addr);
+        self.previous_update = Some(std::time::Instant::now());
+        self.failures = 0;
+        self.retry_at = None;
+        Ok(())
+      },
+      Err(e) => {
+        let delay = std::time::Duration::from_secs(1 << self.failures.min(6)).min(REMOTE_REFRESH_INTERVAL);
+        self.failures = self.failures.saturating_add(1);
+        self.retry_at = Some(std::time::Instant::now() + delay);
+        Err(e)
+      },
+    }
   }
 
   pub fn is_static_ip_addr(&self) -> bool {
     self.is_static_ip_addr
   }
+
+  /// Use an address resolved elsewhere, as if `update_ip_addr` had just resolved it.
+  pub fn set_resolved(&mut self, ip_addr: std::net::IpAddr) {
+    if !self.is_static_ip_addr {
+      self.ip_addr = Some(ip_addr);
+      self.previous_update = Some(std::time::Instant::now());
+    }
+  }
+
+  /// true if both refer to the same remote, regardless of resolution state.
+  pub fn same_remote(&self, other: &AddrString) -> bool {
+    self.addr_string == other.addr_string && self.ip_version == other.ip_version && self.source == other.source
+      && self.resolver == other.resolver
+  }
 }
 
 impl Default for AddrString {
@@ -191,9 +1325,14 @@ impl Default for AddrString {
     AddrString {
       addr_string: "0.0.0.0".to_string(),
       ip_version: IpVersion::V4,
+      source: RemoteSource::Static,
+      resolver: None,
       is_static_ip_addr: true,
       ip_addr: Some(std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))),
       previous_update: None,
+      failures: 0,
+      retry_at: None,
+      refresh_generation: 0,
     }
   }
 }
@@ -207,26 +1346,89 @@ impl core::hash::Hash for AddrString {
 pub struct AddrStringMap<T> {
   values: Vec<T>,
   addrs: Vec<AddrString>,
-  addr_map: HashMap<std::net::IpAddr, usize>,
+  addr_map: HashMap<IpAddr, usize>,
+  /// Addresses claimed by different values, as (address, value that lost it, value it maps to).
+  collisions: Vec<(IpAddr, usize, usize)>,
+  /// Colliding addresses not yet returned by `take_new_collisions`.
+  unreported_collisions: HashSet<IpAddr>,
 }
 
-impl<T> AddrStringMap<T> {
+impl<T: PartialEq> AddrStringMap<T> {
   pub fn new(mut pairs: Vec<(AddrString, T)>) -> Self {
-    let mut values = Vec::new();
-    let mut addrs = Vec::new();
-    let mut addr_map = HashMap::new();
+    let mut map = AddrStringMap {
+      values: Vec::new(),
+      addrs: Vec::new(),
+      addr_map: HashMap::new(),
+      collisions: Vec::new(),
+      unreported_collisions: HashSet::new(),
+    };
     for (addr, value) in pairs.drain(..) {
-      let i = addrs.len();
-      addrs.push(addr);
-      if let Some(ip_addr) = addrs[i].try_get_ip_addr() {
-        addr_map.insert(ip_addr, i);
+      map.addrs.push(addr);
+      map.values.push(value);
+    }
+    map.rebuild_addr_map();
+    map
+  }
+
+  /// Replace the entries with `pairs` after a configuration reload.
+  /// Entries whose value and remote are unchanged keep their resolved address,
+  /// entries for removed values are dropped and changed remotes are re-keyed.
+  pub fn reconcile(&mut self, mut pairs: Vec<(AddrString, T)>) {
+    let mut old_values = std::mem::take(&mut self.values);
+    let mut old_addrs = std::mem::take(&mut self.addrs);
+    for (addr, value) in pairs.drain(..) {
+      let existing = old_values.iter().zip(old_addrs.iter()).position(|(old_value, old_addr)| *old_value == value && old_addr.same_remote(&addr));
+      let addr = match existing {
+        Some(i) => {
+          old_values.swap_remove(i);
+          old_addrs.swap_remove(i)
+        },
+        None => addr,
+      };
+      self.addrs.push(addr);
+      self.values.push(value);
+    }
+    self.rebuild_addr_map();
+  }
+
+  /// Map the resolved addresses to their values. When different values resolve to the
+  /// same address, the later one gets it and the collision is recorded.
+  fn rebuild_addr_map(&mut self) {
+    let previous: HashSet<IpAddr> = self.collisions.iter().map(|(ip_addr, _, _)| *ip_addr).collect();
+    self.addr_map.clear();
+    self.collisions.clear();
+    for (i, addr) in self.addrs.iter().enumerate() {
+      if let Some(ip_addr) = addr.try_get_ip_addr() {
+        if let Some(j) = self.addr_map.insert(ip_addr, i) {
+          if self.values[j] != self.values[i] {
+            self.collisions.push((ip_addr, j, i));
+          }
+        }
       }
-      values.push(value);
     }
-    AddrStringMap { values, addrs, addr_map }
+    let current: HashSet<IpAddr> = self.collisions.iter().map(|(ip_addr, _, _)| *ip_addr).collect();
+    self.unreported_collisions.extend(current.difference(&previous));
+    self.unreported_collisions.retain(|ip_addr| current.contains(ip_addr));
+  }
+
+  /// Collisions that appeared since the last call, as (address, value that lost it,
+  /// value it maps to). Each is returned once, until it is resolved and comes back.
+  pub fn take_new_collisions(&mut self) -> Vec<(IpAddr, &T, &T)> {
+    let unreported = std::mem::take(&mut self.unreported_collisions);
+    self.collisions.iter()
+      .filter(|(ip_addr, _, _)| unreported.contains(ip_addr))
+      .map(|(ip_addr, shadowed, winner)| (*ip_addr, &self.values[*shadowed], &self.values[*winner]))
+      .collect()
+  }
+
+  /// Resolved addresses of the entries with `value`.
+  pub fn addrs_of<'a>(&'a self, value: &'a T) -> impl Iterator<Item = IpAddr> + 'a {
+    self.values.iter().zip(self.addrs.iter())
+      .filter(move |(entry_value, _)| *entry_value == value)
+      .filter_map(|(_, addr)| addr.try_get_ip_addr())
   }
 
-  pub fn get(&self, ip_addr: &std::net::IpAddr) -> Option<&T> {
+  pub fn get(&self, ip_addr: &IpAddr) -> Option<&T> {
     if let Some(i) = self.addr_map.get(ip_addr) {
       Some(&self.values[*i])
     } else {
@@ -238,13 +1440,253 @@ impl<T> AddrStringMap<T> {
     for addr in &mut self.addrs {
       addr.update_ip_addr().await?;
     }
-    self.addr_map.clear();
-    for (i, addr) in self.addrs.iter().enumerate() {
-      if let Some(ip_addr) = addr.try_get_ip_addr() {
-        self.addr_map.insert(ip_addr, i);
-      }
-    }
+    self.rebuild_addr_map();
     Ok(())
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn static_addr(addr: &str) -> AddrString {
+    AddrString::with_source(addr.to_string(), IpVersion::V4, RemoteSource::Static)
+  }
+
+  /// A hostname entry, resolved to `ip_addr` as if `update` had looked it up.
+  fn resolved_addr(hostname: &str, ip_addr: &str) -> AddrString {
+    let mut addr = AddrString::new(hostname.to_string(), IpVersion::V4);
+    addr.set_resolved(ip_addr.parse().unwrap());
+    addr
+  }
+
+  fn ip(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+  }
+
+  #[test]
+  fn reconcile_drops_removed_links() {
+    let mut map = AddrStringMap::new(vec![
+      (static_addr("192.0.2.10"), "a".to_string()),
+      (resolved_addr("b.example", "192.0.2.20"), "b".to_string()),
+    ]);
+    map.reconcile(vec![(resolved_addr("b.example", "192.0.2.20"), "b".to_string())]);
+    assert_eq!(map.get(&ip("192.0.2.10")), None);
+    assert_eq!(map.get(&ip("192.0.2.20")), Some(&"b".to_string()));
+    assert_eq!(map.addrs_of(&"a".to_string()).count(), 0);
+  }
+
+  #[test]
+  fn reconcile_keeps_the_resolved_address_of_unchanged_links() {
+    let mut map = AddrStringMap::new(vec![(resolved_addr("b.example", "192.0.2.20"), "b".to_string())]);
+    // A reloaded configuration has not resolved anything yet.
+    map.reconcile(vec![(AddrString::new("b.example".to_string(), IpVersion::V4), "b".to_string())]);
+    assert_eq!(map.get(&ip("192.0.2.20")), Some(&"b".to_string()));
+    assert_eq!(map.addrs_of(&"b".to_string()).collect::<Vec<_>>(), vec![ip("192.0.2.20")]);
+  }
+
+  #[test]
+  fn reconcile_re_keys_renamed_links() {
+    let mut map = AddrStringMap::new(vec![
+      (static_addr("192.0.2.10"), "a".to_string()),
+      (resolved_addr("b.example", "192.0.2.20"), "b".to_string()),
+    ]);
+    map.reconcile(vec![
+      (static_addr("192.0.2.10"), "renamed".to_string()),
+      (AddrString::new("b.example".to_string(), IpVersion::V4), "b2".to_string()),
+    ]);
+    assert_eq!(map.get(&ip("192.0.2.10")), Some(&"renamed".to_string()));
+    assert_eq!(map.addrs_of(&"a".to_string()).count(), 0);
+    // A renamed link is a new entry; its hostname is resolved again.
+    assert_eq!(map.get(&ip("192.0.2.20")), None);
+    assert_eq!(map.addrs_of(&"b2".to_string()).count(), 0);
+  }
+
+  #[test]
+  fn reconcile_re_keys_links_with_a_changed_remote() {
+    let mut map = AddrStringMap::new(vec![(static_addr("192.0.2.10"), "a".to_string())]);
+    map.reconcile(vec![(static_addr("192.0.2.11"), "a".to_string())]);
+    assert_eq!(map.get(&ip("192.0.2.10")), None);
+    assert_eq!(map.get(&ip("192.0.2.11")), Some(&"a".to_string()));
+  }
+
+  #[test]
+  fn links_sharing_a_remote_are_reported_once() {
+    let mut map = AddrStringMap::new(vec![
+      (static_addr("192.0.2.10"), "a".to_string()),
+      (resolved_addr("b.example", "192.0.2.10"), "b".to_string()),
+    ]);
+    // The later link gets the address.
+    assert_eq!(map.get(&ip("192.0.2.10")), Some(&"b".to_string()));
+    assert_eq!(map.take_new_collisions(), vec![(ip("192.0.2.10"), &"a".to_string(), &"b".to_string())]);
+    assert_eq!(map.take_new_collisions(), vec![]);
+
+    // Once the remotes differ, the collision is gone; when it comes back, it is reported again.
+    map.reconcile(vec![(static_addr("192.0.2.10"), "a".to_string()), (static_addr("192.0.2.20"), "b".to_string())]);
+    assert_eq!(map.get(&ip("192.0.2.10")), Some(&"a".to_string()));
+    assert_eq!(map.take_new_collisions(), vec![]);
+    map.reconcile(vec![(static_addr("192.0.2.10"), "a".to_string()), (static_addr("192.0.2.10"), "b".to_string())]);
+    assert_eq!(map.take_new_collisions().len(), 1);
+  }
+
+  /// Configuration with a single link `a` of `link` TOML lines.
+  fn config_with_link(link: &str) -> Result<Config, anyhow::Error> {
+    Config::parse(&format!("log_level = \"Warn\"\n[links.a]\n{}", link))
+  }
+
+  #[test]
+  fn literal_remotes_must_match_the_ip_version() {
+    for remote_source in ["static", "dns"] {
+      let error = config_with_link(&format!("remote = \"2001:db8::1\"\nip_version = \"V4\"\nremote_source = \"{}\"", remote_source)).expect_err("IPv6 remote accepted for V4").to_string();
+      assert!(error.contains("Link a") && error.contains("2001:db8::1 is an IPv6 address, but `ip_version` is V4"), "{}", error);
+      let error = config_with_link(&format!("remote = \"192.0.2.10\"\nip_version = \"V6\"\nremote_source = \"{}\"", remote_source)).expect_err("IPv4 remote accepted for V6").to_string();
+      assert!(error.contains("Link a") && error.contains("192.0.2.10 is an IPv4 address, but `ip_version` is V6"), "{}", error);
+    }
+    config_with_link("remote = \"192.0.2.10\"\nip_version = \"V4\"").expect("matching IPv4 remote");
+    config_with_link("remote = \"2001:db8::1\"\nip_version = \"V6\"").expect("matching IPv6 remote");
+  }
+
+  #[test]
+  fn addresses_read_for_a_remote_must_match_the_ip_version() {
+    assert_eq!(parse_remote_addr("192.0.2.10\n", IpVersion::V4).unwrap(), ip("192.0.2.10"));
+    let error = parse_remote_addr("2001:db8::1\n", IpVersion::V4).expect_err("IPv6 address read for V4");
+    assert_eq!(error.to_string(), "2001:db8::1 is not an V4 address");
+    let error = parse_remote_addr("192.0.2.10", IpVersion::V6).expect_err("IPv4 address read for V6");
+    assert_eq!(error.to_string(), "192.0.2.10 is not an V6 address");
+  }
+
+  #[test]
+  fn link_local_remotes_need_an_interface() {
+    let error = config_with_link("remote = \"fe80::2\"\nip_version = \"V6\"").expect_err("accepted without an interface").to_string();
+    assert!(error.contains("Link a") && error.contains("`interface` must be set"), "{}", error);
+  }
+
+  #[test]
+  fn link_local_remotes_are_scoped_to_their_interface() {
+    let config = config_with_link("remote = \"fe80::2\"\nip_version = \"V6\"\ninterface = \"lo\"").expect("valid configuration");
+    let scope_ids = config.scope_ids();
+    assert_eq!(scope_ids.len(), 1);
+    let (link_name, scope) = &scope_ids[0];
+    assert_eq!(link_name, "a");
+    assert_eq!(scope.as_ref().unwrap(), &("fe80::2".parse().unwrap(), crate::interface_index("lo").unwrap()));
+  }
+
+  /// Queries seen by a `dns_stub`.
+  #[derive(Default)]
+  struct DnsStubStats {
+    queries: std::sync::atomic::AtomicUsize,
+    in_flight: std::sync::atomic::AtomicUsize,
+    max_in_flight: std::sync::atomic::AtomicUsize,
+  }
+
+  /// Start a DNS server on the loopback address that answers every query with the A
+  /// record `answer` after `delay`, counting the queries it holds at once.
+  async fn dns_stub(answer: std::net::Ipv4Addr, delay: std::time::Duration) -> (SocketAddr, Arc<DnsStubStats>) {
+    use std::sync::atomic::Ordering;
+
+    let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.expect("bind the DNS stub"));
+    let addr = socket.local_addr().unwrap();
+    let stats = Arc::new(DnsStubStats::default());
+    let server_stats = stats.clone();
+    tokio::spawn(async move {
+      let mut buf = [0u8; 512];
+      loop {
+        let Ok((n, peer)) = socket.recv_from(&mut buf).await else {
+          return;
+        };
+        // The question ends after its labels and the type and class.
+        let mut end = 12;
+        while end < n && buf[end] != 0 {
+          end += buf[end] as usize + 1;
+        }
+        end += 5;
+        if end > n {
+          continue;
+        }
+        let mut response = vec![buf[0], buf[1], 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        response.extend_from_slice(&buf[12..end]);
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&answer.octets());
+
+        let (socket, stats) = (socket.clone(), server_stats.clone());
+        stats.queries.fetch_add(1, Ordering::Relaxed);
+        let in_flight = stats.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        stats.max_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+        tokio::spawn(async move {
+          tokio::time::sleep(delay).await;
+          stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+          let _ = socket.send_to(&response, peer).await;
+        });
+      }
+    });
+    (addr, stats)
+  }
+
+  #[tokio::test]
+  async fn concurrent_resolutions_are_bounded() {
+    use std::sync::atomic::Ordering;
+
+    let (resolver, stats) = dns_stub(std::net::Ipv4Addr::new(192, 0, 2, 30), std::time::Duration::from_millis(100)).await;
+    let mut config_str = String::from("log_level = \"Warn\"\nmax_concurrent_resolutions = 3\n");
+    for i in 0..12 {
+      config_str.push_str(&format!("[links.l{}]\nremote = \"l{}.test\"\nip_version = \"V4\"\nremote_source = \"dns\"\nresolver = \"{}\"\n", i, i, resolver));
+    }
+    let mut config = Config::parse(&config_str).expect("valid configuration");
+    set_max_concurrent_resolutions(config.max_concurrent_resolutions);
+
+    let pending = config.resolve_remotes().await;
+    assert!(pending.is_empty(), "{:?}", pending);
+    assert!(config.links.values().all(|link| link.resolved_remote == Some(ip("192.0.2.30"))));
+    assert_eq!(stats.queries.load(Ordering::Relaxed), 12);
+    // Lookups of other tests share the permits, so fewer may have been in flight here.
+    let max_in_flight = stats.max_in_flight.load(Ordering::Relaxed);
+    assert!((2..=3).contains(&max_in_flight), "{} lookups in flight at once", max_in_flight);
+  }
+
+  #[tokio::test]
+  async fn remotes_with_a_resolver_are_looked_up_there() {
+    use std::sync::atomic::Ordering;
+
+    let (resolver, stats) = dns_stub(std::net::Ipv4Addr::new(192, 0, 2, 40), std::time::Duration::ZERO).await;
+    let config = config_with_link(&format!("remote = \"peer.test\"\nip_version = \"V4\"\nremote_source = \"dns\"\nresolver = \"{}\"", resolver)).expect("valid configuration");
+    let mut remote = config.links["a"].remote_addr();
+    remote.update_ip_addr().await.expect("resolved through the stub");
+    assert_eq!(remote.try_get_ip_addr(), Some(ip("192.0.2.40")));
+    assert_eq!(stats.queries.load(Ordering::Relaxed), 1);
+
+    // Without a resolver, the system one is used; it knows localhost without asking DNS.
+    assert_eq!(lookup_remote("localhost", IpVersion::V4, RemoteSource::Dns, None).await.ok(), Some(ip("127.0.0.1")));
+    assert_eq!(stats.queries.load(Ordering::Relaxed), 1);
+
+    let error = config_with_link(&format!("remote = \"/run/peer\"\nip_version = \"V4\"\nremote_source = \"file\"\nresolver = \"{}\"", resolver)).expect_err("resolver of a file remote").to_string();
+    assert!(error.contains("resolver requires"), "{}", error);
+  }
+
+  #[test]
+  fn key_files_are_read_once_opened() {
+    let key_file = std::env::temp_dir().join(format!("etheripd-test-{}-key", std::process::id()));
+    let mut auth: AuthConfig = toml::from_str(&format!("key_file = {:?}", key_file)).unwrap();
+    let error = auth.clone().load_key().expect_err("loaded a missing key file").to_string();
+    assert!(error.starts_with(&format!("cannot read `key_file` {}: ", key_file.display())), "{}", error);
+
+    std::fs::write(&key_file, "00112233445566778899aabbccddeeff\n").unwrap();
+    auth.load_key().expect("load the key");
+    std::fs::remove_file(&key_file).unwrap();
+    assert_eq!(auth.key.as_deref(), Some("00112233445566778899aabbccddeeff\n"));
+  }
+
+  #[test]
+  fn authenticators_are_built_when_the_configuration_is_loaded() {
+    let config = config_with_link("remote = \"192.0.2.10\"\nip_version = \"V4\"\n[links.a.auth]\nkey = \"00112233445566778899aabbccddeeff\"").expect("valid key");
+    assert!(config.links["a"].auth.as_ref().unwrap().authenticator().is_some());
+
+    let error = config_with_link("remote = \"192.0.2.10\"\nip_version = \"V4\"\n[links.a.auth]\nkey = \"0011\"").expect_err("accepted a short key").to_string();
+    assert!(error.starts_with("Link a: invalid `auth`: authentication key must be at least"), "{}", error);
+
+    // A link configuration that did not go through loading has no authenticator to run with.
+    let link: LinkConfig = toml::from_str("remote = \"192.0.2.10\"\nip_version = \"V4\"\n[auth]\nkey = \"00112233445566778899aabbccddeeff\"").unwrap();
+    let error = link.validate().expect_err("validated without a loaded key").to_string();
+    assert_eq!(error, "invalid `auth`: the authentication key has not been loaded");
+  }
+}
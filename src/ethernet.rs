@@ -0,0 +1,176 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Ethernet frame headers.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::serde;
+
+use serde::{Deserialize, Deserializer};
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Size of an 802.1Q VLAN tag.
+pub const VLAN_TAG_SIZE: usize = 4;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// MAC address.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+  pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+
+  pub fn octets(&self) -> [u8; 6] {
+    self.0
+  }
+
+  pub fn is_broadcast(&self) -> bool {
+    *self == Self::BROADCAST
+  }
+
+  /// true for group addresses, including broadcast.
+  pub fn is_multicast(&self) -> bool {
+    self.0[0] & 0x01 != 0
+  }
+}
+
+impl From<[u8; 6]> for MacAddr {
+  fn from(octets: [u8; 6]) -> Self {
+    MacAddr(octets)
+  }
+}
+
+impl fmt::Display for MacAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let o = &self.0;
+    write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", o[0], o[1], o[2], o[3], o[4], o[5])
+  }
+}
+
+impl fmt::Debug for MacAddr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+impl FromStr for MacAddr {
+  type Err = std::io::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid MAC address: {}", s));
+    let mut octets = [0u8; 6];
+    let mut parts = s.split([':', '-']);
+    for octet in octets.iter_mut() {
+      let part = parts.next().ok_or_else(invalid)?;
+      if part.len() != 2 {
+        return Err(invalid());
+      }
+      *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+    }
+    if parts.next().is_some() {
+      return Err(invalid());
+    }
+    Ok(MacAddr(octets))
+  }
+}
+
+impl<'de> Deserialize<'de> for MacAddr {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Ethernet II header (without VLAN tags).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetHeader {
+  pub destination: MacAddr,
+  pub source: MacAddr,
+  pub ethertype: u16,
+}
+
+impl EthernetHeader {
+  /// Parse the header of an Ethernet frame, returning it with the payload.
+  pub fn parse(frame: &[u8]) -> Option<(EthernetHeader, &[u8])> {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+      return None;
+    }
+    let mut destination = [0u8; 6];
+    let mut source = [0u8; 6];
+    destination.copy_from_slice(&frame[0..6]);
+    source.copy_from_slice(&frame[6..12]);
+    let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+    Some((EthernetHeader {
+      destination: MacAddr(destination),
+      source: MacAddr(source),
+      ethertype,
+    }, &frame[ETHERNET_HEADER_SIZE..]))
+  }
+
+  /// Write the header to the start of `buf`, returning the number of bytes written.
+  pub fn write(&self, buf: &mut [u8]) -> Option<usize> {
+    if buf.len() < ETHERNET_HEADER_SIZE {
+      return None;
+    }
+    buf[0..6].copy_from_slice(&self.destination.0);
+    buf[6..12].copy_from_slice(&self.source.0);
+    buf[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    Some(ETHERNET_HEADER_SIZE)
+  }
+}
+
+/// Translation between the MAC addresses of local hosts and the addresses they use
+/// through the tunnel, for bridging into a segment where they would conflict.
+/// Only Ethernet headers are translated: ARP and ND payloads keep the local addresses.
+#[derive(Debug, Clone, Default)]
+pub struct MacRewriter {
+  outbound: HashMap<MacAddr, MacAddr>,
+  inbound: HashMap<MacAddr, MacAddr>,
+}
+
+impl MacRewriter {
+  /// Create a rewriter from `local -> tunnel` mappings, which should be one-to-one.
+  pub fn new(mappings: &HashMap<MacAddr, MacAddr>) -> Self {
+    Self {
+      outbound: mappings.clone(),
+      inbound: mappings.iter().map(|(local, tunnel)| (*tunnel, *local)).collect(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.outbound.is_empty()
+  }
+
+  /// Translate the source of a frame about to be tunnelled. Returns whether it was translated.
+  pub fn rewrite_outbound(&self, frame: &mut [u8]) -> bool {
+    let Some((mut header, _)) = EthernetHeader::parse(frame) else {
+      return false;
+    };
+    let Some(source) = self.outbound.get(&header.source) else {
+      return false;
+    };
+    header.source = *source;
+    header.write(frame).is_some()
+  }
+
+  /// Translate the destination of a frame received through the tunnel back to the local address.
+  /// Returns whether it was translated.
+  pub fn rewrite_inbound(&self, frame: &mut [u8]) -> bool {
+    let Some((mut header, _)) = EthernetHeader::parse(frame) else {
+      return false;
+    };
+    let Some(destination) = self.inbound.get(&header.destination) else {
+      return false;
+    };
+    header.destination = *destination;
+    header.write(frame).is_some()
+  }
+}
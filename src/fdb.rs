@@ -0,0 +1,235 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Table of the MAC addresses learned behind the remote of a link, for diagnostics.
+//!
+//! The source addresses of received frames are recorded with the address of the remote
+//! that sent them. Forwarding does not consult the table; it only shows operators which
+//! hosts the daemon has seen on the far side of a tunnel.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::parking_lot::Mutex;
+
+use crate::ethernet::{EthernetHeader, MacAddr};
+
+/// What is known about a learned MAC address.
+#[derive(Debug, Clone, Copy)]
+pub struct FdbEntry {
+  /// Remote the address was last seen from.
+  pub remote: IpAddr,
+  pub frames: u64,
+  pub first_seen: Instant,
+  pub last_seen: Instant,
+}
+
+/// MAC addresses learned on a link, at most `capacity` of them. When it is full, the
+/// least recently seen eighth of the entries is evicted at once, as in `FlowTable`.
+#[derive(Debug, Default)]
+pub struct Fdb {
+  /// Largest number of entries; 0 disables the table.
+  capacity: AtomicUsize,
+  entries: Mutex<HashMap<MacAddr, FdbEntry>>,
+  /// Entries evicted to make room for new ones.
+  evictions: AtomicU64,
+  /// Times a learned address was seen from a different remote.
+  moves: AtomicU64,
+}
+
+impl Fdb {
+  /// Set the largest number of entries, forgetting all entries if it changes.
+  pub fn set_capacity(&self, capacity: usize) {
+    if self.capacity.swap(capacity, Ordering::Relaxed) != capacity {
+      self.entries.lock().clear();
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.capacity.load(Ordering::Relaxed) > 0
+  }
+
+  /// Learn the source address of a frame received from `remote`, if the table is enabled.
+  /// Multicast sources, which no host may use, are ignored.
+  pub fn learn(&self, frame: &[u8], remote: &IpAddr) {
+    let capacity = self.capacity.load(Ordering::Relaxed);
+    if capacity == 0 {
+      return;
+    }
+    let Some((header, _)) = EthernetHeader::parse(frame) else {
+      return;
+    };
+    if header.source.is_multicast() {
+      return;
+    }
+    let now = Instant::now();
+    let mut entries = self.entries.lock();
+    if !entries.contains_key(&header.source) && entries.len() >= capacity {
+      let evicted = evict_least_recent(&mut entries, capacity.div_ceil(8));
+      self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+    let entry = entries.entry(header.source).or_insert(FdbEntry { remote: *remote, frames: 0, first_seen: now, last_seen: now });
+    if entry.remote != *remote {
+      entry.remote = *remote;
+      self.moves.fetch_add(1, Ordering::Relaxed);
+    }
+    entry.frames += 1;
+    entry.last_seen = now;
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.lock().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn evictions(&self) -> u64 {
+    self.evictions.load(Ordering::Relaxed)
+  }
+
+  pub fn moves(&self) -> u64 {
+    self.moves.load(Ordering::Relaxed)
+  }
+
+  /// The `n` most recently seen entries, most recent first.
+  pub fn recent(&self, n: usize) -> Vec<(MacAddr, FdbEntry)> {
+    let mut entries: Vec<(MacAddr, FdbEntry)> = self.entries.lock().iter().map(|(mac, entry)| (*mac, *entry)).collect();
+    entries.sort_unstable_by_key(|(mac, entry)| (std::cmp::Reverse(entry.last_seen), *mac));
+    entries.truncate(n);
+    entries
+  }
+}
+
+/// Evict the `count` least recently seen entries, returning how many were evicted.
+fn evict_least_recent(entries: &mut HashMap<MacAddr, FdbEntry>, count: usize) -> usize {
+  let mut by_age: Vec<(Instant, MacAddr)> = entries.iter().map(|(mac, entry)| (entry.last_seen, *mac)).collect();
+  let count = count.min(by_age.len());
+  if count == 0 {
+    return 0;
+  }
+  by_age.select_nth_unstable_by_key(count - 1, |(last_seen, _)| *last_seen);
+  for (_, mac) in &by_age[..count] {
+    entries.remove(mac);
+  }
+  count
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  const REMOTE_A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+  const REMOTE_B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+  fn mac(n: u8) -> MacAddr {
+    MacAddr([0x02, 0, 0, 0, 0, n])
+  }
+
+  /// A frame sent by `source`.
+  fn frame_from(source: MacAddr) -> Vec<u8> {
+    let mut frame = vec![0u8; 60];
+    frame[..6].fill(0xff);
+    frame[6..12].copy_from_slice(&source.0);
+    frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+    frame
+  }
+
+  /// Learn `source`, making sure its `last_seen` is later than that of the entries before.
+  fn learn_later(fdb: &Fdb, source: MacAddr, remote: &IpAddr) {
+    std::thread::sleep(Duration::from_millis(1));
+    fdb.learn(&frame_from(source), remote);
+  }
+
+  #[test]
+  fn disabled_tables_learn_nothing() {
+    let fdb = Fdb::default();
+    assert!(!fdb.is_enabled());
+    fdb.learn(&frame_from(mac(1)), &REMOTE_A);
+    assert!(fdb.is_empty());
+  }
+
+  #[test]
+  fn multicast_sources_and_truncated_frames_are_ignored() {
+    let fdb = Fdb::default();
+    fdb.set_capacity(8);
+    fdb.learn(&frame_from(MacAddr([0x01, 0, 0x5e, 0, 0, 1])), &REMOTE_A);
+    fdb.learn(&frame_from(mac(1))[..13], &REMOTE_A);
+    assert!(fdb.is_empty());
+  }
+
+  #[test]
+  fn entries_follow_their_remote_and_count_moves() {
+    let fdb = Fdb::default();
+    fdb.set_capacity(8);
+    fdb.learn(&frame_from(mac(1)), &REMOTE_A);
+    learn_later(&fdb, mac(1), &REMOTE_A);
+    assert_eq!(fdb.moves(), 0);
+    learn_later(&fdb, mac(1), &REMOTE_B);
+    assert_eq!(fdb.moves(), 1);
+    let [(learned, entry)] = fdb.recent(usize::MAX)[..] else {
+      panic!("one entry expected");
+    };
+    assert_eq!(learned, mac(1));
+    assert_eq!((entry.remote, entry.frames), (REMOTE_B, 3));
+    assert!(entry.last_seen > entry.first_seen);
+  }
+
+  #[test]
+  fn recent_entries_come_first() {
+    let fdb = Fdb::default();
+    fdb.set_capacity(8);
+    for n in 1..=3 {
+      learn_later(&fdb, mac(n), &REMOTE_A);
+    }
+    learn_later(&fdb, mac(1), &REMOTE_A);
+    let recent: Vec<MacAddr> = fdb.recent(2).into_iter().map(|(mac, _)| mac).collect();
+    assert_eq!(recent, [mac(1), mac(3)]);
+  }
+
+  #[test]
+  fn full_tables_evict_the_least_recently_seen_eighth() {
+    let fdb = Fdb::default();
+    fdb.set_capacity(16);
+    for n in 0..16 {
+      learn_later(&fdb, mac(n), &REMOTE_A);
+    }
+    // Seeing the oldest entries again keeps them.
+    learn_later(&fdb, mac(0), &REMOTE_A);
+    learn_later(&fdb, mac(1), &REMOTE_A);
+    assert_eq!((fdb.len(), fdb.evictions()), (16, 0));
+
+    learn_later(&fdb, mac(16), &REMOTE_A);
+    assert_eq!((fdb.len(), fdb.evictions()), (15, 2));
+    let learned: Vec<MacAddr> = fdb.recent(usize::MAX).into_iter().map(|(mac, _)| mac).collect();
+    assert!(!learned.contains(&mac(2)) && !learned.contains(&mac(3)), "{:?}", learned);
+    assert!(learned.contains(&mac(0)) && learned.contains(&mac(1)) && learned.contains(&mac(16)));
+  }
+
+  #[test]
+  fn eviction_is_bounded_by_the_entries() {
+    let now = Instant::now();
+    let mut entries: HashMap<MacAddr, FdbEntry> = (0..3)
+      .map(|n| (mac(n), FdbEntry { remote: REMOTE_A, frames: 1, first_seen: now, last_seen: now + Duration::from_secs(n as u64) }))
+      .collect();
+    assert_eq!(evict_least_recent(&mut entries, 1), 1);
+    assert!(!entries.contains_key(&mac(0)));
+    assert_eq!(evict_least_recent(&mut entries, 10), 2);
+    assert_eq!(evict_least_recent(&mut entries, 1), 0);
+  }
+
+  #[test]
+  fn changing_the_capacity_forgets_all_entries() {
+    let fdb = Fdb::default();
+    fdb.set_capacity(8);
+    fdb.learn(&frame_from(mac(1)), &REMOTE_A);
+    fdb.set_capacity(8);
+    assert_eq!(fdb.len(), 1);
+    fdb.set_capacity(4);
+    assert!(fdb.is_empty());
+  }
+}
@@ -0,0 +1,263 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Table of the inner flows seen on a link, for diagnostics.
+//!
+//! Frames are keyed by the 5-tuple of the IP packet they carry. Nothing is enforced;
+//! the table only shows operators which flows traverse a tunnel.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::parking_lot::Mutex;
+
+use crate::ethernet::{EthernetHeader, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ETHERTYPE_VLAN, VLAN_TAG_SIZE};
+
+/// IP protocols whose first 4 bytes are the source and destination ports.
+const PROTOCOLS_WITH_PORTS: [u8; 4] = [6, 17, 132, 136];
+
+/// 5-tuple of an inner IP packet. Ports are 0 for protocols without them and for
+/// non-first fragments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+  pub protocol: u8,
+  pub src: IpAddr,
+  pub dst: IpAddr,
+  pub src_port: u16,
+  pub dst_port: u16,
+}
+
+impl FlowKey {
+  /// Parse the 5-tuple of the IP packet in an Ethernet frame, behind at most one VLAN tag.
+  /// IPv6 extension headers are not followed: the ports are those of the upper-layer
+  /// header only if it comes first.
+  pub fn parse(frame: &[u8]) -> Option<Self> {
+    let (header, mut payload) = EthernetHeader::parse(frame)?;
+    let mut ethertype = header.ethertype;
+    if ethertype == ETHERTYPE_VLAN {
+      ethertype = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]);
+      payload = payload.get(VLAN_TAG_SIZE..)?;
+    }
+    let (protocol, src, dst, upper, first_fragment) = match ethertype {
+      ETHERTYPE_IPV4 => {
+        let header_len = ((*payload.first()? & 0x0f) as usize) * 4;
+        if payload.len() < header_len.max(20) {
+          return None;
+        }
+        let fragment_offset = u16::from_be_bytes([payload[6], payload[7]]) & 0x1fff;
+        let src: [u8; 4] = payload[12..16].try_into().ok()?;
+        let dst: [u8; 4] = payload[16..20].try_into().ok()?;
+        (payload[9], IpAddr::from(src), IpAddr::from(dst), &payload[header_len..], fragment_offset == 0)
+      },
+      ETHERTYPE_IPV6 => {
+        if payload.len() < 40 {
+          return None;
+        }
+        let src: [u8; 16] = payload[8..24].try_into().ok()?;
+        let dst: [u8; 16] = payload[24..40].try_into().ok()?;
+        (payload[6], IpAddr::from(src), IpAddr::from(dst), &payload[40..], true)
+      },
+      _ => return None,
+    };
+    let (src_port, dst_port) = match upper.get(..4) {
+      Some(ports) if first_fragment && PROTOCOLS_WITH_PORTS.contains(&protocol) => {
+        (u16::from_be_bytes([ports[0], ports[1]]), u16::from_be_bytes([ports[2], ports[3]]))
+      },
+      _ => (0, 0),
+    };
+    Some(Self { protocol, src, dst, src_port, dst_port })
+  }
+}
+
+/// Traffic of a flow.
+#[derive(Debug, Clone, Copy)]
+pub struct FlowEntry {
+  pub frames: u64,
+  pub bytes: u64,
+  pub last_seen: Instant,
+}
+
+/// Flows of a link, at most `capacity` of them. When it is full, the least recently
+/// seen eighth of the flows is evicted at once, to keep eviction off most frames.
+#[derive(Debug, Default)]
+pub struct FlowTable {
+  /// Largest number of flows tracked; 0 disables the table.
+  capacity: AtomicUsize,
+  flows: Mutex<HashMap<FlowKey, FlowEntry>>,
+}
+
+impl FlowTable {
+  /// Set the largest number of flows tracked, forgetting all flows if it changes.
+  pub fn set_capacity(&self, capacity: usize) {
+    if self.capacity.swap(capacity, Ordering::Relaxed) != capacity {
+      self.flows.lock().clear();
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.capacity.load(Ordering::Relaxed) > 0
+  }
+
+  /// Account a frame to its flow, if the table is enabled and the frame carries IP.
+  pub fn record(&self, frame: &[u8]) {
+    let capacity = self.capacity.load(Ordering::Relaxed);
+    if capacity == 0 {
+      return;
+    }
+    let Some(key) = FlowKey::parse(frame) else {
+      return;
+    };
+    let now = Instant::now();
+    let mut flows = self.flows.lock();
+    if !flows.contains_key(&key) && flows.len() >= capacity {
+      evict_least_recent(&mut flows, capacity.div_ceil(8));
+    }
+    let entry = flows.entry(key).or_insert(FlowEntry { frames: 0, bytes: 0, last_seen: now });
+    entry.frames += 1;
+    entry.bytes += frame.len() as u64;
+    entry.last_seen = now;
+  }
+
+  /// The `n` flows with the most bytes, largest first.
+  pub fn top(&self, n: usize) -> Vec<(FlowKey, FlowEntry)> {
+    let mut flows: Vec<(FlowKey, FlowEntry)> = self.flows.lock().iter().map(|(key, entry)| (*key, *entry)).collect();
+    flows.sort_unstable_by_key(|(_, entry)| std::cmp::Reverse(entry.bytes));
+    flows.truncate(n);
+    flows
+  }
+}
+
+fn evict_least_recent(flows: &mut HashMap<FlowKey, FlowEntry>, count: usize) {
+  let mut by_age: Vec<(Instant, FlowKey)> = flows.iter().map(|(key, entry)| (entry.last_seen, *key)).collect();
+  let count = count.min(by_age.len());
+  if count == 0 {
+    return;
+  }
+  by_age.select_nth_unstable_by_key(count - 1, |(last_seen, _)| *last_seen);
+  for (_, key) in &by_age[..count] {
+    flows.remove(key);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  /// An Ethernet frame carrying an IPv4 UDP packet from `src_port` to port 53.
+  fn udp_frame(src_port: u16) -> Vec<u8> {
+    let mut frame = vec![0u8; 14 + 20 + 8];
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    frame[14] = 0x45;
+    frame[14 + 9] = 17;
+    frame[14 + 12..14 + 16].copy_from_slice(&[192, 0, 2, 1]);
+    frame[14 + 16..14 + 20].copy_from_slice(&[192, 0, 2, 2]);
+    frame[34..36].copy_from_slice(&src_port.to_be_bytes());
+    frame[36..38].copy_from_slice(&53u16.to_be_bytes());
+    frame
+  }
+
+  fn key(src_port: u16) -> FlowKey {
+    FlowKey {
+      protocol: 17,
+      src: IpAddr::from([192, 0, 2, 1]),
+      dst: IpAddr::from([192, 0, 2, 2]),
+      src_port,
+      dst_port: 53,
+    }
+  }
+
+  #[test]
+  fn keys_are_parsed_behind_a_vlan_tag_and_without_ports_in_fragments() {
+    let frame = udp_frame(1234);
+    assert_eq!(FlowKey::parse(&frame), Some(key(1234)));
+
+    let mut tagged = frame[..12].to_vec();
+    tagged.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+    tagged.extend_from_slice(&[0x00, 0x2a]);
+    tagged.extend_from_slice(&frame[12..]);
+    assert_eq!(FlowKey::parse(&tagged), Some(key(1234)));
+
+    let mut fragment = frame.clone();
+    fragment[14 + 7] = 0x08;
+    assert_eq!(FlowKey::parse(&fragment), Some(FlowKey { src_port: 0, dst_port: 0, ..key(0) }));
+
+    assert_eq!(FlowKey::parse(&frame[..14 + 19]), None);
+    let mut arp = frame;
+    arp[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+    assert_eq!(FlowKey::parse(&arp), None);
+  }
+
+  #[test]
+  fn disabled_tables_record_nothing() {
+    let table = FlowTable::default();
+    assert!(!table.is_enabled());
+    table.record(&udp_frame(1));
+    assert!(table.top(usize::MAX).is_empty());
+  }
+
+  #[test]
+  fn frames_are_accounted_to_their_flow_and_ranked_by_bytes() {
+    let table = FlowTable::default();
+    table.set_capacity(16);
+    let small = udp_frame(1);
+    let mut large = udp_frame(2);
+    large.resize(1000, 0);
+    table.record(&small);
+    table.record(&small);
+    table.record(&large);
+    let top = table.top(usize::MAX);
+    assert_eq!(top.iter().map(|(key, entry)| (key.src_port, entry.frames, entry.bytes)).collect::<Vec<_>>(), [
+      (2, 1, 1000),
+      (1, 2, 2 * small.len() as u64),
+    ]);
+    assert_eq!(table.top(1).len(), 1);
+  }
+
+  #[test]
+  fn full_tables_stay_within_their_capacity() {
+    let table = FlowTable::default();
+    table.set_capacity(16);
+    for port in 0..100 {
+      table.record(&udp_frame(port));
+      assert!(table.top(usize::MAX).len() <= 16);
+    }
+    // The newest flow is always kept.
+    assert!(table.top(usize::MAX).iter().any(|(key, _)| key.src_port == 99));
+    // Known flows are updated without evicting anything.
+    let before = table.top(usize::MAX).len();
+    table.record(&udp_frame(99));
+    assert_eq!(table.top(usize::MAX).len(), before);
+  }
+
+  #[test]
+  fn eviction_drops_the_least_recently_seen_flows() {
+    let start = Instant::now();
+    let mut flows: HashMap<FlowKey, FlowEntry> = (0..16)
+      .map(|port| (key(port), FlowEntry { frames: 1, bytes: 1, last_seen: start + Duration::from_secs(port as u64) }))
+      .collect();
+    evict_least_recent(&mut flows, 16usize.div_ceil(8));
+    let mut kept: Vec<u16> = flows.keys().map(|key| key.src_port).collect();
+    kept.sort_unstable();
+    assert_eq!(kept, (2..16).collect::<Vec<_>>());
+
+    evict_least_recent(&mut flows, 100);
+    assert!(flows.is_empty());
+    evict_least_recent(&mut flows, 1);
+  }
+
+  #[test]
+  fn changing_the_capacity_forgets_all_flows() {
+    let table = FlowTable::default();
+    table.set_capacity(16);
+    table.record(&udp_frame(1));
+    table.set_capacity(16);
+    assert_eq!(table.top(usize::MAX).len(), 1);
+    table.set_capacity(32);
+    assert!(table.top(usize::MAX).is_empty());
+    table.set_capacity(0);
+    assert!(!table.is_enabled());
+  }
+}
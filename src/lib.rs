@@ -13,14 +13,54 @@ pub use libc;
 pub use futures;
 pub use crossbeam_channel;
 pub use nix;
+pub use lz4_flex;
+pub use hmac;
+pub use sha2;
+pub use hickory_resolver;
+#[cfg(feature = "task-metrics")]
+pub use tokio_metrics;
+#[cfg(feature = "codec")]
+pub use tokio_util;
 
+pub mod acl;
+pub mod affinity;
+pub mod arena;
+pub mod arp;
+pub mod auth;
+pub mod backoff;
+pub mod bpf;
+pub mod caps;
+#[cfg(feature = "codec")]
+pub mod codec;
+pub mod compress;
 pub mod config;
+pub mod ethernet;
+pub mod fdb;
+pub mod flows;
+pub mod logging;
+pub mod metrics;
+pub mod mtu;
+pub mod netns;
+pub mod packet;
+pub mod privileges;
+pub mod probe;
+pub mod queue;
+pub mod ratelimit;
+pub mod seqno;
+pub mod stats;
 pub mod tap;
+pub mod tcp;
+pub mod transport;
+pub mod udp;
+pub mod watchdog;
 
 use std::io::{Error, ErrorKind};
 use std::os::fd::AsRawFd;
 
 use std::net::{IpAddr, Ipv6Addr};
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::RwLock;
 
 use tokio::io::Interest;
 use tokio::io::unix::AsyncFd;
@@ -44,9 +84,331 @@ pub fn from_ipv6_addr(v6_addr: Ipv6Addr) -> IpAddr {
   }
 }
 
+/// Whether an address is an IPv4 address mapped to IPv6 (`::ffff:a.b.c.d`).
+pub fn is_ipv4_mapped(v6_addr: &Ipv6Addr) -> bool {
+  v6_addr.to_ipv4_mapped().is_some()
+}
+
+/// Error for a v4-mapped destination that an IPv6-only socket cannot reach.
+fn ipv4_mapped_unreachable(v6_addr: &Ipv6Addr) -> Error {
+  Error::new(ErrorKind::Unsupported, format!(
+    "cannot send to {} over an IPv6-only socket; set the sysctl net.ipv6.bindv6only to 0 or enable native_ipv4",
+    from_ipv6_addr(*v6_addr),
+  ))
+}
+
+/// Path MTU to `addr` as currently known to the kernel: the MTU of the route, lowered
+/// by ICMP "packet too big" messages for anything sent there. Read with `IP_MTU` or
+/// `IPV6_MTU` from a connected UDP socket, which sends nothing.
+pub fn path_mtu(addr: &IpAddr) -> std::io::Result<u32> {
+  let addr = match addr {
+    IpAddr::V6(v6_addr) => from_ipv6_addr(*v6_addr),
+    addr => *addr,
+  };
+  let (unspecified, level, name) = match addr {
+    IpAddr::V4(_) => (IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), libc::IPPROTO_IP, libc::IP_MTU),
+    IpAddr::V6(_) => (IpAddr::V6(Ipv6Addr::UNSPECIFIED), libc::IPPROTO_IPV6, libc::IPV6_MTU),
+  };
+  let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+  // The port does not matter: only the route to the address is looked up.
+  socket.connect((addr, 9))?;
+  let mut mtu: libc::c_int = 0;
+  let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+  unsafe {
+    if libc::getsockopt(socket.as_raw_fd(), level, name, &mut mtu as *mut libc::c_int as *mut libc::c_void, &mut len) < 0 {
+      return Err(Error::last_os_error());
+    }
+  }
+  Ok(mtu as u32)
+}
+
+/// Source address the kernel picks for packets to `addr` from an unbound socket,
+/// found by connecting a UDP socket, which sends nothing. Fails if `addr` is unreachable.
+pub fn source_address(addr: &IpAddr) -> std::io::Result<IpAddr> {
+  let addr = match addr {
+    IpAddr::V6(v6_addr) => from_ipv6_addr(*v6_addr),
+    addr => *addr,
+  };
+  let unspecified = match addr {
+    IpAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+  };
+  let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+  socket.connect((addr, 9))?;
+  Ok(socket.local_addr()?.ip())
+}
+
+/// Index of the interface the kernel routes packets to `addr` through, found from the
+/// local address it picks for them. Fails if `addr` is unreachable; for hosts with the same
+/// address on several interfaces, the first one with it is returned.
+pub fn route_interface(addr: &IpAddr) -> std::io::Result<u32> {
+  let local_addr = source_address(addr)?;
+
+  let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+  if unsafe { libc::getifaddrs(&mut ifaddrs) } < 0 {
+    return Err(Error::last_os_error());
+  }
+  let mut index = 0;
+  let mut ifaddr = ifaddrs;
+  while !ifaddr.is_null() && index == 0 {
+    let entry = unsafe { &*ifaddr };
+    if !entry.ifa_addr.is_null() {
+      let entry_addr = match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+        libc::AF_INET => {
+          let sin = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+          Some(IpAddr::V4(sin.sin_addr.s_addr.to_ne_bytes().into()))
+        },
+        libc::AF_INET6 => {
+          let sin6 = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+          Some(IpAddr::V6(sin6.sin6_addr.s6_addr.into()))
+        },
+        _ => None,
+      };
+      if entry_addr == Some(local_addr) {
+        index = unsafe { libc::if_nametoindex(entry.ifa_name) };
+      }
+    }
+    ifaddr = entry.ifa_next;
+  }
+  unsafe { libc::freeifaddrs(ifaddrs) };
+  if index == 0 {
+    return Err(Error::new(ErrorKind::NotFound, format!("no interface has the local address {}", local_addr)));
+  }
+  Ok(index)
+}
+
+/// Get the index of a network interface by name.
+pub fn interface_index(ifname: &str) -> std::io::Result<u32> {
+  let name = std::ffi::CString::new(ifname).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+  let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+  if index == 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(index)
+}
+
+/// Get the addresses assigned to the local interfaces, with v4-mapped addresses unmapped.
+pub fn local_addrs() -> std::io::Result<HashSet<IpAddr>> {
+  let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+  if unsafe { libc::getifaddrs(&mut ifaddrs) } < 0 {
+    return Err(Error::last_os_error());
+  }
+  let mut addrs = HashSet::new();
+  let mut ifaddr = ifaddrs;
+  while !ifaddr.is_null() {
+    let entry = unsafe { &*ifaddr };
+    if !entry.ifa_addr.is_null() {
+      match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+        libc::AF_INET => {
+          let sin = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+          addrs.insert(IpAddr::V4(sin.sin_addr.s_addr.to_ne_bytes().into()));
+        },
+        libc::AF_INET6 => {
+          let sin6 = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+          addrs.insert(from_ipv6_addr(sin6.sin6_addr.s6_addr.into()));
+        },
+        _ => {},
+      }
+    }
+    ifaddr = entry.ifa_next;
+  }
+  unsafe { libc::freeifaddrs(ifaddrs) };
+  Ok(addrs)
+}
+
+/// Address family of a raw IP socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SocketFamily {
+  /// AF_INET6 socket, reaching IPv4 peers through v4-mapped addresses.
+  #[default]
+  Inet6,
+  /// Native AF_INET socket for hosts where IPv6 is disabled. Only IPv4 peers are reachable.
+  Inet,
+}
+
 #[derive(Debug)]
 pub struct RawIpSocket {
   socket_fd: libc::c_int,
+  family: SocketFamily,
+  /// Whether this AF_INET6 socket rejects v4-mapped addresses (`IPV6_V6ONLY`).
+  v6only: bool,
+  /// Outbound scope (interface index) of link-local IPv6 peers.
+  scope_ids: RwLock<HashMap<Ipv6Addr, u32>>,
+}
+
+/// `struct group_source_req` from `<netinet/in.h>`, which is missing from `libc`.
+#[repr(C)]
+struct GroupSourceReq {
+  gsr_interface: u32,
+  gsr_group: libc::sockaddr_storage,
+  gsr_source: libc::sockaddr_storage,
+}
+
+/// Convert a `sockaddr_storage` filled in by the kernel to an `IpAddr`, unmapping v4-mapped addresses.
+fn sockaddr_storage_to_ip_addr(storage: &libc::sockaddr_storage) -> std::io::Result<IpAddr> {
+  match storage.ss_family as libc::c_int {
+    libc::AF_INET => {
+      let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+      Ok(IpAddr::V4(sin.sin_addr.s_addr.to_ne_bytes().into()))
+    },
+    libc::AF_INET6 => {
+      let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+      Ok(from_ipv6_addr(sin6.sin6_addr.s6_addr.into()))
+    },
+    _ => Err(Error::new(ErrorKind::InvalidData, "unexpected address family")),
+  }
+}
+
+/// Mask of the flow label in `sin6_flowinfo`.
+pub const IPV6_FLOW_LABEL_MASK: u32 = 0x000f_ffff;
+
+/// Build a `sockaddr_in6`, converting each field to the byte order the kernel expects:
+/// the family and the scope ID are in host byte order, the flow information in network
+/// byte order, and the address is a byte array in network order already.
+/// Only the low 20 bits of `flow_label` are used.
+pub fn ipv6_sockaddr(addr: &Ipv6Addr, flow_label: u32, scope_id: u32) -> libc::sockaddr_in6 {
+  let mut sin6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+  sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+  sin6.sin6_flowinfo = (flow_label & IPV6_FLOW_LABEL_MASK).to_be();
+  sin6.sin6_addr = libc::in6_addr {
+    s6_addr: addr.octets(),
+  };
+  sin6.sin6_scope_id = scope_id;
+  sin6
+}
+
+/// Convert an `IpAddr` to a `sockaddr_storage` of the matching family.
+/// IPv6 addresses get the given scope ID and no flow label.
+fn ip_addr_to_sockaddr_storage(addr: &IpAddr, scope_id: u32) -> libc::sockaddr_storage {
+  let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+  match addr {
+    IpAddr::V4(v4_addr) => {
+      let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+      // The family is in host byte order; the address octets are already in network order.
+      sin.sin_family = libc::AF_INET as libc::sa_family_t;
+      sin.sin_addr = libc::in_addr {
+        s_addr: u32::from_ne_bytes(v4_addr.octets()),
+      };
+    },
+    IpAddr::V6(v6_addr) => {
+      let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+      *sin6 = ipv6_sockaddr(v6_addr, 0, scope_id);
+    },
+  }
+  storage
+}
+
+/// Error payload of a received packet longer than the buffer, which was dropped
+/// rather than returned truncated. Get it back with `TruncatedPacket::from_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedPacket {
+  /// Length of the packet as sent, excluding the IP header.
+  pub len: usize,
+  /// Source address of the packet.
+  pub src_addr: IpAddr,
+}
+
+impl TruncatedPacket {
+  /// The truncated packet an error reports, if it does.
+  pub fn from_error(error: &Error) -> Option<Self> {
+    error.get_ref()?.downcast_ref::<Self>().copied()
+  }
+}
+
+impl std::fmt::Display for TruncatedPacket {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "packet of {} bytes from {} is longer than the receive buffer", self.len, self.src_addr)
+  }
+}
+
+impl std::error::Error for TruncatedPacket {}
+
+/// Error payload of a datagram dropped because the send queue of a transport was full,
+/// which is congestion rather than a failure of the path. Get it back with `QueueFull::from_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+impl QueueFull {
+  /// Whether an error reports a full send queue.
+  pub fn from_error(error: &Error) -> Option<Self> {
+    error.get_ref()?.downcast_ref::<Self>().copied()
+  }
+}
+
+impl std::fmt::Display for QueueFull {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "send queue is full")
+  }
+}
+
+impl std::error::Error for QueueFull {}
+
+impl From<QueueFull> for Error {
+  fn from(queue_full: QueueFull) -> Self {
+    Error::new(ErrorKind::WouldBlock, queue_full)
+  }
+}
+
+/// Error payload of a packet the kernel refused to send because it is larger than the
+/// path or the protocol allows (`EMSGSIZE`). Get it back with `TooLarge::from_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLarge {
+  /// Length of the packet, excluding the IP header.
+  pub len: usize,
+}
+
+impl TooLarge {
+  /// The oversized packet an error reports, if it does.
+  pub fn from_error(error: &Error) -> Option<Self> {
+    error.get_ref()?.downcast_ref::<Self>().copied()
+  }
+
+  /// Turn an `EMSGSIZE` error from sending `len` bytes into a `TooLarge` error,
+  /// passing other errors through.
+  pub fn map_send_error(error: Error, len: usize) -> Error {
+    match error.raw_os_error() {
+      Some(libc::EMSGSIZE) => TooLarge { len }.into(),
+      _ => error,
+    }
+  }
+}
+
+impl std::fmt::Display for TooLarge {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "packet of {} bytes is too large to send", self.len)
+  }
+}
+
+impl std::error::Error for TooLarge {}
+
+impl From<TooLarge> for Error {
+  fn from(too_large: TooLarge) -> Self {
+    Error::new(ErrorKind::InvalidInput, too_large)
+  }
+}
+
+/// What is known about how a packet was received, besides its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecvInfo {
+  /// Traffic class (DSCP and ECN) of the packet.
+  pub tclass: Option<u8>,
+  /// Index of the interface the packet arrived on, known after `set_recv_pktinfo(true)`.
+  pub ifindex: Option<u32>,
+  /// Destination address of the packet before any redirection (e.g. TPROXY),
+  /// known after `set_recv_origdstaddr(true)`.
+  pub orig_dst: Option<IpAddr>,
+}
+
+impl From<TruncatedPacket> for Error {
+  fn from(truncated: TruncatedPacket) -> Self {
+    Error::new(ErrorKind::InvalidData, truncated)
+  }
+}
+
+/// Whether a socket error means the socket itself is unusable, as opposed to
+/// a transient condition affecting a single packet.
+pub fn is_fatal_socket_error(error: &Error) -> bool {
+  matches!(error.raw_os_error(), Some(libc::EBADF) | Some(libc::ENOTSOCK) | Some(libc::EFAULT))
 }
 
 /// Configuration for Path MTU Discovery (PMTUD) for an `IpSocket`.
@@ -59,14 +421,87 @@ pub enum FragmentConfig {
 }
 
 impl RawIpSocket {
-  fn new_raw(proto: libc::c_int) -> std::io::Result<Self> {
-    let socket_fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_RAW | libc::SOCK_NONBLOCK, proto) };
+  fn new_raw(family: SocketFamily, proto: libc::c_int) -> std::io::Result<Self> {
+    let domain = match family {
+      SocketFamily::Inet6 => libc::AF_INET6,
+      SocketFamily::Inet => libc::AF_INET,
+    };
+    let socket_fd = unsafe { libc::socket(domain, libc::SOCK_RAW | libc::SOCK_NONBLOCK, proto) };
     if socket_fd < 0 {
-      return Err(Error::last_os_error());
+      return Err(caps::explain_permission_error(Error::last_os_error(), caps::Capability::NetRaw, "open a raw IP socket"));
     }
-    Ok(Self {
+    let mut socket = Self {
       socket_fd,
-    })
+      family,
+      v6only: false,
+      scope_ids: RwLock::new(HashMap::new()),
+    };
+    // The default follows the net.ipv6.bindv6only sysctl. It cannot be changed here:
+    // Linux refuses IPV6_V6ONLY on raw sockets, which are bound to their protocol.
+    if family == SocketFamily::Inet6 {
+      socket.v6only = socket.getsockopt::<libc::c_int>(libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)? != 0;
+    }
+    Ok(socket)
+  }
+
+  pub fn family(&self) -> SocketFamily {
+    self.family
+  }
+
+  /// Whether IPv4 peers are unreachable because this AF_INET6 socket is IPv6-only.
+  pub fn is_v6only(&self) -> bool {
+    self.v6only
+  }
+
+  /// Replace the outbound scopes of link-local IPv6 peers.
+  pub fn set_scope_ids(&self, scope_ids: HashMap<Ipv6Addr, u32>) {
+    *self.scope_ids.write() = scope_ids;
+  }
+
+  /// Socket address of a peer in this socket's address family.
+  fn peer_sockaddr(&self, addr: &IpAddr) -> std::io::Result<(libc::sockaddr_storage, libc::socklen_t)> {
+    match (self.family, addr) {
+      (SocketFamily::Inet6, _) => {
+        let v6_addr = to_ipv6_addr(*addr);
+        // Checked here because the kernel only reports a bare EINVAL or ENETUNREACH.
+        if self.v6only && is_ipv4_mapped(&v6_addr) {
+          return Err(ipv4_mapped_unreachable(&v6_addr));
+        }
+        let scope_id = match v6_addr.is_unicast_link_local() {
+          true => self.scope_ids.read().get(&v6_addr).copied()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "link-local destination without a scope"))?,
+          false => 0,
+        };
+        Ok((ip_addr_to_sockaddr_storage(&IpAddr::V6(v6_addr), scope_id), std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t))
+      },
+      (SocketFamily::Inet, IpAddr::V4(_)) => Ok((ip_addr_to_sockaddr_storage(addr, 0), std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)),
+      (SocketFamily::Inet, IpAddr::V6(v6_addr)) => match v6_addr.to_ipv4_mapped() {
+        Some(v4_addr) => self.peer_sockaddr(&IpAddr::V4(v4_addr)),
+        None => Err(Error::new(ErrorKind::InvalidInput, "IPv6 destination on an AF_INET socket")),
+      },
+    }
+  }
+
+  /// Socket address to bind this socket to. Unlike peers, local addresses are never
+  /// v4-mapped: raw AF_INET6 sockets cannot bind IPv4 addresses.
+  fn local_sockaddr(&self, addr: &IpAddr) -> std::io::Result<(libc::sockaddr_storage, libc::socklen_t)> {
+    let addr = match addr {
+      IpAddr::V6(v6_addr) => v6_addr.to_ipv4_mapped().map_or(*addr, IpAddr::V4),
+      IpAddr::V4(_) => *addr,
+    };
+    match (self.family, addr) {
+      (SocketFamily::Inet6, IpAddr::V6(v6_addr)) if v6_addr.is_unicast_link_local() => {
+        Err(Error::new(ErrorKind::InvalidInput, format!("cannot bind the link-local address {} without an interface", v6_addr)))
+      },
+      (SocketFamily::Inet6, IpAddr::V6(_)) => Ok((ip_addr_to_sockaddr_storage(&addr, 0), std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)),
+      (SocketFamily::Inet6, IpAddr::V4(v4_addr)) => {
+        Err(Error::new(ErrorKind::InvalidInput, format!("cannot bind the IPv4 address {} to an AF_INET6 socket", v4_addr)))
+      },
+      (SocketFamily::Inet, IpAddr::V4(_)) => Ok((ip_addr_to_sockaddr_storage(&addr, 0), std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)),
+      (SocketFamily::Inet, IpAddr::V6(v6_addr)) => {
+        Err(Error::new(ErrorKind::InvalidInput, format!("cannot bind the IPv6 address {} to an AF_INET socket", v6_addr)))
+      },
+    }
   }
 
   /// NO-OP because it is not supported.
@@ -88,19 +523,102 @@ impl RawIpSocket {
     // }
   }
 
+  /// Whether multicast packets sent by this socket are looped back to local listeners,
+  /// including this socket itself (`IPV6_MULTICAST_LOOP`, or `IP_MULTICAST_LOOP` on AF_INET sockets).
+  /// The kernel enables it by default.
+  pub fn set_multicast_loop(&self, enable: bool) -> std::io::Result<()> {
+    match self.family {
+      SocketFamily::Inet6 => self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_LOOP, &(enable as libc::c_int)),
+      SocketFamily::Inet => self.setsockopt(libc::IPPROTO_IP, libc::IP_MULTICAST_LOOP, &(enable as libc::c_int)),
+    }
+  }
+
+  /// Attach a classic BPF program, replacing any attached one.
+  pub fn attach_filter(&self, program: &[libc::sock_filter]) -> std::io::Result<()> {
+    let len = program.len().try_into().map_err(|_| Error::new(ErrorKind::InvalidInput, "BPF program too long"))?;
+    let fprog = libc::sock_fprog {
+      len,
+      filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    self.setsockopt(libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)
+  }
+
+  /// Detach the attached BPF program. Succeeds if none is attached.
+  pub fn detach_filter(&self) -> std::io::Result<()> {
+    let unused: libc::c_int = 0;
+    match self.setsockopt(libc::SOL_SOCKET, libc::SO_DETACH_FILTER, &unused) {
+      Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+      result => result,
+    }
+  }
+
+  fn setsockopt<T>(&self, level: libc::c_int, name: libc::c_int, value: &T) -> std::io::Result<()> {
+    let len = std::mem::size_of::<T>() as libc::socklen_t;
+    unsafe {
+      if libc::setsockopt(self.socket_fd, level, name, value as *const T as *const libc::c_void, len) < 0 {
+        return Err(Error::last_os_error());
+      }
+    }
+    Ok(())
+  }
+
+  fn getsockopt<T: Copy>(&self, level: libc::c_int, name: libc::c_int) -> std::io::Result<T> {
+    let mut value = std::mem::MaybeUninit::<T>::zeroed();
+    let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+    unsafe {
+      if libc::getsockopt(self.socket_fd, level, name, value.as_mut_ptr() as *mut libc::c_void, &mut len) < 0 {
+        return Err(Error::last_os_error());
+      }
+      Ok(value.assume_init())
+    }
+  }
+
+  fn source_group_request(&self, name: libc::c_int, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    let level = match (group, source) {
+      (IpAddr::V4(_), IpAddr::V4(_)) => libc::IPPROTO_IP,
+      (IpAddr::V6(_), IpAddr::V6(_)) => libc::IPPROTO_IPV6,
+      _ => return Err(Error::new(ErrorKind::InvalidInput, "multicast group and source must be of the same address family")),
+    };
+    if !group.is_multicast() {
+      return Err(Error::new(ErrorKind::InvalidInput, "not a multicast group address"));
+    }
+    let request = GroupSourceReq {
+      gsr_interface: ifindex,
+      gsr_group: ip_addr_to_sockaddr_storage(group, 0),
+      gsr_source: ip_addr_to_sockaddr_storage(source, 0),
+    };
+    self.setsockopt(level, name, &request)
+  }
+
+  /// Join a source-specific multicast group (`MCAST_JOIN_SOURCE_GROUP`).
+  /// An `ifindex` of 0 lets the kernel choose the interface.
+  /// IPv4 groups are only accepted by the kernel on `SocketFamily::Inet` sockets.
+  pub fn join_ssm(&self, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    self.source_group_request(libc::MCAST_JOIN_SOURCE_GROUP, group, source, ifindex)
+  }
+
+  /// Leave a source-specific multicast group (`MCAST_LEAVE_SOURCE_GROUP`).
+  pub fn leave_ssm(&self, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    self.source_group_request(libc::MCAST_LEAVE_SOURCE_GROUP, group, source, ifindex)
+  }
+
+  /// Bind to the unspecified address of the socket's family.
+  /// Raw sockets have no ports and the kernel lets any number of them bind the same
+  /// address, so a restarted daemon never finds it in use and `SO_REUSEADDR` is not needed.
   fn bind_unspecified(&self) -> std::io::Result<()> {
-    let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
-    addr.sin6_family = libc::AF_INET6 as u16;
-    addr.sin6_port = 0;
-    addr.sin6_flowinfo = 0;
-    addr.sin6_addr = libc::in6_addr {
-      s6_addr: [0; 16],
+    let unspecified = match self.family {
+      SocketFamily::Inet6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+      SocketFamily::Inet => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
     };
-    addr.sin6_scope_id = 0;
+    self.bind(&unspecified)
+  }
 
-    let addr_len = std::mem::size_of_val(&addr) as u32;
+  /// Bind to a local address, which becomes the source address of sent packets.
+  /// Only packets sent to it are received.
+  fn bind(&self, addr: &IpAddr) -> std::io::Result<()> {
+    let (addr, addr_len) = self.local_sockaddr(addr)?;
     unsafe {
-      if libc::bind(self.socket_fd, &addr as *const libc::sockaddr_in6 as *const libc::sockaddr, addr_len) < 0 {
+      if libc::bind(self.socket_fd, &addr as *const libc::sockaddr_storage as *const libc::sockaddr, addr_len) < 0 {
         return Err(Error::last_os_error());
       }
       Ok(())
@@ -108,49 +626,220 @@ impl RawIpSocket {
   }
 
   pub fn new(proto: libc::c_int) -> std::io::Result<Self> {
-    let socket = Self::new_raw(proto)?;
+    Self::new_with_family(SocketFamily::Inet6, proto)
+  }
+
+  pub fn new_with_family(family: SocketFamily, proto: libc::c_int) -> std::io::Result<Self> {
+    let socket = Self::new_raw(family, proto)?;
     socket.set_mtu_discovery(&FragmentConfig::Fragment)?;
     socket.bind_unspecified()?;
     Ok(socket)
   }
 
+  /// Create a socket bound to `addr` instead of the unspecified address.
+  pub fn new_bound(family: SocketFamily, proto: libc::c_int, addr: &IpAddr) -> std::io::Result<Self> {
+    let socket = Self::new_raw(family, proto)?;
+    socket.set_mtu_discovery(&FragmentConfig::Fragment)?;
+    socket.bind(addr)?;
+    Ok(socket)
+  }
+
   pub fn new_with_fragment_config(proto: libc::c_int, fragment_config: FragmentConfig) -> std::io::Result<Self> {
-    let socket = Self::new_raw(proto)?;
+    let socket = Self::new_raw(SocketFamily::Inet6, proto)?;
     socket.set_mtu_discovery(&fragment_config)?;
     socket.bind_unspecified()?;
     Ok(socket)
   }
 
-  fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, libc::sockaddr_in6)> {
-    let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
-    let addr_len = std::mem::size_of_val(&addr) as u32;
-    let n = unsafe {
-      libc::recvfrom(
-        self.socket_fd,
-        buf.as_mut_ptr() as *mut libc::c_void,
-        buf.len(),
-        0,
-        &mut addr as *mut libc::sockaddr_in6 as *mut libc::sockaddr,
-        &addr_len as *const u32 as *mut u32
-      )
+  /// Report the traffic class of received packets (`IPV6_RECVTCLASS`).
+  /// AF_INET sockets always know it from the IPv4 header they receive.
+  pub fn set_recv_tclass(&self, enable: bool) -> std::io::Result<()> {
+    match self.family {
+      SocketFamily::Inet6 => self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, &(enable as libc::c_int)),
+      SocketFamily::Inet => Ok(()),
+    }
+  }
+
+  /// Report the arrival interface of received packets (`IP_PKTINFO` or `IPV6_RECVPKTINFO`).
+  pub fn set_recv_pktinfo(&self, enable: bool) -> std::io::Result<()> {
+    match self.family {
+      SocketFamily::Inet6 => self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, &(enable as libc::c_int)),
+      SocketFamily::Inet => self.setsockopt(libc::IPPROTO_IP, libc::IP_PKTINFO, &(enable as libc::c_int)),
+    }
+  }
+
+  /// Report the original destination address of received packets (`IP_RECVORIGDSTADDR`
+  /// or `IPV6_RECVORIGDSTADDR`), for transparent termination behind policy routing.
+  pub fn set_recv_origdstaddr(&self, enable: bool) -> std::io::Result<()> {
+    match self.family {
+      SocketFamily::Inet6 => self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_RECVORIGDSTADDR, &(enable as libc::c_int)),
+      SocketFamily::Inet => self.setsockopt(libc::IPPROTO_IP, libc::IP_RECVORIGDSTADDR, &(enable as libc::c_int)),
+    }
+  }
+
+  /// Local address the socket is bound to, unmapped; unspecified unless bound with `new_bound`.
+  pub fn local_addr(&self) -> std::io::Result<IpAddr> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut addr_len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    unsafe {
+      if libc::getsockname(self.socket_fd, &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut addr_len) < 0 {
+        return Err(Error::last_os_error());
+      }
+    }
+    sockaddr_storage_to_ip_addr(&addr)
+  }
+
+  /// Receive a packet with its traffic class, arrival interface and original destination, if known.
+  /// AF_INET raw sockets return the IPv4 header too; it is stripped here.
+  /// Packets longer than `buf` fail with a `TruncatedPacket` error.
+  fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, libc::sockaddr_storage, RecvInfo)> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+      iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+      iov_len: buf.len(),
     };
+    let mut control = [0u64; 24];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control);
+    // With MSG_TRUNC, the real length of a packet is returned even if it does not fit.
+    let n = unsafe { libc::recvmsg(self.socket_fd, &mut msg, libc::MSG_TRUNC) };
     if n < 0 {
       return Err(Error::last_os_error());
     }
-    Ok((n as usize, addr))
+    if n as usize > buf.len() {
+      let header_len = match self.family {
+        SocketFamily::Inet => buf.first().map_or(0, |first| ((first & 0x0f) as usize) * 4),
+        SocketFamily::Inet6 => 0,
+      };
+      return Err(TruncatedPacket {
+        len: n as usize - header_len,
+        src_addr: sockaddr_storage_to_ip_addr(&addr)?,
+      }.into());
+    }
+
+    let mut info = RecvInfo::default();
+    unsafe {
+      let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+      while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+          (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+            info.tclass = Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as u8);
+          },
+          (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+            info.ifindex = Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo).ipi6_ifindex);
+          },
+          (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+            info.ifindex = Some(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo).ipi_ifindex as u32);
+          },
+          (libc::IPPROTO_IPV6, libc::IPV6_ORIGDSTADDR) | (libc::IPPROTO_IP, libc::IP_ORIGDSTADDR) => {
+            let mut orig_dst: libc::sockaddr_storage = std::mem::zeroed();
+            let len = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize).min(std::mem::size_of_val(&orig_dst));
+            std::ptr::copy_nonoverlapping(libc::CMSG_DATA(cmsg), &mut orig_dst as *mut libc::sockaddr_storage as *mut u8, len);
+            info.orig_dst = sockaddr_storage_to_ip_addr(&orig_dst).ok();
+          },
+          _ => {},
+        }
+        cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+      }
+    }
+
+    let mut n = n as usize;
+    if self.family == SocketFamily::Inet {
+      let (len, tos) = strip_ipv4_header(buf, n)?;
+      info.tclass = Some(tos);
+      n = len;
+    }
+    Ok((n, addr, info))
+  }
+
+  /// Send a packet with the given traffic class (`IPV6_TCLASS` or `IP_TOS` ancillary data).
+  fn send_to_with_tclass(&self, buf: &[u8], addr: &IpAddr, tclass: u8) -> std::io::Result<usize> {
+    let (level, name) = match self.family {
+      SocketFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+      SocketFamily::Inet => (libc::IPPROTO_IP, libc::IP_TOS),
+    };
+    self.send_to_with_control(buf, addr, level, name, tclass as libc::c_int)
+  }
+
+  fn send_to_with_hoplimit(&self, buf: &[u8], addr: &IpAddr, hoplimit: u8) -> std::io::Result<usize> {
+    let (level, name) = match self.family {
+      SocketFamily::Inet6 => (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT),
+      SocketFamily::Inet => (libc::IPPROTO_IP, libc::IP_TTL),
+    };
+    self.send_to_with_control(buf, addr, level, name, hoplimit as libc::c_int)
+  }
+
+  /// Send a packet with one integer control message, which applies to this packet only.
+  fn send_to_with_control(&self, buf: &[u8], addr: &IpAddr, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> std::io::Result<usize> {
+    let (mut addr, addr_len) = self.peer_sockaddr(addr)?;
+    let mut iov = libc::iovec {
+      iov_base: buf.as_ptr() as *mut libc::c_void,
+      iov_len: buf.len(),
+    };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = addr_len;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    unsafe {
+      let value_len = std::mem::size_of::<libc::c_int>() as u32;
+      msg.msg_controllen = libc::CMSG_SPACE(value_len) as usize;
+      let cmsg = libc::CMSG_FIRSTHDR(&msg);
+      (*cmsg).cmsg_level = level;
+      (*cmsg).cmsg_type = name;
+      (*cmsg).cmsg_len = libc::CMSG_LEN(value_len) as usize;
+      std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, value);
+    }
+    let n = unsafe { libc::sendmsg(self.socket_fd, &msg, 0) };
+    if n < 0 {
+      return Err(TooLarge::map_send_error(Error::last_os_error(), buf.len()));
+    }
+    Ok(n as usize)
   }
 
-  fn send_to(&self, buf: &[u8], addr: &libc::sockaddr_in6) -> std::io::Result<usize> {
+  fn send_to(&self, buf: &[u8], addr: &IpAddr) -> std::io::Result<usize> {
+    let (addr, addr_len) = self.peer_sockaddr(addr)?;
     let n = unsafe {
       libc::sendto(
         self.socket_fd,
         buf.as_ptr() as *const libc::c_void,
         buf.len(),
         0,
-        addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
-        std::mem::size_of_val(addr) as u32
+        &addr as *const libc::sockaddr_storage as *const libc::sockaddr,
+        addr_len
       )
     };
+    if n < 0 {
+      return Err(TooLarge::map_send_error(Error::last_os_error(), buf.len()));
+    }
+    Ok(n as usize)
+  }
+}
+
+impl RawIpSocket {
+  /// Send up to `UIO_MAXIOV` packets with a single `sendmmsg` call, returning the number sent.
+  fn send_many(&self, messages: &[(&[u8], &(libc::sockaddr_storage, libc::socklen_t))]) -> std::io::Result<usize> {
+    let messages = &messages[..messages.len().min(libc::UIO_MAXIOV as usize)];
+    let mut iovecs: Vec<libc::iovec> = messages.iter().map(|(buf, _)| libc::iovec {
+      iov_base: buf.as_ptr() as *mut libc::c_void,
+      iov_len: buf.len(),
+    }).collect();
+    let mut headers: Vec<libc::mmsghdr> = messages.iter().zip(iovecs.iter_mut()).map(|((_, addr), iov)| {
+      let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+      header.msg_hdr.msg_name = &addr.0 as *const libc::sockaddr_storage as *mut libc::c_void;
+      header.msg_hdr.msg_namelen = addr.1;
+      header.msg_hdr.msg_iov = iov as *mut libc::iovec;
+      header.msg_hdr.msg_iovlen = 1;
+      header
+    }).collect();
+    let n = unsafe { libc::sendmmsg(self.socket_fd, headers.as_mut_ptr(), headers.len() as libc::c_uint, 0) };
     if n < 0 {
       return Err(Error::last_os_error());
     }
@@ -172,6 +861,69 @@ impl Drop for RawIpSocket {
   }
 }
 
+/// Shortest IPv4 header, without options.
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+
+/// Remove the IPv4 header that AF_INET raw sockets receive in front of the first `n` bytes
+/// of `buf`, returning the length of the payload and the TOS byte of the header.
+/// Nothing but the header length is read until it is known to be valid.
+fn strip_ipv4_header(buf: &mut [u8], n: usize) -> std::io::Result<(usize, u8)> {
+  let header_len = buf.first().map_or(0, |first| ((first & 0x0f) as usize) * 4);
+  if header_len < IPV4_MIN_HEADER_SIZE || n < header_len {
+    return Err(Error::new(ErrorKind::InvalidData, "truncated IPv4 header"));
+  }
+  let tos = buf[1];
+  buf.copy_within(header_len..n, 0);
+  Ok((n - header_len, tos))
+}
+
+/// Consecutive `WouldBlock`s after which an I/O loop yields to other tasks.
+const WOULD_BLOCK_YIELD_INTERVAL: u32 = 16;
+
+/// Consecutive `WouldBlock`s after which the readiness of a socket is considered broken.
+const MAX_WOULD_BLOCK_RETRIES: u32 = 1024;
+
+/// Bounds the retries of an I/O loop whose socket reports readiness but keeps failing
+/// with `WouldBlock`, which would otherwise spin without ever returning.
+#[derive(Debug, Default)]
+struct WouldBlockRetries {
+  count: u32,
+}
+
+impl WouldBlockRetries {
+  /// Record a `WouldBlock`, yielding now and then and failing once the limit is reached.
+  async fn would_block(&mut self) -> std::io::Result<()> {
+    self.count += 1;
+    if self.count >= MAX_WOULD_BLOCK_RETRIES {
+      return Err(Error::new(ErrorKind::WouldBlock, "socket keeps reporting readiness without completing I/O"));
+    }
+    if self.count.is_multiple_of(WOULD_BLOCK_YIELD_INTERVAL) {
+      tokio::task::yield_now().await;
+    }
+    Ok(())
+  }
+
+  /// Start counting again after some I/O completed.
+  fn reset(&mut self) {
+    self.count = 0;
+  }
+
+  /// Wait for readiness with `ready` and try `io` on the guard it returns, again while
+  /// `io` reports `WouldBlock` by returning `None`.
+  async fn retry<G, T, R>(ready: impl Fn() -> R, mut io: impl FnMut(G) -> Option<std::io::Result<T>>) -> std::io::Result<T>
+  where
+    R: std::future::Future<Output = std::io::Result<G>>,
+  {
+    let mut retries = Self::default();
+    loop {
+      match io(ready().await?) {
+        Some(result) => return result,
+        None => retries.would_block().await?,
+      }
+    }
+  }
+}
+
 pub trait IpProtocol {
   fn protocol_number(&self) -> libc::c_int;
 }
@@ -188,7 +940,7 @@ impl IpProtocol for libc::c_uint {
   }
 }
 
-/// Raw IPv4/IPv6 dualstack socket backed by AF_INET6.
+/// Raw IPv4/IPv6 dualstack socket backed by AF_INET6 (or IPv4-only with `SocketFamily::Inet`).
 /// Large packets are fragmented by the kernel by default.
 /// There is no need to `split` the `IpSocket` into a reader and a writer,
 /// because it does not need to borrow self mutably to call `recv_from` and `send_to`.
@@ -207,7 +959,20 @@ where
   P: IpProtocol,
 {
   pub fn new(protocol: P) -> std::io::Result<Self> {
-    let socket = RawIpSocket::new(protocol.protocol_number())?;
+    Self::new_with_family(protocol, SocketFamily::Inet6)
+  }
+
+  pub fn new_with_family(protocol: P, family: SocketFamily) -> std::io::Result<Self> {
+    let socket = RawIpSocket::new_with_family(family, protocol.protocol_number())?;
+    Ok(Self {
+      inner: AsyncFd::with_interest(socket, Interest::READABLE | Interest::WRITABLE)?,
+      protocol,
+    })
+  }
+
+  /// Create a socket bound to the local address `addr`, the source address of sent packets.
+  pub fn new_bound(protocol: P, family: SocketFamily, addr: &IpAddr) -> std::io::Result<Self> {
+    let socket = RawIpSocket::new_bound(family, protocol.protocol_number(), addr)?;
     Ok(Self {
       inner: AsyncFd::with_interest(socket, Interest::READABLE | Interest::WRITABLE)?,
       protocol,
@@ -222,71 +987,178 @@ where
     })
   }
 
+  /// Replace the outbound scopes (interface indices) of link-local IPv6 peers.
+  pub fn set_scope_ids(&self, scope_ids: HashMap<Ipv6Addr, u32>) {
+    self.inner.get_ref().set_scope_ids(scope_ids);
+  }
+
+  /// Attach a classic BPF program, replacing any attached one.
+  pub fn attach_filter(&self, program: &[libc::sock_filter]) -> std::io::Result<()> {
+    self.inner.get_ref().attach_filter(program)
+  }
+
+  /// Detach the attached BPF program, if any.
+  pub fn detach_filter(&self) -> std::io::Result<()> {
+    self.inner.get_ref().detach_filter()
+  }
+
   pub fn protocol(&self) -> libc::c_int {
     self.protocol.protocol_number()
   }
 
-  async fn recv_from_raw(&self, buf: &mut [u8]) -> std::io::Result<(usize, libc::sockaddr_in6)> {
-    loop {
-      let mut guard = self.inner.readable().await?;
-      match guard.try_io(|inner| inner.get_ref().recv_from(buf)) {
-        Ok(result) => return result,
-        Err(_would_block) => continue,
-      }
-    }
+  pub fn family(&self) -> SocketFamily {
+    self.inner.get_ref().family()
+  }
+
+  /// Whether IPv4 peers are unreachable because this AF_INET6 socket is IPv6-only.
+  pub fn is_v6only(&self) -> bool {
+    self.inner.get_ref().is_v6only()
+  }
+
+  /// Join a source-specific multicast group.
+  pub fn join_ssm(&self, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    self.inner.get_ref().join_ssm(group, source, ifindex)
+  }
+
+  /// Leave a source-specific multicast group.
+  pub fn leave_ssm(&self, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    self.inner.get_ref().leave_ssm(group, source, ifindex)
+  }
+
+  async fn recv_from_raw(&self, buf: &mut [u8]) -> std::io::Result<(usize, libc::sockaddr_storage, RecvInfo)> {
+    WouldBlockRetries::retry(|| self.inner.readable(), |mut guard| guard.try_io(|inner| inner.get_ref().recv_from(buf)).ok()).await
   }
 
   pub async fn recv_from_ipv6(&self, buf: &mut [u8]) -> std::io::Result<(usize, Ipv6Addr)> {
-    let (n, addr) = self.recv_from_raw(buf).await?;
-    Ok((n, addr.sin6_addr.s6_addr.into()))
+    let (n, addr) = self.recv_from(buf).await?;
+    Ok((n, to_ipv6_addr(addr)))
   }
 
   pub async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, IpAddr)> {
-    let (n, addr) = self.recv_from_ipv6(buf).await?;
-    Ok((n, from_ipv6_addr(addr)))
+    let (n, addr, _) = self.recv_from_raw(buf).await?;
+    Ok((n, sockaddr_storage_to_ip_addr(&addr)?))
   }
 
-  async fn send_to_raw(&self, buf: &[u8], addr: &libc::sockaddr_in6) -> std::io::Result<usize> {
-    loop {
-      let mut guard = self.inner.writable().await?;
-      match guard.try_io(|inner| inner.get_ref().send_to(buf, addr)) {
-        Ok(result) => return result,
-        Err(_would_block) => continue,
-      }
-    }
+  /// Receive a packet along with its traffic class.
+  /// The traffic class of IPv6 packets is only known after `set_recv_tclass(true)`.
+  pub async fn recv_from_with_tclass(&self, buf: &mut [u8]) -> std::io::Result<(usize, IpAddr, Option<u8>)> {
+    let (n, addr, info) = self.recv_from_raw(buf).await?;
+    Ok((n, sockaddr_storage_to_ip_addr(&addr)?, info.tclass))
   }
 
-  pub async fn send_to_ipv6(&self, buf: &[u8], addr: &Ipv6Addr) -> std::io::Result<usize> {
-    let in6_addr = libc::in6_addr {
-      s6_addr: addr.octets(),
-    };
-    let addr = libc::sockaddr_in6 {
-      sin6_family: libc::AF_INET6 as u16,
-      sin6_port: 0,
-      sin6_flowinfo: 0,
-      sin6_addr: in6_addr,
-      sin6_scope_id: 0,
-    };
-    self.send_to_raw(buf, &addr).await
+  /// Receive a packet along with its traffic class and arrival interface.
+  pub async fn recv_from_with_info(&self, buf: &mut [u8]) -> std::io::Result<(usize, IpAddr, RecvInfo)> {
+    let (n, addr, info) = self.recv_from_raw(buf).await?;
+    Ok((n, sockaddr_storage_to_ip_addr(&addr)?, info))
   }
 
-  pub async fn send_to(&self, buf: &[u8], addr: &IpAddr) -> std::io::Result<usize> {
-    self.send_to_ipv6(buf, &to_ipv6_addr(*addr)).await
+  /// Report the traffic class of received packets.
+  pub fn set_recv_tclass(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.get_ref().set_recv_tclass(enable)
   }
-}
 
-/// EtherIP protocol
-#[derive(Debug)]
-pub struct EtherIp ();
+  /// Report the arrival interface of received packets.
+  pub fn set_recv_pktinfo(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.get_ref().set_recv_pktinfo(enable)
+  }
 
-impl IpProtocol for EtherIp {
-  fn protocol_number(&self) -> libc::c_int {
-    PROTO_ETHERIP
+  /// Report the original destination address of received packets.
+  pub fn set_recv_origdstaddr(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.get_ref().set_recv_origdstaddr(enable)
   }
-}
 
-/// EtherIP socket
-/// Large packets are fragmented by the kernel by default.
+  /// Local address the socket is bound to.
+  pub fn local_addr(&self) -> std::io::Result<IpAddr> {
+    self.inner.get_ref().local_addr()
+  }
+
+  /// Loop sent multicast packets back to local listeners.
+  pub fn set_multicast_loop(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.get_ref().set_multicast_loop(enable)
+  }
+
+  /// Send a packet with the given traffic class (DSCP and ECN bits).
+  pub async fn send_to_with_tclass(&self, buf: &[u8], addr: &IpAddr, tclass: u8) -> std::io::Result<usize> {
+    WouldBlockRetries::retry(|| self.inner.writable(), |mut guard| guard.try_io(|inner| inner.get_ref().send_to_with_tclass(buf, addr, tclass)).ok()).await
+  }
+
+  /// Send a packet with the given hop limit (TTL for IPv4), without changing the socket's default.
+  pub async fn send_to_with_hoplimit(&self, buf: &[u8], addr: &IpAddr, hoplimit: u8) -> std::io::Result<usize> {
+    WouldBlockRetries::retry(|| self.inner.writable(), |mut guard| guard.try_io(|inner| inner.get_ref().send_to_with_hoplimit(buf, addr, hoplimit)).ok()).await
+  }
+
+  async fn send_to_raw(&self, buf: &[u8], addr: &IpAddr) -> std::io::Result<usize> {
+    WouldBlockRetries::retry(|| self.inner.writable(), |mut guard| guard.try_io(|inner| inner.get_ref().send_to(buf, addr)).ok()).await
+  }
+
+  pub async fn send_to_ipv6(&self, buf: &[u8], addr: &Ipv6Addr) -> std::io::Result<usize> {
+    self.send_to_raw(buf, &IpAddr::V6(*addr)).await
+  }
+
+  pub async fn send_to(&self, buf: &[u8], addr: &IpAddr) -> std::io::Result<usize> {
+    self.send_to_raw(buf, addr).await
+  }
+
+  /// Send several packets with as few `sendmmsg` calls as possible.
+  /// Returns one result per packet; a failing packet does not prevent the following ones from being sent.
+  pub async fn send_many(&self, packets: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    let socket = self.inner.get_ref();
+    let addrs: Vec<std::io::Result<(libc::sockaddr_storage, libc::socklen_t)>> = packets.iter().map(|(_, addr)| socket.peer_sockaddr(addr)).collect();
+    // A packet whose destination cannot be used fails on its own, without holding back the others.
+    let messages: Vec<(&[u8], &(libc::sockaddr_storage, libc::socklen_t))> = packets.iter().zip(addrs.iter())
+      .filter_map(|((buf, _), addr)| Some((*buf, addr.as_ref().ok()?)))
+      .collect();
+    let mut sent = self.send_messages(&messages).await?.into_iter();
+    Ok(addrs.into_iter().map(|addr| match addr {
+      Ok(_) => sent.next().unwrap_or_else(|| Err(Error::from(ErrorKind::Other))),
+      Err(e) => Err(e),
+    }).collect())
+  }
+
+  async fn send_messages(&self, messages: &[(&[u8], &(libc::sockaddr_storage, libc::socklen_t))]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    let mut results = Vec::with_capacity(messages.len());
+    let mut retries = WouldBlockRetries::default();
+    while results.len() < messages.len() {
+      let mut guard = self.inner.writable().await?;
+      let remaining = &messages[results.len()..];
+      match guard.try_io(|inner| inner.get_ref().send_many(remaining)) {
+        Ok(Ok(sent)) => {
+          results.extend(remaining[..sent].iter().map(|(buf, _)| Ok(buf.len())));
+          retries.reset();
+        },
+        // The first remaining packet failed; record it and carry on with the rest.
+        Ok(Err(e)) => {
+          results.push(Err(TooLarge::map_send_error(e, remaining[0].0.len())));
+          retries.reset();
+        },
+        Err(_would_block) => retries.would_block().await?,
+      }
+    }
+    Ok(results)
+  }
+}
+
+impl<P> AsRawFd for IpSocket<P>
+where
+  P: IpProtocol,
+{
+  fn as_raw_fd(&self) -> libc::c_int {
+    self.inner.get_ref().as_raw_fd()
+  }
+}
+
+/// EtherIP protocol
+#[derive(Debug)]
+pub struct EtherIp ();
+
+impl IpProtocol for EtherIp {
+  fn protocol_number(&self) -> libc::c_int {
+    PROTO_ETHERIP
+  }
+}
+
+/// EtherIP socket
+/// Large packets are fragmented by the kernel by default.
 /// There is no need to `split` the `IpSocket` into a reader and a writer,
 /// because it does not need to borrow self mutably to call `recv_from` and `send_to`.
 /// Just wrap it in `Arc` and clone it.
@@ -304,22 +1176,155 @@ impl EtherIpSocket {
     })
   }
 
-  /// Create a new EtherIP socket from a raw socket.
-  pub fn from(socket: IpSocket<EtherIp>) -> Self {
+  /// Create a new EtherIP socket of the given address family.
+  pub fn new_with_family(family: SocketFamily) -> std::io::Result<Self> {
+    let inner = IpSocket::new_with_family(EtherIp (), family)?;
+    Ok(Self {
+      inner,
+    })
+  }
+
+  /// Create a new EtherIP socket bound to the local address `addr`, which becomes the
+  /// source address of sent datagrams. Only datagrams sent to it are received.
+  pub fn new_bound(family: SocketFamily, addr: &IpAddr) -> std::io::Result<Self> {
+    let inner = IpSocket::new_bound(EtherIp (), family, addr)?;
+    Ok(Self {
+      inner,
+    })
+  }
+
+  pub fn family(&self) -> SocketFamily {
+    self.inner.family()
+  }
+
+  /// Whether IPv4 peers are unreachable because this AF_INET6 socket is IPv6-only.
+  pub fn is_v6only(&self) -> bool {
+    self.inner.is_v6only()
+  }
+
+  /// Wrap an EtherIP `IpSocket` that was created and configured separately,
+  /// e.g. with socket options set through its file descriptor.
+  pub fn from_socket(socket: IpSocket<EtherIp>) -> Self {
     Self {
       inner: socket,
     }
   }
 
+  /// Create a new EtherIP socket from a raw socket. Same as `from_socket`.
+  pub fn from(socket: IpSocket<EtherIp>) -> Self {
+    Self::from_socket(socket)
+  }
+
+  /// Replace the outbound scopes (interface indices) of link-local IPv6 peers.
+  /// Datagrams to a link-local peer without a scope fail with `InvalidInput`.
+  pub fn set_scope_ids(&self, scope_ids: HashMap<Ipv6Addr, u32>) {
+    self.inner.set_scope_ids(scope_ids);
+  }
+
+  /// Drop datagrams from sources other than `peers` in the kernel.
+  pub fn attach_peer_filter(&self, peers: &[IpAddr]) -> std::io::Result<()> {
+    let program = bpf::peer_filter(self.family(), peers)
+      .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "too many peers for a BPF program"))?;
+    self.inner.attach_filter(&program)
+  }
+
+  /// Detach the peer filter, if any.
+  pub fn detach_filter(&self) -> std::io::Result<()> {
+    self.inner.detach_filter()
+  }
+
+  /// Join a source-specific multicast group to receive EtherIP only from `source`.
+  pub fn join_ssm(&self, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    self.inner.join_ssm(group, source, ifindex)
+  }
+
+  /// Leave a source-specific multicast group.
+  pub fn leave_ssm(&self, group: &IpAddr, source: &IpAddr, ifindex: u32) -> std::io::Result<()> {
+    self.inner.leave_ssm(group, source, ifindex)
+  }
+
   /// Receive an EtherIP Datagram.
-  pub async fn recv_from(&self, datagram: &mut EtherIpDatagram) -> std::io::Result<(usize, IpAddr)> {
-    let (n, src_addr) = self.inner.recv_from(&mut datagram.data).await?;
-    datagram.len = n;
+  pub async fn recv_from<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    let (len, data) = datagram.parts_mut();
+    let (n, src_addr) = self.inner.recv_from(data).await?;
+    *len = n;
     Ok((n, src_addr))
   }
 
+  /// Receive an EtherIP Datagram, giving up after `timeout`.
+  /// Returns `None` on timeout, in which case `datagram` is left untouched.
+  pub async fn recv_from_timeout<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D, timeout: std::time::Duration) -> std::io::Result<Option<(usize, IpAddr)>> {
+    match tokio::time::timeout(timeout, self.recv_from(datagram)).await {
+      Ok(result) => result.map(Some),
+      Err(_) => Ok(None),
+    }
+  }
+
+  /// Receive an EtherIP Datagram along with its traffic class.
+  pub async fn recv_from_with_tclass<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr, Option<u8>)> {
+    let (len, data) = datagram.parts_mut();
+    let (n, src_addr, tclass) = self.inner.recv_from_with_tclass(data).await?;
+    *len = n;
+    Ok((n, src_addr, tclass))
+  }
+
+  /// Receive an EtherIP Datagram along with its traffic class and arrival interface.
+  pub async fn recv_from_with_info<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr, RecvInfo)> {
+    let (len, data) = datagram.parts_mut();
+    let (n, src_addr, info) = self.inner.recv_from_with_info(data).await?;
+    *len = n;
+    Ok((n, src_addr, info))
+  }
+
+  /// Report the traffic class of received datagrams.
+  pub fn set_recv_tclass(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.set_recv_tclass(enable)
+  }
+
+  /// Report the interface each datagram arrived on.
+  pub fn set_recv_pktinfo(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.set_recv_pktinfo(enable)
+  }
+
+  /// Receive an EtherIP Datagram along with its original destination address, which differs
+  /// from the local address it was delivered to when redirected (e.g. by TPROXY).
+  /// The address is only known after `set_recv_origdstaddr(true)`.
+  pub async fn recv_from_with_origdst<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr, Option<IpAddr>)> {
+    let (n, src_addr, info) = self.recv_from_with_info(datagram).await?;
+    Ok((n, src_addr, info.orig_dst))
+  }
+
+  /// Report the original destination address of received datagrams.
+  pub fn set_recv_origdstaddr(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.set_recv_origdstaddr(enable)
+  }
+
+  /// Local address the socket is bound to, which is the source of all datagrams unless unspecified.
+  pub fn local_addr(&self) -> std::io::Result<IpAddr> {
+    self.inner.local_addr()
+  }
+
+  /// Loop datagrams sent to multicast groups back to this host, where this socket would
+  /// receive its own datagrams. Disable it on sockets carrying multicast links.
+  pub fn set_multicast_loop(&self, enable: bool) -> std::io::Result<()> {
+    self.inner.set_multicast_loop(enable)
+  }
+
+  /// Send an EtherIP Datagram with the given traffic class.
+  pub async fn send_to_with_tclass<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr, tclass: u8) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    self.inner.send_to_with_tclass(data, dst_addr, tclass).await
+  }
+
+  /// Send an EtherIP Datagram with the given hop limit (TTL for IPv4), e.g. 255 for GTSM.
+  /// The socket's default hop limit is left unchanged.
+  pub async fn send_to_with_hoplimit<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr, hoplimit: u8) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    self.inner.send_to_with_hoplimit(data, dst_addr, hoplimit).await
+  }
+
   /// Send an EtherIP Datagram.
-  pub async fn send_to(&self, datagram: &EtherIpDatagram, dst_addr: &IpAddr) -> std::io::Result<usize> {
+  pub async fn send_to<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr) -> std::io::Result<usize> {
     let data = if let Some(data) = datagram.datagram() {
       data
     } else {
@@ -327,80 +1332,611 @@ impl EtherIpSocket {
     };
     self.inner.send_to(data, dst_addr).await
   }
+
+  /// Send several EtherIP Datagrams in a batch, returning one result per datagram.
+  pub async fn send_many<D: EtherIpBuffer + ?Sized>(&self, datagrams: &[(&D, IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    let mut packets = Vec::with_capacity(datagrams.len());
+    for (datagram, dst_addr) in datagrams {
+      let data = datagram.datagram().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+      packets.push((data, *dst_addr));
+    }
+    self.inner.send_many(&packets).await
+  }
+
+  /// Send several already encoded EtherIP Datagrams in a batch, returning one result per datagram.
+  /// Datagrams with an invalid EtherIP header are not sent and fail with `InvalidData`.
+  pub async fn send_many_raw(&self, datagrams: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    let valid: Vec<(&[u8], IpAddr)> = datagrams.iter().filter(|(data, _)| is_valid_datagram(data)).copied().collect();
+    let mut sent = self.inner.send_many(&valid).await?.into_iter();
+    Ok(datagrams.iter().map(|(data, _)| {
+      if is_valid_datagram(data) {
+        sent.next().unwrap_or_else(|| Err(Error::from(ErrorKind::Other)))
+      } else {
+        Err(Error::new(ErrorKind::InvalidData, "Invalid EtherIP Datagram"))
+      }
+    }).collect())
+  }
 }
 
-/// EtherIP Datagram (excluding IP header)
-#[derive(Debug, Clone)]
-pub struct EtherIpDatagram {
-  /// Datagram size (including EtherIP header and Ethernet frame)
-  len: usize,
+impl From<IpSocket<EtherIp>> for EtherIpSocket {
+  fn from(socket: IpSocket<EtherIp>) -> Self {
+    Self::from_socket(socket)
+  }
+}
 
-  /// EtherIP Datagram (excluding IP header)
-  data: [u8; 65536]
+impl AsRawFd for EtherIpSocket {
+  fn as_raw_fd(&self) -> libc::c_int {
+    self.inner.as_raw_fd()
+  }
 }
 
-impl EtherIpDatagram {
-  pub fn new() -> Self {
-    let mut datagram = Self {
-      len: 2,
-      data: [0; 65536]
+/// Datagram I/O shared by `IpSocket` of any protocol and `EtherIpSocket`, for code that
+/// does not care which raw socket it is given. Buffers hold the IP payload only.
+pub trait AsyncIpSocket {
+  /// Receive a packet into `buf`, returning its length and source address.
+  fn recv_from(&self, buf: &mut [u8]) -> impl std::future::Future<Output = std::io::Result<(usize, IpAddr)>> + Send;
+
+  /// Send `buf` to `addr`.
+  fn send_to(&self, buf: &[u8], addr: &IpAddr) -> impl std::future::Future<Output = std::io::Result<usize>> + Send;
+}
+
+impl<P> AsyncIpSocket for IpSocket<P>
+where
+  P: IpProtocol + Sync,
+{
+  async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, IpAddr)> {
+    IpSocket::recv_from(self, buf).await
+  }
+
+  async fn send_to(&self, buf: &[u8], addr: &IpAddr) -> std::io::Result<usize> {
+    IpSocket::send_to(self, buf, addr).await
+  }
+}
+
+/// Buffers are whole encoded EtherIP Datagrams; those with an invalid EtherIP header
+/// are not sent and fail with `InvalidData`.
+impl AsyncIpSocket for EtherIpSocket {
+  async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, IpAddr)> {
+    self.inner.recv_from(buf).await
+  }
+
+  async fn send_to(&self, buf: &[u8], addr: &IpAddr) -> std::io::Result<usize> {
+    if !is_valid_datagram(buf) {
+      return Err(Error::new(ErrorKind::InvalidData, "Invalid EtherIP Datagram"));
+    }
+    self.inner.send_to(buf, addr).await
+  }
+}
+
+/// Wait until `fd` is ready for `events`, for at most `timeout` (forever if `None`).
+/// Returns `false` on timeout.
+fn poll_fd(fd: libc::c_int, events: libc::c_short, timeout: Option<std::time::Duration>) -> std::io::Result<bool> {
+  let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+  loop {
+    let timeout_ms = match deadline {
+      // Rounded up, so that a short timeout does not turn into a busy loop.
+      Some(deadline) => deadline.saturating_duration_since(std::time::Instant::now()).as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int,
+      None => -1,
     };
-    datagram.data[0] = 0b0011_0000;
-    datagram
+    let mut pollfd = libc::pollfd { fd, events, revents: 0 };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+      n if n > 0 => return Ok(true),
+      0 if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) => return Ok(false),
+      0 => {},
+      _ => {
+        let error = Error::last_os_error();
+        if error.kind() != ErrorKind::Interrupted {
+          return Err(error);
+        }
+      },
+    }
   }
+}
 
-  /// Validate the EtherIP Datagram and get a reference to the encapsulated Ethernet frame.
-  pub fn ethrnet_frame<'a>(&'a self) -> Option<&'a [u8]> {
-    if self.len > self.data.len() {
-      return None;
+/// EtherIP socket with blocking I/O, for a thread-per-socket model without an async runtime.
+///
+/// Clones share the socket (its file descriptor) and can be moved to other threads: one
+/// thread may receive while others send, and several threads receiving at once each get
+/// different datagrams. The read timeout belongs to each clone, not to the socket.
+#[derive(Debug, Clone)]
+pub struct BlockingEtherIpSocket {
+  inner: std::sync::Arc<RawIpSocket>,
+  read_timeout: Option<std::time::Duration>,
+}
+
+impl BlockingEtherIpSocket {
+  /// Create a new EtherIP socket.
+  pub fn new() -> std::io::Result<Self> {
+    Self::new_with_family(SocketFamily::Inet6)
+  }
+
+  /// Create a new EtherIP socket of the given address family.
+  pub fn new_with_family(family: SocketFamily) -> std::io::Result<Self> {
+    Ok(Self::from_raw(RawIpSocket::new_with_family(family, PROTO_ETHERIP)?))
+  }
+
+  /// Create a new EtherIP socket bound to the local address `addr`, which becomes the
+  /// source address of sent datagrams. Only datagrams sent to it are received.
+  pub fn new_bound(family: SocketFamily, addr: &IpAddr) -> std::io::Result<Self> {
+    Ok(Self::from_raw(RawIpSocket::new_bound(family, PROTO_ETHERIP, addr)?))
+  }
+
+  fn from_raw(socket: RawIpSocket) -> Self {
+    Self {
+      inner: std::sync::Arc::new(socket),
+      read_timeout: None,
     }
-    let buf: &'a [u8] = &self.data[..self.len];
-    if buf.len() < 2 {
-      return None;
+  }
+
+  pub fn family(&self) -> SocketFamily {
+    self.inner.family()
+  }
+
+  pub fn local_addr(&self) -> std::io::Result<IpAddr> {
+    self.inner.local_addr()
+  }
+
+  /// How long `recv_from` waits for a datagram, forever if `None`.
+  pub fn read_timeout(&self) -> Option<std::time::Duration> {
+    self.read_timeout
+  }
+
+  /// Set how long `recv_from` of this clone waits for a datagram, forever if `None`.
+  pub fn set_read_timeout(&mut self, timeout: Option<std::time::Duration>) {
+    self.read_timeout = timeout;
+  }
+
+  /// Receive an EtherIP Datagram, waiting at most the read timeout.
+  /// Fails with `TimedOut` if no datagram arrives in time.
+  pub fn recv_from<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    let deadline = self.read_timeout.map(|timeout| std::time::Instant::now() + timeout);
+    loop {
+      let timeout = deadline.map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()));
+      if !poll_fd(self.inner.as_raw_fd(), libc::POLLIN, timeout)? {
+        return Err(Error::new(ErrorKind::TimedOut, "no EtherIP datagram received before the read timeout"));
+      }
+      let (len, data) = datagram.parts_mut();
+      match self.inner.recv_from(data) {
+        Ok((n, src_addr, _)) => {
+          *len = n;
+          return Ok((n, sockaddr_storage_to_ip_addr(&src_addr)?));
+        },
+        // Another clone took the datagram.
+        Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  /// Receive datagrams and pass their Ethernet frames to `handler` with their source,
+  /// until one read times out (`Ok`) or fails. Datagrams with an invalid EtherIP header
+  /// are skipped. Without a read timeout, this only returns on an error.
+  pub fn recv_loop(&self, mut handler: impl FnMut(&[u8], IpAddr)) -> std::io::Result<()> {
+    let mut datagram = Box::new(EtherIpDatagram::new());
+    loop {
+      let (_, src_addr) = match self.recv_from(datagram.as_mut()) {
+        Ok(received) => received,
+        Err(e) if e.kind() == ErrorKind::TimedOut => return Ok(()),
+        Err(e) => return Err(e),
+      };
+      if let Some(frame) = datagram.ethrnet_frame() {
+        handler(frame, src_addr);
+      }
+    }
+  }
+
+  /// Send an EtherIP Datagram, waiting for room in the send buffer.
+  pub fn send_to<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    loop {
+      match self.inner.send_to(data, dst_addr) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+          poll_fd(self.inner.as_raw_fd(), libc::POLLOUT, None)?;
+        },
+        result => return result,
+      }
+    }
+  }
+}
+
+impl AsRawFd for BlockingEtherIpSocket {
+  fn as_raw_fd(&self) -> libc::c_int {
+    self.inner.as_raw_fd()
+  }
+}
+
+/// Size of the EtherIP header.
+pub const ETHERIP_HEADER_SIZE: usize = 2;
+
+/// EtherIP version defined by RFC 3378.
+pub const ETHERIP_VERSION: u8 = 3;
+
+/// How strictly received EtherIP headers are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+pub enum ParserMode {
+  /// Require the 12 reserved bits to be zero, as RFC 3378 demands.
+  #[default]
+  Strict,
+  /// Only check the version, ignoring reserved bits set by some older senders.
+  Lenient,
+}
+
+/// What becomes of the reserved bits of a received header when the datagram is sent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+pub enum ReservedBits {
+  /// Zero them, as RFC 3378 demands of senders.
+  #[default]
+  Clear,
+  /// Keep them as received, for relaying datagrams transparently.
+  Preserve,
+}
+
+/// Decoded EtherIP header: a 4-bit version followed by 12 reserved bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EtherIpHeader {
+  pub version: u8,
+  pub reserved: u16,
+}
+
+impl EtherIpHeader {
+  /// Decode the header at the start of `data`.
+  pub fn decode(data: &[u8]) -> Option<Self> {
+    let header = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    Some(Self {
+      version: (header >> 12) as u8,
+      reserved: header & 0x0fff,
+    })
+  }
+
+  pub fn encode(&self) -> [u8; ETHERIP_HEADER_SIZE] {
+    (((self.version as u16) << 12) | (self.reserved & 0x0fff)).to_be_bytes()
+  }
+
+  /// Whether a datagram with this header is accepted in `mode`.
+  pub fn is_acceptable(&self, mode: ParserMode) -> bool {
+    self.version == ETHERIP_VERSION && (mode == ParserMode::Lenient || self.reserved == 0)
+  }
+
+  /// The header to send a received datagram on with, keeping or clearing the reserved bits.
+  pub fn for_retransmit(&self, reserved_bits: ReservedBits) -> Self {
+    Self {
+      version: ETHERIP_VERSION,
+      reserved: match reserved_bits {
+        ReservedBits::Clear => 0,
+        ReservedBits::Preserve => self.reserved & 0x0fff,
+      },
+    }
+  }
+}
+
+impl Default for EtherIpHeader {
+  fn default() -> Self {
+    Self {
+      version: ETHERIP_VERSION,
+      reserved: 0,
     }
-    let (etherip_header, eth_frame) = buf.split_at(2);
-    if etherip_header != &[0b0011_0000, 0b0000_0000] {
+  }
+}
+
+/// Check the EtherIP header of an encoded datagram to be sent, as `EtherIpBuffer::datagram` does.
+fn is_valid_datagram(data: &[u8]) -> bool {
+  EtherIpHeader::decode(data).is_some_and(|header| header.is_acceptable(ParserMode::Lenient))
+}
+
+/// Size of the buffer of an `EtherIpDatagram`, enough for the largest IP payload.
+pub const ETHERIP_DATAGRAM_BUFFER_SIZE: usize = 65536;
+
+// Checked at compile time so that a smaller buffer cannot slip in.
+const _: () = assert!(ETHERIP_DATAGRAM_BUFFER_SIZE >= u16::MAX as usize, "the EtherIP datagram buffer must hold the largest IP payload");
+
+/// Buffer holding an EtherIP Datagram (excluding IP header), whatever its storage.
+/// The header validation and the accessors are shared by all implementations, and
+/// sockets and transports accept any of them, including `dyn EtherIpBuffer`.
+///
+/// Debug builds assert that the datagram size never exceeds the buffer, both when it is
+/// set through `ethrnet_frame_mut` or `datagram_mut` and when the datagram is read back.
+/// Received contents are never asserted on; invalid headers from peers still yield `None`.
+pub trait EtherIpBuffer: Send + Sync {
+  /// The datagram size (including EtherIP header and Ethernet frame) and the whole buffer.
+  fn parts(&self) -> (usize, &[u8]);
+
+  /// Mutable access to the datagram size and the whole buffer.
+  fn parts_mut(&mut self) -> (&mut usize, &mut [u8]);
+
+  /// Decode the EtherIP header, if the datagram is long enough to have one.
+  fn header(&self) -> Option<EtherIpHeader> {
+    let (len, data) = self.parts();
+    debug_assert_datagram_len(len, data.len());
+    EtherIpHeader::decode(data.get(..len)?)
+  }
+
+  /// Validate the EtherIP Datagram and get a reference to the encapsulated Ethernet frame.
+  fn ethrnet_frame(&self) -> Option<&[u8]> {
+    self.ethrnet_frame_with_mode(ParserMode::Strict)
+  }
+
+  /// Validate the EtherIP Datagram in `mode` and get a reference to the encapsulated Ethernet frame.
+  fn ethrnet_frame_with_mode(&self, mode: ParserMode) -> Option<&[u8]> {
+    if !self.header()?.is_acceptable(mode) {
       return None;
     }
-    Some(eth_frame)
+    let (len, data) = self.parts();
+    debug_assert_datagram_len(len, data.len());
+    Some(&data[ETHERIP_HEADER_SIZE..len])
   }
 
   /// Get a mutable reference to the encapsulated Ethernet frame.
-  pub fn ethrnet_frame_mut<'a>(&'a mut self) -> (EthernetFrameLength<'a>, &'a mut [u8]) {
-    let (_etherip_header, eth_frame) = self.data.split_at_mut(2);
+  fn ethrnet_frame_mut(&mut self) -> (EthernetFrameLength<'_>, &mut [u8]) {
+    let (len, data) = self.parts_mut();
+    let capacity = data.len();
+    let (_etherip_header, eth_frame) = data.split_at_mut(ETHERIP_HEADER_SIZE);
     (EthernetFrameLength {
-      etherip_datagram_len: &mut self.len
+      etherip_datagram_len: len,
+      capacity,
     }, eth_frame)
   }
 
-  /// Validate and get a reference to the EtherIP Datagram.
-  pub fn datagram(&self) -> Option<&[u8]> {
-    if self.len > self.data.len() {
-      return None;
-    }
-    if self.len < 2 {
+  /// Validate and get a reference to the EtherIP Datagram, for sending it. Only the
+  /// version is checked, so that reserved bits kept by `prepare_retransmit` go out as they are.
+  fn datagram(&self) -> Option<&[u8]> {
+    if !self.header()?.is_acceptable(ParserMode::Lenient) {
       return None;
     }
-    if self.data[0] != 0b0011_0000 || self.data[1] != 0b0000_0000 {
-      return None;
-    }
-    Some(&self.data[..self.len])
+    let (len, data) = self.parts();
+    debug_assert_datagram_len(len, data.len());
+    Some(&data[..len])
+  }
+
+  /// Rewrite the header of a received datagram so that it can be sent on, with the
+  /// reserved bits handled by `reserved_bits`. Does nothing to a datagram without a header.
+  fn prepare_retransmit(&mut self, reserved_bits: ReservedBits) {
+    let Some(header) = self.header() else {
+      return;
+    };
+    let (_, data) = self.parts_mut();
+    data[..ETHERIP_HEADER_SIZE].copy_from_slice(&header.for_retransmit(reserved_bits).encode());
   }
 
   /// Get a mutable reference to the EtherIP Datagram.
-  pub fn datagram_mut<'a>(&'a mut self) -> (EtherIpDatagramLength<'a>, &'a mut [u8]) {
+  fn datagram_mut(&mut self) -> (EtherIpDatagramLength<'_>, &mut [u8]) {
+    let (len, data) = self.parts_mut();
+    let capacity = data.len();
     (EtherIpDatagramLength {
-      etherip_datagram_len: &mut self.len
-    }, &mut self.data)
+      etherip_datagram_len: len,
+      capacity,
+    }, data)
+  }
+}
+
+/// EtherIP Datagram (excluding IP header) in a buffer of `N` bytes, by default
+/// `ETHERIP_DATAGRAM_BUFFER_SIZE`. A buffer too small for the smallest datagram
+/// carrying a frame fails to compile.
+#[derive(Debug, Clone)]
+pub struct EtherIpDatagram<const N: usize = ETHERIP_DATAGRAM_BUFFER_SIZE> {
+  /// Datagram size (including EtherIP header and Ethernet frame)
+  len: usize,
+
+  /// EtherIP Datagram (excluding IP header)
+  data: [u8; N]
+}
+
+impl EtherIpDatagram {
+  pub fn new() -> Self {
+    Self::new_sized()
+  }
+
+  /// Largest Ethernet frame the buffer carries: `ETHERIP_MAX_FRAME_SIZE`, that is the
+  /// buffer minus the EtherIP header. The TAP MTU must leave room for the Ethernet header
+  /// within it.
+  ///
+  /// ```
+  /// use etherip::EtherIpDatagram;
+  /// // A 65536-byte buffer less the 2-byte EtherIP header.
+  /// assert_eq!(EtherIpDatagram::max_ethernet_frame(), 65536 - 2);
+  /// ```
+  pub const fn max_ethernet_frame() -> usize {
+    ETHERIP_MAX_FRAME_SIZE
+  }
+
+  /// Smallest Ethernet frame accepted from peers: a bare Ethernet header.
+  ///
+  /// ```
+  /// use etherip::EtherIpDatagram;
+  /// // Destination and source MAC addresses and the EtherType.
+  /// assert_eq!(EtherIpDatagram::min_ethernet_frame(), 6 + 6 + 2);
+  /// ```
+  pub const fn min_ethernet_frame() -> usize {
+    ethernet::ETHERNET_HEADER_SIZE
+  }
+
+  /// Largest EtherIP datagram the buffer holds, EtherIP header included.
+  ///
+  /// ```
+  /// use etherip::EtherIpDatagram;
+  /// // The largest frame after the 2-byte EtherIP header fills the buffer.
+  /// assert_eq!(EtherIpDatagram::max_datagram(), 2 + EtherIpDatagram::max_ethernet_frame());
+  /// assert_eq!(EtherIpDatagram::max_datagram(), 65536);
+  /// ```
+  pub const fn max_datagram() -> usize {
+    ETHERIP_DATAGRAM_BUFFER_SIZE
+  }
+
+  /// Smallest EtherIP datagram carrying a frame, EtherIP header included.
+  ///
+  /// ```
+  /// use etherip::EtherIpDatagram;
+  /// // The 2-byte EtherIP header and a bare 14-byte Ethernet header.
+  /// assert_eq!(EtherIpDatagram::min_datagram(), 2 + 14);
+  /// ```
+  pub const fn min_datagram() -> usize {
+    ETHERIP_HEADER_SIZE + Self::min_ethernet_frame()
+  }
+}
+
+impl<const N: usize> EtherIpDatagram<N> {
+  /// Create a datagram in a buffer of `N` bytes, with a default header and an empty Ethernet frame.
+  pub fn new_sized() -> Self {
+    const {
+      assert!(N >= ETHERIP_HEADER_SIZE + ethernet::ETHERNET_HEADER_SIZE, "the EtherIP datagram buffer N must hold at least an EtherIP header and an Ethernet header");
+    }
+    let mut datagram = Self {
+      len: ETHERIP_HEADER_SIZE,
+      data: [0; N]
+    };
+    datagram.data[..ETHERIP_HEADER_SIZE].copy_from_slice(&EtherIpHeader::default().encode());
+    datagram
+  }
+
+  /// Move the buffer out together with its datagram length, leaving this datagram
+  /// reset as if by `new_sized`: a default header and an empty Ethernet frame.
+  /// Moving a datagram copies its buffer; to hand one on without copying, keep it in a `Box`.
+  pub fn take_buffer(&mut self) -> Self {
+    std::mem::take(self)
+  }
+
+  /// Exchange buffers with `other`. Each datagram length travels with its buffer.
+  pub fn swap_buffer(&mut self, other: &mut Self) {
+    std::mem::swap(self, other);
+  }
+}
+
+impl<const N: usize> EtherIpBuffer for EtherIpDatagram<N> {
+  fn parts(&self) -> (usize, &[u8]) {
+    (self.len, &self.data)
+  }
+
+  fn parts_mut(&mut self) -> (&mut usize, &mut [u8]) {
+    (&mut self.len, &mut self.data)
+  }
+}
+
+// Boxed datagrams, including `Box<dyn EtherIpBuffer>`, are passed as they are.
+impl<D: EtherIpBuffer + ?Sized> EtherIpBuffer for Box<D> {
+  fn parts(&self) -> (usize, &[u8]) {
+    (**self).parts()
+  }
+
+  fn parts_mut(&mut self) -> (&mut usize, &mut [u8]) {
+    (**self).parts_mut()
+  }
+}
+
+impl<const N: usize> Default for EtherIpDatagram<N> {
+  fn default() -> Self {
+    Self::new_sized()
   }
 }
 
+/// Longest IPv4 header, options included.
+const IPV4_MAX_HEADER_SIZE: usize = 60;
+
+/// Largest Ethernet frame an `EtherIpDatagram` can carry.
+pub const ETHERIP_MAX_FRAME_SIZE: usize = ETHERIP_DATAGRAM_BUFFER_SIZE - ETHERIP_HEADER_SIZE;
+
+/// EtherIP Datagram (excluding IP header) in a buffer sized at runtime, for hosts that
+/// do not need room for the largest frames in every buffer.
+/// Received datagrams longer than the buffer are dropped with a `TruncatedPacket` error.
+#[derive(Debug, Clone)]
+pub struct HeapEtherIpDatagram {
+  /// Datagram size (including EtherIP header and Ethernet frame)
+  len: usize,
+
+  /// EtherIP Datagram (excluding IP header)
+  data: Box<[u8]>,
+}
+
+impl HeapEtherIpDatagram {
+  /// Create a datagram whose buffer holds Ethernet frames of up to `max_frame_size` bytes,
+  /// clamped to `ETHERIP_MAX_FRAME_SIZE`. The buffer also has room for the IPv4 header
+  /// that AF_INET raw sockets receive in front of the datagram.
+  pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+    let buffer_size = (ETHERIP_HEADER_SIZE + max_frame_size + IPV4_MAX_HEADER_SIZE).min(ETHERIP_DATAGRAM_BUFFER_SIZE);
+    let mut data = vec![0u8; buffer_size].into_boxed_slice();
+    data[..ETHERIP_HEADER_SIZE].copy_from_slice(&EtherIpHeader::default().encode());
+    Self {
+      len: ETHERIP_HEADER_SIZE,
+      data,
+    }
+  }
+
+  /// Size of the buffer, EtherIP header included.
+  pub fn buffer_size(&self) -> usize {
+    self.data.len()
+  }
+
+  /// Largest Ethernet frame the buffer carries when sending. Frames received over an
+  /// AF_INET socket share the buffer with the IPv4 header, as `with_max_frame_size` allows for.
+  ///
+  /// ```
+  /// use etherip::HeapEtherIpDatagram;
+  /// let datagram = HeapEtherIpDatagram::with_max_frame_size(1514);
+  /// // The requested frame plus room for a 60-byte IPv4 header.
+  /// assert_eq!(datagram.max_ethernet_frame(), 1514 + 60);
+  /// ```
+  pub fn max_ethernet_frame(&self) -> usize {
+    self.data.len() - ETHERIP_HEADER_SIZE
+  }
+
+  /// Smallest Ethernet frame accepted from peers, as for `EtherIpDatagram`.
+  ///
+  /// ```
+  /// use etherip::HeapEtherIpDatagram;
+  /// assert_eq!(HeapEtherIpDatagram::min_ethernet_frame(), 14);
+  /// ```
+  pub const fn min_ethernet_frame() -> usize {
+    EtherIpDatagram::min_ethernet_frame()
+  }
+
+  /// Largest EtherIP datagram the buffer holds, EtherIP header included.
+  ///
+  /// ```
+  /// use etherip::HeapEtherIpDatagram;
+  /// let datagram = HeapEtherIpDatagram::with_max_frame_size(1514);
+  /// // The 2-byte EtherIP header, the frame and room for a 60-byte IPv4 header.
+  /// assert_eq!(datagram.max_datagram(), 2 + 1514 + 60);
+  /// // Requests beyond the largest IP payload are clamped to it.
+  /// assert_eq!(HeapEtherIpDatagram::with_max_frame_size(100_000).max_datagram(), 65536);
+  /// ```
+  pub fn max_datagram(&self) -> usize {
+    self.data.len()
+  }
+
+  /// Smallest EtherIP datagram carrying a frame, as for `EtherIpDatagram`.
+  ///
+  /// ```
+  /// use etherip::HeapEtherIpDatagram;
+  /// assert_eq!(HeapEtherIpDatagram::min_datagram(), 2 + 14);
+  /// ```
+  pub const fn min_datagram() -> usize {
+    EtherIpDatagram::min_datagram()
+  }
+}
+
+impl EtherIpBuffer for HeapEtherIpDatagram {
+  fn parts(&self) -> (usize, &[u8]) {
+    (self.len, &self.data)
+  }
+
+  fn parts_mut(&mut self) -> (&mut usize, &mut [u8]) {
+    (&mut self.len, &mut self.data)
+  }
+}
+
+/// Check in debug builds that a datagram size fits its buffer. A violation is a bug in
+/// the code that set the size, as received sizes are bounded by the buffer.
+#[inline]
+fn debug_assert_datagram_len(len: usize, capacity: usize) {
+  debug_assert!(len <= capacity, "EtherIP datagram size {} exceeds its buffer of {} bytes", len, capacity);
+}
+
 pub struct EthernetFrameLength<'a> {
   etherip_datagram_len: &'a mut usize,
+  capacity: usize,
 }
 
 impl EthernetFrameLength<'_> {
   pub fn set(&mut self, len: usize) {
+    debug_assert_datagram_len(len + ETHERIP_HEADER_SIZE, self.capacity);
     *self.etherip_datagram_len = len + 2;
   }
   
@@ -411,10 +1947,12 @@ impl EthernetFrameLength<'_> {
 
 pub struct EtherIpDatagramLength<'a> {
   etherip_datagram_len: &'a mut usize,
+  capacity: usize,
 }
 
 impl EtherIpDatagramLength<'_> {
   pub fn set(&mut self, len: usize) {
+    debug_assert_datagram_len(len, self.capacity);
     *self.etherip_datagram_len = len;
   }
 
@@ -422,3 +1960,585 @@ impl EtherIpDatagramLength<'_> {
     *self.etherip_datagram_len
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::os::fd::{FromRawFd, OwnedFd};
+
+  /// Deterministic pseudo-random bytes, so that failures are reproducible.
+  fn frame_of(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+      state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+      (state >> 16) as u8
+    }).collect()
+  }
+
+  /// Write `frame` into `sent`, copy the encoded datagram into `received` as the
+  /// wire would, and return the frame decoded from it.
+  fn round_trip<S: EtherIpBuffer + ?Sized, R: EtherIpBuffer + ?Sized>(frame: &[u8], sent: &mut S, received: &mut R) -> Vec<u8> {
+    let (mut len, buf) = sent.ethrnet_frame_mut();
+    buf[..frame.len()].copy_from_slice(frame);
+    len.set(frame.len());
+    let wire = sent.datagram().expect("valid datagram").to_vec();
+    let (mut len, buf) = received.datagram_mut();
+    buf[..wire.len()].copy_from_slice(&wire);
+    len.set(wire.len());
+    received.ethrnet_frame().expect("valid frame").to_vec()
+  }
+
+  #[test]
+  fn frame_round_trips_at_boundary_sizes() {
+    for (seed, size) in [0, 1, 14, 60, 1514, 9000, ETHERIP_MAX_FRAME_SIZE].into_iter().enumerate() {
+      let frame = frame_of(size, seed as u32);
+      let decoded = round_trip(&frame, &mut EtherIpDatagram::new(), &mut EtherIpDatagram::new());
+      assert_eq!(decoded, frame, "frame of {} bytes", size);
+    }
+  }
+
+  /// Send `payload` to the loopback address and wait for it to come back, through any
+  /// `AsyncIpSocket`. `None` if raw sockets cannot be opened here.
+  async fn loop_back<S: AsyncIpSocket>(socket: std::io::Result<S>, payload: &[u8]) -> Option<(Vec<u8>, IpAddr)> {
+    let socket = match socket {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == ErrorKind::PermissionDenied => return None,
+      Err(e) => panic!("cannot open a raw socket: {}", e),
+    };
+    let loopback = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    socket.send_to(payload, &loopback).await.expect("send to the loopback address");
+    let mut buf = [0u8; 256];
+    loop {
+      let (n, src) = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv_from(&mut buf)).await
+        .expect("packet looped back").expect("receive");
+      // Other tests may use raw sockets too; only our packet counts.
+      if &buf[..n] == payload {
+        return Some((buf[..n].to_vec(), src));
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn ip_socket_io_is_generic_over_the_protocol() {
+    // Protocols 253 and 254 are reserved for experimentation (RFC 3692).
+    let Some((received, src)) = loop_back(IpSocket::new(253 as libc::c_int), b"generic c_int").await else {
+      return;
+    };
+    assert_eq!((received.as_slice(), src), (&b"generic c_int"[..], IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    let (received, _) = loop_back(IpSocket::new(254 as libc::c_uint), b"generic c_uint").await.expect("raw sockets work");
+    assert_eq!(received, b"generic c_uint");
+  }
+
+  #[tokio::test]
+  async fn ether_ip_sockets_are_async_ip_sockets() {
+    let datagram = with_frame(EtherIpDatagram::new(), &frame_of(60, 0x47));
+    let payload = datagram.datagram().unwrap();
+    let Some((received, _)) = loop_back(EtherIpSocket::new(), payload).await else {
+      return;
+    };
+    assert_eq!(received, payload);
+
+    let socket = EtherIpSocket::new().expect("raw sockets work");
+    let err = AsyncIpSocket::send_to(&socket, b"not EtherIP", &IpAddr::V6(Ipv6Addr::LOCALHOST)).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+  }
+
+  /// An EtherIP socket of `family`, or `None` if raw sockets cannot be opened here.
+  fn etherip_socket(family: SocketFamily) -> Option<EtherIpSocket> {
+    match EtherIpSocket::new_with_family(family) {
+      Ok(socket) => Some(socket),
+      Err(e) if e.kind() == ErrorKind::PermissionDenied => None,
+      Err(e) => panic!("cannot open an EtherIP socket: {}", e),
+    }
+  }
+
+  #[tokio::test]
+  async fn ssm_groups_are_joined_and_left_on_loopback() {
+    let memberships = [
+      (SocketFamily::Inet, IpAddr::V4(std::net::Ipv4Addr::new(232, 1, 2, 3)), IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)),
+      (SocketFamily::Inet6, "ff3e::8000:1".parse().unwrap(), IpAddr::V6(Ipv6Addr::LOCALHOST)),
+    ];
+    let ifindex = interface_index("lo").expect("loopback interface");
+    for (family, group, source) in memberships {
+      let Some(socket) = etherip_socket(family) else {
+        return;
+      };
+      socket.join_ssm(&group, &source, ifindex).expect("join");
+      assert!(socket.join_ssm(&group, &source, ifindex).is_err(), "{} joined twice", group);
+      socket.leave_ssm(&group, &source, ifindex).expect("leave");
+      assert!(socket.leave_ssm(&group, &source, ifindex).is_err(), "{} left twice", group);
+    }
+  }
+
+  #[tokio::test]
+  async fn send_many_fails_only_the_packets_with_an_unusable_destination() {
+    let socket = match IpSocket::new(253 as libc::c_int) {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+      Err(e) => panic!("cannot open a raw socket: {}", e),
+    };
+    let loopback = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    // A link-local destination needs a scope, which no interface has given it.
+    let unscoped: IpAddr = "fe80::1".parse().unwrap();
+    let results = socket.send_many(&[(b"first", loopback), (b"second", unscoped), (b"third", loopback)]).await.expect("send");
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().ok(), Some(&5));
+    assert_eq!(results[1].as_ref().map_err(|e| e.kind()).err(), Some(ErrorKind::InvalidInput));
+    assert_eq!(results[2].as_ref().ok(), Some(&5));
+  }
+
+  #[test]
+  fn ipv4_headers_are_checked_before_they_are_read() {
+    // Header lengths below the minimum; with 0, the TOS byte would be read from beyond the packet.
+    assert!(strip_ipv4_header(&mut [0x40], 1).is_err());
+    assert!(strip_ipv4_header(&mut [0x44, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 16).is_err());
+    // A header of 24 bytes in a packet of 20.
+    assert!(strip_ipv4_header(&mut [0x46; 24], 20).is_err());
+    assert!(strip_ipv4_header(&mut [], 0).is_err());
+
+    let mut packet = [0u8; 24];
+    packet[0] = 0x45;
+    packet[1] = 0xb8;
+    packet[20..].copy_from_slice(b"data");
+    assert_eq!(strip_ipv4_header(&mut packet, 24).unwrap(), (4, 0xb8));
+    assert_eq!(&packet[..4], b"data");
+  }
+
+  #[test]
+  fn ipv6_sockaddr_puts_the_flow_label_in_network_byte_order() {
+    let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    // The bits above the 20-bit flow label are dropped.
+    let sin6 = ipv6_sockaddr(&addr, 0xfff1_2345, 7);
+    let bytes = unsafe { std::slice::from_raw_parts(&sin6 as *const libc::sockaddr_in6 as *const u8, std::mem::size_of::<libc::sockaddr_in6>()) };
+    assert_eq!(bytes[0..2], (libc::AF_INET6 as libc::sa_family_t).to_ne_bytes());
+    assert_eq!(bytes[2..4], [0, 0]);
+    assert_eq!(bytes[4..8], [0x00, 0x01, 0x23, 0x45]);
+    assert_eq!(bytes[8..24], addr.octets());
+    assert_eq!(bytes[24..28], 7u32.to_ne_bytes());
+  }
+
+  #[tokio::test]
+  async fn recv_from_timeout_leaves_the_datagram_untouched() {
+    let Some(socket) = etherip_socket(SocketFamily::Inet6) else {
+      return;
+    };
+    // Accept datagrams from a documentation address only, so that nothing arrives.
+    socket.attach_peer_filter(&["2001:db8::dead".parse().unwrap()]).expect("attach the filter");
+    let mut datagram = EtherIpDatagram::new();
+    let frame = frame_of(60, 432);
+    let (mut len, buf) = datagram.ethrnet_frame_mut();
+    buf[..frame.len()].copy_from_slice(&frame);
+    len.set(frame.len());
+    let before = datagram.datagram().unwrap().to_vec();
+
+    let received = socket.recv_from_timeout(&mut datagram, std::time::Duration::from_millis(50)).await.expect("receive");
+    assert_eq!(received, None);
+    assert_eq!(datagram.datagram().unwrap(), before);
+  }
+
+  #[tokio::test]
+  async fn hop_limit_override_applies_to_one_packet() {
+    // Unlike `IpSocket`, a plain raw socket keeps the IPv4 header, and with it the TTL.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, 253) };
+    if fd < 0 {
+      return;
+    }
+    let receiver = unsafe { OwnedFd::from_raw_fd(fd) };
+    let timeout = libc::timeval { tv_sec: 5, tv_usec: 0 };
+    unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout as *const libc::timeval as *const libc::c_void, std::mem::size_of_val(&timeout) as libc::socklen_t) };
+    let socket = IpSocket::new_with_family(253 as libc::c_int, SocketFamily::Inet).expect("raw socket");
+    let loopback = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+    socket.send_to_with_hoplimit(b"override", &loopback, 7).await.expect("send with a hop limit");
+    socket.send_to(b"default", &loopback).await.expect("send");
+
+    let (mut overridden, mut default) = (None, None);
+    let mut buf = [0u8; 256];
+    while overridden.is_none() || default.is_none() {
+      let n = unsafe { libc::recv(receiver.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+      assert!(n >= 20, "receive: {}", Error::last_os_error());
+      let header_len = ((buf[0] & 0x0f) as usize) * 4;
+      match &buf[header_len..n as usize] {
+        b"override" => overridden = Some(buf[8]),
+        b"default" => default = Some(buf[8]),
+        _ => {},
+      }
+    }
+    assert_eq!(overridden, Some(7));
+    assert_ne!(default, Some(7));
+  }
+
+  #[test]
+  fn frame_round_trips_at_heap_buffer_size() {
+    for max_frame_size in [0, 1, 60, 1514, ETHERIP_MAX_FRAME_SIZE] {
+      let frame = frame_of(max_frame_size, max_frame_size as u32);
+      let mut sent = HeapEtherIpDatagram::with_max_frame_size(max_frame_size);
+      let mut received = HeapEtherIpDatagram::with_max_frame_size(max_frame_size);
+      assert_eq!(round_trip(&frame, &mut sent, &mut received), frame, "frame of {} bytes", max_frame_size);
+    }
+  }
+
+  #[test]
+  fn multicast_loop_is_toggled() {
+    let options = [
+      (SocketFamily::Inet, libc::IPPROTO_IP, libc::IP_MULTICAST_LOOP),
+      (SocketFamily::Inet6, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_LOOP),
+    ];
+    for (family, level, name) in options {
+      let socket = match RawIpSocket::new_raw(family, 253) {
+        Ok(socket) => socket,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+        Err(e) => panic!("cannot open a raw socket: {}", e),
+      };
+      assert_eq!(socket.getsockopt::<libc::c_int>(level, name).expect("read the default"), 1, "{:?}", family);
+      socket.set_multicast_loop(false).expect("disable");
+      assert_eq!(socket.getsockopt::<libc::c_int>(level, name).expect("read back"), 0, "{:?}", family);
+      socket.set_multicast_loop(true).expect("enable");
+      assert_eq!(socket.getsockopt::<libc::c_int>(level, name).expect("read back"), 1, "{:?}", family);
+    }
+  }
+
+  #[test]
+  fn ipv4_peers_of_ipv6_only_sockets_get_an_actionable_error() {
+    let mut socket = match RawIpSocket::new_raw(SocketFamily::Inet6, 253) {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+      Err(e) => panic!("cannot open a raw socket: {}", e),
+    };
+    let ipv4_peer = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    assert!(is_ipv4_mapped(&to_ipv6_addr(ipv4_peer)));
+    assert!(!is_ipv4_mapped(&"2001:db8::1".parse().unwrap()));
+
+    // As if the net.ipv6.bindv6only sysctl were set when the socket was created.
+    socket.v6only = true;
+    let e = socket.peer_sockaddr(&ipv4_peer).expect_err("IPv4 peer of an IPv6-only socket");
+    assert_eq!(e.kind(), ErrorKind::Unsupported);
+    let message = e.to_string();
+    assert!(message.contains("192.0.2.1") && message.contains("net.ipv6.bindv6only") && message.contains("native_ipv4"), "{}", message);
+    assert!(socket.peer_sockaddr(&"2001:db8::1".parse().unwrap()).is_ok());
+
+    socket.v6only = false;
+    assert!(socket.peer_sockaddr(&ipv4_peer).is_ok());
+  }
+
+  /// Place `wire` in `datagram` as if it had just been received.
+  fn receive_into<D: EtherIpBuffer + ?Sized>(datagram: &mut D, wire: &[u8]) {
+    let (mut len, buf) = datagram.datagram_mut();
+    buf[..wire.len()].copy_from_slice(wire);
+    len.set(wire.len());
+  }
+
+  /// The header checks every `EtherIpBuffer` shares, whatever its storage.
+  fn check_header_validation<D: EtherIpBuffer + ?Sized>(datagram: &mut D) {
+    let frame = frame_of(60, 7);
+    let with_header = |header: [u8; 2]| [&header[..], &frame].concat();
+
+    receive_into(datagram, &with_header([0x30, 0x00]));
+    assert_eq!(datagram.header(), Some(EtherIpHeader { version: 3, reserved: 0 }));
+    assert_eq!(datagram.ethrnet_frame(), Some(&frame[..]));
+
+    // Reserved bits are refused in strict mode only, and kept or cleared on retransmit.
+    receive_into(datagram, &with_header([0x30, 0x05]));
+    assert_eq!(datagram.ethrnet_frame(), None);
+    assert_eq!(datagram.ethrnet_frame_with_mode(ParserMode::Lenient), Some(&frame[..]));
+    datagram.prepare_retransmit(ReservedBits::Preserve);
+    assert_eq!(datagram.datagram().map(|data| data[..2].to_vec()), Some(vec![0x30, 0x05]));
+    datagram.prepare_retransmit(ReservedBits::Clear);
+    assert_eq!(datagram.ethrnet_frame(), Some(&frame[..]));
+
+    // Other versions are refused in every mode, and not sent.
+    receive_into(datagram, &with_header([0x40, 0x00]));
+    assert_eq!(datagram.ethrnet_frame_with_mode(ParserMode::Lenient), None);
+    assert_eq!(datagram.datagram(), None);
+
+    // Too short to have a header.
+    receive_into(datagram, &[0x30]);
+    assert_eq!(datagram.header(), None);
+    assert_eq!(datagram.ethrnet_frame(), None);
+  }
+
+  #[test]
+  fn header_validation_is_shared_by_array_and_heap_datagrams() {
+    check_header_validation(&mut EtherIpDatagram::new());
+    check_header_validation(&mut EtherIpDatagram::<256>::new_sized());
+    check_header_validation(&mut HeapEtherIpDatagram::with_max_frame_size(1514));
+    check_header_validation(&mut HeapEtherIpDatagram::with_max_frame_size(60));
+    let mut boxed: Box<dyn EtherIpBuffer> = Box::new(HeapEtherIpDatagram::with_max_frame_size(1514));
+    check_header_validation(&mut boxed);
+  }
+
+  #[test]
+  fn heap_datagram_sizes_follow_the_requested_frame_size() {
+    let datagram = HeapEtherIpDatagram::with_max_frame_size(1514);
+    assert_eq!(datagram.buffer_size(), ETHERIP_HEADER_SIZE + 1514 + IPV4_MAX_HEADER_SIZE);
+    assert_eq!(datagram.max_datagram(), datagram.buffer_size());
+    assert_eq!(datagram.max_ethernet_frame(), 1514 + IPV4_MAX_HEADER_SIZE);
+    // A new datagram holds a default header and an empty frame, like an array-backed one.
+    assert_eq!(datagram.datagram(), EtherIpDatagram::new().datagram());
+
+    let largest = HeapEtherIpDatagram::with_max_frame_size(usize::MAX / 2);
+    assert_eq!(largest.buffer_size(), ETHERIP_DATAGRAM_BUFFER_SIZE);
+    assert_eq!(largest.max_ethernet_frame(), EtherIpDatagram::max_ethernet_frame());
+    assert_eq!(HeapEtherIpDatagram::min_datagram(), EtherIpDatagram::min_datagram());
+    assert_eq!(HeapEtherIpDatagram::min_ethernet_frame(), EtherIpDatagram::min_ethernet_frame());
+  }
+
+  /// Send the frame in `sent` to the loopback address of `socket` and receive it into
+  /// `received`, skipping EtherIP traffic from elsewhere.
+  async fn etherip_loop_back<S: EtherIpBuffer + ?Sized, R: EtherIpBuffer + ?Sized>(socket: &EtherIpSocket, sent: &S, received: &mut R) -> Vec<u8> {
+    let loopback = match socket.family() {
+      SocketFamily::Inet6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+      SocketFamily::Inet => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+    };
+    let frame = sent.ethrnet_frame().expect("valid frame").to_vec();
+    socket.send_to(sent, &loopback).await.expect("send");
+    loop {
+      let (_, src) = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv_from(received)).await
+        .expect("datagram looped back").expect("receive");
+      if src == loopback && received.ethrnet_frame() == Some(&frame[..]) {
+        return frame;
+      }
+    }
+  }
+
+  /// Fill the Ethernet frame of `datagram` with `frame`.
+  fn with_frame<D: EtherIpBuffer>(mut datagram: D, frame: &[u8]) -> D {
+    let (mut len, buf) = datagram.ethrnet_frame_mut();
+    buf[..frame.len()].copy_from_slice(frame);
+    len.set(frame.len());
+    datagram
+  }
+
+  #[tokio::test]
+  async fn sockets_send_and_receive_both_datagram_types() {
+    for (seed, family) in [SocketFamily::Inet6, SocketFamily::Inet].into_iter().enumerate() {
+      let Some(socket) = etherip_socket(family) else {
+        return;
+      };
+      let frame = frame_of(1514, seed as u32);
+      let array = with_frame(EtherIpDatagram::new(), &frame);
+      let heap = with_frame(HeapEtherIpDatagram::with_max_frame_size(1514), &frame);
+      let boxed: Box<dyn EtherIpBuffer> = Box::new(with_frame(HeapEtherIpDatagram::with_max_frame_size(1514), &frame));
+
+      assert_eq!(etherip_loop_back(&socket, &array, &mut HeapEtherIpDatagram::with_max_frame_size(1514)).await, frame, "{:?}", family);
+      assert_eq!(etherip_loop_back(&socket, &heap, &mut EtherIpDatagram::new()).await, frame, "{:?}", family);
+      let mut received: Box<dyn EtherIpBuffer> = Box::new(EtherIpDatagram::new());
+      assert_eq!(etherip_loop_back(&socket, &boxed, &mut received).await, frame, "{:?}", family);
+    }
+  }
+
+  #[tokio::test]
+  async fn oversized_datagrams_are_dropped_with_their_length() {
+    for (seed, family) in [SocketFamily::Inet6, SocketFamily::Inet].into_iter().enumerate() {
+      let Some(socket) = etherip_socket(family) else {
+        return;
+      };
+      let loopback = match family {
+        SocketFamily::Inet6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        SocketFamily::Inet => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+      };
+      let oversized = with_frame(EtherIpDatagram::new(), &frame_of(1514, seed as u32));
+      socket.send_to(&oversized, &loopback).await.expect("send");
+
+      let mut small = HeapEtherIpDatagram::with_max_frame_size(60);
+      let truncated = loop {
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv_from(&mut small)).await.expect("datagram looped back");
+        // EtherIP traffic from elsewhere may fit the buffer.
+        if let Err(e) = result {
+          break TruncatedPacket::from_error(&e).expect("truncated packet error");
+        }
+      };
+      assert_eq!(truncated, TruncatedPacket { len: ETHERIP_HEADER_SIZE + 1514, src_addr: loopback }, "{:?}", family);
+      // Nothing of the dropped datagram is handed on as a frame.
+      assert!(small.ethrnet_frame().is_none_or(<[u8]>::is_empty), "{:?}", family);
+    }
+  }
+
+  #[test]
+  fn sizes_up_to_the_buffer_are_accepted() {
+    let mut datagram = HeapEtherIpDatagram::with_max_frame_size(60);
+    let capacity = datagram.buffer_size();
+    datagram.ethrnet_frame_mut().0.set(capacity - ETHERIP_HEADER_SIZE);
+    assert_eq!(datagram.ethrnet_frame().map(<[u8]>::len), Some(capacity - ETHERIP_HEADER_SIZE));
+    datagram.datagram_mut().0.set(capacity);
+    assert_eq!(datagram.datagram().map(<[u8]>::len), Some(capacity));
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "exceeds its buffer")]
+  fn frame_size_beyond_the_buffer_panics_in_debug_builds() {
+    let mut datagram = HeapEtherIpDatagram::with_max_frame_size(60);
+    let capacity = datagram.buffer_size();
+    datagram.ethrnet_frame_mut().0.set(capacity - ETHERIP_HEADER_SIZE + 1);
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "exceeds its buffer")]
+  fn datagram_size_beyond_the_buffer_panics_in_debug_builds() {
+    let mut datagram = EtherIpDatagram::new();
+    datagram.datagram_mut().0.set(ETHERIP_DATAGRAM_BUFFER_SIZE + 1);
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "exceeds its buffer")]
+  fn reading_a_size_beyond_the_buffer_panics_in_debug_builds() {
+    let mut datagram = EtherIpDatagram::<64>::new_sized();
+    // Set by an implementation of `parts_mut`, bypassing the checked setters.
+    *datagram.parts_mut().0 = 65;
+    let _ = datagram.ethrnet_frame();
+  }
+
+  #[tokio::test]
+  async fn restarted_sockets_rebind_their_address() {
+    for (seed, family) in [SocketFamily::Inet6, SocketFamily::Inet].into_iter().enumerate() {
+      let loopback = match family {
+        SocketFamily::Inet6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        SocketFamily::Inet => IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+      };
+      let old = match EtherIpSocket::new_bound(family, &loopback) {
+        Ok(socket) => socket,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+        Err(e) => panic!("cannot open an EtherIP socket: {}", e),
+      };
+      // The new daemon may start before the old one has exited, or right after.
+      let new = EtherIpSocket::new_bound(family, &loopback).expect("bind while the old socket is open");
+      drop(old);
+      let restarted = EtherIpSocket::new_bound(family, &loopback).expect("bind after the old socket is closed");
+      drop(new);
+
+      let frame = frame_of(60, seed as u32);
+      let sent = with_frame(EtherIpDatagram::new(), &frame);
+      assert_eq!(etherip_loop_back(&restarted, &sent, &mut EtherIpDatagram::new()).await, frame, "{:?}", family);
+    }
+  }
+
+  #[tokio::test]
+  async fn configured_ip_sockets_are_wrapped_as_they_are() {
+    let socket = match IpSocket::new(EtherIp ()) {
+      Ok(socket) => socket,
+      Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+      Err(e) => panic!("cannot open a raw socket: {}", e),
+    };
+    let fd = socket.as_raw_fd();
+    // An option the crate has no setter for.
+    let mark: libc::c_int = 0x4a7;
+    let result = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, &mark as *const libc::c_int as *const libc::c_void, std::mem::size_of_val(&mark) as libc::socklen_t) };
+    assert_eq!(result, 0, "set SO_MARK: {}", Error::last_os_error());
+
+    let wrapped = EtherIpSocket::from_socket(socket);
+    assert_eq!(wrapped.as_raw_fd(), fd);
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+    let result = unsafe { libc::getsockopt(wrapped.as_raw_fd(), libc::SOL_SOCKET, libc::SO_MARK, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len) };
+    assert_eq!((result, value), (0, mark));
+
+    let frame = frame_of(60, 3);
+    assert_eq!(etherip_loop_back(&wrapped, &with_frame(EtherIpDatagram::new(), &frame), &mut EtherIpDatagram::new()).await, frame);
+    let converted: EtherIpSocket = IpSocket::new(EtherIp ()).expect("raw socket").into();
+    assert_eq!(converted.family(), SocketFamily::Inet6);
+  }
+
+  #[tokio::test]
+  async fn persistent_would_block_fails_after_yielding() {
+    // A fake socket that is always ready but never completes any I/O.
+    let mut attempts = 0;
+    let other_task = tokio::spawn(async {});
+    let result: std::io::Result<()> = WouldBlockRetries::retry(|| async { Ok(()) }, |()| {
+      attempts += 1;
+      None
+    }).await;
+    assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::WouldBlock));
+    assert_eq!(attempts, MAX_WOULD_BLOCK_RETRIES);
+    // The current-thread runtime only ran the other task if the loop yielded.
+    assert!(other_task.is_finished());
+  }
+
+  #[tokio::test]
+  async fn transient_would_block_is_retried() {
+    let mut attempts = 0;
+    let result = WouldBlockRetries::retry(|| async { Ok(()) }, |()| {
+      attempts += 1;
+      (attempts > 100).then_some(Ok(attempts))
+    }).await;
+    assert_eq!(result.ok(), Some(101));
+
+    // Readiness errors are returned without trying the I/O.
+    let result: std::io::Result<()> = WouldBlockRetries::retry(|| async { Err::<(), _>(Error::from(ErrorKind::BrokenPipe)) }, |()| unreachable!()).await;
+    assert_eq!(result.map_err(|e| e.kind()), Err(ErrorKind::BrokenPipe));
+  }
+
+  #[test]
+  fn bind_addresses_must_suit_the_socket() {
+    let (v6_socket, v4_socket) = match (RawIpSocket::new_raw(SocketFamily::Inet6, 253), RawIpSocket::new_raw(SocketFamily::Inet, 253)) {
+      (Ok(v6_socket), Ok(v4_socket)) => (v6_socket, v4_socket),
+      (Err(e), _) | (_, Err(e)) if e.kind() == ErrorKind::PermissionDenied => return,
+      (Err(e), _) | (_, Err(e)) => panic!("cannot open a raw socket: {}", e),
+    };
+    let bind_error = |socket: &RawIpSocket, addr: &str| {
+      let e = socket.local_sockaddr(&addr.parse().unwrap()).expect_err("unsuitable bind address");
+      assert_eq!(e.kind(), ErrorKind::InvalidInput);
+      e.to_string()
+    };
+    assert_eq!(bind_error(&v6_socket, "127.0.0.1"), "cannot bind the IPv4 address 127.0.0.1 to an AF_INET6 socket");
+    assert_eq!(bind_error(&v6_socket, "::ffff:127.0.0.1"), "cannot bind the IPv4 address 127.0.0.1 to an AF_INET6 socket");
+    assert_eq!(bind_error(&v6_socket, "fe80::1"), "cannot bind the link-local address fe80::1 without an interface");
+    assert_eq!(bind_error(&v4_socket, "2001:db8::1"), "cannot bind the IPv6 address 2001:db8::1 to an AF_INET socket");
+    assert!(v4_socket.local_sockaddr(&"::ffff:127.0.0.1".parse().unwrap()).is_ok());
+
+    v4_socket.bind(&"::ffff:127.0.0.1".parse().unwrap()).expect("bind");
+    assert_eq!(v4_socket.local_addr().unwrap(), IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+  }
+
+  /// A blocking EtherIP socket bound to `addr`, or `None` without the permission to open one.
+  fn blocking_socket(addr: &str) -> Option<BlockingEtherIpSocket> {
+    match BlockingEtherIpSocket::new_bound(SocketFamily::Inet, &addr.parse().unwrap()) {
+      Ok(socket) => Some(socket),
+      Err(e) if e.kind() == ErrorKind::PermissionDenied => None,
+      Err(e) => panic!("cannot open a blocking EtherIP socket: {}", e),
+    }
+  }
+
+  #[test]
+  fn blocking_receive_loops_end_at_the_read_timeout() {
+    let Some(mut socket) = blocking_socket("127.0.0.71") else {
+      return;
+    };
+    socket.set_read_timeout(Some(std::time::Duration::from_millis(50)));
+    let started = std::time::Instant::now();
+    let mut received = 0;
+    socket.recv_loop(|_, _| received += 1).expect("receive loop");
+    assert_eq!(received, 0);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(50), "returned after {:?}", started.elapsed());
+    assert!(started.elapsed() < std::time::Duration::from_secs(5), "returned after {:?}", started.elapsed());
+    assert_eq!(socket.recv_from(&mut EtherIpDatagram::new()).err().map(|e| e.kind()), Some(ErrorKind::TimedOut));
+  }
+
+  #[test]
+  fn blocking_clones_send_while_another_thread_receives() {
+    let Some(mut receiver) = blocking_socket("127.0.0.72") else {
+      return;
+    };
+    receiver.set_read_timeout(Some(std::time::Duration::from_millis(500)));
+    // A clone shares the socket, but not the read timeout.
+    let sender = receiver.clone();
+    assert_eq!(sender.read_timeout(), Some(std::time::Duration::from_millis(500)));
+    let receiving = std::thread::spawn(move || {
+      let mut frames = Vec::new();
+      receiver.recv_loop(|frame, src| frames.push((frame.to_vec(), src))).expect("receive loop");
+      frames
+    });
+
+    let dst: IpAddr = "127.0.0.72".parse().unwrap();
+    let sent: Vec<Vec<u8>> = (0..3).map(|seed| frame_of(60, seed)).collect();
+    for frame in &sent {
+      sender.send_to(&with_frame(EtherIpDatagram::new(), frame), &dst).expect("send");
+    }
+    // A datagram with an invalid EtherIP header is not handed on.
+    sender.inner.send_to(&[0xff, 0xff, 0, 0], &dst).expect("send an invalid datagram");
+    let frames = receiving.join().unwrap();
+    assert_eq!(frames, sent.into_iter().map(|frame| (frame, "127.0.0.72".parse().unwrap())).collect::<Vec<_>>());
+  }
+}
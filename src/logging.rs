@@ -0,0 +1,77 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Log level overrides for individual links.
+//!
+//! Messages about a link are logged with the target returned by `link_target`,
+//! and `LinkLevelLogger` filters them by the level configured for that link.
+
+use std::collections::BTreeMap;
+
+use crate::log;
+use crate::parking_lot::{const_rwlock, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Prefix of the log target of messages about a link, followed by the link name.
+pub const LINK_TARGET_PREFIX: &str = "etherip::link::";
+
+/// Log target of messages about a link.
+pub fn link_target(link_name: &str) -> String {
+  format!("{}{}", LINK_TARGET_PREFIX, link_name)
+}
+
+struct Levels {
+  global: LevelFilter,
+  links: BTreeMap<String, LevelFilter>,
+}
+
+static LEVELS: RwLock<Levels> = const_rwlock(Levels {
+  global: LevelFilter::Warn,
+  links: BTreeMap::new(),
+});
+
+/// Set the global level and the per-link overrides, raising the maximum level
+/// of the `log` crate so that the most verbose override gets through.
+pub fn set_levels<I: IntoIterator<Item = (String, LevelFilter)>>(global: LevelFilter, links: I) {
+  let mut levels = LEVELS.write();
+  levels.global = global;
+  levels.links = links.into_iter().collect();
+  let max_level = levels.links.values().copied().fold(global, std::cmp::max);
+  log::set_max_level(max_level);
+}
+
+/// Level that applies to messages with the given target.
+fn level_for(target: &str) -> LevelFilter {
+  let levels = LEVELS.read();
+  target.strip_prefix(LINK_TARGET_PREFIX)
+    .and_then(|link_name| levels.links.get(link_name).copied())
+    .unwrap_or(levels.global)
+}
+
+/// Logger wrapper that applies the per-link levels, falling back to the global level.
+pub struct LinkLevelLogger<L> {
+  inner: L,
+}
+
+impl<L: Log> LinkLevelLogger<L> {
+  pub fn new(inner: L) -> Self {
+    Self { inner }
+  }
+}
+
+impl<L: Log> Log for LinkLevelLogger<L> {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= level_for(metadata.target())
+  }
+
+  fn log(&self, record: &Record) {
+    if self.enabled(record.metadata()) {
+      self.inner.log(record);
+    }
+  }
+
+  fn flush(&self) {
+    self.inner.flush();
+  }
+}
@@ -0,0 +1,221 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Prometheus-style metrics exposition for the EtherIP daemon.
+
+use std::fmt::{Display, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::tokio;
+use crate::log;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(feature = "task-metrics")]
+pub use task_metrics::TaskMonitors;
+
+/// Builder for the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct MetricsWriter {
+  buf: String,
+}
+
+impl MetricsWriter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Write the `HELP` and `TYPE` lines of a metric family.
+  pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(self.buf, "# HELP {} {}", name, help);
+    let _ = writeln!(self.buf, "# TYPE {} {}", name, kind);
+  }
+
+  /// Write a single sample of a metric family.
+  pub fn sample<V: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+    self.buf.push_str(name);
+    if !labels.is_empty() {
+      self.buf.push('{');
+      for (i, (key, label_value)) in labels.iter().enumerate() {
+        if i > 0 {
+          self.buf.push(',');
+        }
+        let _ = write!(self.buf, "{}=\"{}\"", key, escape_label_value(label_value));
+      }
+      self.buf.push('}');
+    }
+    let _ = writeln!(self.buf, " {}", value);
+  }
+
+  pub fn finish(self) -> String {
+    self.buf
+  }
+}
+
+fn escape_label_value(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Endpoints of the metrics server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+  /// `GET /metrics`: the monotonic counters, for Prometheus.
+  Metrics,
+  /// `GET /stats`: the link counts since the last reset.
+  Stats,
+  /// `POST /stats/reset`: the link counts since the last reset, resetting them.
+  StatsReset,
+  /// `GET /flows`: the inner flows with the most traffic on links with a flow table.
+  Flows,
+  /// `GET /fdb`: the MAC addresses learned on links with a table, as gauges.
+  Fdb,
+  /// `GET /fdb/table`: the same as a table for reading.
+  FdbTable,
+}
+
+impl Endpoint {
+  fn from_request(request: &[u8]) -> Option<Self> {
+    if request.starts_with(b"GET /metrics ") {
+      Some(Endpoint::Metrics)
+    } else if request.starts_with(b"GET /stats ") {
+      Some(Endpoint::Stats)
+    } else if request.starts_with(b"POST /stats/reset ") {
+      Some(Endpoint::StatsReset)
+    } else if request.starts_with(b"GET /flows ") {
+      Some(Endpoint::Flows)
+    } else if request.starts_with(b"GET /fdb ") {
+      Some(Endpoint::Fdb)
+    } else if request.starts_with(b"GET /fdb/table ") {
+      Some(Endpoint::FdbTable)
+    } else {
+      None
+    }
+  }
+}
+
+/// Serve the bodies rendered by `render` for each `Endpoint` over HTTP.
+pub async fn serve<F>(addr: SocketAddr, render: F) -> std::io::Result<()>
+where
+  F: Fn(Endpoint) -> String + Send + Sync + 'static,
+{
+  let listener = TcpListener::bind(addr).await?;
+  let render = Arc::new(render);
+  loop {
+    let (stream, peer) = listener.accept().await?;
+    let render = render.clone();
+    tokio::spawn(async move {
+      if let Err(e) = handle_connection(stream, render.as_ref()).await {
+        log::debug!("Metrics request from {} failed: {}", peer, e);
+      }
+    });
+  }
+}
+
+async fn handle_connection<F>(mut stream: TcpStream, render: &F) -> std::io::Result<()>
+where
+  F: Fn(Endpoint) -> String,
+{
+  let mut buf = [0u8; 4096];
+  let mut len = 0;
+  while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+    if len == buf.len() {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request header too large"));
+    }
+    let n = stream.read(&mut buf[len..]).await?;
+    if n == 0 {
+      return Ok(());
+    }
+    len += n;
+  }
+
+  let response = match Endpoint::from_request(&buf[..len]) {
+    Some(endpoint) => {
+      let body = render(endpoint);
+      format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    },
+    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+  };
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await
+}
+
+#[cfg(feature = "task-metrics")]
+mod task_metrics {
+  use std::collections::HashMap;
+
+  use crate::parking_lot::RwLock;
+  use crate::tokio;
+
+  use crate::tokio_metrics::TaskMonitor;
+
+  use super::MetricsWriter;
+
+  /// Per-task instrumentation of the daemon's forwarding tasks.
+  /// Monitors are keyed by the task kind and the link name (empty for shared tasks).
+  #[derive(Debug, Default)]
+  pub struct TaskMonitors {
+    monitors: RwLock<HashMap<(&'static str, String), TaskMonitor>>,
+  }
+
+  impl TaskMonitors {
+    pub fn new() -> Self {
+      Self::default()
+    }
+
+    /// Get the monitor for a task, creating it if it does not exist yet.
+    pub fn monitor(&self, task: &'static str, link_name: &str) -> TaskMonitor {
+      let key = (task, link_name.to_string());
+      if let Some(monitor) = self.monitors.read().get(&key) {
+        return monitor.clone();
+      }
+      self.monitors.write().entry(key).or_default().clone()
+    }
+
+    /// Forget the monitors of links that are no longer configured.
+    pub fn retain_links<F: Fn(&str) -> bool>(&self, keep: F) {
+      self.monitors.write().retain(|(_, link_name), _| link_name.is_empty() || keep(link_name));
+    }
+
+    /// Write per-task busy/idle/scheduled gauges and runtime queue depths.
+    pub fn render(&self, writer: &mut MetricsWriter) {
+      let monitors = self.monitors.read();
+      let mut samples: Vec<_> = monitors.iter().map(|((task, link_name), monitor)| (*task, link_name.as_str(), monitor.cumulative())).collect();
+      samples.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+      writer.family("etherip_task_busy_seconds", "gauge", "Total time spent polling the task.");
+      for (task, link_name, metrics) in &samples {
+        writer.sample("etherip_task_busy_seconds", &labels(task, link_name), metrics.total_poll_duration.as_secs_f64());
+      }
+      writer.family("etherip_task_idle_seconds", "gauge", "Total time the task spent waiting to be woken.");
+      for (task, link_name, metrics) in &samples {
+        writer.sample("etherip_task_idle_seconds", &labels(task, link_name), metrics.total_idle_duration.as_secs_f64());
+      }
+      writer.family("etherip_task_scheduled_seconds", "gauge", "Total time the task spent in the run queue after being woken.");
+      for (task, link_name, metrics) in &samples {
+        writer.sample("etherip_task_scheduled_seconds", &labels(task, link_name), metrics.total_scheduled_duration.as_secs_f64());
+      }
+      writer.family("etherip_task_scheduled_count", "gauge", "Number of times the task was scheduled.");
+      for (task, link_name, metrics) in &samples {
+        writer.sample("etherip_task_scheduled_count", &labels(task, link_name), metrics.total_scheduled_count);
+      }
+
+      if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        let runtime_metrics = handle.metrics();
+        writer.family("etherip_runtime_alive_tasks", "gauge", "Number of alive tasks in the runtime.");
+        writer.sample("etherip_runtime_alive_tasks", &[], runtime_metrics.num_alive_tasks());
+        writer.family("etherip_runtime_global_queue_depth", "gauge", "Number of tasks in the runtime's global queue.");
+        writer.sample("etherip_runtime_global_queue_depth", &[], runtime_metrics.global_queue_depth());
+      }
+    }
+  }
+
+  fn labels<'a>(task: &'a str, link_name: &'a str) -> Vec<(&'a str, &'a str)> {
+    if link_name.is_empty() {
+      vec![("task", task)]
+    } else {
+      vec![("task", task), ("link", link_name)]
+    }
+  }
+}
@@ -0,0 +1,111 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! MTU advertisements exchanged between the two ends of a link.
+//!
+//! Each end periodically tunnels a broadcast frame of the IEEE local experimental
+//! ethertype carrying its maximum MTU, and both set the TAP MTU to the smaller value.
+//!
+//! This is not part of RFC 3378: when enabled, a 1-byte shim telling advertisements from
+//! the frames of the TAP interface is inserted before the Ethernet frame (after the sequence
+//! number and before the compression shim, if any). Advertisements are consumed by the
+//! receiving daemon and never reach the TAP interface, while frames of the same ethertype
+//! sent by hosts are forwarded like any other.
+
+use std::time::Duration;
+
+use crate::ethernet::{EthernetHeader, MacAddr, ETHERNET_HEADER_SIZE};
+
+/// Size of the shim telling advertisements from frames.
+pub const MTU_SHIM_SIZE: usize = 1;
+
+/// Shim of a frame of the TAP interface.
+pub const FLAG_FRAME: u8 = 0;
+
+/// Shim of an MTU advertisement.
+pub const FLAG_ADVERTISEMENT: u8 = 1;
+
+/// IEEE 802 Local Experimental Ethertype 1.
+pub const ETHERTYPE_MTU_ADVERTISEMENT: u16 = 0x88b5;
+
+/// Interval between advertisements.
+pub const MTU_ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(10);
+
+const MAGIC: [u8; 4] = *b"EIPM";
+const VERSION: u8 = 1;
+
+/// Size of an advertisement frame (the minimum Ethernet frame size without FCS).
+pub const MTU_ADVERTISEMENT_SIZE: usize = 60;
+
+/// MTU advertisement of one end of a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtuAdvertisement {
+  pub mtu: u16,
+}
+
+impl MtuAdvertisement {
+  /// Parse an advertisement frame, returning `None` for any other frame.
+  pub fn parse(frame: &[u8]) -> Option<Self> {
+    let (header, payload) = EthernetHeader::parse(frame)?;
+    if header.ethertype != ETHERTYPE_MTU_ADVERTISEMENT || payload.len() < 8 || payload[..4] != MAGIC || payload[4] != VERSION {
+      return None;
+    }
+    Some(Self {
+      mtu: u16::from_be_bytes([payload[6], payload[7]]),
+    })
+  }
+
+  /// Write the advertisement frame into `buf`, returning its length.
+  /// `buf` must be at least `MTU_ADVERTISEMENT_SIZE` bytes long.
+  pub fn write(&self, buf: &mut [u8]) -> usize {
+    let frame = &mut buf[..MTU_ADVERTISEMENT_SIZE];
+    frame.fill(0);
+    EthernetHeader {
+      destination: MacAddr::BROADCAST,
+      source: MacAddr::default(),
+      ethertype: ETHERTYPE_MTU_ADVERTISEMENT,
+    }.write(frame);
+    let payload = &mut frame[ETHERNET_HEADER_SIZE..];
+    payload[..4].copy_from_slice(&MAGIC);
+    payload[4] = VERSION;
+    payload[6..8].copy_from_slice(&self.mtu.to_be_bytes());
+    MTU_ADVERTISEMENT_SIZE
+  }
+}
+
+/// Split the shim off an Ethernet frame payload, returning whether the rest is an advertisement.
+/// Returns `None` for a missing shim or an unknown flag.
+pub fn split_mtu_shim(data: &[u8]) -> Option<(bool, &[u8])> {
+  let (&flag, frame) = data.split_first()?;
+  match flag {
+    FLAG_FRAME => Some((false, frame)),
+    FLAG_ADVERTISEMENT => Some((true, frame)),
+    _ => None,
+  }
+}
+
+/// MTU negotiation state of the receiving end of a link.
+#[derive(Debug, Clone)]
+pub struct MtuNegotiation {
+  local: u16,
+  effective: Option<u16>,
+}
+
+impl MtuNegotiation {
+  pub fn new(local: u16) -> Self {
+    Self {
+      local,
+      effective: None,
+    }
+  }
+
+  /// Record a peer advertisement, returning the new effective MTU if it changed.
+  pub fn observe(&mut self, advertisement: MtuAdvertisement) -> Option<u16> {
+    let mtu = self.local.min(advertisement.mtu);
+    if self.effective == Some(mtu) {
+      return None;
+    }
+    self.effective = Some(mtu);
+    Some(mtu)
+  }
+}
@@ -0,0 +1,50 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Named network namespaces, as created by `ip netns add`.
+//!
+//! Entering a namespace requires CAP_SYS_ADMIN. Only the calling thread is moved,
+//! and it is moved back before returning.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+
+use crate::caps;
+use crate::libc;
+
+/// Directory where `ip netns` bind-mounts named namespaces.
+pub const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// Path of a named network namespace.
+pub fn netns_path(name: &str) -> std::io::Result<PathBuf> {
+  if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+    return Err(Error::new(ErrorKind::InvalidInput, format!("invalid network namespace name: {:?}", name)));
+  }
+  Ok(PathBuf::from(NETNS_RUN_DIR).join(name))
+}
+
+fn enter(namespace: &File) -> std::io::Result<()> {
+  if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+    return Err(caps::explain_permission_error(Error::last_os_error(), caps::Capability::SysAdmin, "enter a network namespace"));
+  }
+  Ok(())
+}
+
+/// Run `f` on the current thread inside the named network namespace.
+/// Sockets and interfaces created by `f` stay in that namespace.
+///
+/// # Panics
+/// Panics if the thread cannot return to its original namespace,
+/// because everything it did afterwards would happen in the wrong one.
+pub fn run_in<T, F: FnOnce() -> std::io::Result<T>>(name: &str, f: F) -> std::io::Result<T> {
+  let original = File::open("/proc/thread-self/ns/net")?;
+  let target = File::open(netns_path(name)?).map_err(|e| Error::new(e.kind(), format!("cannot open network namespace {}: {}", name, e)))?;
+  enter(&target)?;
+  let result = f();
+  if let Err(e) = enter(&original) {
+    panic!("failed to return from network namespace {}: {}", name, e);
+  }
+  result
+}
@@ -0,0 +1,223 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! AF_PACKET sockets, which put Ethernet frames directly on a network interface or
+//! capture them from it, so that a link can be attached to a physical interface
+//! without a TAP and a bridge.
+//!
+//! A link whose input and output are the same interface extends that segment to the
+//! remote transparently. Frames the host itself sends on the interface, including the
+//! ones written by the output, are never captured, so a link does not loop its own
+//! frames back. Nothing prevents loops through the network though: two sites capturing
+//! from interfaces of one segment, or a segment reachable both through the tunnel and
+//! another path, forward broadcasts forever, since the daemon runs no spanning tree.
+
+use std::io::{Error, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::libc;
+use crate::tokio;
+
+use tokio::io::unix::AsyncFd;
+
+use crate::caps;
+use crate::ethernet::{ETHERNET_HEADER_SIZE, VLAN_TAG_SIZE};
+
+/// AF_PACKET socket bound to an interface, sending whole Ethernet frames on it
+/// and, if opened as an input, receiving the frames arriving on it.
+#[derive(Debug)]
+pub struct PacketSocket {
+  inner: AsyncFd<OwnedFd>,
+  ifname: String,
+  /// Longest frame the interface receives with the MTU it had when opened, for inputs.
+  max_frame_size: Option<usize>,
+}
+
+impl PacketSocket {
+  /// Open a socket that sends frames on `ifname`. Its protocol is 0, so the kernel
+  /// hands it no frames to receive. Must be called in the namespace of the interface.
+  pub fn new_output(ifname: &str) -> std::io::Result<Self> {
+    let ifindex = crate::interface_index(ifname)?;
+    let fd = open_socket()?;
+    bind(&fd, ifindex, 0)?;
+    Ok(Self {
+      inner: AsyncFd::new(fd)?,
+      ifname: ifname.to_string(),
+      max_frame_size: None,
+    })
+  }
+
+  /// Open a socket that receives every frame arriving on `ifname` and accepted by `filter`,
+  /// a classic BPF program run on the whole frame, and can send frames on it as well.
+  /// The interface is put in promiscuous mode until the socket is closed.
+  /// Must be called in the namespace of the interface.
+  pub fn new_input(ifname: &str, filter: Option<&[libc::sock_filter]>) -> std::io::Result<Self> {
+    let ifindex = crate::interface_index(ifname)?;
+    let mtu = crate::tap::get_mtu(ifname)?;
+    let fd = open_socket()?;
+    // The filter is attached before binding to a protocol, so no frame gets past it.
+    if let Some(filter) = filter {
+      let len = filter.len().try_into().map_err(|_| Error::new(ErrorKind::InvalidInput, "BPF program too long"))?;
+      let fprog = libc::sock_fprog {
+        len,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+      };
+      setsockopt(&fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)?;
+    }
+    let mut mreq: libc::packet_mreq = unsafe { std::mem::zeroed() };
+    mreq.mr_ifindex = ifindex as libc::c_int;
+    mreq.mr_type = libc::PACKET_MR_PROMISC as libc::c_ushort;
+    setsockopt(&fd, libc::SOL_PACKET, libc::PACKET_ADD_MEMBERSHIP, &mreq)?;
+    bind(&fd, ifindex, (libc::ETH_P_ALL as u16).to_be())?;
+    Ok(Self {
+      inner: AsyncFd::new(fd)?,
+      ifname: ifname.to_string(),
+      max_frame_size: Some(mtu as usize + ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE),
+    })
+  }
+
+  /// Name of the interface.
+  pub fn ifname(&self) -> &str {
+    &self.ifname
+  }
+
+  /// Longest frame expected from an input, including a VLAN tag. Frames merged by
+  /// receive offloads such as GRO can be longer; disable them on the interface.
+  pub fn max_frame_size(&self) -> Option<usize> {
+    self.max_frame_size
+  }
+
+  /// Wait until a frame is available to receive.
+  pub async fn readable(&self) -> std::io::Result<()> {
+    loop {
+      let mut guard = self.inner.readable().await?;
+      let mut pollfd = libc::pollfd { fd: self.inner.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+      let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+      if ret < 0 {
+        return Err(Error::last_os_error());
+      }
+      if pollfd.revents != 0 {
+        return Ok(());
+      }
+      guard.clear_ready();
+    }
+  }
+
+  /// Receive a frame arriving on the interface, returning its full length, which is
+  /// larger than `buf` if the frame was truncated. Frames sent by the host are skipped.
+  pub async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+      let mut guard = self.inner.readable().await?;
+      let result = guard.try_io(|inner| {
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        let mut addr_len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        let n = unsafe {
+          libc::recvfrom(inner.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), libc::MSG_TRUNC, &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr, &mut addr_len)
+        };
+        if n < 0 {
+          return Err(Error::last_os_error());
+        }
+        Ok((n as usize, addr.sll_pkttype))
+      });
+      match result {
+        Ok(Ok((_, pkttype))) if pkttype == libc::PACKET_OUTGOING => continue,
+        Ok(Ok((len, _))) => return Ok(len),
+        Ok(Err(e)) => return Err(e),
+        Err(_would_block) => continue,
+      }
+    }
+  }
+
+  /// Send an Ethernet frame on the interface, as it is.
+  pub async fn send(&self, frame: &[u8]) -> std::io::Result<usize> {
+    loop {
+      let mut guard = self.inner.writable().await?;
+      let result = guard.try_io(|inner| {
+        let n = unsafe { libc::send(inner.as_raw_fd(), frame.as_ptr() as *const libc::c_void, frame.len(), 0) };
+        match n < 0 {
+          true => Err(Error::last_os_error()),
+          false => Ok(n as usize),
+        }
+      });
+      match result {
+        Ok(result) => return result,
+        Err(_would_block) => continue,
+      }
+    }
+  }
+}
+
+impl AsRawFd for PacketSocket {
+  fn as_raw_fd(&self) -> std::os::fd::RawFd {
+    self.inner.as_raw_fd()
+  }
+}
+
+/// Interface a link reads the frames it sends to the remote from,
+/// or writes the frames it receives from the remote to.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Attachment {
+  /// The TAP interface of the link.
+  #[default]
+  Tap,
+  /// An AF_PACKET socket on an existing interface, written as `raw:IFNAME`.
+  Raw(String),
+}
+
+impl std::str::FromStr for Attachment {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.split_once(':') {
+      None if s == "tap" => Ok(Attachment::Tap),
+      Some(("raw", ifname)) if !ifname.is_empty() && ifname.len() < libc::IFNAMSIZ => Ok(Attachment::Raw(ifname.to_string())),
+      _ => Err(Error::new(ErrorKind::InvalidInput, format!("invalid interface {:?}; expected \"tap\" or \"raw:IFNAME\"", s))),
+    }
+  }
+}
+
+impl std::fmt::Display for Attachment {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Attachment::Tap => write!(f, "tap"),
+      Attachment::Raw(ifname) => write!(f, "raw:{}", ifname),
+    }
+  }
+}
+
+impl<'de> crate::serde::Deserialize<'de> for Attachment {
+  fn deserialize<D: crate::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(crate::serde::de::Error::custom)
+  }
+}
+
+/// Open a non-blocking AF_PACKET socket receiving nothing until bound to a protocol.
+fn open_socket() -> std::io::Result<OwnedFd> {
+  let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+  if fd < 0 {
+    return Err(caps::explain_permission_error(Error::last_os_error(), caps::Capability::NetRaw, "open an AF_PACKET socket"));
+  }
+  Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Bind a socket to an interface; `protocol`, in network byte order, selects the frames it receives.
+fn bind(fd: &OwnedFd, ifindex: u32, protocol: u16) -> std::io::Result<()> {
+  let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+  addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+  addr.sll_protocol = protocol;
+  addr.sll_ifindex = ifindex as libc::c_int;
+  let addr_len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+  if unsafe { libc::bind(fd.as_raw_fd(), &addr as *const libc::sockaddr_ll as *const libc::sockaddr, addr_len) } < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(())
+}
+
+fn setsockopt<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, value: &T) -> std::io::Result<()> {
+  let len = std::mem::size_of::<T>() as libc::socklen_t;
+  if unsafe { libc::setsockopt(fd.as_raw_fd(), level, name, value as *const T as *const libc::c_void, len) } < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(())
+}
@@ -0,0 +1,73 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Dropping root privileges once the sockets and TAP interfaces are open.
+//!
+//! File descriptors opened before the drop stay usable; anything needing a capability
+//! afterwards (creating interfaces, opening raw sockets, entering namespaces) fails.
+
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+
+use crate::libc;
+
+/// Size of the buffer for the strings of a passwd or group entry.
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+/// Look up the uid and primary gid of a user.
+pub fn lookup_user(name: &str) -> std::io::Result<(libc::uid_t, libc::gid_t)> {
+  let c_name = CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+  let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+  let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+  let mut result = std::ptr::null_mut();
+  let ret = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+  if ret != 0 {
+    return Err(Error::from_raw_os_error(ret));
+  }
+  if result.is_null() {
+    return Err(Error::new(ErrorKind::NotFound, format!("user {} does not exist", name)));
+  }
+  Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Look up the gid of a group.
+pub fn lookup_group(name: &str) -> std::io::Result<libc::gid_t> {
+  let c_name = CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+  let mut group: libc::group = unsafe { std::mem::zeroed() };
+  let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+  let mut result = std::ptr::null_mut();
+  let ret = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+  if ret != 0 {
+    return Err(Error::from_raw_os_error(ret));
+  }
+  if result.is_null() {
+    return Err(Error::new(ErrorKind::NotFound, format!("group {} does not exist", name)));
+  }
+  Ok(group.gr_gid)
+}
+
+/// Switch every thread of the process to `user`, and to `group` or else the user's
+/// primary group, dropping all supplementary groups. Fails if root could be regained.
+pub fn drop_privileges(user: &str, group: Option<&str>) -> std::io::Result<()> {
+  let (uid, primary_gid) = lookup_user(user)?;
+  let gid = match group {
+    Some(group) => lookup_group(group)?,
+    None => primary_gid,
+  };
+  // The C library applies these to all threads, not only the calling one.
+  unsafe {
+    if libc::setgroups(1, &gid) < 0 {
+      return Err(Error::last_os_error());
+    }
+    if libc::setgid(gid) < 0 {
+      return Err(Error::last_os_error());
+    }
+    if libc::setuid(uid) < 0 {
+      return Err(Error::last_os_error());
+    }
+    if uid != 0 && (libc::setuid(0) == 0 || libc::geteuid() != uid || libc::getegid() != gid) {
+      return Err(Error::new(ErrorKind::PermissionDenied, "privileges could not be dropped for good"));
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,83 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Detection of the runtime environment, for diagnosing deployments.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::libc;
+use crate::caps::Capability;
+use crate::tap;
+
+/// Outcome of probing for a capability or kernel feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+  Yes,
+  No,
+  /// The probe itself failed, with the reason.
+  Unknown(String),
+}
+
+impl Probe {
+  fn from_result(result: std::io::Result<bool>) -> Self {
+    match result {
+      Ok(true) => Probe::Yes,
+      Ok(false) => Probe::No,
+      Err(e) => Probe::Unknown(e.to_string()),
+    }
+  }
+}
+
+impl fmt::Display for Probe {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Probe::Yes => f.write_str("yes"),
+      Probe::No => f.write_str("no"),
+      Probe::Unknown(reason) => write!(f, "unknown ({})", reason),
+    }
+  }
+}
+
+/// Probe the capabilities and kernel features the daemon relies on,
+/// returning them as (name, outcome) pairs in a fixed order.
+/// `tun_device` is the TUN/TAP clone device to check (the default one if `None`).
+pub fn probe_all(tun_device: Option<&Path>) -> Vec<(&'static str, Probe)> {
+  let tun_features = tap::tun_features(tun_device);
+  vec![
+    ("cap_net_raw", Probe::from_result(Capability::NetRaw.is_effective())),
+    ("cap_net_admin", Probe::from_result(Capability::NetAdmin.is_effective())),
+    ("tun_device", Probe::from_result(tun_features.as_ref().map(|_| true).map_err(clone_error))),
+    ("iff_multi_queue", Probe::from_result(tun_features.map(|features| features & libc::IFF_MULTI_QUEUE != 0))),
+    ("tunsetoffload", Probe::from_result(tap::tun_offload_supported(tun_device))),
+    ("ipv6_recverr", Probe::from_result(ipv6_recverr_supported())),
+  ]
+}
+
+fn clone_error(error: &std::io::Error) -> std::io::Error {
+  match error.raw_os_error() {
+    Some(code) => std::io::Error::from_raw_os_error(code),
+    None => std::io::Error::new(error.kind(), error.to_string()),
+  }
+}
+
+/// Check whether `IPV6_RECVERR` can be enabled, on an unprivileged UDP socket.
+fn ipv6_recverr_supported() -> std::io::Result<bool> {
+  let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+  if fd < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  let enable: libc::c_int = 1;
+  let ret = unsafe {
+    libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, &enable as *const libc::c_int as *const libc::c_void, std::mem::size_of_val(&enable) as libc::socklen_t)
+  };
+  let error = std::io::Error::last_os_error();
+  unsafe { libc::close(fd) };
+  if ret < 0 {
+    return match error.raw_os_error() {
+      Some(libc::ENOPROTOOPT) => Ok(false),
+      _ => Err(error),
+    };
+  }
+  Ok(true)
+}
@@ -0,0 +1,199 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Two-level priority queue for the egress path of a link,
+//! so that control frames are not starved by bulk data.
+//! The queued items are opaque byte buffers (the daemon queues encoded EtherIP datagrams).
+
+use std::collections::VecDeque;
+
+use crate::parking_lot::Mutex;
+use crate::tokio;
+
+use tokio::sync::Notify;
+
+use crate::ethernet::{EthernetHeader, ETHERTYPE_ARP, ETHERTYPE_IPV6};
+
+const ETHERTYPE_SLOW_PROTOCOLS: u16 = 0x8809;
+const ETHERTYPE_LLDP: u16 = 0x88cc;
+const IPPROTO_ICMPV6: u8 = 58;
+
+/// Priority of an egress frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+  /// ARP, ICMPv6, LLDP, LACP and IEEE reserved link-local multicast (e.g. STP).
+  Control,
+  /// Everything else.
+  Data,
+}
+
+/// Classify an Ethernet frame by its header.
+pub fn classify(frame: &[u8]) -> Priority {
+  let Some((header, payload)) = EthernetHeader::parse(frame) else {
+    return Priority::Data;
+  };
+  let dst = header.destination.octets();
+  if dst[..5] == [0x01, 0x80, 0xc2, 0x00, 0x00] && dst[5] <= 0x0f {
+    return Priority::Control;
+  }
+  match header.ethertype {
+    ETHERTYPE_ARP | ETHERTYPE_SLOW_PROTOCOLS | ETHERTYPE_LLDP => Priority::Control,
+    ETHERTYPE_IPV6 if payload.len() >= 40 && payload[6] == IPPROTO_ICMPV6 => Priority::Control,
+    _ => Priority::Data,
+  }
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+  control: VecDeque<Vec<u8>>,
+  data: VecDeque<Vec<u8>>,
+}
+
+/// Bounded egress queue that always dequeues control frames before data frames.
+#[derive(Debug)]
+pub struct EgressQueue {
+  queues: Mutex<Queues>,
+  notify: Notify,
+  capacity: usize,
+}
+
+impl EgressQueue {
+  /// Create a queue holding up to `capacity` frames of each priority.
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      queues: Mutex::new(Queues::default()),
+      notify: Notify::new(),
+      capacity,
+    }
+  }
+
+  /// Enqueue a frame. Returns false if the queue of its priority is full and the frame was dropped.
+  pub fn push(&self, priority: Priority, frame: Vec<u8>) -> bool {
+    {
+      let mut queues = self.queues.lock();
+      let queue = match priority {
+        Priority::Control => &mut queues.control,
+        Priority::Data => &mut queues.data,
+      };
+      if queue.len() >= self.capacity {
+        return false;
+      }
+      queue.push_back(frame);
+    }
+    self.notify.notify_one();
+    true
+  }
+
+  /// Dequeue the next frame, waiting until one is available.
+  pub async fn pop(&self) -> Vec<u8> {
+    loop {
+      if let Some(frame) = self.try_pop() {
+        return frame;
+      }
+      self.notify.notified().await;
+    }
+  }
+
+  /// Dequeue up to `max` frames, waiting until at least one is available.
+  pub async fn pop_batch(&self, max: usize) -> Vec<Vec<u8>> {
+    let mut batch = vec![self.pop().await];
+    let mut queues = self.queues.lock();
+    while batch.len() < max {
+      match queues.control.pop_front().or_else(|| queues.data.pop_front()) {
+        Some(frame) => batch.push(frame),
+        None => break,
+      }
+    }
+    batch
+  }
+
+  pub fn try_pop(&self) -> Option<Vec<u8>> {
+    let mut queues = self.queues.lock();
+    queues.control.pop_front().or_else(|| queues.data.pop_front())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  /// An Ethernet frame to `destination` of `ethertype`, carrying `payload`.
+  fn frame(destination: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = destination.to_vec();
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+  }
+
+  /// An IPv6 header carrying `next_header`.
+  fn ipv6(next_header: u8) -> Vec<u8> {
+    let mut header = vec![0u8; 40];
+    header[0] = 0x60;
+    header[6] = next_header;
+    header
+  }
+
+  #[test]
+  fn control_frames_are_told_from_data() {
+    let unicast = [0x02, 0, 0, 0, 0, 0x02];
+    assert_eq!(classify(&frame(unicast, ETHERTYPE_ARP, &[0; 28])), Priority::Control);
+    assert_eq!(classify(&frame(unicast, ETHERTYPE_SLOW_PROTOCOLS, &[1])), Priority::Control);
+    assert_eq!(classify(&frame(unicast, ETHERTYPE_LLDP, &[])), Priority::Control);
+    assert_eq!(classify(&frame(unicast, ETHERTYPE_IPV6, &ipv6(IPPROTO_ICMPV6))), Priority::Control);
+    // STP goes to a reserved link-local group, whatever its length field says.
+    assert_eq!(classify(&frame([0x01, 0x80, 0xc2, 0, 0, 0x00], 0x0026, &[0x42])), Priority::Control);
+
+    assert_eq!(classify(&frame([0x01, 0x80, 0xc2, 0, 0, 0x10], 0x0800, &[])), Priority::Data);
+    assert_eq!(classify(&frame(unicast, ETHERTYPE_IPV6, &ipv6(6))), Priority::Data);
+    // An IPv6 header cut short is not looked into.
+    assert_eq!(classify(&frame(unicast, ETHERTYPE_IPV6, &ipv6(IPPROTO_ICMPV6)[..39])), Priority::Data);
+    assert_eq!(classify(&frame(unicast, 0x0800, &[0x45; 20])), Priority::Data);
+    assert_eq!(classify(&[0x02; 13]), Priority::Data);
+  }
+
+  #[test]
+  fn full_queues_drop_new_frames_of_their_priority_only() {
+    let queue = EgressQueue::new(2);
+    assert!(queue.push(Priority::Data, vec![1]));
+    assert!(queue.push(Priority::Data, vec![2]));
+    assert!(!queue.push(Priority::Data, vec![3]));
+    // Control frames have a queue of their own.
+    assert!(queue.push(Priority::Control, vec![10]));
+    assert!(queue.push(Priority::Control, vec![11]));
+    assert!(!queue.push(Priority::Control, vec![12]));
+
+    // Control frames first, each priority in order; the dropped frames are gone.
+    let popped: Vec<Vec<u8>> = std::iter::from_fn(|| queue.try_pop()).collect();
+    assert_eq!(popped, vec![vec![10], vec![11], vec![1], vec![2]]);
+    assert!(queue.push(Priority::Data, vec![4]));
+  }
+
+  #[tokio::test]
+  async fn pop_waits_for_a_frame() {
+    let queue = Arc::new(EgressQueue::new(4));
+    let popping = tokio::spawn({
+      let queue = queue.clone();
+      async move { queue.pop().await }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!popping.is_finished());
+    queue.push(Priority::Data, vec![1]);
+    assert_eq!(tokio::time::timeout(Duration::from_secs(5), popping).await.unwrap().unwrap(), vec![1]);
+  }
+
+  #[tokio::test]
+  async fn batches_are_bounded_and_control_first() {
+    let queue = EgressQueue::new(8);
+    for i in 0..3 {
+      queue.push(Priority::Data, vec![i]);
+    }
+    queue.push(Priority::Control, vec![10]);
+    assert_eq!(queue.pop_batch(3).await, vec![vec![10], vec![0], vec![1]]);
+    assert_eq!(queue.pop_batch(3).await, vec![vec![2]]);
+    assert_eq!(queue.try_pop(), None);
+  }
+}
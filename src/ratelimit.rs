@@ -0,0 +1,102 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Token bucket rate limiting of frames.
+
+use std::time::Instant;
+
+/// Token bucket allowing `rate` frames per second on average and bursts of up to `burst` frames.
+#[derive(Debug)]
+pub struct TokenBucket {
+  rate: f64,
+  burst: f64,
+  tokens: f64,
+  last_refill: Instant,
+}
+
+impl TokenBucket {
+  /// Create a full bucket.
+  pub fn new(rate: u32, burst: u32) -> Self {
+    Self {
+      rate: rate as f64,
+      burst: burst as f64,
+      tokens: burst as f64,
+      last_refill: Instant::now(),
+    }
+  }
+
+  /// Take a token, returning false if there is none left.
+  pub fn try_take(&mut self) -> bool {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+    self.last_refill = now;
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    if self.tokens < 1.0 {
+      return false;
+    }
+    self.tokens -= 1.0;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  /// Pretend that `elapsed` has passed since the bucket was last refilled.
+  fn wait(bucket: &mut TokenBucket, elapsed: Duration) {
+    bucket.last_refill = bucket.last_refill.checked_sub(elapsed).expect("monotonic clock far enough from its origin");
+  }
+
+  /// Tokens taken until the bucket runs dry.
+  fn drain(bucket: &mut TokenBucket) -> u32 {
+    let mut taken = 0;
+    while bucket.try_take() {
+      taken += 1;
+      assert!(taken <= 1_000_000, "the bucket never runs dry");
+    }
+    taken
+  }
+
+  #[test]
+  fn full_buckets_allow_one_burst() {
+    let mut bucket = TokenBucket::new(1, 5);
+    assert_eq!(drain(&mut bucket), 5);
+    assert!(!bucket.try_take());
+  }
+
+  #[test]
+  fn tokens_refill_at_the_rate() {
+    let mut bucket = TokenBucket::new(10, 100);
+    drain(&mut bucket);
+    wait(&mut bucket, Duration::from_millis(500));
+    assert_eq!(drain(&mut bucket), 5);
+    // Fractions of a token are kept until they add up.
+    wait(&mut bucket, Duration::from_millis(60));
+    assert!(!bucket.try_take());
+    wait(&mut bucket, Duration::from_millis(60));
+    assert!(bucket.try_take());
+  }
+
+  #[test]
+  fn refills_are_capped_at_the_burst() {
+    let mut bucket = TokenBucket::new(1000, 3);
+    drain(&mut bucket);
+    wait(&mut bucket, Duration::from_secs(3600));
+    assert_eq!(drain(&mut bucket), 3);
+  }
+
+  #[test]
+  fn zero_rates_never_refill_and_zero_bursts_allow_nothing() {
+    let mut bucket = TokenBucket::new(0, 2);
+    wait(&mut bucket, Duration::from_secs(3600));
+    assert_eq!(drain(&mut bucket), 2);
+    wait(&mut bucket, Duration::from_secs(3600));
+    assert!(!bucket.try_take());
+
+    let mut bucket = TokenBucket::new(1000, 0);
+    wait(&mut bucket, Duration::from_secs(1));
+    assert!(!bucket.try_take());
+  }
+}
@@ -0,0 +1,131 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Optional tunnel sequence numbers for loss/reorder detection.
+//!
+//! This is not part of RFC 3378: when enabled, a 2-byte big-endian sequence number
+//! is inserted between the EtherIP header and the Ethernet frame.
+//! Both ends of a link must enable it.
+
+use std::sync::atomic::{AtomicU16, Ordering};
+
+/// Size of the sequence number shim.
+pub const SEQNO_SHIM_SIZE: usize = 2;
+
+/// Outcome of receiving a sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceEvent {
+  /// The expected sequence number (or the first one seen).
+  InOrder,
+  /// Newer than expected; the given number of datagrams were skipped.
+  Gap(u16),
+  /// Older than expected (reordered or duplicated).
+  OutOfOrder,
+}
+
+/// Sequence number generator for the sending side.
+/// Shared by everything that sends on a link, so that the peer sees a single sequence.
+#[derive(Debug, Default)]
+pub struct SequenceCounter {
+  next: AtomicU16,
+}
+
+impl SequenceCounter {
+  pub fn next_seqno(&self) -> u16 {
+    self.next.fetch_add(1, Ordering::Relaxed)
+  }
+}
+
+/// Sequence number tracker for the receiving side.
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+  expected: Option<u16>,
+}
+
+impl SequenceTracker {
+  /// Record a received sequence number.
+  pub fn observe(&mut self, seqno: u16) -> SequenceEvent {
+    let Some(expected) = self.expected else {
+      self.expected = Some(seqno.wrapping_add(1));
+      return SequenceEvent::InOrder;
+    };
+    let distance = seqno.wrapping_sub(expected) as i16;
+    if distance < 0 {
+      return SequenceEvent::OutOfOrder;
+    }
+    self.expected = Some(seqno.wrapping_add(1));
+    if distance == 0 {
+      SequenceEvent::InOrder
+    } else {
+      SequenceEvent::Gap(distance as u16)
+    }
+  }
+}
+
+/// Split the sequence number shim off an Ethernet frame payload.
+pub fn split_seqno(data: &[u8]) -> Option<(u16, &[u8])> {
+  if data.len() < SEQNO_SHIM_SIZE {
+    return None;
+  }
+  let (shim, frame) = data.split_at(SEQNO_SHIM_SIZE);
+  Some((u16::from_be_bytes([shim[0], shim[1]]), frame))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counters_wrap_around() {
+    let counter = SequenceCounter { next: AtomicU16::new(u16::MAX - 1) };
+    assert_eq!([counter.next_seqno(), counter.next_seqno(), counter.next_seqno()], [u16::MAX - 1, u16::MAX, 0]);
+  }
+
+  #[test]
+  fn in_order_sequences_wrap_around() {
+    let mut tracker = SequenceTracker::default();
+    // Whatever comes first starts the sequence.
+    for seqno in [u16::MAX - 1, u16::MAX, 0, 1] {
+      assert_eq!(tracker.observe(seqno), SequenceEvent::InOrder, "seqno {}", seqno);
+    }
+  }
+
+  #[test]
+  fn gaps_count_the_skipped_numbers_across_wraparound() {
+    let mut tracker = SequenceTracker::default();
+    tracker.observe(10);
+    assert_eq!(tracker.observe(14), SequenceEvent::Gap(3));
+    let mut tracker = SequenceTracker::default();
+    tracker.observe(u16::MAX - 1);
+    assert_eq!(tracker.observe(2), SequenceEvent::Gap(3));
+    // The sequence carries on after the gap.
+    assert_eq!(tracker.observe(3), SequenceEvent::InOrder);
+  }
+
+  #[test]
+  fn reordered_and_duplicated_numbers_do_not_move_the_sequence() {
+    let mut tracker = SequenceTracker::default();
+    for seqno in [0, 1, 3] {
+      tracker.observe(seqno);
+    }
+    assert_eq!(tracker.observe(2), SequenceEvent::OutOfOrder);
+    assert_eq!(tracker.observe(3), SequenceEvent::OutOfOrder);
+    assert_eq!(tracker.observe(4), SequenceEvent::InOrder);
+
+    // Just before the wraparound is older than just after it.
+    let mut tracker = SequenceTracker::default();
+    tracker.observe(1);
+    assert_eq!(tracker.observe(u16::MAX), SequenceEvent::OutOfOrder);
+    // Half the sequence space ahead is the furthest a gap reaches.
+    assert_eq!(tracker.observe(2 + 0x7fff), SequenceEvent::Gap(0x7fff));
+    // Beyond that, a number counts as older: 2 is half the space behind 0x8002.
+    assert_eq!(tracker.observe(2), SequenceEvent::OutOfOrder);
+  }
+
+  #[test]
+  fn shims_are_split_off_in_network_order() {
+    assert_eq!(split_seqno(&[0x12, 0x34, 0xaa, 0xbb]), Some((0x1234, &[0xaa, 0xbb][..])));
+    assert_eq!(split_seqno(&[0x12, 0x34]), Some((0x1234, &[][..])));
+    assert_eq!(split_seqno(&[0x12]), None);
+  }
+}
@@ -0,0 +1,696 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Counters for the EtherIP daemon.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::libc;
+use crate::parking_lot::{Mutex, RwLock};
+
+use crate::fdb::Fdb;
+use crate::flows::FlowTable;
+use crate::metrics::MetricsWriter;
+use crate::watchdog::Heartbeat;
+
+/// Largest number of shards of a `Counter`.
+const MAX_COUNTER_SHARDS: usize = 16;
+
+/// Number of shards of each `Counter`: the number of CPUs rounded up to a power of two,
+/// at most `MAX_COUNTER_SHARDS`. A single-CPU host gets one shard and no overhead.
+fn counter_shards() -> usize {
+  static SHARDS: OnceLock<usize> = OnceLock::new();
+  *SHARDS.get_or_init(|| shards_for_cpus(std::thread::available_parallelism().map_or(1, |n| n.get())))
+}
+
+fn shards_for_cpus(cpus: usize) -> usize {
+  cpus.next_power_of_two().min(MAX_COUNTER_SHARDS)
+}
+
+/// Shard of `cpu` among a power of two of shards. `sched_getcpu` returns -1 where it is
+/// not supported, which maps to the first shard.
+fn shard_index(cpu: libc::c_int, shards: usize) -> usize {
+  cpu.max(0) as usize & (shards - 1)
+}
+
+/// Part of a counter, on a cache line of its own so that CPUs do not contend for it.
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct CounterShard(AtomicU64);
+
+/// Monotonic event counter, with a reset point for pollers that want counts since a reset.
+/// Each CPU adds to its own shard, and reads sum the shards, so that links forwarded on
+/// several cores do not bounce a shared cache line on every frame.
+#[derive(Debug)]
+pub struct Counter {
+  shards: Box<[CounterShard]>,
+  /// Value of the total at the last reset.
+  reset_at: AtomicU64,
+}
+
+impl Default for Counter {
+  fn default() -> Self {
+    Self::with_shards(counter_shards())
+  }
+}
+
+impl Counter {
+  fn with_shards(shards: usize) -> Self {
+    Self {
+      shards: (0..shards).map(|_| CounterShard::default()).collect(),
+      reset_at: AtomicU64::new(0),
+    }
+  }
+
+  pub fn inc(&self) {
+    self.add(1);
+  }
+
+  pub fn add(&self, n: u64) {
+    self.shard().0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  /// Sum of the shards. Each shard only grows, so the total seen by a reader does too.
+  pub fn get(&self) -> u64 {
+    self.shards.iter().fold(0, |total, shard| total.wrapping_add(shard.0.load(Ordering::Relaxed)))
+  }
+
+  /// Shard of the CPU the calling thread runs on. A thread that migrates right after
+  /// the lookup merely shares a shard for one addition.
+  fn shard(&self) -> &CounterShard {
+    if self.shards.len() == 1 {
+      return &self.shards[0];
+    }
+    let cpu = unsafe { libc::sched_getcpu() };
+    &self.shards[shard_index(cpu, self.shards.len())]
+  }
+
+  /// Events since the last reset, moving the reset point to now if `reset`.
+  /// The reset point only moves forward, so concurrent resets never count an event twice.
+  /// The total is left untouched: `get` stays monotonic.
+  pub fn since_reset(&self, reset: bool) -> u64 {
+    let total = self.get();
+    let reset_at = match reset {
+      true => self.reset_at.fetch_max(total, Ordering::Relaxed),
+      false => self.reset_at.load(Ordering::Relaxed),
+    };
+    total.saturating_sub(reset_at)
+  }
+}
+
+/// Value that can go up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+  pub fn set(&self, value: u64) {
+    self.0.store(value, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// Upper bounds of the frame size histogram buckets, in bytes.
+pub const FRAME_SIZE_BUCKETS: [u64; 8] = [64, 128, 256, 512, 1024, 1518, 4096, 9216];
+
+/// Histogram of frame sizes, with an implicit `+Inf` bucket.
+#[derive(Debug, Default)]
+pub struct FrameSizeHistogram {
+  /// Non-cumulative bucket counts; the last one is `+Inf`.
+  buckets: [Counter; FRAME_SIZE_BUCKETS.len() + 1],
+  sum: Counter,
+}
+
+impl FrameSizeHistogram {
+  pub fn observe(&self, size: usize) {
+    let size = size as u64;
+    let bucket = FRAME_SIZE_BUCKETS.iter().position(|&bound| size <= bound).unwrap_or(FRAME_SIZE_BUCKETS.len());
+    self.buckets[bucket].inc();
+    self.sum.add(size);
+  }
+
+  pub fn count(&self) -> u64 {
+    self.buckets.iter().map(Counter::get).sum()
+  }
+
+  /// Write the histogram samples of a link.
+  fn render(&self, writer: &mut MetricsWriter, name: &str, link_name: &str) {
+    let bucket_name = format!("{}_bucket", name);
+    let mut cumulative = 0;
+    for (i, bucket) in self.buckets.iter().enumerate() {
+      cumulative += bucket.get();
+      let le = FRAME_SIZE_BUCKETS.get(i).map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+      writer.sample(&bucket_name, &[("link", link_name), ("le", &le)], cumulative);
+    }
+    writer.sample(&format!("{}_sum", name), &[("link", link_name)], self.sum.get());
+    writer.sample(&format!("{}_count", name), &[("link", link_name)], cumulative);
+  }
+}
+
+/// Counters of a single link.
+#[derive(Debug, Default)]
+pub struct LinkStats {
+  /// ARP requests answered by the local responder.
+  pub arp_replies: Counter,
+
+  /// Neighbor solicitations answered by the local responder.
+  pub nd_replies: Counter,
+
+  /// Frames dropped because the egress queue was full.
+  pub egress_queue_drops: Counter,
+
+  /// Broadcast and multicast frames dropped by the broadcast rate limit.
+  pub broadcast_limit_drops: Counter,
+
+  /// Frames dropped without a send attempt while sends were suspended by `send_backoff`.
+  pub suppressed_sends: Counter,
+
+  /// Datagrams dropped because the send queue of the transport was full, e.g. while a
+  /// TCP connection is slower than the TAP interface. These are congestion, not errors.
+  pub queue_full_drops: Counter,
+
+  /// Datagrams that could not be sent to the remote, other than `tx_too_large` and `queue_full_drops` ones.
+  pub send_errors: Counter,
+
+  /// Datagrams the kernel refused to send as too large for the path (EMSGSIZE).
+  pub tx_too_large: Counter,
+
+  /// Received sequence numbers that skipped ahead.
+  pub seqno_gaps: Counter,
+
+  /// Datagrams missing according to the sequence numbers.
+  pub seqno_missing: Counter,
+
+  /// Received sequence numbers older than expected.
+  pub seqno_out_of_order: Counter,
+
+  /// Received datagrams with nonzero reserved bits in the EtherIP header.
+  pub reserved_bits_violations: Counter,
+
+  /// Received datagrams dropped because their header or frame is invalid.
+  pub invalid_datagrams: Counter,
+
+  /// Frames read from the TAP interface longer than its MTU allows, dropped.
+  pub tx_oversize_drops: Counter,
+
+  /// Times the watchdog restarted the stalled task reading the TAP interface of the link.
+  pub watchdog_restarts: Counter,
+
+  /// Received datagrams dropped because they were longer than the receive buffer.
+  pub rx_truncated_drops: Counter,
+
+  /// Received datagrams dropped because this host sent them (looped-back multicast).
+  pub looped_back_drops: Counter,
+
+  /// Received datagrams dropped because their authentication tag was missing or wrong.
+  pub auth_failures: Counter,
+
+  /// Received datagrams dropped by the reverse path check, possibly spoofed.
+  pub rpf_drops: Counter,
+
+  /// Frames sent compressed.
+  pub compressed_frames: Counter,
+
+  /// Frames sent uncompressed on a link with compression enabled.
+  pub uncompressed_frames: Counter,
+
+  /// Bytes saved by compressing sent frames.
+  pub compression_saved_bytes: Counter,
+
+  /// Received frames that could not be decompressed.
+  pub decompression_errors: Counter,
+
+  /// Received frames that could not be copied to the `mirror_to` interface.
+  pub mirror_write_errors: Counter,
+
+  /// Frames whose MAC addresses were translated by `mac_rewrite`.
+  pub mac_rewrites: Counter,
+
+  /// Received frames dropped by `inner_ip_filter`.
+  pub inner_ip_filter_drops: Counter,
+
+  /// Times the remote of the link was found to resolve to the address of another link,
+  /// which then received the datagrams from it.
+  pub remote_collisions: Counter,
+
+  /// Sizes of frames read from the TAP interface, if enabled for the link.
+  pub tx_frame_sizes: FrameSizeHistogram,
+
+  /// Sizes of frames written to the TAP interface, if enabled for the link.
+  pub rx_frame_sizes: FrameSizeHistogram,
+
+  /// Path MTU to the remote, if monitored for the link; 0 until known.
+  pub path_mtu: Gauge,
+
+  /// 1 if the link is forwarding, 0 if it is disabled.
+  pub enabled: Gauge,
+
+  /// Source address of the datagrams sent to the remote, once known.
+  pub source_address: RwLock<Option<IpAddr>>,
+
+  /// Most recent failure to send, receive or access the TAP interface.
+  pub last_error: LastError,
+
+  /// Inner flows forwarded in either direction, if enabled for the link.
+  pub flows: FlowTable,
+
+  /// MAC addresses learned from received frames, if enabled for the link.
+  pub fdb: Fdb,
+
+  /// Advanced for every frame read from the TAP interface or input, for the watchdog.
+  pub tx_heartbeat: Heartbeat,
+}
+
+impl LinkStats {
+  /// Metric name suffix, help text and value of every histogram.
+  pub fn histograms(&self) -> Vec<(&'static str, &'static str, &FrameSizeHistogram)> {
+    vec![
+      ("tx_frame_size_bytes", "Sizes of frames read from the TAP interface.", &self.tx_frame_sizes),
+      ("rx_frame_size_bytes", "Sizes of frames written to the TAP interface.", &self.rx_frame_sizes),
+    ]
+  }
+
+  /// Metric name suffix, help text and value of every counter.
+  pub fn counters(&self) -> Vec<(&'static str, &'static str, &Counter)> {
+    vec![
+      ("arp_replies", "ARP requests answered by the local responder.", &self.arp_replies),
+      ("nd_replies", "Neighbor solicitations answered by the local responder.", &self.nd_replies),
+      ("egress_queue_drops", "Frames dropped because the egress queue was full.", &self.egress_queue_drops),
+      ("broadcast_limit_drops", "Broadcast and multicast frames dropped by the broadcast rate limit.", &self.broadcast_limit_drops),
+      ("suppressed_sends", "Frames dropped without a send attempt while sends were suspended after send failures.", &self.suppressed_sends),
+      ("queue_full_drops", "Datagrams dropped because the send queue of the transport was full.", &self.queue_full_drops),
+      ("send_errors", "Datagrams that could not be sent to the remote, other than too large ones and full queue drops.", &self.send_errors),
+      ("tx_too_large", "Datagrams that could not be sent because they are too large for the path (EMSGSIZE).", &self.tx_too_large),
+      ("seqno_gaps", "Received sequence numbers that skipped ahead.", &self.seqno_gaps),
+      ("seqno_missing", "Datagrams missing according to the sequence numbers.", &self.seqno_missing),
+      ("seqno_out_of_order", "Received sequence numbers older than expected.", &self.seqno_out_of_order),
+      ("reserved_bits_violations", "Received datagrams with nonzero reserved bits in the EtherIP header.", &self.reserved_bits_violations),
+      ("invalid_datagrams", "Received datagrams dropped because their header or frame is invalid.", &self.invalid_datagrams),
+      ("tx_oversize_drops", "Frames read from the TAP interface longer than its MTU allows, dropped.", &self.tx_oversize_drops),
+      ("watchdog_restarts", "Times the watchdog restarted the stalled task reading the TAP interface of the link.", &self.watchdog_restarts),
+      ("rx_truncated_drops", "Received datagrams dropped because they were longer than the receive buffer.", &self.rx_truncated_drops),
+      ("looped_back_drops", "Received datagrams dropped because this host sent them.", &self.looped_back_drops),
+      ("auth_failures", "Received datagrams dropped because their authentication tag was missing or wrong.", &self.auth_failures),
+      ("rpf_drops", "Received datagrams dropped by the reverse path check, possibly spoofed.", &self.rpf_drops),
+      ("compressed_frames", "Frames sent compressed.", &self.compressed_frames),
+      ("uncompressed_frames", "Frames sent uncompressed on a link with compression enabled.", &self.uncompressed_frames),
+      ("compression_saved_bytes", "Bytes saved by compressing sent frames.", &self.compression_saved_bytes),
+      ("decompression_errors", "Received frames that could not be decompressed.", &self.decompression_errors),
+      ("mirror_write_errors", "Received frames that could not be copied to the mirror interface.", &self.mirror_write_errors),
+      ("mac_rewrites", "Frames whose MAC addresses were translated.", &self.mac_rewrites),
+      ("inner_ip_filter_drops", "Received frames dropped because their inner IP addresses are not allowed.", &self.inner_ip_filter_drops),
+      ("remote_collisions", "Times the remote of the link resolved to the address of another link, which took its inbound datagrams.", &self.remote_collisions),
+    ]
+  }
+}
+
+/// Counters of all links, keyed by link name.
+/// Counters of a relay.
+#[derive(Debug, Default)]
+pub struct RelayStats {
+  /// Datagrams sent on to the other remote.
+  pub datagrams: Counter,
+
+  /// Bytes of the datagrams sent on, EtherIP header included.
+  pub bytes: Counter,
+
+  /// Datagrams dropped because their frame is longer than `max_frame_size`.
+  pub oversize_drops: Counter,
+
+  /// Datagrams dropped because their EtherIP header is invalid.
+  pub invalid_datagrams: Counter,
+
+  /// Datagrams dropped because the other remote is not resolved yet.
+  pub unresolved_drops: Counter,
+
+  /// Datagrams dropped by the reverse path check.
+  pub rpf_drops: Counter,
+
+  /// Datagrams that could not be sent on, other than `tx_too_large` ones.
+  pub send_errors: Counter,
+
+  /// Datagrams the kernel refused to send as too large for the path (EMSGSIZE).
+  pub tx_too_large: Counter,
+}
+
+impl RelayStats {
+  /// Metric name suffix, help text and value of every counter.
+  pub fn counters(&self) -> Vec<(&'static str, &'static str, &Counter)> {
+    vec![
+      ("datagrams", "Datagrams sent on to the other remote.", &self.datagrams),
+      ("bytes", "Bytes of the datagrams sent on, EtherIP header included.", &self.bytes),
+      ("oversize_drops", "Datagrams dropped because their frame is longer than max_frame_size.", &self.oversize_drops),
+      ("invalid_datagrams", "Datagrams dropped because their EtherIP header is invalid.", &self.invalid_datagrams),
+      ("unresolved_drops", "Datagrams dropped because the other remote is not resolved yet.", &self.unresolved_drops),
+      ("rpf_drops", "Datagrams dropped by the reverse path check.", &self.rpf_drops),
+      ("send_errors", "Datagrams that could not be sent on, other than too large ones.", &self.send_errors),
+      ("tx_too_large", "Datagrams that could not be sent because they are too large for the path (EMSGSIZE).", &self.tx_too_large),
+    ]
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+  links: RwLock<HashMap<String, Arc<LinkStats>>>,
+
+  relays: RwLock<HashMap<String, Arc<RelayStats>>>,
+
+  /// Times the EtherIP socket was recreated after a fatal error.
+  pub socket_recreations: Counter,
+
+  /// Times the watchdog restarted the stalled EtherIP socket receiver.
+  pub socket_receiver_restarts: Counter,
+
+  /// Times the watchdog restarted the stalled shared TAP reader.
+  pub shared_tap_reader_restarts: Counter,
+
+  /// Advanced for every datagram read from the EtherIP socket, for the watchdog.
+  pub socket_rx_heartbeat: Heartbeat,
+}
+
+impl Stats {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Get the counters of a link, creating them if they do not exist yet.
+  pub fn link(&self, link_name: &str) -> Arc<LinkStats> {
+    if let Some(stats) = self.links.read().get(link_name) {
+      return stats.clone();
+    }
+    self.links.write().entry(link_name.to_string()).or_default().clone()
+  }
+
+  /// Forget the counters of links that are no longer configured.
+  pub fn retain_links<F: Fn(&str) -> bool>(&self, keep: F) {
+    self.links.write().retain(|link_name, _| keep(link_name));
+  }
+
+  /// Get the counters of a relay, creating them if they do not exist yet.
+  pub fn relay(&self, relay_name: &str) -> Arc<RelayStats> {
+    if let Some(stats) = self.relays.read().get(relay_name) {
+      return stats.clone();
+    }
+    self.relays.write().entry(relay_name.to_string()).or_default().clone()
+  }
+
+  /// Forget the counters of relays that are no longer configured.
+  pub fn retain_relays<F: Fn(&str) -> bool>(&self, keep: F) {
+    self.relays.write().retain(|relay_name, _| keep(relay_name));
+  }
+
+  /// Write all counters as Prometheus counters, the link counters labelled by link.
+  pub fn render(&self, writer: &mut MetricsWriter) {
+    writer.family("etherip_socket_recreations_total", "counter", "Times the EtherIP socket was recreated after a fatal error.");
+    writer.sample("etherip_socket_recreations_total", &[], self.socket_recreations.get());
+    writer.family("etherip_socket_receiver_restarts_total", "counter", "Times the watchdog restarted the stalled EtherIP socket receiver.");
+    writer.sample("etherip_socket_receiver_restarts_total", &[], self.socket_receiver_restarts.get());
+    writer.family("etherip_shared_tap_reader_restarts_total", "counter", "Times the watchdog restarted the stalled shared TAP reader.");
+    writer.sample("etherip_shared_tap_reader_restarts_total", &[], self.shared_tap_reader_restarts.get());
+
+    let links = self.links.read();
+    writer.family("etherip_links", "gauge", "Number of links currently configured.");
+    writer.sample("etherip_links", &[], links.len());
+
+    let mut link_names: Vec<&String> = links.keys().collect();
+    link_names.sort();
+
+    let families = LinkStats::default().counters().iter().map(|(name, help, _)| (*name, *help)).collect::<Vec<_>>();
+    for (i, (name, help)) in families.into_iter().enumerate() {
+      let metric_name = format!("etherip_link_{}_total", name);
+      writer.family(&metric_name, "counter", help);
+      for link_name in &link_names {
+        let counters = links[*link_name].counters();
+        writer.sample(&metric_name, &[("link", link_name)], counters[i].2.get());
+      }
+    }
+
+    writer.family("etherip_link_enabled", "gauge", "Whether the link is forwarding (1) or disabled (0).");
+    for link_name in &link_names {
+      writer.sample("etherip_link_enabled", &[("link", link_name)], links[*link_name].enabled.get());
+    }
+
+    writer.family("etherip_link_path_mtu_bytes", "gauge", "Path MTU to the remote as known to the kernel.");
+    for link_name in link_names.iter().filter(|link_name| links[**link_name].path_mtu.get() > 0) {
+      writer.sample("etherip_link_path_mtu_bytes", &[("link", link_name)], links[*link_name].path_mtu.get());
+    }
+
+    writer.family("etherip_link_source_address_info", "gauge", "Source address of the datagrams sent to the remote.");
+    for link_name in &link_names {
+      if let Some(addr) = *links[*link_name].source_address.read() {
+        writer.sample("etherip_link_source_address_info", &[("link", link_name), ("address", &addr.to_string())], 1);
+      }
+    }
+
+    render_last_errors(writer, &links, &link_names);
+    self.render_relays(writer);
+
+    let fdb_link_names: Vec<&&String> = link_names.iter().filter(|link_name| links[**link_name].fdb.is_enabled()).collect();
+    writer.family("etherip_link_fdb_entries", "gauge", "MAC addresses currently learned behind the remote.");
+    for link_name in &fdb_link_names {
+      writer.sample("etherip_link_fdb_entries", &[("link", link_name)], links[**link_name].fdb.len());
+    }
+    writer.family("etherip_link_fdb_evictions_total", "counter", "Learned MAC addresses evicted to make room in the full table.");
+    for link_name in &fdb_link_names {
+      writer.sample("etherip_link_fdb_evictions_total", &[("link", link_name)], links[**link_name].fdb.evictions());
+    }
+    writer.family("etherip_link_fdb_moves_total", "counter", "Times a learned MAC address was seen from a different remote address.");
+    for link_name in &fdb_link_names {
+      writer.sample("etherip_link_fdb_moves_total", &[("link", link_name)], links[**link_name].fdb.moves());
+    }
+
+    // Histograms are only written for links that have them enabled and have seen a frame.
+    let families = LinkStats::default().histograms().iter().map(|(name, help, _)| (*name, *help)).collect::<Vec<_>>();
+    for (i, (name, help)) in families.into_iter().enumerate() {
+      let metric_name = format!("etherip_link_{}", name);
+      writer.family(&metric_name, "histogram", help);
+      for link_name in &link_names {
+        let histogram = links[*link_name].histograms()[i].2;
+        if histogram.count() > 0 {
+          histogram.render(writer, &metric_name, link_name);
+        }
+      }
+    }
+  }
+
+  /// Write the counters of the relays, labelled by relay.
+  fn render_relays(&self, writer: &mut MetricsWriter) {
+    let relays = self.relays.read();
+    let mut relay_names: Vec<&String> = relays.keys().collect();
+    relay_names.sort();
+    let families = RelayStats::default().counters().iter().map(|(name, help, _)| (*name, *help)).collect::<Vec<_>>();
+    for (i, (name, help)) in families.into_iter().enumerate() {
+      let metric_name = format!("etherip_relay_{}_total", name);
+      writer.family(&metric_name, "counter", help);
+      for relay_name in &relay_names {
+        writer.sample(&metric_name, &[("relay", relay_name)], relays[*relay_name].counters()[i].2.get());
+      }
+    }
+  }
+
+  /// Write the `n` flows with the most bytes of each link with a flow table as gauges.
+  pub fn render_flows(&self, writer: &mut MetricsWriter, n: usize) {
+    let links = self.links.read();
+    let mut link_names: Vec<&String> = links.keys().filter(|link_name| links[*link_name].flows.is_enabled()).collect();
+    link_names.sort();
+    let top: Vec<_> = link_names.iter().map(|link_name| (*link_name, links[*link_name].flows.top(n))).collect();
+
+    writer.family("etherip_link_flow_bytes", "gauge", "Bytes of the inner flows with the most traffic.");
+    writer.family("etherip_link_flow_frames", "gauge", "Frames of the inner flows with the most traffic.");
+    writer.family("etherip_link_flow_idle_seconds", "gauge", "Seconds since a frame of the flow was last forwarded.");
+    for (link_name, flows) in top {
+      for (key, entry) in flows {
+        let protocol = key.protocol.to_string();
+        let (src, dst) = (key.src.to_string(), key.dst.to_string());
+        let (src_port, dst_port) = (key.src_port.to_string(), key.dst_port.to_string());
+        let labels = [("link", link_name.as_str()), ("protocol", &protocol), ("src", &src), ("src_port", &src_port), ("dst", &dst), ("dst_port", &dst_port)];
+        writer.sample("etherip_link_flow_bytes", &labels, entry.bytes);
+        writer.sample("etherip_link_flow_frames", &labels, entry.frames);
+        writer.sample("etherip_link_flow_idle_seconds", &labels, entry.last_seen.elapsed().as_secs());
+      }
+    }
+  }
+
+  /// Write the `n` most recently seen learned MAC addresses of each link with a table as gauges.
+  pub fn render_fdb(&self, writer: &mut MetricsWriter, n: usize) {
+    writer.family("etherip_link_fdb_entry_frames", "gauge", "Frames received from the learned MAC address.");
+    writer.family("etherip_link_fdb_entry_age_seconds", "gauge", "Seconds since the MAC address was learned.");
+    writer.family("etherip_link_fdb_entry_idle_seconds", "gauge", "Seconds since a frame from the MAC address was last received.");
+    for (link_name, link_stats) in self.fdb_links() {
+      for (mac, entry) in link_stats.fdb.recent(n) {
+        let (mac, remote) = (mac.to_string(), entry.remote.to_string());
+        let labels = [("link", link_name.as_str()), ("mac", &mac), ("remote", &remote)];
+        writer.sample("etherip_link_fdb_entry_frames", &labels, entry.frames);
+        writer.sample("etherip_link_fdb_entry_age_seconds", &labels, entry.first_seen.elapsed().as_secs());
+        writer.sample("etherip_link_fdb_entry_idle_seconds", &labels, entry.last_seen.elapsed().as_secs());
+      }
+    }
+  }
+
+  /// Format the `n` most recently seen learned MAC addresses of each link with a table
+  /// as a table for reading, with the size of each table.
+  pub fn format_fdb_table(&self, n: usize) -> String {
+    let mut table = String::new();
+    for (link_name, link_stats) in self.fdb_links() {
+      let fdb = &link_stats.fdb;
+      let _ = writeln!(table, "link {}: {} entries, {} evictions, {} moves", link_name, fdb.len(), fdb.evictions(), fdb.moves());
+      let _ = writeln!(table, "  {:<17}  {:<39}  {:>10}  {:>8}  {:>8}", "MAC", "REMOTE", "FRAMES", "AGE", "IDLE");
+      for (mac, entry) in fdb.recent(n) {
+        let _ = writeln!(table, "  {:<17}  {:<39}  {:>10}  {:>7}s  {:>7}s", mac, entry.remote, entry.frames, entry.first_seen.elapsed().as_secs(), entry.last_seen.elapsed().as_secs());
+      }
+    }
+    table
+  }
+
+  /// Links with a MAC table, ordered by name.
+  fn fdb_links(&self) -> Vec<(String, Arc<LinkStats>)> {
+    let mut links: Vec<(String, Arc<LinkStats>)> = self.links.read().iter()
+      .filter(|(_, link_stats)| link_stats.fdb.is_enabled())
+      .map(|(link_name, link_stats)| (link_name.clone(), link_stats.clone()))
+      .collect();
+    links.sort_by(|a, b| a.0.cmp(&b.0));
+    links
+  }
+
+  /// Write the link counts since the last reset as gauges, resetting them if `reset`.
+  /// Each counter moves its reset point atomically, so a poller that resets on every
+  /// snapshot sees each event exactly once. Prometheus should scrape the monotonic
+  /// counters of `render` instead, which resets do not affect.
+  pub fn render_since_reset(&self, writer: &mut MetricsWriter, reset: bool) {
+    let links = self.links.read();
+    let mut link_names: Vec<&String> = links.keys().collect();
+    link_names.sort();
+
+    let families = LinkStats::default().counters().iter().map(|(name, help, _)| (*name, *help)).collect::<Vec<_>>();
+    for (i, (name, help)) in families.into_iter().enumerate() {
+      let metric_name = format!("etherip_link_{}_since_reset", name);
+      writer.family(&metric_name, "gauge", help);
+      for link_name in &link_names {
+        let counters = links[*link_name].counters();
+        writer.sample(&metric_name, &[("link", link_name)], counters[i].2.since_reset(reset));
+      }
+    }
+
+    render_last_errors(writer, &links, &link_names);
+  }
+}
+
+/// Longest message kept by `LastError`, in bytes.
+const LAST_ERROR_MAX_LEN: usize = 256;
+
+/// Most recent error of a link and when it happened, so that operators see it without
+/// searching the logs. Only one message of bounded length is kept.
+#[derive(Debug, Default)]
+pub struct LastError {
+  error: Mutex<Option<(Instant, String)>>,
+}
+
+impl LastError {
+  /// Record that `operation` failed with `error`, replacing the previous error.
+  pub fn set(&self, operation: &str, error: &dyn std::fmt::Display) {
+    let mut message = format!("{}: {}", operation, error);
+    if message.len() > LAST_ERROR_MAX_LEN {
+      let mut end = LAST_ERROR_MAX_LEN;
+      while !message.is_char_boundary(end) {
+        end -= 1;
+      }
+      message.truncate(end);
+    }
+    *self.error.lock() = Some((Instant::now(), message));
+  }
+
+  /// The message and how long ago it was recorded, if any error was.
+  pub fn get(&self) -> Option<(String, Duration)> {
+    self.error.lock().as_ref().map(|(at, message)| (message.clone(), at.elapsed()))
+  }
+}
+
+/// Write the last error of each link that had one, labelled by its message.
+fn render_last_errors(writer: &mut MetricsWriter, links: &HashMap<String, Arc<LinkStats>>, link_names: &[&String]) {
+  writer.family("etherip_link_last_error_age_seconds", "gauge", "Seconds since the most recent error of the link, labelled by its message.");
+  for link_name in link_names {
+    if let Some((message, age)) = links[*link_name].last_error.get() {
+      writer.sample("etherip_link_last_error_age_seconds", &[("link", link_name), ("error", &message)], age.as_secs());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shards_are_a_power_of_two_up_to_the_cap() {
+    let shards: Vec<usize> = [1, 2, 3, 5, 8, 12, 16, 17, 64, 1000].into_iter().map(shards_for_cpus).collect();
+    assert_eq!(shards, [1, 2, 4, 8, 8, 16, 16, 16, 16, 16]);
+    assert!(counter_shards().is_power_of_two() && counter_shards() <= MAX_COUNTER_SHARDS);
+    assert_eq!(Counter::default().shards.len(), counter_shards());
+  }
+
+  #[test]
+  fn cpus_map_onto_the_shards() {
+    assert_eq!(shard_index(5, 8), 5);
+    assert_eq!(shard_index(13, 8), 5);
+    assert_eq!(shard_index(13, 1), 0);
+    // sched_getcpu failed.
+    assert_eq!(shard_index(-1, 8), 0);
+    assert_eq!(shard_index(libc::c_int::MIN, 16), 0);
+  }
+
+  #[test]
+  fn totals_sum_all_shards() {
+    let counter = Counter::with_shards(4);
+    for (i, shard) in counter.shards.iter().enumerate() {
+      shard.0.store(1 << (8 * i), Ordering::Relaxed);
+    }
+    assert_eq!(counter.get(), 0x0101_0101);
+    counter.add(0x10);
+    assert_eq!(counter.get(), 0x0101_0111);
+  }
+
+  #[test]
+  fn additions_from_many_threads_are_all_counted() {
+    let counter = Counter::with_shards(MAX_COUNTER_SHARDS);
+    std::thread::scope(|scope| {
+      for _ in 0..8 {
+        scope.spawn(|| {
+          for _ in 0..10_000 {
+            counter.inc();
+          }
+        });
+      }
+    });
+    assert_eq!(counter.get(), 80_000);
+  }
+
+  #[test]
+  fn counts_since_reset_leave_the_total_alone() {
+    let counter = Counter::with_shards(2);
+    counter.add(5);
+    assert_eq!(counter.since_reset(false), 5);
+    assert_eq!(counter.since_reset(true), 5);
+    assert_eq!(counter.since_reset(true), 0);
+    counter.add(3);
+    assert_eq!(counter.since_reset(false), 3);
+    assert_eq!(counter.get(), 8);
+  }
+
+  #[test]
+  fn the_reset_point_never_moves_back() {
+    let counter = Counter::with_shards(2);
+    counter.add(10);
+    // A reset that read a larger total than this one, as a concurrent reset could have.
+    counter.reset_at.store(12, Ordering::Relaxed);
+    assert_eq!(counter.since_reset(true), 0);
+    assert_eq!(counter.reset_at.load(Ordering::Relaxed), 12);
+    counter.add(5);
+    assert_eq!(counter.since_reset(true), 3);
+  }
+}
@@ -1,10 +1,16 @@
 // -*- indent-tabs-mode: nil; tab-width: 2; -*-
 // vim: set ts=&2 sw=2 et ai :
 
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{ready, Context, Poll};
 
 use crate::libc;
 use crate::nix;
+use crate::caps::{explain_permission_error, Capability};
+use crate::ethernet::{MacAddr, ETHERNET_HEADER_SIZE, VLAN_TAG_SIZE};
 use crate::tokio;
 
 use tokio::io::Interest;
@@ -13,12 +19,22 @@ use tokio::io::unix::AsyncFd;
 
 pub const TUNSETIFF: libc::c_ulong = nix::request_code_write!(b'T', 202, std::mem::size_of::<libc::c_int>());
 pub const TUNSETPERSIST: libc::c_ulong = nix::request_code_write!(b'T', 203, std::mem::size_of::<libc::c_int>());
+pub const TUNSETOWNER: libc::c_ulong = nix::request_code_write!(b'T', 204, std::mem::size_of::<libc::c_int>());
+pub const TUNSETGROUP: libc::c_ulong = nix::request_code_write!(b'T', 206, std::mem::size_of::<libc::c_int>());
+pub const TUNGETFEATURES: libc::c_ulong = nix::request_code_read!(b'T', 207, std::mem::size_of::<libc::c_uint>());
+pub const TUNSETOFFLOAD: libc::c_ulong = nix::request_code_write!(b'T', 208, std::mem::size_of::<libc::c_uint>());
 
-pub const TUNDEV: *const libc::c_char = "/dev/net/tun\0".as_ptr() as *const libc::c_char;
+pub const TUNDEV: *const libc::c_char = c"/dev/net/tun".as_ptr();
+
+/// Default path of the TUN/TAP clone device.
+pub const DEFAULT_TUN_DEVICE: &str = "/dev/net/tun";
+
+/// MTU assumed for an interface whose MTU cannot be read.
+pub const DEFAULT_MTU: u32 = 1500;
 
 
 fn ifname_to_cstring(ifname: &str) -> std::io::Result<std::ffi::CString> {
-  if ifname.len() >= libc::IFNAMSIZ || ifname.len() == 0 {
+  if ifname.len() >= libc::IFNAMSIZ || ifname.is_empty() {
     return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "interface name too long or short"));
   }
 
@@ -29,39 +45,261 @@ fn ifname_to_cstring(ifname: &str) -> std::io::Result<std::ffi::CString> {
   std::ffi::CString::new(ifname).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
 }
 
+/// Read the interface name of an `ifreq`, e.g. the one the kernel chose for a `%d` pattern.
+/// The read stops at the first NUL or after `IFNAMSIZ` bytes, so an unterminated name
+/// does not read past the buffer; such a name, or an empty or non-UTF-8 one, is an error.
+fn ifreq_name(ifr: &libc::ifreq) -> std::io::Result<String> {
+  let bytes: Vec<u8> = ifr.ifr_name.iter().take(libc::IFNAMSIZ).take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+  if bytes.is_empty() || bytes.len() >= libc::IFNAMSIZ {
+    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "interface name returned by the kernel is empty or not terminated"));
+  }
+  String::from_utf8(bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Open the TUN/TAP clone device at `path` (the default device if `None`).
+fn open_tun_device(path: Option<&Path>, flags: libc::c_int) -> std::io::Result<libc::c_int> {
+  let fd = match path {
+    None => unsafe { libc::open(TUNDEV, flags) },
+    Some(path) => {
+      let path_cstring = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+      unsafe { libc::open(path_cstring.as_ptr(), flags) }
+    },
+  };
+  if fd < 0 {
+    let error = std::io::Error::last_os_error();
+    if error.kind() == std::io::ErrorKind::NotFound {
+      let path = path.unwrap_or(Path::new(DEFAULT_TUN_DEVICE));
+      return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("TUN/TAP clone device {} does not exist", path.display())));
+    }
+    return Err(error);
+  }
+  Ok(fd)
+}
+
+/// Issue an interface `ioctl` on a throwaway socket, returning the resulting `ifreq`.
+fn interface_ioctl<F: FnOnce(&mut libc::ifreq)>(ifname: &str, request: libc::c_ulong, operation: &'static str, fill: F) -> std::io::Result<libc::ifreq> {
+  let ifname = ifname_to_cstring(ifname)?;
+  let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+  unsafe {
+    libc::strncpy(ifr.ifr_name.as_mut_ptr(), ifname.as_ptr(), libc::IFNAMSIZ);
+  }
+  fill(&mut ifr);
+  unsafe {
+    let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+    let ret = libc::ioctl(fd, request, &mut ifr);
+    let error = std::io::Error::last_os_error();
+    libc::close(fd);
+    if ret < 0 {
+      return Err(explain_permission_error(error, Capability::NetAdmin, operation));
+    }
+  }
+  Ok(ifr)
+}
+
+/// Get the interface flags supported by the TUN/TAP driver (`TUNGETFEATURES`), e.g. `IFF_MULTI_QUEUE`.
+pub fn tun_features(tun_device: Option<&Path>) -> std::io::Result<libc::c_int> {
+  let fd = open_tun_device(tun_device, libc::O_RDWR | libc::O_CLOEXEC)?;
+  let mut features: libc::c_uint = 0;
+  let ret = unsafe { libc::ioctl(fd, TUNGETFEATURES, &mut features) };
+  let error = std::io::Error::last_os_error();
+  unsafe { libc::close(fd) };
+  if ret < 0 {
+    return Err(error);
+  }
+  Ok(features as libc::c_int)
+}
+
+/// Check whether the driver accepts `TUNSETOFFLOAD`.
+/// This needs an attached interface, so a temporary, non-persistent TAP interface is created.
+pub fn tun_offload_supported(tun_device: Option<&Path>) -> std::io::Result<bool> {
+  let fd = open_tun_device(tun_device, libc::O_RDWR | libc::O_CLOEXEC)?;
+  let result = unsafe {
+    let mut ifr: libc::ifreq = std::mem::zeroed();
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as i16;
+    libc::strncpy(ifr.ifr_name.as_mut_ptr(), c"etherip%d".as_ptr(), libc::IFNAMSIZ);
+    if libc::ioctl(fd, TUNSETIFF, &ifr) < 0 {
+      Err(explain_permission_error(std::io::Error::last_os_error(), Capability::NetAdmin, "create a TAP interface"))
+    } else if libc::ioctl(fd, TUNSETOFFLOAD, 0 as libc::c_uint) < 0 {
+      match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EINVAL) => Ok(false),
+        _ => Err(std::io::Error::last_os_error()),
+      }
+    } else {
+      Ok(true)
+    }
+  };
+  unsafe { libc::close(fd) };
+  result
+}
+
+/// Set the MTU of a network interface (`SIOCSIFMTU`).
+pub fn set_interface_mtu(ifname: &str, mtu: u32) -> std::io::Result<()> {
+  interface_ioctl(ifname, libc::SIOCSIFMTU, "set the MTU of an interface", |ifr| {
+    ifr.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+  })?;
+  Ok(())
+}
+
+/// Set the transmit queue length of a network interface (`SIOCSIFTXQLEN`).
+/// The kernel default for TAP interfaces is 1000 frames; bursty high-throughput
+/// tunnels may need several thousand to avoid drops toward the TAP.
+pub fn set_txqueuelen(ifname: &str, len: u32) -> std::io::Result<()> {
+  // `ifr_qlen` is the `int` member of the union, which `libc` names `ifru_metric` among others.
+  interface_ioctl(ifname, libc::SIOCSIFTXQLEN, "set the transmit queue length of an interface", |ifr| {
+    ifr.ifr_ifru.ifru_metric = len as libc::c_int;
+  })?;
+  Ok(())
+}
+
+/// Get the MTU of a network interface (`SIOCGIFMTU`).
+pub fn get_mtu(ifname: &str) -> std::io::Result<u32> {
+  let ifr = interface_ioctl(ifname, libc::SIOCGIFMTU, "get the MTU of an interface", |_| {})?;
+  Ok(unsafe { ifr.ifr_ifru.ifru_mtu } as u32)
+}
+
+/// Get the transmit queue length of a network interface (`SIOCGIFTXQLEN`).
+pub fn get_txqueuelen(ifname: &str) -> std::io::Result<u32> {
+  let ifr = interface_ioctl(ifname, libc::SIOCGIFTXQLEN, "get the transmit queue length of an interface", |_| {})?;
+  Ok(unsafe { ifr.ifr_ifru.ifru_metric } as u32)
+}
+
+/// Set the hardware address of a network interface (`SIOCSIFHWADDR`).
+pub fn set_mac(ifname: &str, mac: &MacAddr) -> std::io::Result<()> {
+  interface_ioctl(ifname, libc::SIOCSIFHWADDR, "set the MAC address of an interface", |ifr| {
+    let hwaddr = unsafe { &mut ifr.ifr_ifru.ifru_hwaddr };
+    hwaddr.sa_family = libc::ARPHRD_ETHER;
+    for (byte, octet) in hwaddr.sa_data.iter_mut().zip(mac.octets()) {
+      *byte = octet as libc::c_char;
+    }
+  })?;
+  Ok(())
+}
+
+/// Bring a network interface up or down (`IFF_UP`), leaving its other flags as they are.
+pub fn set_up(ifname: &str, up: bool) -> std::io::Result<()> {
+  let ifr = interface_ioctl(ifname, libc::SIOCGIFFLAGS, "get the flags of an interface", |_| {})?;
+  let mut flags = unsafe { ifr.ifr_ifru.ifru_flags };
+  match up {
+    true => flags |= libc::IFF_UP as i16,
+    false => flags &= !(libc::IFF_UP as i16),
+  }
+  interface_ioctl(ifname, libc::SIOCSIFFLAGS, "set the flags of an interface", |ifr| {
+    ifr.ifr_ifru.ifru_flags = flags;
+  })?;
+  Ok(())
+}
+
+/// Properties of a TAP interface added by `tap_add_with_options`.
+#[derive(Debug, Clone)]
+pub struct TapAddOptions {
+  /// User allowed to attach to the interface without `CAP_NET_ADMIN`.
+  pub owner: Option<libc::uid_t>,
+
+  /// Group allowed to attach to the interface without `CAP_NET_ADMIN`.
+  pub group: Option<libc::gid_t>,
+
+  /// Keep the interface after the clone device is closed.
+  pub persist: bool,
+
+  /// Path of the TUN/TAP clone device. Defaults to `/dev/net/tun`.
+  pub tun_device: Option<PathBuf>,
+}
+
+impl Default for TapAddOptions {
+  fn default() -> Self {
+    Self { owner: None, group: None, persist: true, tun_device: None }
+  }
+}
+
 /// Add a TAP interface with the given name.
 pub fn tap_add_ioctl(ifname: &str) -> std::io::Result<()> {
+  tap_add_ioctl_at(None, ifname)
+}
+
+/// Add a TAP interface with the given name using the clone device at `tun_device`.
+pub fn tap_add_ioctl_at(tun_device: Option<&Path>, ifname: &str) -> std::io::Result<()> {
+  tap_add_with_options(ifname, &TapAddOptions {
+    tun_device: tun_device.map(Path::to_path_buf),
+    ..TapAddOptions::default()
+  })?;
+  Ok(())
+}
+
+/// Add a TAP interface with the given owner, group and persistence. Returns the attached
+/// clone device: an interface that does not persist is removed when it is closed.
+pub fn tap_add_with_options(ifname: &str, options: &TapAddOptions) -> std::io::Result<OwnedFd> {
   let ifname = ifname_to_cstring(ifname)?;
 
   let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
   unsafe {
     ifr.ifr_ifru.ifru_flags |= (libc::IFF_TAP | libc::IFF_NO_PI) as i16;
     libc::strncpy(ifr.ifr_name.as_mut_ptr(), ifname.as_ptr(), libc::IFNAMSIZ);
+  }
 
-    let fd = libc::open(TUNDEV, libc::O_RDWR);
-    if fd < 0 {
+  let fd = unsafe { OwnedFd::from_raw_fd(open_tun_device(options.tun_device.as_deref(), libc::O_RDWR)?) };
+  if unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETIFF, &ifr) } < 0 {
+    return Err(explain_permission_error(std::io::Error::last_os_error(), Capability::NetAdmin, "attach to a TAP interface"));
+  }
+  if let Some(owner) = options.owner {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETOWNER, owner as libc::c_ulong) } < 0 {
       return Err(std::io::Error::last_os_error());
     }
-
-    let ret = libc::ioctl(fd, TUNSETIFF, &ifr);
-    if ret < 0 {
-      libc::close(fd);
+  }
+  if let Some(group) = options.group {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETGROUP, group as libc::c_ulong) } < 0 {
       return Err(std::io::Error::last_os_error());
     }
+  }
+  if unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETPERSIST, options.persist as libc::c_int) } < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  Ok(fd)
+}
 
-    let ret = libc::ioctl(fd, TUNSETPERSIST, 1);
-    if ret < 0 {
-      libc::close(fd);
-      return Err(std::io::Error::last_os_error());
-    }
+/// A TAP interface of the current network namespace, as listed by `list_taps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapInfo {
+  pub name: String,
 
-    libc::close(fd);
+  /// The interface outlives the processes attached to it.
+  pub persistent: bool,
+}
+
+/// List the TAP interfaces of the current network namespace from their
+/// `tun_flags` in sysfs, sorted by name. TUN interfaces are left out.
+pub fn list_taps() -> std::io::Result<Vec<TapInfo>> {
+  let mut taps = Vec::new();
+  for entry in std::fs::read_dir("/sys/class/net")? {
+    let entry = entry?;
+    // Interfaces other than TUN/TAP have no `tun_flags`; ones removed meanwhile are skipped too.
+    let Ok(flags) = std::fs::read_to_string(entry.path().join("tun_flags")) else {
+      continue;
+    };
+    let flags = flags.trim();
+    let flags = libc::c_int::from_str_radix(flags.trim_start_matches("0x"), 16)
+      .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unexpected tun_flags: {}", flags)))?;
+    if flags & libc::IFF_TAP == 0 {
+      continue;
+    }
+    taps.push(TapInfo {
+      name: entry.file_name().to_string_lossy().into_owned(),
+      persistent: flags & libc::IFF_PERSIST != 0,
+    });
   }
-  Ok(())
+  taps.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(taps)
 }
 
 /// Delete a TAP interface with the given name.
 pub fn tap_del_ioctl(ifname: &str) -> std::io::Result<()> {
+  tap_del_ioctl_at(None, ifname)
+}
+
+/// Delete a TAP interface with the given name using the clone device at `tun_device`.
+pub fn tap_del_ioctl_at(tun_device: Option<&Path>, ifname: &str) -> std::io::Result<()> {
   let ifname = ifname_to_cstring(ifname)?;
 
   let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
@@ -69,15 +307,13 @@ pub fn tap_del_ioctl(ifname: &str) -> std::io::Result<()> {
     ifr.ifr_ifru.ifru_flags |= (libc::IFF_TAP | libc::IFF_NO_PI) as i16;
     libc::strncpy(ifr.ifr_name.as_mut_ptr(), ifname.as_ptr(), libc::IFNAMSIZ);
 
-    let fd = libc::open(TUNDEV, libc::O_RDWR);
-    if fd < 0 {
-      return Err(std::io::Error::last_os_error());
-    }
+    let fd = open_tun_device(tun_device, libc::O_RDWR)?;
 
     let ret = libc::ioctl(fd, TUNSETIFF, &ifr);
     if ret < 0 {
+      let error = std::io::Error::last_os_error();
       libc::close(fd);
-      return Err(std::io::Error::last_os_error());
+      return Err(explain_permission_error(error, Capability::NetAdmin, "attach to a TAP interface"));
     }
 
     let ret = libc::ioctl(fd, TUNSETPERSIST, 0);
@@ -92,31 +328,102 @@ pub fn tap_del_ioctl(ifname: &str) -> std::io::Result<()> {
 }
 
 
+/// Size of the `tun_pi` packet information header.
+pub const TUN_PI_SIZE: usize = 4;
+
+/// `tun_pi` flag set by the kernel when the frame did not fit into the read buffer.
+pub const TUN_PKT_STRIP: u16 = 0x0001;
+
+/// Options for opening a TAP interface.
+#[derive(Debug, Clone, Default)]
+pub struct TapOptions {
+  /// Open the interface without `IFF_NO_PI`, so that every frame is prefixed
+  /// by the 4-byte `tun_pi` header. The header is parsed on read and emitted on write.
+  pub packet_info: bool,
+
+  /// Path of the TUN/TAP clone device. Defaults to `/dev/net/tun`.
+  pub tun_device: Option<PathBuf>,
+}
+
+/// Packet information (`struct tun_pi`) of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PacketInfo {
+  pub flags: u16,
+  /// Ethertype of the frame.
+  pub proto: u16,
+}
+
+impl PacketInfo {
+  fn from_bytes(bytes: [u8; TUN_PI_SIZE]) -> Self {
+    Self {
+      flags: u16::from_ne_bytes([bytes[0], bytes[1]]),
+      proto: u16::from_be_bytes([bytes[2], bytes[3]]),
+    }
+  }
+
+  fn to_bytes(self) -> [u8; TUN_PI_SIZE] {
+    let flags = self.flags.to_ne_bytes();
+    let proto = self.proto.to_be_bytes();
+    [flags[0], flags[1], proto[0], proto[1]]
+  }
+
+  /// Packet information for an outgoing Ethernet frame.
+  pub fn for_frame(frame: &[u8]) -> Self {
+    let proto = if frame.len() >= 14 { u16::from_be_bytes([frame[12], frame[13]]) } else { 0 };
+    Self { flags: 0, proto }
+  }
+}
+
 /// Raw TAP interface.
 #[derive(Debug)]
 pub struct RawTap {
   tap_fd: libc::c_int,
+  packet_info: bool,
+  ifname: String,
+  /// MTU as of creation or the last `set_mtu`/`refresh_mtu`.
+  mtu: AtomicU32,
 }
 
 impl RawTap {
   pub fn new(ifname: &str) -> std::io::Result<Self> {
+    Self::new_with_options(ifname, &TapOptions::default())
+  }
+
+  /// Open a TAP interface using the clone device at `path`.
+  pub fn new_at<P: AsRef<Path>>(path: P, ifname: &str) -> std::io::Result<Self> {
+    Self::new_with_options(ifname, &TapOptions {
+      tun_device: Some(path.as_ref().to_path_buf()),
+      ..TapOptions::default()
+    })
+  }
+
+  /// A name with a `%d` pattern is replaced by the name the kernel chose.
+  pub fn new_with_options(ifname: &str, options: &TapOptions) -> std::io::Result<Self> {
     let ifname = ifname_to_cstring(ifname)?;
 
     let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
     unsafe {
-      ifr.ifr_ifru.ifru_flags |= (libc::IFF_TAP | libc::IFF_NO_PI) as i16;
+      ifr.ifr_ifru.ifru_flags |= libc::IFF_TAP as i16;
+      if !options.packet_info {
+        ifr.ifr_ifru.ifru_flags |= libc::IFF_NO_PI as i16;
+      }
       libc::strncpy(ifr.ifr_name.as_mut_ptr(), ifname.as_ptr(), libc::IFNAMSIZ);
 
-      let fd = libc::open(TUNDEV, libc::O_RDWR | libc::O_NONBLOCK);
-      if fd < 0 {
-        return Err(std::io::Error::last_os_error());
-      }
+      let fd = open_tun_device(options.tun_device.as_deref(), libc::O_RDWR | libc::O_NONBLOCK)?;
 
-      let ret = libc::ioctl(fd, TUNSETIFF, &ifr);
+      let ret = libc::ioctl(fd, TUNSETIFF, &mut ifr);
       if ret < 0 {
+        let error = std::io::Error::last_os_error();
         libc::close(fd);
-        return Err(std::io::Error::last_os_error());
+        return Err(explain_permission_error(error, Capability::NetAdmin, "attach to a TAP interface"));
       }
+      let name = match ifreq_name(&ifr) {
+        Ok(name) => name,
+        Err(e) => {
+          libc::close(fd);
+          return Err(e);
+        },
+      };
 
       let ret = libc::ioctl(fd, TUNSETPERSIST, 1);
       if ret < 0 {
@@ -124,26 +431,116 @@ impl RawTap {
         return Err(std::io::Error::last_os_error());
       }
 
-      Ok(Self { tap_fd: fd })
+      let mtu = AtomicU32::new(get_mtu(&name).unwrap_or(DEFAULT_MTU));
+      Ok(Self { tap_fd: fd, packet_info: options.packet_info, ifname: name, mtu })
     }
   }
 
+  /// Read an Ethernet frame. The packet information header, if enabled, is discarded.
   pub fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-    let ret = unsafe { libc::read(self.tap_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    self.read_with_info(buf).map(|(n, _)| n)
+  }
+
+  /// Read an Ethernet frame along with its packet information header, if enabled.
+  pub fn read_with_info(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<PacketInfo>)> {
+    if !self.packet_info {
+      let ret = unsafe { libc::read(self.tap_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+      if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      return Ok((ret as usize, None));
+    }
+
+    let mut pi = [0u8; TUN_PI_SIZE];
+    let iov = [
+      libc::iovec { iov_base: pi.as_mut_ptr() as *mut libc::c_void, iov_len: pi.len() },
+      libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() },
+    ];
+    let ret = unsafe { libc::readv(self.tap_fd, iov.as_ptr(), iov.len() as libc::c_int) };
     if ret < 0 {
-      Err(std::io::Error::last_os_error())
-    } else {
-      Ok(ret as usize)
+      return Err(std::io::Error::last_os_error());
     }
+    if (ret as usize) < TUN_PI_SIZE {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "short read of packet information header"));
+    }
+    let info = PacketInfo::from_bytes(pi);
+    if info.flags & TUN_PKT_STRIP != 0 {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame truncated by the kernel"));
+    }
+    Ok((ret as usize - TUN_PI_SIZE, Some(info)))
   }
 
+  /// Write an Ethernet frame. The packet information header, if enabled, is derived from the frame.
   pub fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
-    let ret = unsafe { libc::write(self.tap_fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+    self.write_with_info(buf, PacketInfo::for_frame(buf))
+  }
+
+  /// Write an Ethernet frame with an explicit packet information header.
+  /// `info` is ignored unless the interface was opened with packet information enabled.
+  pub fn write_with_info(&self, buf: &[u8], info: PacketInfo) -> std::io::Result<usize> {
+    if !self.packet_info {
+      let ret = unsafe { libc::write(self.tap_fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+      if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      return Ok(ret as usize);
+    }
+
+    let pi = info.to_bytes();
+    let iov = [
+      libc::iovec { iov_base: pi.as_ptr() as *mut libc::c_void, iov_len: pi.len() },
+      libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() },
+    ];
+    let ret = unsafe { libc::writev(self.tap_fd, iov.as_ptr(), iov.len() as libc::c_int) };
     if ret < 0 {
-      Err(std::io::Error::last_os_error())
-    } else {
-      Ok(ret as usize)
+      return Err(std::io::Error::last_os_error());
     }
+    Ok((ret as usize).saturating_sub(TUN_PI_SIZE))
+  }
+
+  pub fn has_packet_info(&self) -> bool {
+    self.packet_info
+  }
+
+  /// Name of the interface.
+  pub fn name(&self) -> &str {
+    &self.ifname
+  }
+
+  /// Set the MTU of the interface.
+  pub fn set_mtu(&self, mtu: u32) -> std::io::Result<()> {
+    set_interface_mtu(&self.ifname, mtu)?;
+    self.mtu.store(mtu, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Cached MTU of the interface.
+  pub fn mtu(&self) -> u32 {
+    self.mtu.load(Ordering::Relaxed)
+  }
+
+  /// Read the MTU of the interface again, in case it was changed by someone else.
+  /// Must be called in the network namespace of the interface.
+  pub fn refresh_mtu(&self) -> std::io::Result<u32> {
+    let mtu = get_mtu(&self.ifname)?;
+    self.mtu.store(mtu, Ordering::Relaxed);
+    Ok(mtu)
+  }
+
+  /// Longest frame the interface sends with its cached MTU, including a VLAN tag.
+  pub fn max_frame_size(&self) -> usize {
+    self.mtu() as usize + ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE
+  }
+
+  /// Close the interface, reporting any error from `close()`.
+  /// Writes to a TAP device are not buffered, so there is nothing left to flush.
+  pub fn close(self) -> std::io::Result<()> {
+    let fd = self.tap_fd;
+    std::mem::forget(self);
+    if unsafe { libc::close(fd) } < 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
   }
 }
 
@@ -171,28 +568,286 @@ pub struct Tap {
 
 impl Tap {
   pub fn new(ifname: &str) -> std::io::Result<Self> {
-    let tap = RawTap::new(ifname)?;
+    Self::new_with_options(ifname, &TapOptions::default())
+  }
+
+  /// Open a TAP interface using the clone device at `path`.
+  pub fn new_at<P: AsRef<Path>>(path: P, ifname: &str) -> std::io::Result<Self> {
+    let tap = RawTap::new_at(path, ifname)?;
     let inner = AsyncFd::with_interest(tap, Interest::READABLE | Interest::WRITABLE)?;
     Ok(Self { inner })
   }
 
+  pub fn new_with_options(ifname: &str, options: &TapOptions) -> std::io::Result<Self> {
+    let tap = RawTap::new_with_options(ifname, options)?;
+    let inner = AsyncFd::with_interest(tap, Interest::READABLE | Interest::WRITABLE)?;
+    Ok(Self { inner })
+  }
+
+  /// Open a TAP interface like `new`, trying up to `attempts` times while another tool
+  /// is still setting up the device. The delay starts at `backoff` and doubles after each
+  /// failure. Permission errors and invalid names are returned at once, as retrying cannot fix them.
+  pub async fn new_with_retry(ifname: &str, attempts: u32, backoff: std::time::Duration) -> std::io::Result<Self> {
+    retry_open(attempts, backoff, || Self::new(ifname)).await
+  }
+
   pub async fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.read_with_info(buf).await.map(|(n, _)| n)
+  }
+
+  /// Read an Ethernet frame along with its packet information header, if enabled.
+  pub async fn read_with_info(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<PacketInfo>)> {
     loop {
       let mut guard = self.inner.readable().await?;
-      match guard.try_io(|inner| inner.get_ref().read(buf)) {
+      match guard.try_io(|inner| inner.get_ref().read_with_info(buf)) {
         Ok(result) => return result,
         Err(_would_block) => continue,
       }
     }
   }
 
+  /// Wait until a frame is waiting to be read. Readiness left over from earlier reads
+  /// is checked with `poll(2)`, so that this does not return while the queue is empty.
+  pub async fn readable(&self) -> std::io::Result<()> {
+    loop {
+      let mut guard = self.inner.readable().await?;
+      let mut pollfd = libc::pollfd { fd: self.inner.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+      let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+      if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      if pollfd.revents != 0 {
+        return Ok(());
+      }
+      guard.clear_ready();
+    }
+  }
+
+  /// Poll for an Ethernet frame, registering for readiness if none is available.
+  /// This lets a single task wait on many interfaces at once.
+  pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+    loop {
+      let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+      match guard.try_io(|inner| inner.get_ref().read(buf)) {
+        Ok(result) => return Poll::Ready(result),
+        Err(_would_block) => continue,
+      }
+    }
+  }
+
   pub async fn write(&self, buf: &[u8]) -> std::io::Result<usize> {
+    self.write_with_info(buf, PacketInfo::for_frame(buf)).await
+  }
+
+  /// Write an Ethernet frame with an explicit packet information header.
+  pub async fn write_with_info(&self, buf: &[u8], info: PacketInfo) -> std::io::Result<usize> {
     loop {
       let mut guard = self.inner.writable().await?;
-      match guard.try_io(|inner| inner.get_ref().write(buf)) {
+      match guard.try_io(|inner| inner.get_ref().write_with_info(buf, info)) {
         Ok(result) => return result,
         Err(_would_block) => continue,
       }
     }
   }
+
+  /// Name of the interface.
+  pub fn name(&self) -> &str {
+    self.inner.get_ref().name()
+  }
+
+  /// Set the MTU of the interface.
+  pub fn set_mtu(&self, mtu: u32) -> std::io::Result<()> {
+    self.inner.get_ref().set_mtu(mtu)
+  }
+
+  /// Cached MTU of the interface.
+  pub fn mtu(&self) -> u32 {
+    self.inner.get_ref().mtu()
+  }
+
+  /// Read the MTU of the interface again. Must be called in its network namespace.
+  pub fn refresh_mtu(&self) -> std::io::Result<u32> {
+    self.inner.get_ref().refresh_mtu()
+  }
+
+  /// Longest frame the interface sends with its cached MTU.
+  pub fn max_frame_size(&self) -> usize {
+    self.inner.get_ref().max_frame_size()
+  }
+
+  /// Deregister from the runtime and close the interface, reporting any error from `close()`.
+  pub fn close(self) -> std::io::Result<()> {
+    self.inner.into_inner().close()
+  }
+}
+
+impl AsRawFd for Tap {
+  fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+    self.inner.as_raw_fd()
+  }
+}
+
+/// Call `open` up to `attempts` times, as `Tap::new_with_retry` describes.
+async fn retry_open<T>(attempts: u32, backoff: std::time::Duration, mut open: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+  let mut delay = backoff;
+  let mut attempt = 1;
+  loop {
+    match open() {
+      Ok(opened) => return Ok(opened),
+      Err(e) if attempt >= attempts || matches!(e.kind(), std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::InvalidInput) => return Err(e),
+      Err(_) => {},
+    }
+    tokio::time::sleep(delay).await;
+    delay = delay.saturating_mul(2);
+    attempt += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Open the TAP interface `ifname`, or `None` if TAP interfaces cannot be created here.
+  fn open_tap(ifname: &str) -> Option<Tap> {
+    match Tap::new(ifname) {
+      Ok(tap) => Some(tap),
+      Err(e) if matches!(e.kind(), std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::NotFound) => None,
+      Err(e) => panic!("cannot open TAP interface {}: {}", ifname, e),
+    }
+  }
+
+  #[tokio::test]
+  async fn closed_tap_releases_its_interface() {
+    let Some(tap) = open_tap("etiptest-close") else {
+      return;
+    };
+    // An interface is attached to one file at a time, so it cannot be opened again
+    // until the file is closed. The descriptor itself is not checked: another test may reuse it.
+    assert_eq!(Tap::new("etiptest-close").err().and_then(|e| e.raw_os_error()), Some(libc::EBUSY));
+
+    tap.close().expect("close");
+    let reopened = Tap::new("etiptest-close").expect("reopen after close");
+    reopened.close().expect("close");
+    tap_del_ioctl("etiptest-close").unwrap();
+  }
+
+  #[tokio::test]
+  async fn mtu_is_read_from_the_interface() {
+    let Some(tap) = open_tap("etiptest-mtu") else {
+      return;
+    };
+    assert_eq!(get_mtu("etiptest-mtu").expect("get the MTU"), tap.mtu());
+
+    // Changed behind the back of the `Tap`, the MTU is only seen once refreshed.
+    set_interface_mtu("etiptest-mtu", 1400).expect("set the MTU");
+    assert_eq!(get_mtu("etiptest-mtu").expect("get the MTU"), 1400);
+    assert_ne!(tap.mtu(), 1400);
+    assert_eq!(tap.refresh_mtu().expect("refresh the MTU"), 1400);
+    assert_eq!(tap.mtu(), 1400);
+    assert_eq!(tap.max_frame_size(), 1400 + ETHERNET_HEADER_SIZE + VLAN_TAG_SIZE);
+
+    tap.close().expect("close");
+    tap_del_ioctl("etiptest-mtu").unwrap();
+  }
+
+  #[tokio::test]
+  async fn opening_is_retried_with_backoff() {
+    // Fails like an interface another tool has not released yet, then succeeds.
+    let mut calls = 0;
+    let started = std::time::Instant::now();
+    let opened = retry_open(3, std::time::Duration::from_millis(10), || {
+      calls += 1;
+      match calls {
+        1 => Err(std::io::Error::from_raw_os_error(libc::EBUSY)),
+        _ => Ok(calls),
+      }
+    }).await;
+    assert_eq!(opened.ok(), Some(2));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(10));
+
+    // The delay doubles, and the last error is returned once the attempts run out.
+    let mut calls = 0;
+    let started = std::time::Instant::now();
+    let opened: std::io::Result<()> = retry_open(3, std::time::Duration::from_millis(10), || {
+      calls += 1;
+      Err(std::io::Error::from_raw_os_error(libc::EBUSY))
+    }).await;
+    assert_eq!(opened.err().and_then(|e| e.raw_os_error()), Some(libc::EBUSY));
+    assert_eq!(calls, 3);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(30));
+
+    // Retrying cannot fix a permission error.
+    let mut calls = 0;
+    let opened: std::io::Result<()> = retry_open(3, std::time::Duration::from_millis(10), || {
+      calls += 1;
+      Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+    }).await;
+    assert!(opened.is_err());
+    assert_eq!(calls, 1);
+  }
+
+  #[tokio::test]
+  async fn busy_tap_is_opened_once_released() {
+    let Some(holder) = open_tap("etiptest-retry") else {
+      return;
+    };
+    let release = tokio::spawn(async move {
+      tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+      holder.close().expect("close");
+    });
+    let tap = Tap::new_with_retry("etiptest-retry", 10, std::time::Duration::from_millis(20)).await.expect("open once released");
+    release.await.unwrap();
+    tap.close().expect("close");
+    tap_del_ioctl("etiptest-retry").unwrap();
+  }
+
+  #[tokio::test]
+  async fn source_address_is_picked_per_destination() {
+    let Some(tap) = open_tap("etiptest-src") else {
+      return;
+    };
+    let set_ipv4 = |request, addr: [u8; 4]| {
+      interface_ioctl("etiptest-src", request, "set an IPv4 address of an interface", |ifr| {
+        let sin = unsafe { &mut *(&mut ifr.ifr_ifru.ifru_addr as *mut libc::sockaddr as *mut libc::sockaddr_in) };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_addr.s_addr = u32::from_ne_bytes(addr);
+      })
+    };
+    set_ipv4(libc::SIOCSIFADDR, [198, 51, 100, 1]).expect("set the address");
+    set_ipv4(libc::SIOCSIFNETMASK, [255, 255, 255, 0]).expect("set the netmask");
+    set_up("etiptest-src", true).expect("bring the interface up");
+
+    // Two local addresses: each destination is reached from the one of its route.
+    assert_eq!(crate::source_address(&"198.51.100.2".parse().unwrap()).expect("route through the TAP"), "198.51.100.1".parse::<std::net::IpAddr>().unwrap());
+    assert_eq!(crate::source_address(&"127.0.0.1".parse().unwrap()).expect("route through lo"), "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+
+    tap.close().expect("close");
+    tap_del_ioctl("etiptest-src").unwrap();
+  }
+
+  /// An `ifreq` whose name buffer holds `name`, unterminated if it fills the buffer.
+  fn ifreq_named(name: &[u8]) -> libc::ifreq {
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (c, &byte) in ifr.ifr_name.iter_mut().zip(name) {
+      *c = byte as libc::c_char;
+    }
+    ifr
+  }
+
+  #[test]
+  fn ifreq_names_are_read_up_to_their_terminator() {
+    assert_eq!(ifreq_name(&ifreq_named(b"etip0")).unwrap(), "etip0");
+    // The longest name leaves room for the terminating NUL.
+    let longest = "e".repeat(libc::IFNAMSIZ - 1);
+    assert_eq!(ifreq_name(&ifreq_named(longest.as_bytes())).unwrap(), longest);
+
+    // A full buffer is not read past, and is not taken for a name.
+    let mut unterminated = ifreq_named(&[b'e'; libc::IFNAMSIZ]);
+    assert_eq!(ifreq_name(&unterminated).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+    unterminated.ifr_name[libc::IFNAMSIZ - 1] = 0;
+    assert_eq!(ifreq_name(&unterminated).unwrap(), longest);
+
+    assert_eq!(ifreq_name(&ifreq_named(b"")).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+    assert_eq!(ifreq_name(&ifreq_named(b"\xffetip")).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+  }
 }
@@ -0,0 +1,240 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! EtherIP datagrams carried over TCP, for networks that block IP protocol 97.
+//!
+//! This is not part of RFC 3378: each datagram is sent with a 2-byte big-endian
+//! length prefix over a TCP connection between two instances of this daemon.
+//! Both ends must use this transport; one connects and the other listens.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::log;
+use crate::tokio;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::config::AddrString;
+use crate::logging::link_target;
+use crate::transport::{DatagramSink, DatagramSource};
+use crate::{from_ipv6_addr, EtherIpBuffer, QueueFull, TruncatedPacket, ETHERIP_HEADER_SIZE};
+
+/// Default TCP port of the transport.
+pub const DEFAULT_TCP_PORT: u16 = 3378;
+
+/// Size of the length prefix of each datagram.
+pub const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Number of datagrams buffered in each direction.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Longest delay between connection attempts.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Which end of the connection this is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpRole {
+  /// Connect to the remote.
+  Connect,
+  /// Accept connections from the remote.
+  Listen,
+}
+
+/// TCP transport of a single link.
+/// `run` maintains the connection; datagrams sent while disconnected fail with `NotConnected`.
+pub struct TcpTransport {
+  link_name: String,
+  remote: Mutex<AddrString>,
+  port: u16,
+  role: TcpRole,
+  connected: AtomicBool,
+  outgoing_sender: mpsc::Sender<Vec<u8>>,
+  outgoing: Mutex<mpsc::Receiver<Vec<u8>>>,
+  incoming_sender: mpsc::Sender<(Vec<u8>, IpAddr)>,
+  incoming: Mutex<mpsc::Receiver<(Vec<u8>, IpAddr)>>,
+}
+
+impl TcpTransport {
+  pub fn new(link_name: String, remote: AddrString, port: u16, role: TcpRole) -> Self {
+    let (outgoing_sender, outgoing) = mpsc::channel(QUEUE_CAPACITY);
+    let (incoming_sender, incoming) = mpsc::channel(QUEUE_CAPACITY);
+    Self {
+      link_name,
+      remote: Mutex::new(remote),
+      port,
+      role,
+      connected: AtomicBool::new(false),
+      outgoing_sender,
+      outgoing: Mutex::new(outgoing),
+      incoming_sender,
+      incoming: Mutex::new(incoming),
+    }
+  }
+
+  pub fn is_connected(&self) -> bool {
+    self.connected.load(Ordering::Relaxed)
+  }
+
+  /// Establish the connection and carry datagrams over it, reconnecting with
+  /// exponential backoff whenever it fails. Only returns if listening fails.
+  pub async fn run(&self) -> std::io::Result<()> {
+    let listener = match self.role {
+      TcpRole::Listen => Some(bind_dual_stack(self.port).await?),
+      TcpRole::Connect => None,
+    };
+    let mut delay = Duration::from_secs(1);
+    loop {
+      let stream = match &listener {
+        Some(listener) => self.accept(listener).await,
+        None => self.connect().await,
+      };
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+          log::warn!(target: &link_target(&self.link_name), "Link {}: TCP connection failed, retrying in {:?}: {}", self.link_name, delay, e);
+          tokio::time::sleep(delay).await;
+          delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+          continue;
+        },
+      };
+      delay = Duration::from_secs(1);
+      let peer = stream.peer_addr().map(|addr| canonical_ip(addr.ip()))?;
+      log::info!(target: &link_target(&self.link_name), "Link {}: TCP connection with {} established", self.link_name, peer);
+      self.connected.store(true, Ordering::Relaxed);
+      let result = self.serve(stream, peer).await;
+      self.connected.store(false, Ordering::Relaxed);
+      match result {
+        Ok(()) => log::info!(target: &link_target(&self.link_name), "Link {}: TCP connection with {} closed", self.link_name, peer),
+        Err(e) => log::warn!(target: &link_target(&self.link_name), "Link {}: TCP connection with {} failed: {}", self.link_name, peer, e),
+      }
+    }
+  }
+
+  async fn connect(&self) -> std::io::Result<TcpStream> {
+    let remote = {
+      let mut remote = self.remote.lock().await;
+      remote.update_ip_addr().await?;
+      remote.try_get_ip_addr().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "remote address unknown"))?
+    };
+    let stream = TcpStream::connect(SocketAddr::new(remote, self.port)).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+  }
+
+  /// Wait for a connection from the remote, turning away other peers.
+  async fn accept(&self, listener: &TcpListener) -> std::io::Result<TcpStream> {
+    loop {
+      let (stream, peer) = listener.accept().await?;
+      let peer = canonical_ip(peer.ip());
+      let remote = {
+        let mut remote = self.remote.lock().await;
+        let _ = remote.update_ip_addr().await;
+        remote.try_get_ip_addr()
+      };
+      if remote != Some(peer) {
+        log::debug!(target: &link_target(&self.link_name), "Link {}: rejected a TCP connection from {}", self.link_name, peer);
+        continue;
+      }
+      stream.set_nodelay(true)?;
+      return Ok(stream);
+    }
+  }
+
+  /// Carry datagrams over an established connection until it fails or is closed by the peer.
+  async fn serve(&self, stream: TcpStream, peer: IpAddr) -> std::io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let receive = async {
+      let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+      loop {
+        if let Err(e) = reader.read_exact(&mut prefix).await {
+          return match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Ok(()),
+            _ => Err(e),
+          };
+        }
+        let len = u16::from_be_bytes(prefix) as usize;
+        if len < ETHERIP_HEADER_SIZE {
+          return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "EtherIP datagram too short"));
+        }
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data).await?;
+        if self.incoming_sender.send((data, peer)).await.is_err() {
+          return Ok(());
+        }
+      }
+    };
+    let send = async {
+      let mut outgoing = self.outgoing.lock().await;
+      let mut buf = Vec::new();
+      while let Some(data) = outgoing.recv().await {
+        buf.clear();
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&data);
+        writer.write_all(&buf).await?;
+      }
+      Ok(())
+    };
+    tokio::select! {
+      result = receive => result,
+      result = send => result,
+    }
+  }
+
+  fn enqueue(&self, data: &[u8]) -> std::io::Result<usize> {
+    if !self.is_connected() {
+      return Err(std::io::Error::from(std::io::ErrorKind::NotConnected));
+    }
+    if data.len() > u16::MAX as usize {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "EtherIP datagram too long"));
+    }
+    self.outgoing_sender.try_send(data.to_vec()).map_err(|e| match e {
+      mpsc::error::TrySendError::Full(_) => QueueFull.into(),
+      mpsc::error::TrySendError::Closed(_) => std::io::Error::from(std::io::ErrorKind::NotConnected),
+    })?;
+    Ok(data.len())
+  }
+}
+
+/// Listen on all addresses, dual-stack if IPv6 is available.
+async fn bind_dual_stack(port: u16) -> std::io::Result<TcpListener> {
+  match TcpListener::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)).await {
+    Ok(listener) => Ok(listener),
+    Err(_) => TcpListener::bind(SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), port)).await,
+  }
+}
+
+/// Unmap v4-mapped addresses reported by dual-stack sockets.
+pub(crate) fn canonical_ip(addr: IpAddr) -> IpAddr {
+  match addr {
+    IpAddr::V6(v6_addr) => from_ipv6_addr(v6_addr),
+    addr => addr,
+  }
+}
+
+impl DatagramSource for TcpTransport {
+  async fn recv_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    let (data, src_addr) = self.incoming.lock().await.recv().await.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+    let (mut len, buf) = datagram.datagram_mut();
+    if data.len() > buf.len() {
+      return Err(TruncatedPacket { len: data.len(), src_addr }.into());
+    }
+    buf[..data.len()].copy_from_slice(&data);
+    len.set(data.len());
+    Ok((data.len(), src_addr))
+  }
+}
+
+impl DatagramSink for TcpTransport {
+  async fn send_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, _dst_addr: &IpAddr) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    self.enqueue(data)
+  }
+
+  async fn send_datagrams(&self, datagrams: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    Ok(datagrams.iter().map(|(data, _)| self.enqueue(data)).collect())
+  }
+}
@@ -0,0 +1,282 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Abstractions over the endpoints the daemon forwards between,
+//! so that forwarding logic does not depend on real sockets and TAPs.
+
+use std::future::Future;
+use std::net::IpAddr;
+
+use crate::tokio;
+
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{EtherIpBuffer, EtherIpSocket, RecvInfo};
+use crate::packet::PacketSocket;
+use crate::tap::Tap;
+
+/// Source of Ethernet frames (the local side of a link).
+pub trait FrameSource: Send + Sync {
+  /// Read an Ethernet frame into `buf`, returning its length.
+  fn recv_frame(&self, buf: &mut [u8]) -> impl Future<Output = std::io::Result<usize>> + Send;
+
+  /// Wait until a frame is likely available, so that a buffer need not be held while idle.
+  /// Sources that cannot tell return at once, and `recv_frame` does the waiting.
+  fn readable(&self) -> impl Future<Output = std::io::Result<()>> + Send {
+    async { Ok(()) }
+  }
+
+  /// Longest frame expected from the source, used to size read buffers. Unbounded by default.
+  fn max_frame_size(&self) -> Option<usize> {
+    None
+  }
+}
+
+/// Sink of Ethernet frames (the local side of a link).
+pub trait FrameSink: Send + Sync {
+  /// Write an Ethernet frame.
+  fn send_frame(&self, frame: &[u8]) -> impl Future<Output = std::io::Result<usize>> + Send;
+
+  /// Set the MTU of the sink. Sinks without an MTU ignore it.
+  fn set_mtu(&self, _mtu: u32) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Source of EtherIP datagrams (the underlay side).
+pub trait DatagramSource: Send + Sync {
+  /// Receive an EtherIP datagram, returning its length and source address.
+  fn recv_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> impl Future<Output = std::io::Result<(usize, IpAddr)>> + Send;
+
+  /// Receive an EtherIP datagram along with the traffic class it arrived with, if known.
+  fn recv_datagram_with_tclass<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> impl Future<Output = std::io::Result<(usize, IpAddr, Option<u8>)>> + Send {
+    async move {
+      let (n, src_addr) = self.recv_datagram(datagram).await?;
+      Ok((n, src_addr, None))
+    }
+  }
+
+  /// Receive an EtherIP datagram along with its traffic class and arrival interface, if known.
+  fn recv_datagram_with_info<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> impl Future<Output = std::io::Result<(usize, IpAddr, RecvInfo)>> + Send {
+    async move {
+      let (n, src_addr, tclass) = self.recv_datagram_with_tclass(datagram).await?;
+      Ok((n, src_addr, RecvInfo { tclass, ..RecvInfo::default() }))
+    }
+  }
+}
+
+/// Sink of EtherIP datagrams (the underlay side).
+pub trait DatagramSink: Send + Sync {
+  /// Send an EtherIP datagram to `dst_addr`.
+  fn send_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr) -> impl Future<Output = std::io::Result<usize>> + Send;
+
+  /// Send an EtherIP datagram with the given traffic class.
+  /// Sinks that cannot set the traffic class send with their default one.
+  fn send_datagram_with_tclass<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr, _tclass: u8) -> impl Future<Output = std::io::Result<usize>> + Send {
+    self.send_datagram(datagram, dst_addr)
+  }
+
+  /// Send a batch of encoded EtherIP datagrams, returning one result per datagram.
+  fn send_datagrams(&self, datagrams: &[(&[u8], IpAddr)]) -> impl Future<Output = std::io::Result<Vec<std::io::Result<usize>>>> + Send;
+
+  /// Source address of all sent datagrams, if the sink is bound to one.
+  /// Otherwise the kernel picks the source per destination.
+  fn local_addr(&self) -> Option<IpAddr> {
+    None
+  }
+}
+
+impl FrameSource for Tap {
+  async fn recv_frame(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.read(buf).await
+  }
+
+  async fn readable(&self) -> std::io::Result<()> {
+    Tap::readable(self).await
+  }
+
+  fn max_frame_size(&self) -> Option<usize> {
+    Some(Tap::max_frame_size(self))
+  }
+}
+
+impl FrameSink for Tap {
+  async fn send_frame(&self, frame: &[u8]) -> std::io::Result<usize> {
+    self.write(frame).await
+  }
+
+  fn set_mtu(&self, mtu: u32) -> std::io::Result<()> {
+    Tap::set_mtu(self, mtu)
+  }
+}
+
+impl FrameSource for PacketSocket {
+  async fn recv_frame(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.recv(buf).await
+  }
+
+  async fn readable(&self) -> std::io::Result<()> {
+    PacketSocket::readable(self).await
+  }
+
+  fn max_frame_size(&self) -> Option<usize> {
+    PacketSocket::max_frame_size(self)
+  }
+}
+
+impl FrameSink for PacketSocket {
+  async fn send_frame(&self, frame: &[u8]) -> std::io::Result<usize> {
+    self.send(frame).await
+  }
+}
+
+impl DatagramSource for EtherIpSocket {
+  async fn recv_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    self.recv_from(datagram).await
+  }
+
+  async fn recv_datagram_with_tclass<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr, Option<u8>)> {
+    self.recv_from_with_tclass(datagram).await
+  }
+
+  async fn recv_datagram_with_info<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr, RecvInfo)> {
+    self.recv_from_with_info(datagram).await
+  }
+}
+
+impl DatagramSink for EtherIpSocket {
+  async fn send_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr) -> std::io::Result<usize> {
+    self.send_to(datagram, dst_addr).await
+  }
+
+  async fn send_datagram_with_tclass<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr, tclass: u8) -> std::io::Result<usize> {
+    self.send_to_with_tclass(datagram, dst_addr, tclass).await
+  }
+
+  async fn send_datagrams(&self, datagrams: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    self.send_many_raw(datagrams).await
+  }
+
+  fn local_addr(&self) -> Option<IpAddr> {
+    EtherIpSocket::local_addr(self).ok().filter(|addr| !addr.is_unspecified())
+  }
+}
+
+/// In-memory frame endpoint backed by channels.
+/// Frames sent to it are queued for `take_sent`, frames pushed with `push_received` are returned by `recv_frame`.
+#[derive(Debug)]
+pub struct MemoryFrames {
+  received: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+  received_sender: mpsc::UnboundedSender<Vec<u8>>,
+  sent: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+  sent_sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl MemoryFrames {
+  pub fn new() -> Self {
+    let (received_sender, received) = mpsc::unbounded_channel();
+    let (sent_sender, sent) = mpsc::unbounded_channel();
+    Self {
+      received: Mutex::new(received),
+      received_sender,
+      sent: Mutex::new(sent),
+      sent_sender,
+    }
+  }
+
+  /// Queue a frame to be returned by `recv_frame`.
+  pub fn push_received(&self, frame: &[u8]) {
+    let _ = self.received_sender.send(frame.to_vec());
+  }
+
+  /// Wait for the next frame passed to `send_frame`.
+  pub async fn take_sent(&self) -> Option<Vec<u8>> {
+    self.sent.lock().await.recv().await
+  }
+}
+
+impl Default for MemoryFrames {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl FrameSource for MemoryFrames {
+  async fn recv_frame(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let frame = self.received.lock().await.recv().await.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+    let len = frame.len().min(buf.len());
+    buf[..len].copy_from_slice(&frame[..len]);
+    Ok(len)
+  }
+}
+
+impl FrameSink for MemoryFrames {
+  async fn send_frame(&self, frame: &[u8]) -> std::io::Result<usize> {
+    self.sent_sender.send(frame.to_vec()).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+    Ok(frame.len())
+  }
+}
+
+/// In-memory datagram endpoint backed by channels, the underlay counterpart of `MemoryFrames`.
+#[derive(Debug)]
+pub struct MemoryDatagrams {
+  received: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, IpAddr)>>,
+  received_sender: mpsc::UnboundedSender<(Vec<u8>, IpAddr)>,
+  sent: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, IpAddr)>>,
+  sent_sender: mpsc::UnboundedSender<(Vec<u8>, IpAddr)>,
+}
+
+impl MemoryDatagrams {
+  pub fn new() -> Self {
+    let (received_sender, received) = mpsc::unbounded_channel();
+    let (sent_sender, sent) = mpsc::unbounded_channel();
+    Self {
+      received: Mutex::new(received),
+      received_sender,
+      sent: Mutex::new(sent),
+      sent_sender,
+    }
+  }
+
+  /// Queue a datagram from `src_addr` to be returned by `recv_datagram`.
+  pub fn push_received(&self, datagram: &[u8], src_addr: IpAddr) {
+    let _ = self.received_sender.send((datagram.to_vec(), src_addr));
+  }
+
+  /// Wait for the next datagram passed to `send_datagram`, with its destination.
+  pub async fn take_sent(&self) -> Option<(Vec<u8>, IpAddr)> {
+    self.sent.lock().await.recv().await
+  }
+}
+
+impl Default for MemoryDatagrams {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DatagramSource for MemoryDatagrams {
+  async fn recv_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    let (data, src_addr) = self.received.lock().await.recv().await.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+    let (mut len, buf) = datagram.datagram_mut();
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    len.set(n);
+    Ok((n, src_addr))
+  }
+}
+
+impl DatagramSink for MemoryDatagrams {
+  async fn send_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, dst_addr: &IpAddr) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    self.sent_sender.send((data.to_vec(), *dst_addr)).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+    Ok(data.len())
+  }
+
+  async fn send_datagrams(&self, datagrams: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    Ok(datagrams.iter().map(|(data, dst_addr)| {
+      self.sent_sender.send((data.to_vec(), *dst_addr)).map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+      Ok(data.len())
+    }).collect())
+  }
+}
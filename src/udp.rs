@@ -0,0 +1,443 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! EtherIP datagrams encapsulated in UDP, for traversing NAT and stateless firewalls.
+//!
+//! This is not part of RFC 3378: each EtherIP datagram is the payload of one UDP datagram.
+//! Both ends must use this transport. Replies go to the address and port the peer was last
+//! heard from, so a peer behind NAT is reachable once it has sent something, and periodic
+//! keepalives (EtherIP headers without a frame) hold its NAT binding open.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+use crate::libc;
+use crate::log;
+use crate::parking_lot;
+use crate::tokio;
+
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+use crate::config::AddrString;
+use crate::logging::link_target;
+use crate::tcp::canonical_ip;
+use crate::transport::{DatagramSink, DatagramSource};
+use crate::{to_ipv6_addr, EtherIpBuffer, EtherIpHeader, TooLarge, ETHERIP_HEADER_SIZE};
+
+/// Default UDP port of the transport.
+pub const DEFAULT_UDP_PORT: u16 = 3378;
+
+/// Default interval between keepalives.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+/// Bind a UDP socket to `[::]:local_port`. Unless `v6only`, `IPV6_V6ONLY` is cleared so that
+/// IPv4 remotes are reachable through v4-mapped addresses even where net.ipv6.bindv6only is set.
+fn bind_ipv6(local_port: u16, v6only: bool) -> std::io::Result<std::net::UdpSocket> {
+  let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+  if fd < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+  // Must be set before binding.
+  set_v6only_option(fd.as_raw_fd(), v6only)?;
+  bind_fd(fd.as_raw_fd(), local_port)?;
+  Ok(std::net::UdpSocket::from(fd))
+}
+
+fn bind_fd(fd: libc::c_int, local_port: u16) -> std::io::Result<()> {
+  let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+  addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+  addr.sin6_port = local_port.to_be();
+  if unsafe { libc::bind(fd, &addr as *const libc::sockaddr_in6 as *const libc::sockaddr, std::mem::size_of_val(&addr) as libc::socklen_t) } < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+fn set_v6only_option(fd: libc::c_int, v6only: bool) -> std::io::Result<()> {
+  let v6only = v6only as libc::c_int;
+  if unsafe { libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &v6only as *const libc::c_int as *const libc::c_void, std::mem::size_of_val(&v6only) as libc::socklen_t) } < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+fn v6only_option(fd: libc::c_int) -> std::io::Result<bool> {
+  let mut v6only: libc::c_int = 0;
+  let mut len = std::mem::size_of_val(&v6only) as libc::socklen_t;
+  if unsafe { libc::getsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &mut v6only as *mut libc::c_int as *mut libc::c_void, &mut len) } < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  Ok(v6only != 0)
+}
+
+/// UDP transport of a single link, with its own socket.
+pub struct UdpTransport {
+  link_name: String,
+  socket: UdpSocket,
+  remote: Mutex<AddrString>,
+  port: u16,
+  /// Address and port the peer was last heard from.
+  endpoint: parking_lot::Mutex<Option<SocketAddr>>,
+  keepalive_interval: Duration,
+}
+
+impl UdpTransport {
+  /// Bind the link's socket to `local_port` on all addresses, dual-stack if IPv6 is available.
+  /// Datagrams are sent to `port` on the remote until the peer is heard from.
+  pub async fn bind(link_name: String, remote: AddrString, port: u16, local_port: u16, keepalive_interval: Duration) -> std::io::Result<Self> {
+    let socket = match bind_ipv6(local_port, false) {
+      Ok(socket) => UdpSocket::from_std(socket)?,
+      Err(_) => UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), local_port)).await?,
+    };
+    Ok(Self {
+      link_name,
+      socket,
+      remote: Mutex::new(remote),
+      port,
+      endpoint: parking_lot::Mutex::new(None),
+      keepalive_interval,
+    })
+  }
+
+  pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+    self.socket.local_addr()
+  }
+
+  /// true if the socket only handles IPv6, i.e. IPv4 remotes are unreachable.
+  /// Sockets that fell back to IPv4 are not.
+  pub fn is_v6only(&self) -> std::io::Result<bool> {
+    match self.socket.local_addr()? {
+      SocketAddr::V6(_) => v6only_option(self.socket.as_raw_fd()),
+      SocketAddr::V4(_) => Ok(false),
+    }
+  }
+
+  /// Make the socket IPv6-only, for callers who want IPv4 remotes refused, or dual-stack again.
+  /// Linux only accepts `IPV6_V6ONLY` before binding, so the socket is replaced by one bound
+  /// to the same port; datagrams queued on the old one are lost. If the new socket cannot
+  /// take the port, the previous setting is restored. Fails if the socket fell back to IPv4.
+  pub fn set_v6only(&mut self, v6only: bool) -> std::io::Result<()> {
+    let local_addr = self.socket.local_addr()?;
+    if local_addr.is_ipv4() {
+      return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "the UDP socket is IPv4-only"));
+    }
+    let previous = self.is_v6only()?;
+    if previous == v6only {
+      return Ok(());
+    }
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    set_v6only_option(fd.as_raw_fd(), v6only)?;
+    // The port is only free once the old socket is closed.
+    drop(std::mem::replace(&mut self.socket, UdpSocket::from_std(std::net::UdpSocket::from(fd))?));
+    let fd = self.socket.as_raw_fd();
+    if let Err(e) = bind_fd(fd, local_addr.port()) {
+      set_v6only_option(fd, previous)?;
+      bind_fd(fd, local_addr.port())?;
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  /// Address and port the peer was last heard from.
+  pub fn endpoint(&self) -> Option<SocketAddr> {
+    *self.endpoint.lock()
+  }
+
+  /// Periodically re-resolve the remote and send keepalives. Never returns.
+  pub async fn run(&self) -> std::io::Result<()> {
+    let keepalive = EtherIpHeader::default().encode();
+    let mut interval = tokio::time::interval(self.keepalive_interval);
+    loop {
+      interval.tick().await;
+      if let Err(e) = self.send(&keepalive).await {
+        log::debug!(target: &link_target(&self.link_name), "Link {}: failed to send a UDP keepalive: {}", self.link_name, e);
+      }
+    }
+  }
+
+  /// Destination of outgoing datagrams, in the socket's address family.
+  async fn destination(&self) -> std::io::Result<SocketAddr> {
+    let destination = match self.endpoint() {
+      Some(endpoint) => endpoint,
+      None => {
+        let mut remote = self.remote.lock().await;
+        remote.update_ip_addr().await?;
+        let remote = remote.try_get_ip_addr().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "remote address unknown"))?;
+        SocketAddr::new(remote, self.port)
+      },
+    };
+    match (self.socket.local_addr()?, destination) {
+      (SocketAddr::V6(_), SocketAddr::V4(v4)) => Ok(SocketAddr::new(IpAddr::V6(to_ipv6_addr(IpAddr::V4(*v4.ip()))), v4.port())),
+      _ => Ok(destination),
+    }
+  }
+
+  async fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+    let destination = self.destination().await?;
+    self.socket.send_to(data, destination).await.map_err(|e| TooLarge::map_send_error(e, data.len()))
+  }
+}
+
+impl DatagramSource for UdpTransport {
+  /// Receive the next EtherIP datagram from the remote, learning its port.
+  /// Datagrams from other addresses and keepalives are dropped.
+  async fn recv_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    loop {
+      let (mut len, buf) = datagram.datagram_mut();
+      let (n, src) = self.socket.recv_from(buf).await?;
+      let src = SocketAddr::new(canonical_ip(src.ip()), src.port());
+      if self.remote.lock().await.try_get_ip_addr() != Some(src.ip()) {
+        log::debug!(target: &link_target(&self.link_name), "Link {}: dropped a UDP datagram from {}", self.link_name, src);
+        continue;
+      }
+      let previous = self.endpoint.lock().replace(src);
+      if previous != Some(src) {
+        log::info!(target: &link_target(&self.link_name), "Link {}: peer endpoint is now {}", self.link_name, src);
+      }
+      if n <= ETHERIP_HEADER_SIZE {
+        continue;
+      }
+      len.set(n);
+      return Ok((n, src.ip()));
+    }
+  }
+}
+
+impl DatagramSink for UdpTransport {
+  async fn send_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, _dst_addr: &IpAddr) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    self.send(data).await
+  }
+
+  async fn send_datagrams(&self, datagrams: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    let mut results = Vec::with_capacity(datagrams.len());
+    for (data, _) in datagrams {
+      results.push(self.send(data).await);
+    }
+    Ok(results)
+  }
+
+  fn local_addr(&self) -> Option<IpAddr> {
+    UdpTransport::local_addr(self).ok().map(|addr| addr.ip()).filter(|addr| !addr.is_unspecified())
+  }
+}
+
+/// EtherIP over a UDP socket that the caller created and connected, for embedders who
+/// manage their own transport. Datagrams are sent to the connected peer whatever their
+/// destination, and are reported as received from it. There are no keepalives and no
+/// peer tracking, unlike `UdpTransport`.
+#[derive(Debug)]
+pub struct ConnectedUdpSocket {
+  socket: UdpSocket,
+  peer: IpAddr,
+}
+
+impl ConnectedUdpSocket {
+  /// Wrap a connected socket. Fails if the socket is not connected.
+  pub fn new(socket: UdpSocket) -> std::io::Result<Self> {
+    let peer = canonical_ip(socket.peer_addr()?.ip());
+    Ok(Self {
+      socket,
+      peer,
+    })
+  }
+
+  pub fn get_ref(&self) -> &UdpSocket {
+    &self.socket
+  }
+
+  pub fn into_inner(self) -> UdpSocket {
+    self.socket
+  }
+
+  async fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+    self.socket.send(data).await.map_err(|e| TooLarge::map_send_error(e, data.len()))
+  }
+}
+
+impl DatagramSource for ConnectedUdpSocket {
+  /// Receive the next EtherIP datagram from the peer. Datagrams without a frame are dropped.
+  async fn recv_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &mut D) -> std::io::Result<(usize, IpAddr)> {
+    loop {
+      let (mut len, buf) = datagram.datagram_mut();
+      let n = self.socket.recv(buf).await?;
+      if n <= ETHERIP_HEADER_SIZE {
+        continue;
+      }
+      len.set(n);
+      return Ok((n, self.peer));
+    }
+  }
+}
+
+impl DatagramSink for ConnectedUdpSocket {
+  async fn send_datagram<D: EtherIpBuffer + ?Sized>(&self, datagram: &D, _dst_addr: &IpAddr) -> std::io::Result<usize> {
+    let data = datagram.datagram().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid EtherIP Datagram"))?;
+    self.send(data).await
+  }
+
+  async fn send_datagrams(&self, datagrams: &[(&[u8], IpAddr)]) -> std::io::Result<Vec<std::io::Result<usize>>> {
+    let mut results = Vec::with_capacity(datagrams.len());
+    for (data, _) in datagrams {
+      results.push(self.send(data).await);
+    }
+    Ok(results)
+  }
+
+  fn local_addr(&self) -> Option<IpAddr> {
+    self.socket.local_addr().ok().map(|addr| canonical_ip(addr.ip())).filter(|addr| !addr.is_unspecified())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::config::IpVersion;
+  use crate::transport::MemoryDatagrams;
+  use crate::EtherIpDatagram;
+
+  async fn transport(local_port: u16) -> std::io::Result<UdpTransport> {
+    UdpTransport::bind("a".to_string(), AddrString::new("127.0.0.1".to_string(), IpVersion::V4), 2362, local_port, Duration::from_secs(10)).await
+  }
+
+  #[tokio::test]
+  async fn restarted_transport_rebinds_its_port() {
+    let first = transport(0).await.expect("bind");
+    let local_port = first.local_addr().unwrap().port();
+    // A second live process must not share the port, as it would split the traffic.
+    assert_eq!(transport(local_port).await.err().map(|e| e.kind()), Some(std::io::ErrorKind::AddrInUse));
+
+    // Without TIME_WAIT, the port is free again as soon as the socket is closed.
+    drop(first);
+    let restarted = transport(local_port).await.expect("rebind after a restart");
+    assert_eq!(restarted.local_addr().unwrap().port(), local_port);
+  }
+
+  #[test]
+  fn dual_stack_sockets_clear_v6only() {
+    let Ok(socket) = bind_ipv6(0, false) else {
+      // No IPv6 on this host.
+      return;
+    };
+    assert!(!v6only_option(socket.as_raw_fd()).expect("getsockopt"));
+
+    // So an IPv4 peer is reachable through its v4-mapped address.
+    let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mapped = SocketAddr::new(IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()), peer.local_addr().unwrap().port());
+    socket.send_to(b"v4-mapped", mapped).expect("send to a v4-mapped address");
+  }
+
+  #[tokio::test]
+  async fn transports_can_be_made_ipv6_only_and_back() {
+    let mut transport = transport(0).await.expect("bind");
+    let local_addr = transport.local_addr().unwrap();
+    if local_addr.is_ipv4() {
+      assert_eq!(transport.set_v6only(true).err().map(|e| e.kind()), Some(std::io::ErrorKind::Unsupported));
+      return;
+    }
+    assert!(!transport.is_v6only().unwrap());
+    let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mapped = SocketAddr::new(IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()), peer.local_addr().unwrap().port());
+
+    transport.set_v6only(true).expect("make IPv6-only");
+    assert!(transport.is_v6only().unwrap());
+    assert_eq!(transport.local_addr().unwrap(), local_addr);
+    assert!(transport.socket.send_to(b"v4-mapped", mapped).await.is_err());
+
+    transport.set_v6only(false).expect("make dual-stack");
+    assert!(!transport.is_v6only().unwrap());
+    assert_eq!(transport.local_addr().unwrap(), local_addr);
+    transport.socket.send_to(b"v4-mapped", mapped).await.expect("send to a v4-mapped address");
+  }
+
+  #[tokio::test]
+  async fn failed_rebinds_keep_the_previous_setting() {
+    let mut transport = transport(0).await.expect("bind");
+    let local_addr = transport.local_addr().unwrap();
+    if local_addr.is_ipv4() {
+      return;
+    }
+    // An IPv4 socket on the port only conflicts with the dual-stack socket to come.
+    transport.set_v6only(true).expect("make IPv6-only");
+    let _squatter = std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), local_addr.port())).expect("bind the IPv4 port");
+    assert_eq!(transport.set_v6only(false).err().map(|e| e.kind()), Some(std::io::ErrorKind::AddrInUse));
+    assert!(transport.is_v6only().unwrap());
+    assert_eq!(transport.local_addr().unwrap(), local_addr);
+  }
+
+  /// An EtherIP datagram carrying `frame`.
+  fn datagram_with(frame: &[u8]) -> EtherIpDatagram {
+    let mut datagram = EtherIpDatagram::new();
+    let (mut len, buf) = datagram.ethrnet_frame_mut();
+    buf[..frame.len()].copy_from_slice(frame);
+    len.set(frame.len());
+    datagram
+  }
+
+  /// Receive a datagram from one transport and send it on another, framing only.
+  async fn forward<S: DatagramSource, T: DatagramSink>(source: &S, sink: &T, dst_addr: IpAddr) -> IpAddr {
+    let mut datagram = EtherIpDatagram::new();
+    let (_, src_addr) = source.recv_datagram(&mut datagram).await.expect("receive");
+    sink.send_datagram(&datagram, &dst_addr).await.expect("send");
+    src_addr
+  }
+
+  /// A pair of UDP sockets on the loopback address, connected to each other.
+  async fn connected_pair() -> (UdpSocket, UdpSocket) {
+    let (a, b) = (UdpSocket::bind("127.0.0.1:0").await.unwrap(), UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    a.connect(b.local_addr().unwrap()).await.unwrap();
+    b.connect(a.local_addr().unwrap()).await.unwrap();
+    (a, b)
+  }
+
+  #[tokio::test]
+  async fn in_memory_transports_carry_the_framing() {
+    let frame = [0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x88, 0xb5, 0x5a];
+    let (inbound, outbound) = (MemoryDatagrams::new(), MemoryDatagrams::new());
+    inbound.push_received(datagram_with(&frame).datagram().unwrap(), "192.0.2.10".parse().unwrap());
+
+    assert_eq!(forward(&inbound, &outbound, "192.0.2.20".parse().unwrap()).await, "192.0.2.10".parse::<IpAddr>().unwrap());
+    let (sent, dst_addr) = outbound.take_sent().await.unwrap();
+    assert_eq!(dst_addr, "192.0.2.20".parse::<IpAddr>().unwrap());
+    assert_eq!(sent, datagram_with(&frame).datagram().unwrap());
+  }
+
+  #[tokio::test]
+  async fn connected_sockets_exchange_datagrams_with_their_peer() {
+    let (a, b) = connected_pair().await;
+    let (a, b) = (ConnectedUdpSocket::new(a).expect("connected"), ConnectedUdpSocket::new(b).expect("connected"));
+    assert_eq!(DatagramSink::local_addr(&a), Some("127.0.0.1".parse().unwrap()));
+
+    // A frame from memory goes to the peer, whatever the destination given.
+    let frame = [0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x88, 0xb5, 0x5a];
+    let inbound = MemoryDatagrams::new();
+    inbound.push_received(datagram_with(&frame).datagram().unwrap(), "192.0.2.10".parse().unwrap());
+    forward(&inbound, &a, "192.0.2.20".parse().unwrap()).await;
+    let mut received = EtherIpDatagram::new();
+    let (_, src_addr) = b.recv_datagram(&mut received).await.expect("receive");
+    assert_eq!(src_addr, "127.0.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(received.ethrnet_frame(), Some(&frame[..]));
+
+    // A bare EtherIP header is skipped, batches arrive in order.
+    let header_only = EtherIpHeader::default().encode();
+    let bare_frame = datagram_with(&frame[..14]);
+    let batch = [(&header_only[..], src_addr), (bare_frame.datagram().unwrap(), src_addr)];
+    assert!(a.send_datagrams(&batch).await.expect("send the batch").iter().all(Result::is_ok));
+    b.recv_datagram(&mut received).await.expect("receive");
+    assert_eq!(received.ethrnet_frame(), Some(&frame[..14]));
+  }
+
+  #[tokio::test]
+  async fn unconnected_sockets_are_refused() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    assert_eq!(ConnectedUdpSocket::new(socket).err().map(|e| e.kind()), Some(std::io::ErrorKind::NotConnected));
+  }
+}
@@ -0,0 +1,100 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+//! Detection of forwarding tasks that stopped making progress.
+//!
+//! A task advances a heartbeat each time it takes an item from its input. An idle task
+//! does not advance it either, so a task is only deemed stalled when its input has had
+//! something waiting for the whole timeout without the heartbeat advancing. Tasks stuck
+//! in a system call or a busy loop block their thread and cannot be recovered this way.
+
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::libc;
+
+/// Progress counter of a task.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+  beats: AtomicU64,
+}
+
+impl Heartbeat {
+  pub fn beat(&self) {
+    self.beats.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.beats.load(Ordering::Relaxed)
+  }
+}
+
+/// Whether something is waiting to be read from `fd`, without waiting.
+pub fn has_pending_input(fd: RawFd) -> std::io::Result<bool> {
+  let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+  if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  Ok(pollfd.revents & libc::POLLIN != 0)
+}
+
+struct WatchedTask<T> {
+  task: T,
+  progress: Box<dyn Fn() -> u64 + Send + Sync>,
+  input: Arc<dyn AsRawFd + Send + Sync>,
+  last_progress: u64,
+  /// Since when the input has had something waiting without progress.
+  stalled_since: Option<Instant>,
+}
+
+/// Tasks identified by `T`, watched for stalls.
+pub struct Watchdog<T> {
+  timeout: Duration,
+  tasks: Vec<WatchedTask<T>>,
+}
+
+impl<T: Clone> Watchdog<T> {
+  pub fn new(timeout: Duration) -> Self {
+    Self {
+      timeout,
+      tasks: Vec::new(),
+    }
+  }
+
+  /// Watch `task`, whose progress is read by `progress` and which reads from `input`.
+  pub fn watch<F>(&mut self, task: T, progress: F, input: Arc<dyn AsRawFd + Send + Sync>)
+  where
+    F: Fn() -> u64 + Send + Sync + 'static,
+  {
+    let last_progress = progress();
+    self.tasks.push(WatchedTask { task, progress: Box::new(progress), input, last_progress, stalled_since: None });
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tasks.is_empty()
+  }
+
+  /// Check the progress of all tasks, returning the ones stalled for the timeout.
+  /// Their stall is forgotten, so that they are reported again only after another timeout.
+  pub fn check(&mut self) -> Vec<T> {
+    let now = Instant::now();
+    let mut stalled = Vec::new();
+    for watched in &mut self.tasks {
+      let progress = (watched.progress)();
+      let pending = has_pending_input(watched.input.as_raw_fd()).unwrap_or(false);
+      if progress != watched.last_progress || !pending {
+        watched.last_progress = progress;
+        watched.stalled_since = None;
+        continue;
+      }
+      let stalled_since = *watched.stalled_since.get_or_insert(now);
+      if now.duration_since(stalled_since) >= self.timeout {
+        watched.stalled_since = None;
+        stalled.push(watched.task.clone());
+      }
+    }
+    stalled
+  }
+}
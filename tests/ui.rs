@@ -0,0 +1,11 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+// The buffer size is checked when `new_sized` is instantiated, so the cases are built
+// rather than only checked; having a passing case makes trybuild build them all.
+#[test]
+fn ui() {
+  let cases = trybuild::TestCases::new();
+  cases.pass("tests/ui/smallest_datagram.rs");
+  cases.compile_fail("tests/ui/undersized_datagram.rs");
+}
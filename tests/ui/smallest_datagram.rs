@@ -0,0 +1,14 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+use etherip::EtherIpBuffer;
+use etherip::EtherIpDatagram;
+
+fn main() {
+  // Room for the EtherIP header and a bare Ethernet header, and nothing more.
+  let mut datagram = EtherIpDatagram::<16>::new_sized();
+  let (mut len, buf) = datagram.ethrnet_frame_mut();
+  assert_eq!(buf.len(), 14);
+  len.set(14);
+  assert_eq!(datagram.ethrnet_frame().map(<[u8]>::len), Some(14));
+}
@@ -0,0 +1,8 @@
+// -*- indent-tabs-mode: nil; tab-width: 2; -*-
+// vim: set ts=&2 sw=2 et ai :
+
+use etherip::EtherIpDatagram;
+
+fn main() {
+  let _datagram = EtherIpDatagram::<10>::new_sized();
+}